@@ -0,0 +1,36 @@
+#!/bin/sh
+# Type-checks the crate, and builds its tests, with no default features, with
+# each optional feature on its own, and with pairs of features which share
+# code, so that code which uses another feature's items without enabling it is
+# caught.
+
+set -ex
+
+FEATURES="
+allocate
+duplicate
+locks
+statvfs
+utilities
+test-util
+strict
+io-uring
+json
+bincode
+allocate,locks
+allocate,statvfs
+allocate,utilities
+duplicate,locks
+locks,statvfs
+statvfs,utilities
+io-uring,allocate
+"
+
+export RUSTFLAGS="-D warnings"
+
+cargo check --no-default-features
+cargo test --no-run --no-default-features
+for feature in $FEATURES; do
+    cargo check --no-default-features --features "$feature"
+    cargo test --no-run --no-default-features --features "$feature"
+done
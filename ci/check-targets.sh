@@ -0,0 +1,48 @@
+#!/bin/sh
+# Type-checks the crate against every target in the platform matrix, so that
+# cfg lists which miss a target are caught without access to the platform.
+#
+# Tier 2 targets are checked with their prebuilt standard library. Tier 3
+# targets have none, so the standard library is built from source, which
+# requires a nightly toolchain with the rust-src component.
+
+set -ex
+
+TIER2_TARGETS="
+x86_64-unknown-linux-gnu
+i686-unknown-linux-gnu
+x86_64-unknown-linux-musl
+aarch64-linux-android
+armv7-linux-androideabi
+x86_64-apple-darwin
+aarch64-apple-ios
+x86_64-unknown-freebsd
+x86_64-unknown-netbsd
+x86_64-unknown-illumos
+x86_64-pc-solaris
+x86_64-unknown-fuchsia
+x86_64-unknown-redox
+x86_64-pc-windows-msvc
+i686-pc-windows-gnu
+wasm32-unknown-unknown
+"
+
+TIER3_TARGETS="
+aarch64-apple-tvos
+aarch64-apple-watchos
+aarch64-apple-visionos
+x86_64-unknown-openbsd
+x86_64-unknown-dragonfly
+x86_64-unknown-haiku
+aarch64-unknown-nto-qnx710
+powerpc64-ibm-aix
+"
+
+for target in $TIER2_TARGETS; do
+    rustup target add "$target"
+    cargo check --target "$target"
+done
+
+for target in $TIER3_TARGETS; do
+    cargo +nightly check -Z build-std --target "$target"
+done
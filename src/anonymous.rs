@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sys;
+
+/// A file with no name, which is deleted when closed.
+///
+/// Created with `anonymous_file`. On Linux the file is opened with
+/// `O_TMPFILE`, so it never appears in the directory, and can be given a name
+/// with `persist`. Elsewhere, and on Linux filesystems without `O_TMPFILE`
+/// support, the file is created under a temporary name and immediately
+/// unlinked, or opened with `FILE_FLAG_DELETE_ON_CLOSE` on Windows (where it
+/// remains visible in the directory until closed).
+#[derive(Debug)]
+pub struct AnonymousFile {
+    file: File,
+    linkable: bool,
+}
+
+impl AnonymousFile {
+    /// Returns `true` if the file can be given a name with `persist`.
+    pub fn is_persistable(&self) -> bool {
+        self.linkable
+    }
+
+    /// Links the file into the filesystem at `path`, which must not exist and
+    /// must be on the same filesystem as the file. The file remains open, and
+    /// is no longer deleted when closed.
+    ///
+    /// Fails with `ErrorKind::Unsupported` unless the file was opened with
+    /// `O_TMPFILE` (see `is_persistable`).
+    pub fn persist<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if !self.linkable {
+            return Err(Error::new(ErrorKind::Unsupported, "anonymous file can not be persisted"));
+        }
+        sys::link_tmpfile(&self.file, path.as_ref())
+    }
+
+    /// Unwraps the file.
+    pub fn into_file(self) -> File {
+        self.file
+    }
+}
+
+impl Deref for AnonymousFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for AnonymousFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+/// Creates an anonymous file in the directory `dir`, opened for reading and
+/// writing.
+///
+/// The file's disk space is reclaimed when it is closed, even if the process
+/// crashes, which makes it suitable for scratch space that is preallocated with
+/// `FileExt::allocate`.
+pub fn anonymous_file<P: AsRef<Path>>(dir: P) -> Result<AnonymousFile> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = dir.as_ref();
+    if let Some(file) = sys::open_tmpfile(dir)? {
+        return Ok(AnonymousFile { file, linkable: true });
+    }
+    let name = format!(".fs2-anonymous-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let file = sys::create_deleted(&dir.join(name))?;
+    Ok(AnonymousFile { file, linkable: false })
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Write;
+    #[cfg(feature = "allocate")]
+    use std::io::{Read, Seek, SeekFrom};
+
+    use anonymous_file;
+    #[cfg(feature = "allocate")]
+    use FileExt;
+
+    /// Tests that anonymous files can be used, and leave nothing behind.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn anonymous() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+
+        let mut file = anonymous_file(tempdir.path()).unwrap();
+        file.allocate(1024).unwrap();
+        file.write_all(b"scratch").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 7];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(b"scratch", &buf);
+
+        if cfg!(unix) {
+            assert_eq!(0, fs::read_dir(tempdir.path()).unwrap().count());
+        }
+        drop(file);
+        assert_eq!(0, fs::read_dir(tempdir.path()).unwrap().count());
+    }
+
+    /// Tests giving an anonymous file a name.
+    #[test]
+    fn anonymous_persist() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+
+        let mut file = anonymous_file(tempdir.path()).unwrap();
+        file.write_all(b"contents").unwrap();
+        if file.is_persistable() {
+            file.persist(&path).unwrap();
+            drop(file);
+            assert_eq!(b"contents".to_vec(), fs::read(&path).unwrap());
+        } else {
+            assert_eq!(::std::io::ErrorKind::Unsupported, file.persist(&path).unwrap_err().kind());
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs::{self, OpenOptions};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use AllocateOptions;
+
+/// An emergency reserve of disk space, held by a preallocated file.
+///
+/// When the file system fills up, releasing the ballast deletes the file and
+/// frees its space, giving operators room to recover, for example to run
+/// cleanup jobs or to let a database compact.
+///
+/// ```no_run
+/// use fs2::Ballast;
+///
+/// let ballast = Ballast::create("/var/lib/app/ballast", 1024 * 1024 * 1024).unwrap();
+/// // Later, when the disk is full:
+/// ballast.release().unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ballast {
+    path: PathBuf,
+    size: u64,
+}
+
+impl Ballast {
+    /// Creates a ballast file of `size` bytes at `path`, or resizes an
+    /// existing one.
+    ///
+    /// The space is allocated and verified as with
+    /// `FileExt::allocate_strict`, so the reserve is real even on file systems
+    /// which would otherwise create a sparse file, and the file is synced to
+    /// disk.
+    pub fn create<P>(path: P, size: u64) -> Result<Ballast> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if file.metadata()?.len() > size {
+            file.set_len(size)?;
+        }
+        AllocateOptions::new().verify(true).allocate(&file, size)?;
+        file.sync_all()?;
+        Ok(Ballast { path: path.to_path_buf(), size })
+    }
+
+    /// Opens an existing ballast file, for example one created by a previous
+    /// run of the process.
+    pub fn open<P>(path: P) -> Result<Ballast> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let size = fs::metadata(path)?.len();
+        Ok(Ballast { path: path.to_path_buf(), size })
+    }
+
+    /// Returns the path of the ballast file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the size of the ballast in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Deletes the ballast file, freeing its space. Returns the size of the
+    /// ballast.
+    ///
+    /// The space is only freed once every open handle to the file is closed.
+    pub fn release(self) -> Result<u64> {
+        fs::remove_file(&self.path)?;
+        Ok(self.size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+
+    use super::Ballast;
+    use FileExt;
+
+    /// Tests creating, reopening, and releasing a ballast.
+    #[test]
+    fn ballast() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("ballast");
+        let size = 1024 * 1024;
+
+        let ballast = Ballast::create(&path, size).unwrap();
+        assert_eq!(size, ballast.size());
+        let file = fs::File::open(&path).unwrap();
+        assert_eq!(size, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() >= size);
+        drop(file);
+
+        // Recreating a ballast resizes it.
+        Ballast::create(&path, size / 2).unwrap();
+        let ballast = Ballast::open(&path).unwrap();
+        assert_eq!(size / 2, ballast.size());
+        assert_eq!(path, ballast.path());
+
+        assert_eq!(size / 2, ballast.release().unwrap());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,153 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+/// A count of bytes, such as a file size or an amount of disk space.
+///
+/// Arithmetic is checked: helpers which could overflow return `None` or an
+/// error instead of wrapping, which matters on platforms whose file systems
+/// report sizes in units wider than 64 bits, or which hand back signed sizes.
+///
+/// `Display` formats the count with binary units, e.g. `1.5 GiB`.
+///
+/// ```
+/// use fs2::Bytes;
+///
+/// let size = Bytes::from_blocks(4096, 1 << 20).unwrap();
+/// assert_eq!(4 << 30, size.as_u64());
+/// assert_eq!("4.0 GiB", size.to_string());
+/// assert!(Bytes::from_blocks(4096, u64::MAX).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(u64);
+
+impl Bytes {
+    /// Creates a count of `bytes` bytes.
+    pub fn new(bytes: u64) -> Bytes {
+        Bytes(bytes)
+    }
+
+    /// Returns the size of `count` blocks of `block_size` bytes, failing with
+    /// `ErrorKind::InvalidData` if it overflows a 64-bit byte count.
+    pub fn from_blocks(block_size: u64, count: u64) -> Result<Bytes> {
+        block_size.checked_mul(count).map(Bytes).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "size overflows a 64-bit byte count")
+        })
+    }
+
+    /// Converts a signed size, such as a Windows `LARGE_INTEGER`, failing with
+    /// `ErrorKind::InvalidData` if it is negative.
+    pub fn from_i64(bytes: i64) -> Result<Bytes> {
+        u64::try_from(bytes).map(Bytes).map_err(|_| {
+            Error::new(ErrorKind::InvalidData, "size is negative")
+        })
+    }
+
+    /// Returns the count as a `u64`.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Converts the count to a signed size, such as a Windows `LARGE_INTEGER`,
+    /// failing with `ErrorKind::InvalidInput` if it is too large.
+    pub fn to_i64(self) -> Result<i64> {
+        i64::try_from(self.0).map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, "file offset or length is too large for this platform")
+        })
+    }
+
+    /// Adds two counts, returning `None` on overflow.
+    pub fn checked_add(self, other: Bytes) -> Option<Bytes> {
+        self.0.checked_add(other.0).map(Bytes)
+    }
+
+    /// Subtracts a count, returning `None` if it is larger than this count.
+    pub fn checked_sub(self, other: Bytes) -> Option<Bytes> {
+        self.0.checked_sub(other.0).map(Bytes)
+    }
+
+    /// Subtracts a count, stopping at zero.
+    pub fn saturating_sub(self, other: Bytes) -> Bytes {
+        Bytes(self.0.saturating_sub(other.0))
+    }
+
+    /// Multiplies the count, returning `None` on overflow.
+    pub fn checked_mul(self, factor: u64) -> Option<Bytes> {
+        self.0.checked_mul(factor).map(Bytes)
+    }
+
+    /// Rounds the count up to a multiple of `unit`, such as a cluster size,
+    /// returning `None` on overflow. A `unit` of 0 leaves the count unchanged.
+    pub fn checked_round_up(self, unit: u64) -> Option<Bytes> {
+        if unit == 0 {
+            return Some(self);
+        }
+        self.0.div_ceil(unit).checked_mul(unit).map(Bytes)
+    }
+}
+
+impl From<u64> for Bytes {
+    fn from(bytes: u64) -> Bytes {
+        Bytes(bytes)
+    }
+}
+
+impl From<Bytes> for u64 {
+    fn from(bytes: Bytes) -> u64 {
+        bytes.0
+    }
+}
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::ErrorKind;
+
+    use super::Bytes;
+
+    /// Tests that conversions and arithmetic fail rather than wrap.
+    #[test]
+    fn checked() {
+        assert_eq!(Bytes::new(4096 * 10), Bytes::from_blocks(4096, 10).unwrap());
+        assert_eq!(ErrorKind::InvalidData, Bytes::from_blocks(4096, u64::MAX).unwrap_err().kind());
+
+        assert_eq!(Bytes::new(1), Bytes::from_i64(1).unwrap());
+        assert_eq!(ErrorKind::InvalidData, Bytes::from_i64(-1).unwrap_err().kind());
+        assert_eq!(i64::MAX, Bytes::new(i64::MAX as u64).to_i64().unwrap());
+        assert_eq!(ErrorKind::InvalidInput, Bytes::new(u64::MAX).to_i64().unwrap_err().kind());
+
+        assert_eq!(None, Bytes::new(u64::MAX).checked_add(Bytes::new(1)));
+        assert_eq!(None, Bytes::new(0).checked_sub(Bytes::new(1)));
+        assert_eq!(Bytes::new(0), Bytes::new(0).saturating_sub(Bytes::new(1)));
+        assert_eq!(None, Bytes::new(u64::MAX).checked_mul(2));
+
+        assert_eq!(Some(Bytes::new(8192)), Bytes::new(4097).checked_round_up(4096));
+        assert_eq!(Some(Bytes::new(5)), Bytes::new(5).checked_round_up(0));
+        assert_eq!(None, Bytes::new(u64::MAX).checked_round_up(4096));
+    }
+
+    /// Tests formatting byte counts with binary units.
+    #[test]
+    fn display() {
+        assert_eq!("0 B", Bytes::new(0).to_string());
+        assert_eq!("1023 B", Bytes::new(1023).to_string());
+        assert_eq!("1.5 KiB", Bytes::new(1536).to_string());
+        assert_eq!("16.0 EiB", Bytes::new(u64::MAX).to_string());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::{File, OpenOptions};
+use std::cmp;
+use std::io::{Read, Result, Write};
+use std::path::Path;
+
+use progress::{self, Progress};
+use sys;
+
+/// The most data copied between progress updates.
+const CHUNK: u64 = 8 << 20;
+
+/// The method used by `copy_fast` to copy a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CopyStrategy {
+    /// The copy shares the source's disk blocks until either is modified
+    /// (`FICLONE` on Linux, `FSCTL_DUPLICATE_EXTENTS_TO_FILE` on Windows).
+    Reflink,
+    /// The data was copied within the kernel with `copy_file_range(2)`, which
+    /// may itself share blocks or offload the copy to the storage device.
+    CopyFileRange,
+    /// The data was copied within the kernel with `sendfile(2)`.
+    Sendfile,
+    /// The data was read into and written from a buffer.
+    Buffered,
+}
+
+/// Copies the contents and permissions of the file at `src` to `dst`,
+/// creating or truncating `dst`, with the fastest method the platform and
+/// filesystems support. Returns the method which was used.
+///
+/// Each method is tried in the order of `CopyStrategy`, falling back to the
+/// next when the platform, filesystem, or pair of files does not support it
+/// (for example, a reflink between filesystems). Once a method has copied any
+/// data, its errors are returned instead.
+pub fn copy_fast<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<CopyStrategy> {
+    copy_fast_with_progress(src, dst, &mut Progress::new())
+}
+
+/// Like `copy_fast`, but reports progress and checks for cancellation after
+/// every chunk of up to 8 MiB. A reflink is reported as a single chunk.
+///
+/// The total is the size the source reported when it was opened, or the
+/// number of bytes copied so far if that is larger. The source is copied to
+/// its end regardless, even if it grew or reports no size at all.
+///
+/// If the copy is cancelled, `dst` is left partially written.
+pub fn copy_fast_with_progress<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, progress: &mut Progress)
+                                                              -> Result<CopyStrategy> {
+    let mut src = File::open(src)?;
+    let metadata = src.metadata()?;
+    let mut dst = OpenOptions::new().write(true).create(true).truncate(true).open(dst)?;
+    dst.set_permissions(metadata.permissions())?;
+    let len = metadata.len();
+
+    match sys::reflink(&src, &dst) {
+        Ok(()) => {
+            progress::update(progress, len, len)?;
+            return Ok(CopyStrategy::Reflink);
+        }
+        Err(ref err) if sys::is_copy_unsupported(err) => (),
+        Err(err) => return Err(err),
+    }
+    if copy_chunks(len, progress, |offset, len| sys::copy_file_range(&src, &dst, offset, len))? {
+        return Ok(CopyStrategy::CopyFileRange);
+    }
+    if copy_chunks(len, progress, |offset, len| sys::sendfile_to_file(&src, &dst, offset, len))? {
+        return Ok(CopyStrategy::Sendfile);
+    }
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut done = 0;
+    let mut since_update = 0;
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref err) if err.kind() == ::std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        dst.write_all(&buf[..n])?;
+        done += n as u64;
+        since_update += n as u64;
+        if since_update >= CHUNK {
+            since_update = 0;
+            progress::update(progress, done, cmp::max(done, len))?;
+        }
+    }
+    progress::update(progress, done, cmp::max(done, len))?;
+    Ok(CopyStrategy::Buffered)
+}
+
+/// Copies the source with `copy`, which copies a chunk at an offset, until it
+/// reaches the end of the source. `len` is only the expected total reported to
+/// `progress`, since files such as those in `/proc` report a size of 0.
+/// Returns `false` if the first chunk fails because the method is not
+/// supported, or copies nothing, in which case the next method is tried.
+fn copy_chunks<F>(len: u64, progress: &mut Progress, mut copy: F) -> Result<bool>
+where F: FnMut(u64, u64) -> Result<u64> {
+    let mut offset = 0;
+    loop {
+        match copy(offset, CHUNK) {
+            Ok(0) if offset == 0 => return Ok(false),
+            Ok(0) => break,
+            Ok(n) => offset += n,
+            Err(ref err) if offset == 0 && sys::is_copy_unsupported(err) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        progress::update(progress, offset, cmp::max(offset, len))?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::cell::Cell;
+    use std::fs;
+    use std::sync::atomic::AtomicBool;
+
+    use {copy_fast, copy_fast_with_progress, Cancelled, Progress};
+
+    /// Tests copying files of various sizes.
+    #[test]
+    fn copy() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let src = tempdir.path().join("src");
+        let dst = tempdir.path().join("dst");
+
+        for &len in &[0, 1, 4096, 1 << 20] {
+            let contents = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            fs::write(&src, &contents).unwrap();
+            let strategy = copy_fast(&src, &dst).unwrap();
+            assert_eq!(contents, fs::read(&dst).unwrap(), "copied {} bytes with {:?}", len, strategy);
+        }
+
+        // Copying over a longer file truncates it.
+        fs::write(&src, b"short").unwrap();
+        copy_fast(&src, &dst).unwrap();
+        assert_eq!(b"short".to_vec(), fs::read(&dst).unwrap());
+    }
+
+    /// Tests progress reporting and cancellation while copying.
+    #[test]
+    fn copy_progress() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let src = tempdir.path().join("src");
+        let dst = tempdir.path().join("dst");
+        let len = 20 << 20;
+        fs::write(&src, vec![1; len as usize]).unwrap();
+
+        let last = Cell::new((0, 0));
+        copy_fast_with_progress(&src, &dst, Progress::new().on_progress(|done, total| last.set((done, total))))
+            .unwrap();
+        assert_eq!((len, len), last.get());
+
+        let cancel = AtomicBool::new(true);
+        let err = copy_fast_with_progress(&src, &dst, Progress::new().cancel_flag(&cancel)).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<Cancelled>());
+    }
+
+    /// Tests copying a file which reports a size of 0, but has contents.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copy_unsized() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let dst = tempdir.path().join("dst");
+        assert_eq!(0, fs::metadata("/proc/version").unwrap().len());
+
+        let last = Cell::new((0, 0));
+        copy_fast_with_progress("/proc/version", &dst,
+                                Progress::new().on_progress(|done, total| last.set((done, total))))
+            .unwrap();
+        let contents = fs::read("/proc/version").unwrap();
+        assert!(!contents.is_empty());
+        assert_eq!(contents, fs::read(&dst).unwrap());
+        assert_eq!((contents.len() as u64, contents.len() as u64), last.get());
+    }
+}
@@ -0,0 +1,184 @@
+//! Errors carrying the context of a failed operation.
+//!
+//! `Error` is also exported at the crate root. `Result` is only exported
+//! here, so that `use fs2::*` does not shadow the prelude's `Result`.
+
+use std::error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::result;
+
+/// A `Result` whose error carries the context of the failed operation.
+pub type Result<T> = result::Result<T, Error>;
+
+/// An I/O error with the context it occurred in: the operation which failed,
+/// and the path or handle it failed on.
+///
+/// Functions returning `io::Result`, such as `statvfs` and `free_space`, wrap
+/// this error inside an `io::Error` of the same kind, so existing error
+/// handling keeps working; use `Error::of` to recover the context. The
+/// wrapping `io::Error` does not report the raw OS error code itself, which is
+/// available from `raw_os_error`.
+///
+/// ```no_run
+/// # #[cfg(feature = "statvfs")]
+/// # fn main() {
+/// use fs2::Error;
+///
+/// if let Err(err) = fs2::statvfs("/mnt/data") {
+///     match Error::of(&err) {
+///         Some(context) => eprintln!("{}", context),
+///         None => eprintln!("statvfs failed: {}", err),
+///     }
+/// }
+/// # }
+/// # #[cfg(not(feature = "statvfs"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct Error {
+    operation: &'static str,
+    path: Option<PathBuf>,
+    handle: Option<u64>,
+    source: io::Error,
+}
+
+impl Error {
+    /// Creates an error from `source`, which occurred during `operation`.
+    pub fn new(operation: &'static str, source: io::Error) -> Error {
+        Error { operation, path: None, handle: None, source }
+    }
+
+    /// Sets the path the operation failed on.
+    pub fn with_path<P>(mut self, path: P) -> Error where P: Into<PathBuf> {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Sets the raw descriptor or handle value the operation failed on.
+    pub fn with_handle(mut self, handle: u64) -> Error {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// Returns the context of `err`, if it carries any.
+    pub fn of(err: &io::Error) -> Option<&Error> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<Error>())
+    }
+
+    /// Returns the name of the failed operation, such as `"statvfs"`.
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// Returns the path the operation failed on, if known.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the raw descriptor or handle value the operation failed on, if
+    /// known.
+    pub fn handle(&self) -> Option<u64> {
+        self.handle
+    }
+
+    /// Returns the kind of the underlying error.
+    pub fn kind(&self) -> ErrorKind {
+        self.source.kind()
+    }
+
+    /// Returns the raw OS error code of the underlying error, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    /// Returns the underlying error.
+    pub fn inner(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// Unwraps the underlying error.
+    pub fn into_inner(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.operation)?;
+        if let Some(ref path) = self.path {
+            write!(f, " {}", path.display())?;
+        }
+        if let Some(handle) = self.handle {
+            write!(f, " (handle {})", handle)?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Wraps the error in an `io::Error` of the same kind.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Unwraps an `io::Error` which carries context, or gives it the operation
+/// `"io"`.
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        if Error::of(&err).is_none() {
+            return Error::new("io", err);
+        }
+        *err.into_inner().unwrap().downcast::<Error>().unwrap()
+    }
+}
+
+/// Returns a function which adds the context of `operation` on `path` to an
+/// error.
+#[cfg(feature = "statvfs")]
+pub(crate) fn with_path<'a>(operation: &'static str, path: &'a Path) -> impl FnOnce(io::Error) -> io::Error + 'a {
+    move |source| Error::new(operation, source).with_path(path).into()
+}
+
+#[cfg(all(test, feature = "statvfs"))]
+mod test {
+
+    use std::error::Error as StdError;
+    use std::io::{self, ErrorKind};
+    use std::path::Path;
+
+    use super::{with_path, Error};
+
+    /// Tests that errors keep their kind and OS error code, and gain context.
+    #[test]
+    fn error_context() {
+        let err = with_path("statvfs", Path::new("/missing"))(io::Error::from_raw_os_error(2));
+        assert_eq!(io::Error::from_raw_os_error(2).kind(), err.kind());
+        assert!(err.to_string().starts_with("statvfs /missing: "), "{}", err);
+
+        let context = Error::of(&err).unwrap();
+        assert_eq!("statvfs", context.operation());
+        assert_eq!(Some(Path::new("/missing")), context.path());
+        assert_eq!(None, context.handle());
+        assert_eq!(Some(2), context.raw_os_error());
+        assert!(context.source().is_some());
+
+        // Converting back recovers the context.
+        let err = Error::from(err);
+        assert_eq!(Some(2), err.raw_os_error());
+        assert_eq!("statvfs", err.operation());
+
+        let err = Error::from(io::Error::from(ErrorKind::Other)).with_handle(3);
+        assert_eq!("io", err.operation());
+        assert!(err.to_string().starts_with("io (handle 3): "), "{}", err);
+        assert!(Error::of(&io::Error::from(ErrorKind::Other)).is_none());
+    }
+}
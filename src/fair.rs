@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::io::Result;
+use std::sync::{Mutex, MutexGuard};
+
+use positional::{read_at, write_at};
+use {lock_unpoisoned, sys, LockMode};
+
+/// The next ticket to be issued, stored as a little-endian `u64`. The range is
+/// locked exclusively while a ticket is being taken.
+const COUNTER: u64 = 0;
+const COUNTER_LEN: u64 = 8;
+
+/// Each ticket is represented by a byte at `SLOTS_BASE + ticket % SLOTS`,
+/// which is locked exclusively from when the ticket is taken until the lock
+/// is released. The ring is kept below 2 GiB so that offsets fit a 32-bit
+/// `off_t`.
+const SLOTS_BASE: u64 = 1 << 30;
+const SLOTS: u64 = 1 << 30;
+
+/// A waiter waits for the slots of up to this many preceding tickets, so this
+/// many tickets may be outstanding at once before the ring wraps onto them.
+const WINDOW: u64 = SLOTS / 2;
+
+fn slot(ticket: u64) -> u64 {
+    SLOTS_BASE + ticket % SLOTS
+}
+
+/// An exclusive file lock granted in first-come, first-served order.
+///
+/// Plain file locks make no fairness guarantees, and a process which
+/// repeatedly releases and reacquires a lock may starve the others. A
+/// `FairFileLock` issues each waiter a ticket from a counter stored at the
+/// start of the file, and grants the lock to tickets in order: each waiter
+/// blocks until the byte-range locks held by every earlier ticket are
+/// released. If a process exits while waiting, its ticket is skipped, but the
+/// waiters behind it still wait for the tickets ahead of it; if it exits
+/// holding the lock, the lock passes to the next ticket.
+///
+/// Every process using the lock must use a `FairFileLock`, and the first 8
+/// bytes of the file are reserved for the counter. Threads sharing one
+/// `FairFileLock` are serialized by an in-process mutex, which is not FIFO.
+/// See `FileSemaphore` for the platform notes on byte-range locks.
+#[derive(Debug)]
+pub struct FairFileLock {
+    file: File,
+    local: Mutex<()>,
+}
+
+impl FairFileLock {
+    /// Wraps a file to be locked. The file must be opened for reading and
+    /// writing, and is made non-inheritable.
+    pub fn new(file: File) -> FairFileLock {
+        let _ = sys::set_inheritable(&file, false);
+        FairFileLock { file, local: Mutex::new(()) }
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Locks the file, blocking until every earlier waiter has held and
+    /// released the lock. The lock is released when the returned guard is
+    /// dropped.
+    pub fn lock(&self) -> Result<FairLockGuard<'_>> {
+        let local = lock_unpoisoned(&self.local);
+
+        sys::lock_range(&self.file, COUNTER, COUNTER_LEN, LockMode::Exclusive, true)?;
+        let ticket = self.take_ticket();
+        sys::unlock_range(&self.file, COUNTER, COUNTER_LEN)?;
+        let ticket = ticket?;
+
+        if let Err(err) = self.wait_for_predecessors(ticket) {
+            let _ = sys::unlock_range(&self.file, slot(ticket), 1);
+            return Err(err);
+        }
+
+        Ok(FairLockGuard { lock: self, ticket, _local: local })
+    }
+
+    /// Blocks until the slots of every earlier ticket in the window are
+    /// unlocked. Waiting on all of them, rather than only the predecessor,
+    /// keeps a waiter which gave up from admitting its successor early.
+    fn wait_for_predecessors(&self, ticket: u64) -> Result<()> {
+        let (start, end) = (slot(ticket.saturating_sub(WINDOW)), slot(ticket));
+        // The window may wrap around the end of the ring.
+        let ranges = if start <= end {
+            [(start, end - start), (SLOTS_BASE, 0)]
+        } else {
+            [(start, SLOTS_BASE + SLOTS - start), (SLOTS_BASE, end - SLOTS_BASE)]
+        };
+        for &(offset, len) in ranges.iter().filter(|&&(_, len)| len > 0) {
+            sys::lock_range(&self.file, offset, len, LockMode::Shared, true)?;
+            sys::unlock_range(&self.file, offset, len)?;
+        }
+        Ok(())
+    }
+
+    /// Issues the next ticket and locks its slot. The counter must be locked.
+    fn take_ticket(&self) -> Result<u64> {
+        let mut buf = [0; COUNTER_LEN as usize];
+        // A file too short to hold the counter has issued no tickets.
+        let ticket = if read_at(&self.file, &mut buf, COUNTER)? == buf.len() {
+            u64::from_le_bytes(buf)
+        } else {
+            0
+        };
+        write_at(&self.file, &ticket.wrapping_add(1).to_le_bytes(), COUNTER)?;
+        sys::lock_range(&self.file, slot(ticket), 1, LockMode::Exclusive, true)?;
+        Ok(ticket)
+    }
+}
+
+/// A lock held on a `FairFileLock`, which is released when dropped.
+#[derive(Debug)]
+pub struct FairLockGuard<'a> {
+    lock: &'a FairFileLock,
+    ticket: u64,
+    _local: MutexGuard<'a, ()>,
+}
+
+impl<'a> FairLockGuard<'a> {
+    /// Returns the ticket with which the lock was acquired. Tickets are
+    /// granted in increasing order.
+    pub fn ticket(&self) -> u64 {
+        self.ticket
+    }
+}
+
+impl<'a> Drop for FairLockGuard<'a> {
+    fn drop(&mut self) {
+        let _ = sys::unlock_range(&self.lock.file, slot(self.ticket), 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{COUNTER, COUNTER_LEN, slot};
+    use {sys, FairFileLock, LockMode};
+
+    fn open(path: &Path) -> File {
+        fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+    }
+
+    fn tickets_issued(path: &Path) -> u64 {
+        let bytes = fs::read(path).unwrap();
+        let mut counter = [0; 8];
+        if bytes.len() >= 8 {
+            counter.copy_from_slice(&bytes[..8]);
+        }
+        u64::from_le_bytes(counter)
+    }
+
+    /// Tests that tickets are issued and granted in order.
+    #[test]
+    fn fair_lock_tickets() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let lock = FairFileLock::new(open(&path));
+
+        assert_eq!(0, lock.lock().unwrap().ticket());
+        assert_eq!(1, lock.lock().unwrap().ticket());
+        assert_eq!(2, tickets_issued(&path));
+    }
+
+    /// Tests that waiters are granted the lock in the order they arrived.
+    #[cfg(any(windows, target_os = "linux", target_os = "android"))]
+    #[test]
+    fn fair_lock_fifo() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let lock = FairFileLock::new(open(&path));
+        let guard = lock.lock().unwrap();
+
+        let mut waiters = Vec::new();
+        for waiter in 0..3 {
+            let waiter_path = path.clone();
+            let order = order.clone();
+            waiters.push(thread::spawn(move || {
+                let lock = FairFileLock::new(open(&waiter_path));
+                let guard = lock.lock().unwrap();
+                order.lock().unwrap().push(waiter);
+                drop(guard);
+            }));
+            // Wait for the waiter to take its ticket before starting the next.
+            while tickets_issued(&path) < waiter + 2 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        assert!(order.lock().unwrap().is_empty());
+        drop(guard);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(vec![0, 1, 2], *order.lock().unwrap());
+    }
+
+    /// Tests that a waiter which gives up does not admit the waiters behind it
+    /// while the lock is still held.
+    #[cfg(any(windows, target_os = "linux", target_os = "android"))]
+    #[test]
+    fn fair_lock_cancelled_waiter() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let lock = FairFileLock::new(open(&path));
+        let guard = lock.lock().unwrap();
+
+        // A waiter takes a ticket, then gives up without acquiring the lock.
+        let cancelled = FairFileLock::new(open(&path));
+        sys::lock_range(&cancelled.file, COUNTER, COUNTER_LEN, LockMode::Exclusive, true).unwrap();
+        let ticket = cancelled.take_ticket().unwrap();
+        sys::unlock_range(&cancelled.file, COUNTER, COUNTER_LEN).unwrap();
+        sys::unlock_range(&cancelled.file, slot(ticket), 1).unwrap();
+
+        let waiter_path = path.clone();
+        let waiter_order = order.clone();
+        let waiter = thread::spawn(move || {
+            let lock = FairFileLock::new(open(&waiter_path));
+            let guard = lock.lock().unwrap();
+            waiter_order.lock().unwrap().push(guard.ticket());
+        });
+        while tickets_issued(&path) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(order.lock().unwrap().is_empty());
+        drop(guard);
+        waiter.join().unwrap();
+        assert_eq!(vec![2], *order.lock().unwrap());
+    }
+}
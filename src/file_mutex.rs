@@ -0,0 +1,301 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::str::{self, FromStr};
+use std::thread;
+
+#[cfg(any(feature = "bincode", feature = "json"))]
+use serde::de::DeserializeOwned;
+#[cfg(any(feature = "bincode", feature = "json"))]
+use serde::Serialize;
+
+use positional::{read_at, write_at};
+use {FileExt, FileLockGuard};
+
+/// Converts values to and from the bytes stored by a `FileMutex`.
+pub trait Codec<T> {
+    /// Encodes a value.
+    fn encode(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a value. The bytes are never empty; an empty file holds the
+    /// default value.
+    fn decode(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// A codec storing the bytes of a `Vec<u8>` as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytesCodec;
+
+impl Codec<Vec<u8>> for BytesCodec {
+    fn encode(&self, value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// A codec storing values as UTF-8 text, with `Display` and `FromStr`.
+///
+/// This suits strings, numbers, and other types with a textual form which
+/// round-trips.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TextCodec;
+
+impl<T> Codec<T> for TextCodec where T: Display + FromStr, T::Err: Display {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(value.to_string().into_bytes())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        let text = str::from_utf8(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        text.parse().map_err(|err: T::Err| Error::new(ErrorKind::InvalidData, err.to_string()))
+    }
+}
+
+/// A codec storing values as JSON, with `serde_json`.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl<T> Codec<T> for JsonCodec where T: Serialize + DeserializeOwned {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(Error::from)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(Error::from)
+    }
+}
+
+/// A codec storing values in bincode's compact binary format.
+///
+/// The format is not self-describing, so changing the type stored in a file
+/// makes its existing contents undecodable, or decodes them wrongly.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl<T> Codec<T> for BincodeCodec where T: Serialize + DeserializeOwned {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+/// A value stored in a file, shared between processes.
+///
+/// Locking the mutex takes an exclusive lock on the file and loads the value
+/// with the codec. If the value is modified through the guard, it is written
+/// back and flushed to disk with `File::sync_data` when the guard is released.
+/// An empty file holds `T::default()`.
+///
+/// The value is rewritten in place, so a crash while it is being saved may
+/// leave the file corrupt. Combine with `PoisonFileLock`, or use a codec with
+/// a checksum, if that must be detected.
+#[derive(Debug)]
+pub struct FileMutex<T, C> {
+    file: File,
+    codec: C,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T, C> FileMutex<T, C> where T: Default, C: Codec<T> {
+    /// Wraps a file holding a value encoded with `codec`. The file must be
+    /// opened for reading and writing, and is made non-inheritable.
+    pub fn new(file: File, codec: C) -> FileMutex<T, C> {
+        let _ = file.set_inheritable(false);
+        FileMutex { file, codec, value: PhantomData }
+    }
+
+    /// Returns the underlying file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Unwraps the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Locks the mutex and loads its value, blocking until the lock is
+    /// available. Changes to the value are saved when the guard is dropped.
+    pub fn lock(&self) -> Result<FileMutexGuard<'_, T, C>> {
+        let lock = self.file.lock_exclusive_guard()?;
+        let value = self.load()?;
+        Ok(FileMutexGuard { mutex: self, value, modified: false, _lock: lock })
+    }
+
+    /// Loads a copy of the value under a shared lock.
+    pub fn read(&self) -> Result<T> {
+        let _lock = self.file.lock_shared_guard()?;
+        self.load()
+    }
+
+    fn load(&self) -> Result<T> {
+        let mut bytes = Vec::new();
+        let mut buf = [0; 8192];
+        loop {
+            match read_at(&self.file, &mut buf, bytes.len() as u64)? {
+                0 => break,
+                n => bytes.extend_from_slice(&buf[..n]),
+            }
+        }
+        if bytes.is_empty() {
+            Ok(T::default())
+        } else {
+            self.codec.decode(&bytes)
+        }
+    }
+
+    fn store(&self, value: &T) -> Result<()> {
+        let bytes = self.codec.encode(value)?;
+        write_at(&self.file, &bytes, 0)?;
+        self.file.set_len(bytes.len() as u64)?;
+        self.file.sync_data()
+    }
+}
+
+/// A lock held on a `FileMutex`, which dereferences to the loaded value.
+///
+/// Dropping the guard saves the value if it was modified, ignoring errors,
+/// unless the thread is panicking. Use `save` to observe errors.
+#[derive(Debug)]
+pub struct FileMutexGuard<'a, T, C> where T: Default + 'a, C: Codec<T> + 'a {
+    mutex: &'a FileMutex<T, C>,
+    value: T,
+    modified: bool,
+    _lock: FileLockGuard<'a>,
+}
+
+impl<'a, T, C> FileMutexGuard<'a, T, C> where T: Default, C: Codec<T> {
+    /// Saves the value if it was modified, and releases the lock.
+    pub fn save(mut self) -> Result<()> {
+        if self.modified {
+            self.modified = false;
+            self.mutex.store(&self.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T, C> Deref for FileMutexGuard<'a, T, C> where T: Default, C: Codec<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'a, T, C> DerefMut for FileMutexGuard<'a, T, C> where T: Default, C: Codec<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.modified = true;
+        &mut self.value
+    }
+}
+
+impl<'a, T, C> Drop for FileMutexGuard<'a, T, C> where T: Default, C: Codec<T> {
+    fn drop(&mut self) {
+        if self.modified && !thread::panicking() {
+            let _ = self.mutex.store(&self.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    use {BytesCodec, FileMutex, TextCodec};
+
+    fn open(path: &Path) -> fs::File {
+        fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+    }
+
+    /// Tests that values are loaded on lock and saved on release.
+    #[test]
+    fn file_mutex() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mutex1 = FileMutex::<u64, _>::new(open(&path), TextCodec);
+        let mutex2 = FileMutex::<u64, _>::new(open(&path), TextCodec);
+
+        assert_eq!(0, *mutex1.lock().unwrap());
+        *mutex1.lock().unwrap() += 1000;
+        assert_eq!(1000, mutex2.read().unwrap());
+
+        // Shorter values truncate the file.
+        let mut guard = mutex2.lock().unwrap();
+        *guard = 7;
+        guard.save().unwrap();
+        assert_eq!("7", fs::read_to_string(&path).unwrap());
+        assert_eq!(7, mutex1.read().unwrap());
+
+        fs::write(&path, "not a number").unwrap();
+        assert_eq!(ErrorKind::InvalidData, mutex1.lock().unwrap_err().kind());
+    }
+
+    /// Tests that unmodified values are not rewritten.
+    #[test]
+    fn file_mutex_unmodified() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mutex = FileMutex::new(open(&path), BytesCodec);
+
+        assert_eq!(Vec::<u8>::new(), *mutex.lock().unwrap());
+        assert_eq!(0, path.metadata().unwrap().len());
+        mutex.lock().unwrap().extend_from_slice(b"abc");
+        assert_eq!(b"abc".to_vec(), mutex.read().unwrap());
+    }
+
+    /// Tests storing values as JSON.
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_codec() {
+        use std::collections::BTreeMap;
+        use JsonCodec;
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mutex = FileMutex::<BTreeMap<String, u64>, _>::new(open(&path), JsonCodec);
+
+        mutex.lock().unwrap().insert("hits".to_owned(), 3);
+        assert_eq!(r#"{"hits":3}"#, fs::read_to_string(&path).unwrap());
+        assert_eq!(Some(&3), mutex.read().unwrap().get("hits"));
+
+        fs::write(&path, "{").unwrap();
+        assert_eq!(ErrorKind::UnexpectedEof, mutex.read().unwrap_err().kind());
+        fs::write(&path, "[]").unwrap();
+        assert_eq!(ErrorKind::InvalidData, mutex.read().unwrap_err().kind());
+    }
+
+    /// Tests storing values with bincode.
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_codec() {
+        use BincodeCodec;
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mutex = FileMutex::<(u32, String), _>::new(open(&path), BincodeCodec);
+
+        *mutex.lock().unwrap() = (7, "seven".to_owned());
+        assert_eq!((7, "seven".to_owned()), mutex.read().unwrap());
+
+        fs::write(&path, [1]).unwrap();
+        assert_eq!(ErrorKind::InvalidData, mutex.read().unwrap_err().kind());
+    }
+}
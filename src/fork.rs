@@ -0,0 +1,246 @@
+//! Handling of held whole-file locks in the child of `fork(2)`.
+//!
+//! A forked child inherits its parent's descriptors, and with them the
+//! parent's `flock` and open file description locks: both processes then own
+//! the same lock, and either one unlocking releases it for both. POSIX record
+//! locks are not inherited at all. With a policy other than `Inherit`, handlers
+//! registered with `pthread_atfork` give each handle holding a whole-file lock
+//! a description of its own in the child, which shares no locks with the
+//! parent.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What happens to the whole-file locks this process holds when it forks, as
+/// set with `fork_policy`.
+///
+/// The policy applies to locks taken through `FileExt`, its guards,
+/// `TrackedFile`, and `LockOptions` without a range, while the policy is not
+/// `Inherit`. It has no effect on Windows, which has no `fork`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ForkPolicy {
+    /// The child shares the parent's locks, as the operating system arranges.
+    /// This is the default, and registers no handlers.
+    #[default]
+    Inherit,
+    /// The child's handles are reopened on the same files, at the same
+    /// offsets, without the locks. The parent keeps its locks, and the child
+    /// unlocking or closing its handles does not affect them.
+    Release,
+    /// As `Release`, after which the child takes the same locks again on its
+    /// own. The child blocks in `fork` until it holds them, so this suits
+    /// daemonizing, where the parent exits right after forking.
+    Reacquire,
+    /// The child's handles are replaced with read-only handles of
+    /// `/dev/null`, so the child can neither use the files nor the locks by
+    /// mistake.
+    Invalidate,
+}
+
+const INHERIT: usize = 0;
+const RELEASE: usize = 1;
+const REACQUIRE: usize = 2;
+const INVALIDATE: usize = 3;
+
+static POLICY: AtomicUsize = AtomicUsize::new(INHERIT);
+
+/// Sets what happens to held whole-file locks in the child when this process
+/// forks.
+///
+/// Setting a policy other than `ForkPolicy::Inherit` registers `pthread_atfork`
+/// handlers the first time, which stay registered; only locks taken after the
+/// policy is set are known to them. Like the lock registry, the policy is
+/// global and should be configured once at startup.
+pub fn fork_policy(policy: ForkPolicy) {
+    let policy = match policy {
+        ForkPolicy::Inherit => INHERIT,
+        ForkPolicy::Release => RELEASE,
+        ForkPolicy::Reacquire => REACQUIRE,
+        ForkPolicy::Invalidate => INVALIDATE,
+    };
+    #[cfg(unix)]
+    {
+        if policy != INHERIT {
+            handlers::register();
+        }
+    }
+    POLICY.store(policy, Ordering::SeqCst);
+}
+
+/// Returns `true` if held locks must be recorded for the fork handlers.
+pub fn is_tracking() -> bool {
+    POLICY.load(Ordering::SeqCst) != INHERIT
+}
+
+#[cfg(unix)]
+mod handlers {
+    extern crate libc;
+
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs::{File, OpenOptions};
+    use std::mem::{self, ManuallyDrop};
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::Ordering;
+    use std::sync::{MutexGuard, Once};
+
+    use super::{INHERIT, INVALIDATE, POLICY, REACQUIRE};
+    use registry::{self, Holder};
+    use {sys, FileId, LockMode};
+
+    /// The state carried from the prepare handler to the parent and child
+    /// handlers, on the forking thread.
+    struct Forking {
+        policy: usize,
+        /// Held for the duration of the fork, so that the child sees a
+        /// consistent registry.
+        holders: MutexGuard<'static, Option<HashMap<FileId, Vec<Holder>>>>,
+        /// The held handles, the descriptions which replace them in the child,
+        /// and the modes of their locks.
+        replacements: Vec<(u64, File, LockMode)>,
+    }
+
+    thread_local! {
+        static FORKING: RefCell<Option<Forking>> = const { RefCell::new(None) };
+    }
+
+    static REGISTER: Once = Once::new();
+
+    pub fn register() {
+        REGISTER.call_once(|| unsafe {
+            libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+        });
+    }
+
+    /// Opens the replacements for the held handles before forking, when it is
+    /// still safe to allocate and open files.
+    ///
+    /// The registry stays locked until the parent and child handlers run, as
+    /// with any lock taken in a `pthread_atfork` prepare handler: otherwise
+    /// another thread could hold it at the moment of the fork, and the child
+    /// would inherit it locked by a thread which does not exist there.
+    extern "C" fn prepare() {
+        let policy = POLICY.load(Ordering::SeqCst);
+        if policy == INHERIT {
+            return;
+        }
+        let holders = registry::holders();
+        let null = if policy == INVALIDATE { OpenOptions::new().read(true).open("/dev/null").ok() } else { None };
+        let mut replacements = Vec::new();
+        let held = holders.iter()
+                          .flat_map(|holders| holders.iter())
+                          .flat_map(|(&id, file_holders)| file_holders.iter().map(move |holder| (id, holder)));
+        for (id, holder) in held {
+            // A pending handle holds no lock yet, and a handle closed without unlocking may since
+            // have been reused for another object.
+            if holder.pending || sys::handle_file_id(holder.handle).ok() != Some(id) {
+                continue;
+            }
+            // The registry does not own the handle, so it must not be closed here.
+            let file = ManuallyDrop::new(unsafe { File::from_raw_fd(holder.handle as libc::c_int) });
+            let replacement = match null {
+                Some(ref null) => null.try_clone(),
+                None => sys::reopen_description(&file),
+            };
+            // A handle which can not be replaced is inherited as usual.
+            if let Ok(replacement) = replacement {
+                replacements.push((holder.handle, replacement, holder.mode));
+            }
+        }
+        FORKING.with(|forking| {
+            *forking.borrow_mut() = Some(Forking { policy, holders, replacements });
+        });
+    }
+
+    /// Closes the unused replacements in the parent.
+    extern "C" fn parent() {
+        FORKING.with(|forking| drop(forking.borrow_mut().take()));
+    }
+
+    /// Installs the replacements in the child, making only async-signal-safe
+    /// calls.
+    extern "C" fn child() {
+        FORKING.with(|forking| {
+            let forking = match forking.borrow_mut().take() {
+                Some(forking) => forking,
+                None => return,
+            };
+            for &(handle, ref replacement, mode) in &forking.replacements {
+                if sys::replace_handle(handle, replacement).is_err() || forking.policy != REACQUIRE {
+                    continue;
+                }
+                let file = ManuallyDrop::new(unsafe { File::from_raw_fd(handle as libc::c_int) });
+                let _ = match mode {
+                    LockMode::Shared => sys::lock_shared(&file),
+                    LockMode::Exclusive => sys::lock_exclusive(&file),
+                };
+            }
+            // The replacements are closed, but their memory is not freed: the
+            // allocator may not be usable in the child of a multithreaded
+            // process.
+            let Forking { holders, replacements, .. } = forking;
+            let mut replacements = ManuallyDrop::new(replacements);
+            for (_, replacement, _) in replacements.drain(..) {
+                drop(replacement);
+            }
+            mem::drop(holders);
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+
+    extern crate libc;
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    use super::{fork_policy, ForkPolicy};
+    use test::in_own_process;
+    use {lock_contended_error, FileExt};
+
+    /// Tests that a released child neither holds nor can release the parent's
+    /// lock, but keeps using the file.
+    #[test]
+    fn fork_release() {
+        if !in_own_process("fork::test::fork_release") {
+            return;
+        }
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(b"fs2").unwrap();
+
+        fork_policy(ForkPolicy::Release);
+        FileExt::lock_exclusive(&file).unwrap();
+        let fd = file.as_raw_fd();
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            // Only async-signal-safe calls are made in the child. The handle is
+            // a new description at the same offset, which the parent's lock
+            // excludes.
+            unsafe {
+                let mut buf = [0u8; 3];
+                let ok = libc::lseek(fd, 0, libc::SEEK_CUR) == 3
+                    && libc::pread(fd, buf.as_mut_ptr() as *mut libc::c_void, 3, 0) == 3
+                    && buf == *b"fs2"
+                    && libc::flock(fd, libc::LOCK_SH | libc::LOCK_NB) == -1
+                    && libc::flock(fd, libc::LOCK_UN) == 0;
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+        }
+        assert!(child > 0, "fork failed");
+        let mut status = 0;
+        assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+        fork_policy(ForkPolicy::Inherit);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+        // The child's unlock did not release the parent's lock.
+        let other = fs::File::open(&path).unwrap();
+        assert_eq!(lock_contended_error().kind(), FileExt::try_lock_shared(&other).unwrap_err().kind());
+        FileExt::unlock(&file).unwrap();
+        FileExt::try_lock_shared(&other).unwrap();
+    }
+}
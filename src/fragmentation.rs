@@ -0,0 +1,146 @@
+use std::cmp;
+use std::fs::File;
+use std::io::Result;
+
+use sys;
+
+/// Fragmentation statistics of a file, as returned by
+/// `FileExt::fragmentation`.
+///
+/// Physically contiguous segments are counted as one extent, so a file
+/// written in one piece has a single extent however the file system splits
+/// it internally.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fragmentation {
+    extent_count: u64,
+    allocated_size: u64,
+    block_size: u64,
+}
+
+impl Fragmentation {
+    /// Computes the statistics of the physical segments `runs`, as
+    /// `(physical offset, length)` pairs in file order, on a file system with
+    /// blocks of `block_size` bytes.
+    fn from_runs<I>(runs: I, block_size: u64) -> Fragmentation where I: IntoIterator<Item = (u64, u64)> {
+        let mut extent_count = 0;
+        let mut allocated_size = 0;
+        let mut next_physical = None;
+        for (physical, len) in runs {
+            if next_physical != Some(physical) {
+                extent_count += 1;
+            }
+            allocated_size += len;
+            next_physical = Some(physical + len);
+        }
+        Fragmentation { extent_count, allocated_size, block_size: cmp::max(block_size, 1) }
+    }
+
+    /// Returns the number of physically contiguous extents.
+    pub fn extent_count(&self) -> u64 {
+        self.extent_count
+    }
+
+    /// Returns the number of bytes allocated to the file's extents.
+    pub fn allocated_size(&self) -> u64 {
+        self.allocated_size
+    }
+
+    /// Returns the average size of an extent in bytes, or 0 if the file has
+    /// no extents.
+    pub fn average_extent_size(&self) -> u64 {
+        self.allocated_size.checked_div(self.extent_count).unwrap_or(0)
+    }
+
+    /// Returns a fragmentation score between 0 and 1: the number of breaks
+    /// between extents, divided by the most breaks a file with as many blocks
+    /// could have. A contiguous file scores 0, and a file with every block in
+    /// a separate extent scores 1.
+    pub fn score(&self) -> f64 {
+        let blocks = self.allocated_size.div_ceil(self.block_size);
+        if self.extent_count <= 1 || blocks <= 1 {
+            return 0.0;
+        }
+        ((self.extent_count - 1) as f64 / (blocks - 1) as f64).min(1.0)
+    }
+}
+
+pub fn fragmentation(file: &File) -> Result<Fragmentation> {
+    let block_size = sys::allocation_granularity(file)?;
+    Ok(Fragmentation::from_runs(physical_runs(file)?, block_size))
+}
+
+/// Returns the allocated segments of the file as `(physical offset, length)`
+/// pairs, omitting data with no location of its own.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn physical_runs(file: &File) -> Result<Vec<(u64, u64)>> {
+    Ok(::os::linux::fiemap(file)?
+        .iter()
+        .filter(|extent| !extent.is_unknown() && !extent.is_inline())
+        .map(|extent| (extent.physical(), extent.len()))
+        .collect())
+}
+
+#[cfg(windows)]
+fn physical_runs(file: &File) -> Result<Vec<(u64, u64)>> {
+    Ok(::os::windows::retrieval_pointers(file)?
+        .iter()
+        .filter_map(|pointer| pointer.physical().map(|physical| (physical, pointer.len())))
+        .collect())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn physical_runs(_file: &File) -> Result<Vec<(u64, u64)>> {
+    Err(::std::io::Error::new(::std::io::ErrorKind::Unsupported,
+                              "physical extents are not available on this platform"))
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{ErrorKind, Write};
+
+    use super::Fragmentation;
+    use FileExt;
+
+    /// Tests computing statistics from physical segments.
+    #[test]
+    fn fragmentation_from_runs() {
+        let empty = Fragmentation::from_runs(vec![], 4096);
+        assert_eq!((0, 0, 0.0), (empty.extent_count(), empty.average_extent_size(), empty.score()));
+
+        // Contiguous segments are merged.
+        let contiguous = Fragmentation::from_runs(vec![(4096, 8192), (12288, 4096)], 4096);
+        assert_eq!(1, contiguous.extent_count());
+        assert_eq!(12288, contiguous.average_extent_size());
+        assert_eq!(0.0, contiguous.score());
+
+        let scattered = Fragmentation::from_runs(vec![(0, 4096), (40960, 4096), (8192, 4096)], 4096);
+        assert_eq!(3, scattered.extent_count());
+        assert_eq!(4096, scattered.average_extent_size());
+        assert_eq!(1.0, scattered.score());
+
+        let partial = Fragmentation::from_runs(vec![(0, 8192), (40960, 12288)], 4096);
+        assert_eq!(0.25, partial.score());
+    }
+
+    /// Tests the fragmentation of a freshly written file.
+    #[test]
+    fn file_fragmentation() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+
+        let fragmentation = match file.fragmentation() {
+            Ok(fragmentation) => fragmentation,
+            Err(ref err) if err.kind() == ErrorKind::Unsupported => return,
+            Err(err) => panic!("{}", err),
+        };
+        assert!(fragmentation.extent_count() >= 1);
+        assert!(fragmentation.allocated_size() >= 64 * 1024);
+        assert!(fragmentation.score() >= 0.0 && fragmentation.score() <= 1.0);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+
+/// The type of a file system, as reported by `FsStats::kind`.
+///
+/// It is decoded from the `statfs::f_type` magic number on Linux, the
+/// `statfs::f_fstypename` name on the BSDs and macOS, and the file system name
+/// of the volume on Windows. Unrecognized types are kept as `Other`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FsKind {
+    /// ext2, ext3 or ext4, which share a magic number on Linux.
+    Ext,
+    Xfs,
+    Btrfs,
+    Zfs,
+    F2fs,
+    Tmpfs,
+    Overlay,
+    Fuse,
+    Apfs,
+    /// HFS or HFS+.
+    Hfs,
+    Ufs,
+    Ntfs,
+    Refs,
+    /// FAT12, FAT16 or FAT32.
+    Fat,
+    Exfat,
+    Iso9660,
+    Udf,
+    Squashfs,
+    Nfs,
+    /// SMB or CIFS.
+    Smb,
+    NineP,
+    Afs,
+    Afp,
+    WebDav,
+    Ceph,
+    Lustre,
+    Coda,
+    Ncp,
+    /// A file system type this crate does not recognize: its name, or its
+    /// magic number in hexadecimal on Linux.
+    Other(String),
+}
+
+impl FsKind {
+    /// Decodes a Linux `statfs::f_type` magic number.
+    pub fn from_magic(magic: u32) -> FsKind {
+        match magic {
+            0xef53 => FsKind::Ext,
+            0x5846_5342 => FsKind::Xfs,
+            0x9123_683e => FsKind::Btrfs,
+            0x2fc1_2fc1 => FsKind::Zfs,
+            0xf2f5_2010 => FsKind::F2fs,
+            0x0102_1994 => FsKind::Tmpfs,
+            0x794c_7630 => FsKind::Overlay,
+            0x6573_5546 => FsKind::Fuse,
+            0x482b => FsKind::Hfs,
+            0x0001_1954 => FsKind::Ufs,
+            0x5346_544e | 0x7366_746e => FsKind::Ntfs,
+            0x4d44 => FsKind::Fat,
+            0x2011_bab0 => FsKind::Exfat,
+            0x9660 => FsKind::Iso9660,
+            0x1501_3346 => FsKind::Udf,
+            0x7371_7368 => FsKind::Squashfs,
+            0x6969 => FsKind::Nfs,
+            0x517b | 0xfe53_4d42 | 0xff53_4d42 => FsKind::Smb,
+            0x0102_1997 => FsKind::NineP,
+            0x5346_414f | 0x6b41_4653 => FsKind::Afs,
+            0x00c3_6400 => FsKind::Ceph,
+            0x0bd0_0bd0 => FsKind::Lustre,
+            0x7375_7245 => FsKind::Coda,
+            0x564c => FsKind::Ncp,
+            _ => FsKind::Other(format!("{:#x}", magic)),
+        }
+    }
+
+    /// Decodes a file system name, as reported by `statfs::f_fstypename` on
+    /// the BSDs and macOS or by `GetVolumeInformationW` on Windows. Names are
+    /// compared case-insensitively.
+    pub fn from_name(name: &str) -> FsKind {
+        match &*name.to_ascii_lowercase() {
+            "ext2fs" | "ext2" | "ext3" | "ext4" => FsKind::Ext,
+            "xfs" => FsKind::Xfs,
+            "btrfs" => FsKind::Btrfs,
+            "zfs" => FsKind::Zfs,
+            "tmpfs" => FsKind::Tmpfs,
+            "fusefs" | "fuse" | "macfuse" | "osxfuse" => FsKind::Fuse,
+            "apfs" => FsKind::Apfs,
+            "hfs" => FsKind::Hfs,
+            "ufs" | "ffs" => FsKind::Ufs,
+            "ntfs" => FsKind::Ntfs,
+            "refs" => FsKind::Refs,
+            "fat" | "fat12" | "fat16" | "fat32" | "msdos" | "msdosfs" => FsKind::Fat,
+            "exfat" => FsKind::Exfat,
+            "cd9660" | "cdfs" => FsKind::Iso9660,
+            "udf" => FsKind::Udf,
+            "nfs" => FsKind::Nfs,
+            "smbfs" | "cifs" => FsKind::Smb,
+            "9p" => FsKind::NineP,
+            "afs" => FsKind::Afs,
+            "afpfs" => FsKind::Afp,
+            "webdav" => FsKind::WebDav,
+            _ => FsKind::Other(name.to_owned()),
+        }
+    }
+
+    /// Returns `true` if this is a network or cluster file system, where
+    /// clients on other machines may share the files.
+    pub fn is_network(&self) -> bool {
+        matches!(*self, FsKind::Nfs | FsKind::Smb | FsKind::NineP | FsKind::Afs | FsKind::Afp
+                        | FsKind::WebDav | FsKind::Ceph | FsKind::Lustre | FsKind::Coda
+                        | FsKind::Ncp)
+    }
+}
+
+/// Returns the maximum size of a file on file systems of this kind, if the format limits it below
+/// what the platform reports.
+#[cfg(any(feature = "allocate", feature = "statvfs"))]
+pub fn format_max_file_size(kind: &FsKind) -> Option<u64> {
+    match *kind {
+        // FAT stores file sizes in 32 bits.
+        FsKind::Fat => Some(u32::MAX as u64),
+        _ => None,
+    }
+}
+
+impl fmt::Display for FsKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            FsKind::Ext => "ext",
+            FsKind::Xfs => "xfs",
+            FsKind::Btrfs => "btrfs",
+            FsKind::Zfs => "zfs",
+            FsKind::F2fs => "f2fs",
+            FsKind::Tmpfs => "tmpfs",
+            FsKind::Overlay => "overlay",
+            FsKind::Fuse => "fuse",
+            FsKind::Apfs => "apfs",
+            FsKind::Hfs => "hfs",
+            FsKind::Ufs => "ufs",
+            FsKind::Ntfs => "ntfs",
+            FsKind::Refs => "refs",
+            FsKind::Fat => "fat",
+            FsKind::Exfat => "exfat",
+            FsKind::Iso9660 => "iso9660",
+            FsKind::Udf => "udf",
+            FsKind::Squashfs => "squashfs",
+            FsKind::Nfs => "nfs",
+            FsKind::Smb => "smb",
+            FsKind::NineP => "9p",
+            FsKind::Afs => "afs",
+            FsKind::Afp => "afp",
+            FsKind::WebDav => "webdav",
+            FsKind::Ceph => "ceph",
+            FsKind::Lustre => "lustre",
+            FsKind::Coda => "coda",
+            FsKind::Ncp => "ncp",
+            FsKind::Other(ref name) => name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::FsKind;
+
+    /// Tests decoding magic numbers and names.
+    #[test]
+    fn decode() {
+        assert_eq!(FsKind::Ext, FsKind::from_magic(0xef53));
+        assert_eq!(FsKind::Nfs, FsKind::from_magic(0x6969));
+        assert_eq!(FsKind::Other("0x1234".to_owned()), FsKind::from_magic(0x1234));
+
+        assert_eq!(FsKind::Apfs, FsKind::from_name("apfs"));
+        assert_eq!(FsKind::Ntfs, FsKind::from_name("NTFS"));
+        assert_eq!(FsKind::Fat, FsKind::from_name("FAT32"));
+        assert_eq!(FsKind::Other("CSVFS".to_owned()), FsKind::from_name("CSVFS"));
+
+        assert!(FsKind::from_magic(0xff53_4d42).is_network());
+        assert!(FsKind::from_name("smbfs").is_network());
+        assert!(!FsKind::Ext.is_network());
+        assert_eq!("9p", FsKind::NineP.to_string());
+        assert_eq!("CSVFS", FsKind::from_name("CSVFS").to_string());
+    }
+
+    /// Tests the maximum file sizes imposed by on-disk formats.
+    #[cfg(any(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn format_max_file_size() {
+        assert_eq!(Some(u32::MAX as u64), super::format_max_file_size(&FsKind::from_name("FAT32")));
+        assert_eq!(None, super::format_max_file_size(&FsKind::Ext));
+    }
+}
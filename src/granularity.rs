@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use error::with_path;
+use lock_unpoisoned;
+use sys::{self, VolumeKey};
+
+/// The allocation granularity of each volume queried so far.
+static CACHE: Mutex<Option<HashMap<VolumeKey, u64>>> = Mutex::new(None);
+
+fn cache() -> MutexGuard<'static, Option<HashMap<VolumeKey, u64>>> {
+    lock_unpoisoned(&CACHE)
+}
+
+pub fn allocation_granularity(path: &Path) -> Result<u64> {
+    let key = sys::volume_key(path).map_err(with_path("allocation_granularity", path))?;
+    if let Some(&granularity) = cache().as_ref().and_then(|cache| cache.get(&key)) {
+        return Ok(granularity);
+    }
+    let granularity = sys::statvfs(path).map_err(with_path("allocation_granularity", path))?
+                                        .allocation_granularity();
+    cache().get_or_insert_with(HashMap::new).insert(key, granularity);
+    Ok(granularity)
+}
+
+/// Forgets the cached allocation granularities of every volume, so that they
+/// are queried again by `allocation_granularity`.
+///
+/// Granularities only change when a volume is reformatted, or a different
+/// file system is mounted in its place.
+pub fn clear_granularity_cache() {
+    *cache() = None;
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use super::{cache, clear_granularity_cache};
+    use {allocation_granularity, statvfs, sys};
+
+    /// Tests that granularities are cached per volume.
+    #[test]
+    fn granularity_cache() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let expected = statvfs(tempdir.path()).unwrap().allocation_granularity();
+        assert_eq!(expected, allocation_granularity(tempdir.path()).unwrap());
+        let key = sys::volume_key(tempdir.path()).unwrap();
+        assert_eq!(Some(&expected), cache().as_ref().unwrap().get(&key));
+        assert_eq!(expected, allocation_granularity(tempdir.path()).unwrap());
+
+        clear_granularity_cache();
+        assert_eq!(expected, allocation_granularity(tempdir.path()).unwrap());
+        assert!(allocation_granularity(tempdir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::Result;
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+use {lock_unpoisoned, sys};
+
+/// A shared/exclusive lock which coordinates both the threads of this process
+/// and other processes.
+///
+/// File locks belong to the file handle, not to a thread, so threads sharing
+/// one `File` pass straight through each other's locks: a second thread
+/// calling `lock_exclusive` on an already locked handle succeeds. A
+/// `HybridLock` pairs the file lock with a process-local `RwLock`, so threads
+/// exclude each other as they would with an `RwLock`, and processes exclude
+/// each other through the file lock.
+///
+/// While any thread holds a read guard the file is locked shared, and while a
+/// thread holds the write guard it is locked exclusively.
+#[derive(Debug)]
+pub struct HybridLock {
+    file: File,
+    local: RwLock<()>,
+    /// The number of read guards, which share one shared file lock.
+    readers: Mutex<usize>,
+}
+
+impl HybridLock {
+    /// Wraps a file to be locked, and makes it non-inheritable.
+    pub fn new(file: File) -> HybridLock {
+        let _ = sys::set_inheritable(&file, false);
+        HybridLock { file, local: RwLock::new(()), readers: Mutex::new(0) }
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Unwraps the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Locks for shared access, blocking while another thread or process holds
+    /// the lock exclusively.
+    pub fn read(&self) -> Result<HybridReadGuard<'_>> {
+        let local = self.local.read().unwrap_or_else(|err| err.into_inner());
+        self.add_reader(local, sys::lock_shared)
+    }
+
+    /// Locks for shared access, or returns an error if another thread or
+    /// process holds the lock exclusively (see `lock_contended_error`).
+    pub fn try_read(&self) -> Result<HybridReadGuard<'_>> {
+        let local = match self.local.try_read() {
+            Ok(local) => local,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(sys::lock_error()),
+        };
+        self.add_reader(local, sys::try_lock_shared)
+    }
+
+    /// Locks for exclusive access, blocking while another thread or process
+    /// holds the lock.
+    pub fn write(&self) -> Result<HybridWriteGuard<'_>> {
+        let local = self.local.write().unwrap_or_else(|err| err.into_inner());
+        sys::lock_exclusive(&self.file)?;
+        Ok(HybridWriteGuard { lock: self, _local: local })
+    }
+
+    /// Locks for exclusive access, or returns an error if another thread or
+    /// process holds the lock (see `lock_contended_error`).
+    pub fn try_write(&self) -> Result<HybridWriteGuard<'_>> {
+        let local = match self.local.try_write() {
+            Ok(local) => local,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(sys::lock_error()),
+        };
+        sys::try_lock_exclusive(&self.file)?;
+        Ok(HybridWriteGuard { lock: self, _local: local })
+    }
+
+    /// Registers a reader holding `local`, taking the shared file lock with
+    /// `lock` if it is the first.
+    fn add_reader<'a>(&'a self, local: RwLockReadGuard<'a, ()>, lock: fn(&File) -> Result<()>)
+                      -> Result<HybridReadGuard<'a>> {
+        let mut readers = self.readers();
+        if *readers == 0 {
+            lock(&self.file)?;
+        }
+        *readers += 1;
+        Ok(HybridReadGuard { lock: self, _local: local })
+    }
+
+    fn readers(&self) -> MutexGuard<'_, usize> {
+        lock_unpoisoned(&self.readers)
+    }
+}
+
+/// Shared access to a `HybridLock`, released when dropped.
+#[derive(Debug)]
+pub struct HybridReadGuard<'a> {
+    lock: &'a HybridLock,
+    _local: RwLockReadGuard<'a, ()>,
+}
+
+impl<'a> Deref for HybridReadGuard<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock.file
+    }
+}
+
+impl<'a> Drop for HybridReadGuard<'a> {
+    fn drop(&mut self) {
+        // The local lock is released after this, so no writer can run until
+        // the file lock is released.
+        let mut readers = self.lock.readers();
+        *readers -= 1;
+        if *readers == 0 {
+            let _ = sys::unlock(&self.lock.file);
+        }
+    }
+}
+
+/// Exclusive access to a `HybridLock`, released when dropped.
+#[derive(Debug)]
+pub struct HybridWriteGuard<'a> {
+    lock: &'a HybridLock,
+    _local: RwLockWriteGuard<'a, ()>,
+}
+
+impl<'a> Deref for HybridWriteGuard<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock.file
+    }
+}
+
+impl<'a> Drop for HybridWriteGuard<'a> {
+    fn drop(&mut self) {
+        let _ = sys::unlock(&self.lock.file);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use {FileExt, HybridLock, lock_contended_error};
+
+    /// Tests that threads sharing a file exclude each other, and that other
+    /// handles are excluded through the file lock.
+    #[test]
+    fn hybrid_lock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let other = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let lock = HybridLock::new(file);
+
+        let reader1 = lock.read().unwrap();
+        let reader2 = lock.try_read().unwrap();
+        assert_eq!(lock.try_write().unwrap_err().kind(), lock_contended_error().kind());
+        assert_eq!(other.try_lock_exclusive().unwrap_err().kind(), lock_contended_error().kind());
+        drop(reader1);
+        // The file stays locked while any reader remains.
+        assert_eq!(other.try_lock_exclusive().unwrap_err().kind(), lock_contended_error().kind());
+        drop(reader2);
+
+        let writer = lock.try_write().unwrap();
+        assert_eq!(lock.try_read().unwrap_err().kind(), lock_contended_error().kind());
+        assert_eq!(FileExt::try_lock_shared(&other).unwrap_err().kind(),
+                   lock_contended_error().kind());
+        drop(writer);
+        FileExt::try_lock_shared(&other).unwrap();
+        assert_eq!(lock.try_write().unwrap_err().kind(), lock_contended_error().kind());
+    }
+
+    /// Tests that writers on different threads never overlap.
+    #[test]
+    fn hybrid_lock_threads() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let lock = Arc::new(HybridLock::new(file));
+        let inside = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let lock = lock.clone();
+            let inside = inside.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let _guard = lock.write().unwrap();
+                    assert_eq!(0, inside.fetch_add(1, Ordering::SeqCst));
+                    thread::sleep(Duration::from_millis(1));
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Crash-safe replacement of file contents.
+//!
+//! `commit` replaces the contents of a file so that, after a crash or power
+//! failure at any point, the file holds either the old or the new contents in
+//! full. The new contents are written to a sidecar file next to the target,
+//! flushed to disk, and renamed over the target; the parent directory is then
+//! flushed so that the rename itself is durable.
+//!
+//! A crash before the rename leaves the sidecar behind, possibly torn. Call
+//! `recover` on startup, before committing again, to discard it.
+//!
+//! Commits to the same path must not run concurrently, since they share the
+//! sidecar; serialize them with a lock if several processes may commit.
+
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the path of the sidecar file used while committing to `path`.
+pub fn sidecar_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".fs2-journal");
+    path.with_file_name(name)
+}
+
+/// Atomically and durably replaces the contents of the file at `path` with
+/// `bytes`, creating it if it does not exist.
+///
+/// When this returns successfully the new contents will survive a crash. If it
+/// fails, the file holds either its old or its new contents.
+///
+/// An existing file keeps its permissions, which are copied to the sidecar
+/// before anything is written to it.
+pub fn commit<P: AsRef<Path>>(path: P, bytes: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let sidecar = sidecar_path(path);
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&sidecar)?;
+    match fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&sidecar, path)?;
+    sync_parent(path)
+}
+
+/// Cleans up after a commit to `path` which was interrupted by a crash,
+/// returning `true` if one was found.
+///
+/// An interrupted commit never reached the rename, so the file still holds its
+/// previous contents, and the sidecar (which may be torn) is removed.
+pub fn recover<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    match fs::remove_file(sidecar_path(path)) {
+        Ok(()) => {
+            sync_parent(path)?;
+            Ok(true)
+        }
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Flushes the directory containing `path`, making renames and removals in it
+/// durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    fs::File::open(parent)?.sync_all()
+}
+
+/// Windows makes directory entries durable with the file's metadata, and
+/// directories can not be opened with `File::open`.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+
+    use journal;
+
+    /// Tests committing new contents to a file.
+    #[test]
+    fn commit() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+
+        journal::commit(&path, b"first").unwrap();
+        assert_eq!(b"first".to_vec(), fs::read(&path).unwrap());
+        journal::commit(&path, b"second version").unwrap();
+        assert_eq!(b"second version".to_vec(), fs::read(&path).unwrap());
+        assert!(!journal::sidecar_path(&path).exists());
+        assert!(!journal::recover(&path).unwrap());
+    }
+
+    /// Tests that committing keeps the permissions of the file.
+    #[cfg(unix)]
+    #[test]
+    fn commit_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::write(&path, b"secret").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        journal::commit(&path, b"new secret").unwrap();
+        assert_eq!(b"new secret".to_vec(), fs::read(&path).unwrap());
+        assert_eq!(0o600, fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+    }
+
+    /// Tests recovering from a power failure at each step of a commit, by
+    /// leaving the files as they would be found on restart.
+    #[test]
+    fn recover_torn_commit() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let sidecar = journal::sidecar_path(&path);
+        assert_eq!(tempdir.path().join("fs2.fs2-journal"), sidecar);
+        journal::commit(&path, b"old contents").unwrap();
+
+        // Failures while the sidecar is created, written or flushed.
+        for torn in &[&b""[..], &b"new con"[..], &b"new contents"[..]] {
+            fs::write(&sidecar, torn).unwrap();
+            assert!(journal::recover(&path).unwrap());
+            assert!(!sidecar.exists());
+            assert_eq!(b"old contents".to_vec(), fs::read(&path).unwrap());
+        }
+
+        // A failure after the rename leaves nothing to recover.
+        fs::write(&sidecar, b"new contents").unwrap();
+        fs::rename(&sidecar, &path).unwrap();
+        assert!(!journal::recover(&path).unwrap());
+        assert_eq!(b"new contents".to_vec(), fs::read(&path).unwrap());
+
+        // Recovering a file which was never committed.
+        let fresh = tempdir.path().join("fresh");
+        fs::write(journal::sidecar_path(&fresh), b"torn").unwrap();
+        assert!(journal::recover(&fresh).unwrap());
+        assert!(!fresh.exists());
+    }
+}
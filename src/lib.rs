@@ -1,11 +1,17 @@
 //! Extended utilities for working with files and filesystems in Rust.
 
-#![doc(html_root_url = "https://docs.rs/fs2/0.4.3")]
+#![doc(html_root_url = "https://docs.rs/fs2/0.5.0")]
 
-#![cfg_attr(test, feature(test))]
+#![cfg_attr(all(test, feature = "unstable"), feature(test))]
 
 #[cfg(windows)]
 extern crate winapi;
+#[cfg(feature = "bincode")]
+extern crate bincode;
+#[cfg(any(feature = "bincode", feature = "json"))]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
 
 #[cfg(unix)]
 mod unix;
@@ -17,9 +23,169 @@ mod windows;
 #[cfg(windows)]
 use windows as sys;
 
-use std::fs::File;
-use std::io::{Error, Result};
+#[cfg(not(any(unix, windows)))]
+mod unsupported;
+#[cfg(not(any(unix, windows)))]
+use unsupported as sys;
+
+pub mod error;
+#[cfg(feature = "utilities")]
+pub mod journal;
+pub mod os;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+#[cfg(feature = "utilities")]
+mod anonymous;
+#[cfg(feature = "allocate")]
+mod ballast;
+mod bytes;
+#[cfg(feature = "utilities")]
+mod copy;
+#[cfg(feature = "locks")]
+mod fork;
+mod fs_kind;
+#[cfg(feature = "utilities")]
+mod fair;
+#[cfg(feature = "utilities")]
+mod file_mutex;
+#[cfg(feature = "allocate")]
+mod fragmentation;
+#[cfg(feature = "utilities")]
+mod lock_file;
+#[cfg(feature = "utilities")]
+mod lock_metadata;
+#[cfg(feature = "statvfs")]
+mod granularity;
+#[cfg(feature = "utilities")]
+mod hybrid;
+#[cfg(feature = "utilities")]
+mod named;
+#[cfg(feature = "utilities")]
+mod notify;
+#[cfg(feature = "locks")]
+mod mmap;
+#[cfg(feature = "statvfs")]
+mod monitor;
+#[cfg(any(feature = "allocate", feature = "utilities"))]
+mod progress;
+#[cfg(feature = "statvfs")]
+mod sampler;
+#[cfg(feature = "utilities")]
+mod poison;
+#[cfg(any(feature = "allocate", feature = "utilities"))]
+mod positional;
+#[cfg(feature = "utilities")]
+mod reentrant;
+#[cfg(all(feature = "allocate", feature = "utilities"))]
+mod region;
+#[cfg(feature = "locks")]
+mod registry;
+#[cfg(feature = "utilities")]
+mod rwlock;
+#[cfg(feature = "utilities")]
+mod semaphore;
+#[cfg(feature = "locks")]
+mod strict;
+#[cfg(feature = "locks")]
+mod tracked;
+
+#[cfg(feature = "utilities")]
+pub use anonymous::{anonymous_file, AnonymousFile};
+#[cfg(feature = "allocate")]
+pub use ballast::Ballast;
+pub use bytes::Bytes;
+#[cfg(feature = "utilities")]
+pub use copy::{copy_fast, copy_fast_with_progress, CopyStrategy};
+pub use error::Error;
+#[cfg(feature = "locks")]
+pub use fork::{fork_policy, ForkPolicy};
+pub use fs_kind::FsKind;
+#[cfg(feature = "utilities")]
+pub use fair::{FairFileLock, FairLockGuard};
+#[cfg(feature = "utilities")]
+pub use file_mutex::{BytesCodec, Codec, FileMutex, FileMutexGuard, TextCodec};
+#[cfg(feature = "bincode")]
+pub use file_mutex::BincodeCodec;
+#[cfg(feature = "json")]
+pub use file_mutex::JsonCodec;
+#[cfg(feature = "allocate")]
+pub use fragmentation::Fragmentation;
+#[cfg(feature = "statvfs")]
+pub use granularity::clear_granularity_cache;
+#[cfg(feature = "utilities")]
+pub use hybrid::{HybridLock, HybridReadGuard, HybridWriteGuard};
+#[cfg(feature = "utilities")]
+pub use named::NamedMutex;
+#[cfg(feature = "utilities")]
+pub use notify::Notify;
+#[cfg(feature = "utilities")]
+pub use poison::{PoisonFileLock, PoisonGuard};
+#[cfg(feature = "utilities")]
+pub use lock_file::{BreakPolicy, LockFile};
+#[cfg(feature = "utilities")]
+pub use lock_metadata::{HolderState, LockMetadata};
+#[cfg(feature = "locks")]
+pub use mmap::{mmap_locked, LockedMap, MapMode};
+#[cfg(feature = "statvfs")]
+pub use monitor::{SpaceEvent, SpaceMonitor, SpaceWatcher, Threshold};
+#[cfg(any(feature = "allocate", feature = "utilities"))]
+pub use progress::{Cancelled, Progress};
+#[cfg(feature = "statvfs")]
+pub use sampler::StatsSampler;
+#[cfg(feature = "utilities")]
+pub use reentrant::{ReentrantFileLock, ReentrantGuard};
+#[cfg(all(feature = "allocate", feature = "utilities"))]
+pub use region::{Pod, RegionReadGuard, RegionWriteGuard, SharedRegion};
+#[cfg(feature = "locks")]
+pub use registry::{held_locks, lock_registry, set_lock_registry, HeldLock, RegistryPolicy};
+#[cfg(feature = "utilities")]
+pub use rwlock::PoliteRwFileLock;
+#[cfg(feature = "utilities")]
+pub use semaphore::{FileSemaphore, SemaphorePermit};
+#[cfg(feature = "locks")]
+pub use tracked::TrackedFile;
+
+#[cfg(all(unix, feature = "statvfs"))]
+use std::ffi::CStr;
+#[cfg(any(feature = "allocate", feature = "locks"))]
+use std::cmp;
+#[cfg(any(feature = "allocate", feature = "statvfs"))]
+use std::convert::TryFrom;
+#[cfg(all(feature = "allocate", feature = "locks"))]
+use std::ffi::OsString;
+use std::fmt;
+#[cfg(feature = "locks")]
+use std::mem;
+#[cfg(all(feature = "allocate", feature = "locks"))]
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(any(feature = "allocate", feature = "duplicate", feature = "locks", feature = "statvfs"))]
+use std::io::ErrorKind;
+#[cfg(feature = "locks")]
+use std::ops::{Deref, Range};
+#[cfg(any(feature = "statvfs", all(feature = "allocate", feature = "locks")))]
 use std::path::Path;
+use std::path::PathBuf;
+#[cfg(all(feature = "allocate", feature = "locks"))]
+use std::process;
+use std::process::{Child, Command};
+#[cfg(feature = "locks")]
+use std::ptr;
+#[cfg(all(feature = "allocate", feature = "locks"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "locks")]
+use std::sync::{Arc, RwLock};
+#[cfg(any(feature = "locks", feature = "statvfs"))]
+use std::sync::{Mutex, MutexGuard};
+#[cfg(feature = "locks")]
+use std::thread;
+#[cfg(feature = "locks")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "statvfs")]
+use error::with_path;
 
 /// Extension trait for `std::fs::File` which provides allocation, duplication and locking methods.
 ///
@@ -38,7 +204,9 @@ use std::path::Path;
 ///     concurrently.
 ///   * Duplicated files should not be locked without great care.
 ///   * Files to be locked should be opened with at least read or write
-///     permissions.
+///     permissions. On Windows, locking a file opened without read or write
+///     data access (for instance, append-only) fails with
+///     `ErrorKind::PermissionDenied`; on Unix it succeeds.
 ///   * File locks may only be relied upon to be advisory.
 ///
 /// See the tests in `lib.rs` for cross-platform lock behavior that may be
@@ -47,6 +215,19 @@ use std::path::Path;
 /// [`flock(2)`](http://man7.org/linux/man-pages/man2/flock.2.html) on Unix and
 /// [`LockFile`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa365202(v=vs.85).aspx)
 /// on Windows.
+///
+/// `FileExt` is dyn compatible, so implementations can be stored as
+/// `Box<dyn FileExt>`; methods added to it must not take `self` by value, and
+/// generic methods must be bounded by `Self: Sized`.
+///
+/// The methods which belong to an optional feature have default
+/// implementations, so that an implementation outside this crate keeps
+/// compiling when another crate in the build enables more features. The
+/// defaults fail with `ErrorKind::Unsupported`, except that the `*_timeout`
+/// methods poll the corresponding `try_lock_*` method, and `with_shared_lock`
+/// and `with_exclusive_lock` use `lock_shared`, `lock_exclusive` and
+/// `unlock`. Guards can only be taken on a `File`, so the `*_guard` methods
+/// are not supported by other implementations.
 pub trait FileExt {
 
     /// Returns a duplicate instance of the file.
@@ -56,247 +237,3034 @@ pub trait FileExt {
     ///
     /// If using rustc version 1.9 or later, prefer using `File::try_clone` to this.
     ///
+    /// On Windows the duplicate is inherited by child processes; use
+    /// `DuplicateOptions` to control this.
+    ///
     /// # Notes
     ///
     /// This is implemented with
     /// [`dup(2)`](http://man7.org/linux/man-pages/man2/dup.2.html) on Unix and
     /// [`DuplicateHandle`](https://msdn.microsoft.com/en-us/library/windows/desktop/ms724251(v=vs.85).aspx)
     /// on Windows.
-    fn duplicate(&self) -> Result<File>;
+    #[cfg(feature = "duplicate")]
+    fn duplicate(&self) -> io::Result<File> {
+        Err(not_implemented("duplicate"))
+    }
+
+    /// Sets whether the file is closed when the process executes another
+    /// program, i.e. whether child processes inherit it.
+    ///
+    /// This is the inverse of `set_inheritable`.
+    fn set_cloexec(&self, cloexec: bool) -> io::Result<()>;
+
+    /// Sets whether child processes inherit the file.
+    ///
+    /// Files opened by the standard library are not inherited already, but
+    /// descriptors from other sources (and duplicates made with `duplicate`)
+    /// may be. A child which inherits a locked file shares its lock, and keeps
+    /// the file locked after the parent exits, blocking a restarted parent.
+    /// The lock types built on `FileExt`, such as `FileMutex` and
+    /// `HybridLock`, make their files non-inheritable. See also
+    /// `spawn_without_locks`.
+    ///
+    /// # Notes
+    ///
+    /// This clears `FD_CLOEXEC` on Unix, and sets `HANDLE_FLAG_INHERIT` on
+    /// Windows.
+    fn set_inheritable(&self, inheritable: bool) -> io::Result<()>;
 
     /// Returns the amount of physical space allocated for a file.
-    fn allocated_size(&self) -> Result<u64>;
+    ///
+    /// The file's position is preserved across the call.
+    #[cfg(feature = "allocate")]
+    fn allocated_size(&self) -> io::Result<u64> {
+        Err(not_implemented("allocated_size"))
+    }
+
+    /// Opens a new instance of the file with `options`.
+    ///
+    /// Unlike `duplicate`, the returned file has its own file position, and
+    /// may be opened with different access. Locks are not shared with the
+    /// original file.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented by opening `/proc/self/fd/N` on Linux and Android,
+    /// which finds the file even if it has been renamed or unlinked. On macOS
+    /// and iOS the file's current path is retrieved with `F_GETPATH` and then
+    /// opened; this fails if the file has been unlinked. On Windows the path
+    /// is retrieved with `GetFinalPathNameByHandleW` and then opened; use
+    /// `os::windows::reopen` to reopen the handle itself with explicit access
+    /// rights. Other platforms return an `ErrorKind::Unsupported` error.
+    fn reopen(&self, options: &OpenOptions) -> io::Result<File>;
+
+    /// Returns a path to the file.
+    ///
+    /// The result is best-effort: if the file has been renamed, the new path
+    /// may or may not be returned, and if the file has been unlinked the path
+    /// may no longer exist. A file with several hard links may be reported by
+    /// any of them. The path should be used for diagnostics, rather than to
+    /// reopen the file (see `reopen`).
+    ///
+    /// # Notes
+    ///
+    /// This is implemented by reading the `/proc/self/fd/N` link on Linux and
+    /// Android, with `F_GETPATH` on macOS and iOS, and with
+    /// [`GetFinalPathNameByHandleW`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa364962(v=vs.85).aspx)
+    /// on Windows, which returns a verbatim (`\\?\`) path. Other platforms return
+    /// an `ErrorKind::Unsupported` error.
+    fn path(&self) -> io::Result<PathBuf>;
+
+    /// Returns the identity of the underlying file.
+    ///
+    /// Two open files have the same identity if and only if they refer to the
+    /// same file, regardless of the paths or links used to open them.
+    ///
+    /// # Notes
+    ///
+    /// This is the device and inode number on Unix, and the volume serial
+    /// number and file index from
+    /// [`GetFileInformationByHandle`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa364952(v=vs.85).aspx)
+    /// on Windows.
+    fn file_id(&self) -> io::Result<FileId>;
+
+    /// Returns a holder of a lock on the file, or `None` if the file is not
+    /// locked.
+    ///
+    /// This is intended for diagnostics when a lock is contended, and is
+    /// best-effort: the holder may release the lock at any time, and if the
+    /// file is locked by several holders only one is returned. The holder may
+    /// be this process.
+    ///
+    /// # Notes
+    ///
+    /// On Linux and Android the holder is found in `/proc/locks`, which lists
+    /// both `flock(2)` and record locks. Other Unix platforms query record
+    /// locks with `F_GETLK`, which may not report locks taken with `flock(2)`.
+    /// Windows returns an `ErrorKind::Unsupported` error.
+    #[cfg(feature = "locks")]
+    fn lock_holder(&self) -> io::Result<Option<LockHolder>> {
+        Err(not_implemented("lock_holder"))
+    }
+
+    /// Returns the available space in bytes to non-privileged users in the
+    /// file system containing the file.
+    ///
+    /// This is equivalent to `available_space` for the file's path, without
+    /// needing the path.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented with
+    /// [`fstatvfs(3)`](http://man7.org/linux/man-pages/man3/fstatvfs.3.html)
+    /// on Unix. On Windows the volume is found from the file's final path.
+    #[cfg(feature = "statvfs")]
+    fn available_space(&self) -> io::Result<u64> {
+        Err(not_implemented("available_space"))
+    }
+
+    /// Returns the number of free bytes in the file system containing the
+    /// file.
+    ///
+    /// Like `available_space`, this works even if the file's original path
+    /// has since been renamed or removed.
+    #[cfg(feature = "statvfs")]
+    fn free_space(&self) -> io::Result<u64> {
+        Err(not_implemented("free_space"))
+    }
+
+    /// Returns the total space in bytes in the file system containing the
+    /// file.
+    #[cfg(feature = "statvfs")]
+    fn total_space(&self) -> io::Result<u64> {
+        Err(not_implemented("total_space"))
+    }
 
     /// Ensures that at least `len` bytes of disk space are allocated for the
     /// file, and the file size is at least `len` bytes. After a successful call
     /// to `allocate`, subsequent writes to the file within the specified length
     /// are guaranteed not to fail because of lack of disk space.
-    fn allocate(&self, len: u64) -> Result<()>;
+    ///
+    /// This is equivalent to allocating with the default `AllocateOptions`.
+    /// The file's position is preserved across the call, so allocation may be
+    /// interleaved with buffered writes.
+    #[cfg(feature = "allocate")]
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Err(not_implemented("allocate"))
+    }
+
+    /// Ensures that at least `len` bytes of disk space are allocated for the
+    /// file, and verifies that the filesystem really allocated them.
+    ///
+    /// This is equivalent to allocating with `AllocateOptions::verify` set;
+    /// see that method for details. Use `AllocateOptions::touch` for a
+    /// stronger guarantee.
+    #[cfg(feature = "allocate")]
+    fn allocate_strict(&self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Err(not_implemented("allocate_strict"))
+    }
+
+    /// Ensures that at least `len` bytes of disk space are allocated for the
+    /// file, writing zeros past the end of the file if the filesystem has no
+    /// native preallocation, as on ZFS and some network filesystems.
+    ///
+    /// This is equivalent to allocating with `AllocateMode::ZeroFill`; use
+    /// `AllocateOptions::allocate_with_progress` with that mode to report
+    /// progress while zeros are written.
+    #[cfg(feature = "allocate")]
+    fn allocate_zeroed(&self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Err(not_implemented("allocate_zeroed"))
+    }
+
+    /// Returns an iterator over the data and hole segments of the file.
+    ///
+    /// The returned extents are ordered by offset, do not overlap, and
+    /// together cover the file from offset 0 to its current length. On
+    /// platforms or filesystems which do not track holes the entire file is
+    /// reported as a single data extent.
+    ///
+    /// The file's position is preserved across the call.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented with the `SEEK_DATA` and `SEEK_HOLE` modes of
+    /// [`lseek(2)`](http://man7.org/linux/man-pages/man2/lseek.2.html) on Unix
+    /// and
+    /// [`FSCTL_QUERY_ALLOCATED_RANGES`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa364582(v=vs.85).aspx)
+    /// on Windows.
+    #[cfg(feature = "allocate")]
+    fn extents(&self) -> io::Result<Extents> {
+        Err(not_implemented("extents"))
+    }
+
+    /// Returns statistics on how fragmented the file's physical storage is.
+    ///
+    /// The physical layout is read with `os::linux::fiemap` on Linux and
+    /// Android and `os::windows::retrieval_pointers` on Windows. Fails with
+    /// `ErrorKind::Unsupported` on other platforms, and on file systems
+    /// which do not report physical locations.
+    #[cfg(feature = "allocate")]
+    fn fragmentation(&self) -> io::Result<Fragmentation> {
+        Err(not_implemented("fragmentation"))
+    }
+
+    /// Returns `true` if the file is sparse.
+    ///
+    /// On Unix a file is considered sparse if it has less physical space
+    /// allocated than its length. On Windows the file's sparse attribute is
+    /// checked.
+    #[cfg(feature = "allocate")]
+    fn is_sparse(&self) -> io::Result<bool> {
+        Err(not_implemented("is_sparse"))
+    }
+
+    /// Marks the file as sparse, allowing ranges of it to be deallocated.
+    ///
+    /// This is a no-op on Unix, where any file may be sparse.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented with
+    /// [`FSCTL_SET_SPARSE`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa364596(v=vs.85).aspx)
+    /// on Windows.
+    #[cfg(feature = "allocate")]
+    fn mark_sparse(&self) -> io::Result<()> {
+        Err(not_implemented("mark_sparse"))
+    }
+
+    /// Deallocates the disk space backing `len` bytes of the file starting at
+    /// `offset`. The range reads as zeros afterwards, and the file's length is
+    /// unchanged.
+    ///
+    /// Filesystems deallocate whole blocks, and zero the parts of partial
+    /// blocks at the ends of the range. On Windows, space is only deallocated
+    /// if the file is sparse (see `mark_sparse`); otherwise the range is
+    /// zeroed. The file's position is preserved across the call.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented with `FALLOC_FL_PUNCH_HOLE` on Linux, `F_PUNCHHOLE`
+    /// on macOS and iOS, and
+    /// [`FSCTL_SET_ZERO_DATA`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa364597(v=vs.85).aspx)
+    /// on Windows. Other platforms return `ErrorKind::Unsupported`.
+    #[cfg(feature = "allocate")]
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        let _ = (offset, len);
+        Err(not_implemented("punch_hole"))
+    }
+
+    /// Truncates the file to `len` bytes, and releases disk space allocated
+    /// past the end of the file.
+    ///
+    /// Some filesystems keep space preallocated past the end of a file after
+    /// it is truncated (XFS speculative preallocation, or space allocated with
+    /// `FALLOC_FL_KEEP_SIZE`), so `allocated_size` does not shrink with the
+    /// file. If the file is already shorter than `len`, it is truncated to its
+    /// current length, which leaves the length unchanged but still releases
+    /// space past its end. The file must not be extended concurrently, since
+    /// data written past the length read at the start of the call is lost.
+    ///
+    /// The file's position is preserved across the call, even if it lies past
+    /// the new end of the file.
+    ///
+    /// # Notes
+    ///
+    /// The space is released by `ftruncate(2)` on Unix, and also with
+    /// `FALLOC_FL_PUNCH_HOLE` from the end of the file onwards on Linux, for
+    /// file systems which keep preallocated space across a truncation. On
+    /// Windows the file's allocation size is set.
+    #[cfg(feature = "allocate")]
+    fn shrink_to(&self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Err(not_implemented("shrink_to"))
+    }
 
     /// Locks the file for shared usage, blocking if the file is currently
     /// locked exclusively.
-    fn lock_shared(&self) -> Result<()>;
+    #[cfg(feature = "locks")]
+    fn lock_shared(&self) -> io::Result<()> {
+        Err(not_implemented("lock_shared"))
+    }
 
     /// Locks the file for exclusive usage, blocking if the file is currently
     /// locked.
-    fn lock_exclusive(&self) -> Result<()>;
+    #[cfg(feature = "locks")]
+    fn lock_exclusive(&self) -> io::Result<()> {
+        Err(not_implemented("lock_exclusive"))
+    }
 
     /// Locks the file for shared usage, or returns a an error if the file is
     /// currently locked (see `lock_contended_error`).
-    fn try_lock_shared(&self) -> Result<()>;
+    #[cfg(feature = "locks")]
+    fn try_lock_shared(&self) -> io::Result<()> {
+        Err(not_implemented("try_lock_shared"))
+    }
 
     /// Locks the file for shared usage, or returns a an error if the file is
     /// currently locked (see `lock_contended_error`).
-    fn try_lock_exclusive(&self) -> Result<()>;
+    #[cfg(feature = "locks")]
+    fn try_lock_exclusive(&self) -> io::Result<()> {
+        Err(not_implemented("try_lock_exclusive"))
+    }
 
     /// Unlocks the file.
-    fn unlock(&self) -> Result<()>;
+    #[cfg(feature = "locks")]
+    fn unlock(&self) -> io::Result<()> {
+        Err(not_implemented("unlock"))
+    }
+
+    /// Locks the file for shared usage, blocking for at most `timeout` if the
+    /// file is currently locked exclusively. Returns an `ErrorKind::TimedOut`
+    /// error if the lock could not be acquired in time.
+    ///
+    /// The lock is polled for, with a backoff of up to 64ms between attempts,
+    /// so the lock may not be acquired as soon as it is released.
+    #[cfg(feature = "locks")]
+    fn lock_shared_timeout(&self, timeout: Duration) -> io::Result<()> {
+        lock_with_timeout(timeout, || self.try_lock_shared())
+    }
+
+    /// Locks the file for exclusive usage, blocking for at most `timeout` if
+    /// the file is currently locked. Returns an `ErrorKind::TimedOut` error if
+    /// the lock could not be acquired in time.
+    ///
+    /// The lock is polled for, as with `lock_shared_timeout`.
+    #[cfg(feature = "locks")]
+    fn lock_exclusive_timeout(&self, timeout: Duration) -> io::Result<()> {
+        lock_with_timeout(timeout, || self.try_lock_exclusive())
+    }
+
+    /// Locks the file for shared usage, blocking if the file is currently
+    /// locked exclusively. The lock is released when the returned guard is
+    /// dropped.
+    ///
+    /// Only a shared reference to the file is required, so guards may be
+    /// taken on files shared through an `Arc`.
+    #[cfg(feature = "locks")]
+    fn lock_shared_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        Err(not_implemented("lock_shared_guard"))
+    }
+
+    /// Locks the file for exclusive usage, blocking if the file is currently
+    /// locked. The lock is released when the returned guard is dropped.
+    #[cfg(feature = "locks")]
+    fn lock_exclusive_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        Err(not_implemented("lock_exclusive_guard"))
+    }
+
+    /// Locks the file for shared usage, or returns an error if the file is
+    /// currently locked (see `lock_contended_error`). The lock is released
+    /// when the returned guard is dropped.
+    #[cfg(feature = "locks")]
+    fn try_lock_shared_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        Err(not_implemented("try_lock_shared_guard"))
+    }
+
+    /// Locks the file for exclusive usage, or returns an error if the file is
+    /// currently locked (see `lock_contended_error`). The lock is released
+    /// when the returned guard is dropped.
+    #[cfg(feature = "locks")]
+    fn try_lock_exclusive_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        Err(not_implemented("try_lock_exclusive_guard"))
+    }
+
+    /// Locks the file for shared usage, blocking for at most `timeout` (see
+    /// `lock_shared_timeout`). The lock is released when the returned guard is
+    /// dropped.
+    #[cfg(feature = "locks")]
+    fn lock_shared_guard_timeout(&self, timeout: Duration) -> io::Result<FileLockGuard<'_>> {
+        let _ = timeout;
+        Err(not_implemented("lock_shared_guard_timeout"))
+    }
+
+    /// Locks the file for exclusive usage, blocking for at most `timeout` (see
+    /// `lock_exclusive_timeout`). The lock is released when the returned guard
+    /// is dropped.
+    #[cfg(feature = "locks")]
+    fn lock_exclusive_guard_timeout(&self, timeout: Duration) -> io::Result<FileLockGuard<'_>> {
+        let _ = timeout;
+        Err(not_implemented("lock_exclusive_guard_timeout"))
+    }
+
+    /// Locks the file for shared usage, blocking if the file is currently
+    /// locked exclusively, and calls `f` while the lock is held.
+    ///
+    /// The lock is released when `f` returns, and also if it panics. An error
+    /// unlocking the file after `f` returns is returned in place of its
+    /// result.
+    #[cfg(feature = "locks")]
+    fn with_shared_lock<T, F>(&self, f: F) -> io::Result<T> where Self: Sized, F: FnOnce(&Self) -> T {
+        with_lock(self, Self::lock_shared, f)
+    }
+
+    /// Locks the file for exclusive usage, blocking if the file is currently
+    /// locked, and calls `f` while the lock is held (see `with_shared_lock`).
+    #[cfg(feature = "locks")]
+    fn with_exclusive_lock<T, F>(&self, f: F) -> io::Result<T> where Self: Sized, F: FnOnce(&Self) -> T {
+        with_lock(self, Self::lock_exclusive, f)
+    }
 }
 
 impl FileExt for File {
-    fn duplicate(&self) -> Result<File> {
-        sys::duplicate(self)
+    #[cfg(feature = "duplicate")]
+    fn duplicate(&self) -> io::Result<File> {
+        let duplicate = sys::duplicate(self)?;
+        #[cfg(feature = "locks")]
+        strict::duplicated(self, &duplicate);
+        Ok(duplicate)
+    }
+    fn set_cloexec(&self, cloexec: bool) -> io::Result<()> {
+        sys::set_inheritable(self, !cloexec)
+    }
+    fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        sys::set_inheritable(self, inheritable)
     }
-    fn allocated_size(&self) -> Result<u64> {
+    #[cfg(feature = "allocate")]
+    fn allocated_size(&self) -> io::Result<u64> {
         sys::allocated_size(self)
     }
-    fn allocate(&self, len: u64) -> Result<()> {
-        sys::allocate(self, len)
+    fn reopen(&self, options: &OpenOptions) -> io::Result<File> {
+        sys::reopen(self, options)
     }
-    fn lock_shared(&self) -> Result<()> {
-        sys::lock_shared(self)
+    fn path(&self) -> io::Result<PathBuf> {
+        sys::path(self)
     }
-    fn lock_exclusive(&self) -> Result<()> {
-        sys::lock_exclusive(self)
+    fn file_id(&self) -> io::Result<FileId> {
+        sys::file_id(self)
     }
-    fn try_lock_shared(&self) -> Result<()> {
-        sys::try_lock_shared(self)
+    #[cfg(feature = "locks")]
+    fn lock_holder(&self) -> io::Result<Option<LockHolder>> {
+        sys::lock_holder(self)
     }
-    fn try_lock_exclusive(&self) -> Result<()> {
-        sys::try_lock_exclusive(self)
+    #[cfg(feature = "statvfs")]
+    fn available_space(&self) -> io::Result<u64> {
+        sys::fstatvfs(self).map(|stat| stat.available_space)
     }
-    fn unlock(&self) -> Result<()> {
-        sys::unlock(self)
+    #[cfg(feature = "statvfs")]
+    fn free_space(&self) -> io::Result<u64> {
+        sys::fstatvfs(self).map(|stat| stat.free_space)
+    }
+    #[cfg(feature = "statvfs")]
+    fn total_space(&self) -> io::Result<u64> {
+        sys::fstatvfs(self).map(|stat| stat.total_space)
+    }
+    #[cfg(feature = "allocate")]
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        AllocateOptions::new().allocate(self, len)
+    }
+    #[cfg(feature = "allocate")]
+    fn allocate_strict(&self, len: u64) -> io::Result<()> {
+        AllocateOptions::new().verify(true).allocate(self, len)
+    }
+
+    #[cfg(feature = "allocate")]
+    fn allocate_zeroed(&self, len: u64) -> io::Result<()> {
+        AllocateOptions::new().mode(AllocateMode::ZeroFill).allocate(self, len)
+    }
+    #[cfg(feature = "allocate")]
+    fn extents(&self) -> io::Result<Extents> {
+        sys::extents(self).map(|extents| Extents { inner: extents.into_iter() })
+    }
+    #[cfg(feature = "allocate")]
+    fn fragmentation(&self) -> io::Result<Fragmentation> {
+        fragmentation::fragmentation(self)
+    }
+    #[cfg(feature = "allocate")]
+    fn is_sparse(&self) -> io::Result<bool> {
+        sys::is_sparse(self)
+    }
+    #[cfg(feature = "allocate")]
+    fn mark_sparse(&self) -> io::Result<()> {
+        sys::mark_sparse(self)
+    }
+    #[cfg(feature = "allocate")]
+    fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        sys::punch_hole(self, offset, len)
+    }
+    #[cfg(feature = "allocate")]
+    fn shrink_to(&self, len: u64) -> io::Result<()> {
+        let len = cmp::min(len, self.metadata()?.len());
+        // Truncating to the current length still releases preallocated space on file systems
+        // such as ext4, which do not punch holes past the end of a file.
+        self.set_len(len)?;
+        sys::release_tail(self, len)
+    }
+    #[cfg(feature = "locks")]
+    fn lock_shared(&self) -> io::Result<()> {
+        strict::lock(self, LockMode::Shared, registry::lock_shared)
+    }
+    #[cfg(feature = "locks")]
+    fn lock_exclusive(&self) -> io::Result<()> {
+        strict::lock(self, LockMode::Exclusive, registry::lock_exclusive)
+    }
+    #[cfg(feature = "locks")]
+    fn try_lock_shared(&self) -> io::Result<()> {
+        strict::lock(self, LockMode::Shared, registry::try_lock_shared)
+    }
+    #[cfg(feature = "locks")]
+    fn try_lock_exclusive(&self) -> io::Result<()> {
+        strict::lock(self, LockMode::Exclusive, registry::try_lock_exclusive)
+    }
+    #[cfg(feature = "locks")]
+    fn unlock(&self) -> io::Result<()> {
+        strict::unlock(self, registry::unlock)
+    }
+    #[cfg(feature = "locks")]
+    fn lock_shared_timeout(&self, timeout: Duration) -> io::Result<()> {
+        strict::lock(self, LockMode::Shared,
+                     |file| lock_with_timeout(timeout, || registry::try_lock_shared(file)))
+    }
+    #[cfg(feature = "locks")]
+    fn lock_exclusive_timeout(&self, timeout: Duration) -> io::Result<()> {
+        strict::lock(self, LockMode::Exclusive,
+                     |file| lock_with_timeout(timeout, || registry::try_lock_exclusive(file)))
+    }
+    #[cfg(feature = "locks")]
+    fn lock_shared_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        let start = Instant::now();
+        FileExt::lock_shared(self).map(|()| FileLockGuard::new(self, LockMode::Shared, None, start))
+    }
+    #[cfg(feature = "locks")]
+    fn lock_exclusive_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        let start = Instant::now();
+        FileExt::lock_exclusive(self).map(|()| FileLockGuard::new(self, LockMode::Exclusive, None, start))
+    }
+    #[cfg(feature = "locks")]
+    fn try_lock_shared_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        let start = Instant::now();
+        FileExt::try_lock_shared(self).map(|()| FileLockGuard::new(self, LockMode::Shared, None, start))
+    }
+    #[cfg(feature = "locks")]
+    fn try_lock_exclusive_guard(&self) -> io::Result<FileLockGuard<'_>> {
+        let start = Instant::now();
+        FileExt::try_lock_exclusive(self).map(|()| FileLockGuard::new(self, LockMode::Exclusive, None, start))
+    }
+    #[cfg(feature = "locks")]
+    fn lock_shared_guard_timeout(&self, timeout: Duration) -> io::Result<FileLockGuard<'_>> {
+        let start = Instant::now();
+        self.lock_shared_timeout(timeout).map(|()| FileLockGuard::new(self, LockMode::Shared, None, start))
+    }
+    #[cfg(feature = "locks")]
+    fn lock_exclusive_guard_timeout(&self, timeout: Duration) -> io::Result<FileLockGuard<'_>> {
+        let start = Instant::now();
+        self.lock_exclusive_timeout(timeout).map(|()| FileLockGuard::new(self, LockMode::Exclusive, None, start))
+    }
+    #[cfg(feature = "locks")]
+    fn with_shared_lock<T, F>(&self, f: F) -> io::Result<T> where F: FnOnce(&File) -> T {
+        let guard = self.lock_shared_guard()?;
+        let value = f(self);
+        guard.unlock().map(|()| value)
+    }
+    #[cfg(feature = "locks")]
+    fn with_exclusive_lock<T, F>(&self, f: F) -> io::Result<T> where F: FnOnce(&File) -> T {
+        let guard = self.lock_exclusive_guard()?;
+        let value = f(self);
+        guard.unlock().map(|()| value)
+    }
+}
+
+/// Returns the error of a `FileExt` method which an implementation does not
+/// provide.
+#[cfg(any(feature = "allocate", feature = "duplicate", feature = "locks", feature = "statvfs"))]
+fn not_implemented(method: &str) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, format!("FileExt::{} is not implemented for this type", method))
+}
+
+/// Locks `file` with `lock`, and calls `f` while the lock is held. The file is
+/// unlocked when `f` returns, and also if it panics.
+#[cfg(feature = "locks")]
+fn with_lock<S, T, F>(file: &S, lock: fn(&S) -> io::Result<()>, f: F) -> io::Result<T>
+where S: FileExt, F: FnOnce(&S) -> T {
+    /// Unlocks the file if `f` panics.
+    struct Unlock<'a, S: FileExt + 'a>(&'a S);
+    impl<'a, S: FileExt> Drop for Unlock<'a, S> {
+        fn drop(&mut self) {
+            let _ = self.0.unlock();
+        }
+    }
+
+    lock(file)?;
+    let unlock = Unlock(file);
+    let value = f(file);
+    mem::forget(unlock);
+    file.unlock().map(|()| value)
+}
+
+/// Locks `mutex`, ignoring poisoning.
+///
+/// The mutexes of this crate guard bookkeeping which each critical section
+/// leaves consistent, since nothing between its related updates can panic. A
+/// panic while one was locked therefore left nothing half done, and failing
+/// every later caller would only spread it.
+#[cfg(any(feature = "locks", feature = "statvfs"))]
+fn lock_unpoisoned<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// The longest pause between attempts when polling for a lock.
+#[cfg(feature = "locks")]
+const MAX_BACKOFF: Duration = Duration::from_millis(64);
+
+/// Calls `try_lock` until it succeeds, fails with an error other than lock
+/// contention, or `timeout` elapses.
+#[cfg(feature = "locks")]
+fn lock_with_timeout<F>(timeout: Duration, mut try_lock: F) -> io::Result<()> where F: FnMut() -> io::Result<()> {
+    let start = Instant::now();
+    let mut backoff = Duration::from_millis(1);
+    loop {
+        match try_lock() {
+            Err(ref err) if err.kind() == sys::lock_error().kind() => (),
+            result => return result,
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            return Err(io::Error::new(ErrorKind::TimedOut, "timed out waiting for a file lock"));
+        }
+        thread::sleep(cmp::min(backoff, timeout - elapsed));
+        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// A handler for slowly acquired locks, and the wait which counts as slow.
+#[cfg(feature = "locks")]
+type SlowLockHandler = (Duration, Arc<dyn Fn(&FileLockGuard) + Send + Sync>);
+
+#[cfg(feature = "locks")]
+static SLOW_LOCK_HANDLER: RwLock<Option<SlowLockHandler>> = RwLock::new(None);
+
+/// Installs a handler which is called whenever acquiring a `FileLockGuard`
+/// takes at least `threshold`, replacing any previous handler.
+///
+/// The handler is called on the acquiring thread, once the lock is held, with
+/// the new guard. This makes it a convenient place to log contention, for
+/// example together with a backtrace of the call site. Locks taken without a
+/// guard (`FileExt::lock_exclusive` and friends) are not reported.
+#[cfg(feature = "locks")]
+pub fn set_slow_lock_handler<F>(threshold: Duration, handler: F)
+where F: Fn(&FileLockGuard) + Send + Sync + 'static {
+    *SLOW_LOCK_HANDLER.write().unwrap_or_else(|err| err.into_inner()) = Some((threshold, Arc::new(handler)));
+}
+
+/// Removes the handler installed with `set_slow_lock_handler`.
+#[cfg(feature = "locks")]
+pub fn clear_slow_lock_handler() {
+    *SLOW_LOCK_HANDLER.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// A lock held on a file, which is released when the guard is dropped.
+///
+/// Guards are created with the `*_guard` methods of `FileExt` or with
+/// `LockOptions`, and dereference to the locked file. Errors while unlocking on
+/// drop are ignored.
+#[cfg(feature = "locks")]
+#[derive(Debug)]
+pub struct FileLockGuard<'a> {
+    file: &'a File,
+    mode: LockMode,
+    /// The locked byte range as `(offset, len)`, or `None` for the whole file.
+    range: Option<(u64, u64)>,
+    /// The backend holding the lock, with `LockBackend::Auto` resolved on Unix.
+    backend: LockBackend,
+    unreliable: bool,
+    wait: Duration,
+    /// Set when a failed upgrade lost the lock, so there is nothing to release.
+    released: bool,
+}
+
+#[cfg(feature = "locks")]
+impl<'a> FileLockGuard<'a> {
+    /// Creates a guard for a lock requested at `start`, and reports it to the
+    /// slow lock handler if acquiring it took too long.
+    fn new(file: &'a File, mode: LockMode, range: Option<(u64, u64)>, start: Instant) -> FileLockGuard<'a> {
+        FileLockGuard::with_backend(file, mode, range, LockBackend::Flock, start)
+    }
+
+    fn with_backend(file: &'a File, mode: LockMode, range: Option<(u64, u64)>, backend: LockBackend,
+                    start: Instant) -> FileLockGuard<'a> {
+        let guard = FileLockGuard { file, mode, range, backend, unreliable: false, wait: start.elapsed(),
+                                    released: false };
+        let handler = SLOW_LOCK_HANDLER.read().unwrap_or_else(|err| err.into_inner()).clone();
+        if let Some((threshold, handler)) = handler {
+            if guard.wait >= threshold {
+                handler(&guard);
+            }
+        }
+        guard
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &'a File {
+        self.file
+    }
+
+    /// Returns the mode the file is locked in.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns `true` if the file is locked exclusively.
+    pub fn is_exclusive(&self) -> bool {
+        self.mode == LockMode::Exclusive
+    }
+
+    /// Returns the locked byte range, or `None` if the whole file is locked.
+    pub fn range(&self) -> Option<Range<u64>> {
+        self.range.map(|(offset, len)| offset..offset.saturating_add(len))
+    }
+
+    /// Returns how long it took to acquire the lock, including any time spent
+    /// waiting for other holders to release it.
+    pub fn wait_duration(&self) -> Duration {
+        self.wait
+    }
+
+    /// Returns `true` if the lock was taken with `NetworkFsPolicy::Warn` on a
+    /// network file system, so that it may not exclude other clients.
+    pub fn is_unreliable(&self) -> bool {
+        self.unreliable
+    }
+
+    /// Returns `true` if the guard no longer holds a lock, because a failed
+    /// `try_upgrade` could not take the shared lock again. Unlocking such a
+    /// guard does nothing.
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Returns the byte range locked with `fcntl(2)` or `LockFileEx`, or
+    /// `None` for a whole-file lock taken through the lock registry.
+    fn record_range(&self) -> Option<(u64, u64)> {
+        match self.range {
+            None if self.backend != LockBackend::Flock => Some((0, 0)),
+            range => range,
+        }
+    }
+
+    /// Converts an exclusive lock into a shared lock, without releasing it. A
+    /// shared guard is returned unchanged.
+    ///
+    /// On Windows, and on Unix platforms where locks are emulated with
+    /// `fcntl(2)`, the conversion is atomic. With `flock(2)` the conversion
+    /// may briefly release the lock, allowing another process to lock the
+    /// file exclusively before the shared lock is granted; this call then
+    /// blocks until the shared lock is available. If the conversion fails the
+    /// lock is released. Byte-range locks on Unix are converted atomically.
+    pub fn downgrade(mut self) -> io::Result<FileLockGuard<'a>> {
+        if self.mode == LockMode::Exclusive {
+            match self.record_range() {
+                None => {
+                    sys::downgrade(self.file)?;
+                    registry::set_mode(self.file, LockMode::Shared);
+                }
+                Some((offset, len)) => sys::downgrade_range(self.file, offset, len, self.backend)?,
+            }
+            self.mode = LockMode::Shared;
+        }
+        Ok(self)
+    }
+
+    /// Attempts to convert a shared lock into an exclusive lock, without
+    /// blocking. An exclusive guard is returned unchanged.
+    ///
+    /// If the file is locked by another handle, the error (see
+    /// `lock_contended_error`) is returned together with the original shared
+    /// guard, so the caller keeps its lock. The conversion is not atomic
+    /// everywhere: with `flock(2)` a failed conversion may release the shared
+    /// lock, and on Windows the shared lock is released before the exclusive
+    /// lock is attempted (for whole-file and byte-range locks alike). In both
+    /// cases the shared lock is taken again,
+    /// blocking if another handle locked the file exclusively in the
+    /// meantime. If that fails, the error is the one from re-locking, and the
+    /// returned guard no longer holds a lock: `is_released` returns `true`,
+    /// and unlocking or dropping the guard does nothing.
+    pub fn try_upgrade(mut self) -> ::std::result::Result<FileLockGuard<'a>, (FileLockGuard<'a>, io::Error)> {
+        if self.mode == LockMode::Shared {
+            let result = match self.record_range() {
+                None => sys::try_upgrade(self.file),
+                Some((offset, len)) => sys::try_upgrade_range(self.file, offset, len, self.backend),
+            };
+            if let Err((error, held)) = result {
+                if !held {
+                    // Clear the bookkeeping for the lost lock; the unlock itself may fail.
+                    let _ = self.release();
+                    self.released = true;
+                }
+                return Err((self, error));
+            }
+            if self.record_range().is_none() {
+                registry::set_mode(self.file, LockMode::Exclusive);
+            }
+            self.mode = LockMode::Exclusive;
+        }
+        Ok(self)
+    }
+
+    /// Releases the lock, returning any error from unlocking the file.
+    ///
+    /// Dropping the guard also releases the lock, but ignores errors.
+    pub fn unlock(self) -> io::Result<()> {
+        let result = self.release();
+        mem::forget(self);
+        result
+    }
+
+    fn release(&self) -> io::Result<()> {
+        if self.released {
+            return Ok(());
+        }
+        match self.record_range() {
+            None => strict::unlock(self.file, registry::unlock),
+            Some((offset, len)) => sys::unlock_range_with(self.file, offset, len, self.backend),
+        }
+    }
+}
+
+#[cfg(feature = "locks")]
+impl<'a> Deref for FileLockGuard<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.file
+    }
+}
+
+#[cfg(feature = "locks")]
+impl<'a> Drop for FileLockGuard<'a> {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// The identity of a file, as returned by `FileExt::file_id`.
+///
+/// Identities are ordered, so they may be used to lock several files in a
+/// consistent order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId {
+    device: u64,
+    index: u64,
+}
+
+impl FileId {
+    /// Returns the device (Unix) or volume serial number (Windows) of the
+    /// file.
+    pub fn device(&self) -> u64 {
+        self.device
+    }
+
+    /// Returns the inode number (Unix) or file index (Windows) of the file.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+/// Returns `true` if the two files refer to the same underlying file.
+pub fn is_same_file(file1: &File, file2: &File) -> io::Result<bool> {
+    Ok(sys::file_id(file1)? == sys::file_id(file2)?)
+}
+
+/// Options which can be used to configure how disk space is allocated for a
+/// file.
+///
+/// Options are configured with chained setters, and then applied to a file
+/// with `allocate`. `FileExt::allocate` uses the default options.
+#[cfg(feature = "allocate")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllocateOptions {
+    contiguous: bool,
+    verify: bool,
+    touch: bool,
+    mode: AllocateMode,
+}
+
+#[cfg(feature = "allocate")]
+impl AllocateOptions {
+    /// Creates a new set of options with the default configuration.
+    pub fn new() -> AllocateOptions {
+        AllocateOptions {
+            contiguous: true,
+            verify: false,
+            touch: false,
+            mode: AllocateMode::Portable,
+        }
+    }
+
+    /// Sets how space is reserved on filesystems which can not allocate it
+    /// natively. Defaults to `AllocateMode::Portable`.
+    pub fn mode(&mut self, mode: AllocateMode) -> &mut AllocateOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether the allocation should request contiguous disk space,
+    /// falling back to non-contiguous space if no contiguous region is
+    /// available. Defaults to `true`.
+    ///
+    /// Disabling this skips the search for a contiguous region, which may be
+    /// slow on fragmented volumes. This is only respected on macOS and iOS
+    /// (`F_ALLOCATECONTIG`); other platforms leave layout to the filesystem.
+    pub fn contiguous(&mut self, contiguous: bool) -> &mut AllocateOptions {
+        self.contiguous = contiguous;
+        self
+    }
+
+    /// Sets whether the allocation is checked with `FileExt::allocated_size`
+    /// afterwards, failing with `ErrorKind::StorageFull` if less than `len`
+    /// bytes are allocated. Defaults to `false`.
+    ///
+    /// Some filesystems report success without reserving space, or only
+    /// extend the file's length, so that writes may later fail with `ENOSPC`.
+    /// Compressing or deduplicating filesystems may fail the check even though
+    /// the space is available.
+    pub fn verify(&mut self, verify: bool) -> &mut AllocateOptions {
+        self.verify = verify;
+        self
+    }
+
+    /// Sets whether one byte in each allocation unit of the file is rewritten
+    /// with its current value after allocating, forcing the filesystem to back
+    /// every unit with disk space. Defaults to `false`.
+    ///
+    /// This is slow for large files, but guarantees that the space exists on
+    /// filesystems which allocate lazily. The file must be opened for reading
+    /// and writing.
+    pub fn touch(&mut self, touch: bool) -> &mut AllocateOptions {
+        self.touch = touch;
+        self
+    }
+
+    /// Ensures that at least `len` bytes of disk space are allocated for
+    /// `file`, and the file size is at least `len` bytes, according to these
+    /// options.
+    ///
+    /// The file's position is preserved across the call, including while
+    /// touching allocation units.
+    ///
+    /// Fails up front with `ErrorKind::FileTooLarge` if the file must grow to
+    /// `len` bytes and `len` exceeds the process's `RLIMIT_FSIZE` limit or the
+    /// file system's maximum file size (see `max_writable_size`), rather than
+    /// with `EFBIG` and a `SIGXFSZ` signal partway through.
+    pub fn allocate(&self, file: &File, len: u64) -> io::Result<()> {
+        check_writable_size(file, len)?;
+        sys::allocate(file, len, self)?;
+        if self.touch {
+            touch(file, 0, len, touch_granularity(file)?)?;
+        }
+        self.check_allocated(file, len)
+    }
+
+    /// Like `allocate`, but allocates in chunks of up to 64 MiB, reporting
+    /// progress and checking for cancellation after each.
+    ///
+    /// Touching and verification are applied as by `allocate`. Each chunk is
+    /// touched as it is allocated, so when touching, progress starts from zero
+    /// rather than from the space already allocated.
+    ///
+    /// If the allocation is cancelled, the space allocated so far is kept, and
+    /// the file may have been extended.
+    pub fn allocate_with_progress(&self, file: &File, len: u64, progress: &mut Progress) -> io::Result<()> {
+        const CHUNK: u64 = 64 << 20;
+        check_writable_size(file, len)?;
+        let granularity = if self.touch { touch_granularity(file)? } else { 0 };
+        let mut done = if self.touch { 0 } else { cmp::min(sys::allocated_size(file)?, len) };
+        let mut touched = 0;
+        loop {
+            progress::update(progress, done, len)?;
+            if done >= len {
+                return self.check_allocated(file, len);
+            }
+            done = cmp::min(done.saturating_add(CHUNK), len);
+            sys::allocate(file, done, self)?;
+            if self.touch {
+                touched = touch(file, touched, done, granularity)?;
+            }
+        }
+    }
+
+    /// Fails with `ErrorKind::StorageFull` if verifying and less than `len`
+    /// bytes are allocated for `file`.
+    fn check_allocated(&self, file: &File, len: u64) -> io::Result<()> {
+        if self.verify && sys::allocated_size(file)? < len {
+            return Err(io::Error::new(ErrorKind::StorageFull, "filesystem did not allocate the requested space"));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the distance between the bytes rewritten by `touch`.
+#[cfg(feature = "allocate")]
+fn touch_granularity(file: &File) -> io::Result<u64> {
+    Ok(cmp::max(sys::allocation_granularity(file)?, 1))
+}
+
+/// Rewrites the byte at `offset`, and at every `granularity` bytes after it
+/// before `end`, with its current value. Returns the offset after the last
+/// byte rewritten.
+#[cfg(feature = "allocate")]
+fn touch(file: &File, mut offset: u64, end: u64, granularity: u64) -> io::Result<u64> {
+    let mut byte = [0];
+    while offset < end {
+        positional::read_exact_at(file, &mut byte, offset)?;
+        positional::write_at(file, &byte, offset)?;
+        offset += granularity;
+    }
+    Ok(offset)
+}
+
+#[cfg(feature = "allocate")]
+impl Default for AllocateOptions {
+    fn default() -> AllocateOptions {
+        AllocateOptions::new()
+    }
+}
+
+/// Returns the largest size `file` can be extended to, or `None` if no limit
+/// is known.
+///
+/// This is the smaller of the process's soft `RLIMIT_FSIZE` limit on Unix and
+/// the maximum file size of the file system containing `file` (see
+/// `FsStatsExt::max_file_size`). Free space is not taken into account.
+#[cfg(feature = "allocate")]
+pub fn max_writable_size(file: &File) -> io::Result<Option<u64>> {
+    let limit = sys::file_size_limit()?;
+    let max = sys::max_file_size(file)?;
+    Ok(match (limit, max) {
+        (Some(limit), Some(max)) => Some(cmp::min(limit, max)),
+        (limit, max) => limit.or(max),
+    })
+}
+
+/// Fails with `ErrorKind::FileTooLarge` if `file` can not be extended to
+/// `len` bytes.
+///
+/// Nothing is checked if `file` is already at least `len` bytes long, and the
+/// file system's maximum is best effort, since failing to determine it must
+/// not fail the allocation.
+#[cfg(feature = "allocate")]
+fn check_writable_size(file: &File, len: u64) -> io::Result<()> {
+    if len <= file.metadata()?.len() {
+        return Ok(());
+    }
+    if let Some(limit) = sys::file_size_limit()? {
+        if len > limit {
+            return Err(io::Error::new(ErrorKind::FileTooLarge,
+                                      format!("{} bytes exceeds the file size limit (RLIMIT_FSIZE) of {} bytes", len, limit)));
+        }
+    }
+    if let Some(max) = sys::max_file_size(file).ok().flatten() {
+        if len > max {
+            return Err(io::Error::new(ErrorKind::FileTooLarge,
+                                      format!("{} bytes exceeds the file system's maximum file size of {} bytes", len, max)));
+        }
+    }
+    Ok(())
+}
+
+/// How `AllocateOptions::allocate` reserves space when the filesystem does
+/// not support native allocation, as on some FUSE and older NFS mounts where
+/// Linux `fallocate(2)` fails with `EOPNOTSUPP`.
+///
+/// Windows always allocates natively, and ignores the mode.
+#[cfg(feature = "allocate")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AllocateMode {
+    /// Only allocate natively, failing with `ErrorKind::Unsupported` rather
+    /// than writing to the file.
+    Fast,
+    /// Fall back to `posix_fallocate(3)`, which the C library may emulate by
+    /// writing to each block. Platforms with no allocation API only extend
+    /// the file's length.
+    #[default]
+    Portable,
+    /// Fall back to writing zeros from the end of the file to the requested
+    /// length, which backs every block with disk space on any filesystem.
+    /// Holes within the file are left alone.
+    ///
+    /// Zeros are written in aligned 1 MiB chunks, and flushed to disk every
+    /// 64 MiB so that a lack of space is found early and dirty pages do not
+    /// accumulate. This is slow for large files.
+    ZeroFill,
+}
+
+/// Deallocates the disk space of every aligned `block_size` block of the file
+/// which reads as all zeros, returning the number of bytes of disk space
+/// reclaimed.
+///
+/// Only data extents are scanned, so existing holes are skipped. The block size
+/// should be a multiple of the filesystem's allocation granularity, since
+/// partial blocks can not be deallocated. The file is marked sparse first (see
+/// `FileExt::mark_sparse`), and its contents are unchanged.
+#[cfg(feature = "allocate")]
+pub fn sparsify(file: &File, block_size: u64) -> io::Result<u64> {
+    if block_size == 0 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "block size must be non-zero"));
+    }
+    sys::mark_sparse(file)?;
+    let before = sys::allocated_size(file)?;
+    let mut buf = vec![0; usize::try_from(block_size).map_err(|_| {
+        io::Error::new(ErrorKind::InvalidInput, "block size is too large")
+    })?];
+
+    for extent in sys::extents(file)? {
+        if extent.is_hole {
+            continue;
+        }
+        let start = extent.offset.div_ceil(block_size) * block_size;
+        let end = (extent.offset + extent.len) / block_size * block_size;
+        let mut zeros = None;
+        let mut offset = start;
+        while offset < end {
+            positional::read_exact_at(file, &mut buf, offset)?;
+            if buf.iter().all(|&b| b == 0) {
+                zeros.get_or_insert(offset);
+            } else if let Some(zeros) = zeros.take() {
+                sys::punch_hole(file, zeros, offset - zeros)?;
+            }
+            offset += block_size;
+        }
+        if let Some(zeros) = zeros {
+            sys::punch_hole(file, zeros, end - zeros)?;
+        }
+    }
+
+    Ok(before.saturating_sub(sys::allocated_size(file)?))
+}
+
+/// Spawns `command`, after making sure that none of `files` is inherited by
+/// the child.
+///
+/// A lock belongs to the open file, not the process, so a child process which
+/// inherits a locked file holds the lock too, and keeps holding it after the
+/// parent has unlocked or exited. Each file is marked close-on-exec (see
+/// `FileExt::set_cloexec`) before spawning, and stays marked afterwards.
+///
+/// This does not help a child created with a bare `fork(2)` which does not
+/// execute another program: such a child shares every lock of its parent
+/// until it closes the files or exits, and unlocking in either process
+/// releases the lock for both.
+pub fn spawn_without_locks(command: &mut Command, files: &[&File]) -> io::Result<Child> {
+    for file in files {
+        sys::set_inheritable(file, false)?;
+    }
+    command.spawn()
+}
+
+/// Options which can be used to configure how a file handle is duplicated.
+///
+/// Unlike `FileExt::duplicate`, which keeps the historical behavior of
+/// creating an inheritable handle on Windows, duplicates are not inherited by
+/// child processes unless `inheritable` is set.
+#[cfg(feature = "duplicate")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateOptions {
+    inheritable: bool,
+}
+
+#[cfg(feature = "duplicate")]
+impl DuplicateOptions {
+    /// Creates a new set of options with the default configuration.
+    pub fn new() -> DuplicateOptions {
+        DuplicateOptions {
+            inheritable: false,
+        }
+    }
+
+    /// Sets whether the duplicate is inherited by child processes. Defaults to
+    /// `false`.
+    ///
+    /// On Unix this controls `FD_CLOEXEC`, and on Windows the handle's
+    /// inherit flag.
+    pub fn inheritable(&mut self, inheritable: bool) -> &mut DuplicateOptions {
+        self.inheritable = inheritable;
+        self
+    }
+
+    /// Duplicates `file` according to these options. The duplicate shares the
+    /// file's position and locks.
+    pub fn duplicate(&self, file: &File) -> io::Result<File> {
+        let duplicate = sys::duplicate_with(file, self.inheritable)?;
+        #[cfg(feature = "locks")]
+        strict::duplicated(file, &duplicate);
+        Ok(duplicate)
+    }
+}
+
+#[cfg(feature = "duplicate")]
+impl Default for DuplicateOptions {
+    fn default() -> DuplicateOptions {
+        DuplicateOptions::new()
+    }
+}
+
+/// Options which can be used to configure how a file is created by
+/// `create_sized`.
+#[cfg(all(feature = "allocate", feature = "locks"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreateOptions {
+    sparse: bool,
+    lock: bool,
+    allocate: AllocateOptions,
+}
+
+#[cfg(all(feature = "allocate", feature = "locks"))]
+impl CreateOptions {
+    /// Creates a new set of options with the default configuration.
+    pub fn new() -> CreateOptions {
+        CreateOptions {
+            sparse: false,
+            lock: false,
+            allocate: AllocateOptions::new(),
+        }
+    }
+
+    /// Sets whether the file is marked sparse before space is allocated (see
+    /// `FileExt::mark_sparse`). Defaults to `false`.
+    pub fn sparse(&mut self, sparse: bool) -> &mut CreateOptions {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Sets whether the returned file holds an exclusive lock. Defaults to
+    /// `false`.
+    ///
+    /// The lock is taken before the file appears at its path, so no other
+    /// process can lock the file before the caller. It is taken like
+    /// `FileExt::lock_exclusive`, so it is known to the lock registry and to
+    /// the `strict` checks, and is released with `FileExt::unlock`.
+    pub fn lock(&mut self, lock: bool) -> &mut CreateOptions {
+        self.lock = lock;
+        self
+    }
+
+    /// Sets the options used to allocate space for the file.
+    pub fn allocate_options(&mut self, options: AllocateOptions) -> &mut CreateOptions {
+        self.allocate = options;
+        self
+    }
+
+    /// Creates a new file at `path` with `len` bytes allocated, according to
+    /// these options. See `create_sized`.
+    pub fn create<P: AsRef<Path>>(&self, path: P, len: u64) -> io::Result<File> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = path.as_ref();
+        let name = path.file_name()
+                       .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "path has no file name"))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".fs2-create-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let temp_path = path.with_file_name(temp_name);
+
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&temp_path)?;
+        let result = self.prepare(&file, len)
+                         .and_then(|()| fs::hard_link(&temp_path, path));
+        let _ = fs::remove_file(&temp_path);
+        match result {
+            Ok(()) => Ok(file),
+            Err(err) => {
+                if self.lock {
+                    let _ = strict::unlock(&file, registry::unlock);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn prepare(&self, file: &File, len: u64) -> io::Result<()> {
+        if self.lock {
+            strict::lock(file, LockMode::Exclusive, registry::lock_exclusive)?;
+        }
+        if self.sparse {
+            sys::mark_sparse(file)?;
+        }
+        self.allocate.allocate(file, len)
+    }
+}
+
+#[cfg(all(feature = "allocate", feature = "locks"))]
+impl Default for CreateOptions {
+    fn default() -> CreateOptions {
+        CreateOptions::new()
+    }
+}
+
+/// Creates a new file at `path`, with at least `len` bytes of disk space
+/// allocated and a length of `len` bytes.
+///
+/// The file is prepared under a temporary name in the same directory, and
+/// linked into place only once it is fully allocated (and locked and marked
+/// sparse, if requested), so other processes never observe a partially
+/// initialized file. Fails with `ErrorKind::AlreadyExists` if `path` exists.
+///
+/// # Notes
+///
+/// The file is linked into place with `fs::hard_link`, so the file system must
+/// support hard links. On file systems which do not, such as FAT, exFAT, and
+/// some SMB shares, this fails with the error of the link, and no file is
+/// created at `path`.
+#[cfg(all(feature = "allocate", feature = "locks"))]
+pub fn create_sized<P: AsRef<Path>>(path: P, len: u64, options: &CreateOptions) -> io::Result<File> {
+    options.create(path, len)
+}
+
+/// Options which can be used to configure how a file is locked.
+///
+/// Options are configured with chained setters, and then applied to a file
+/// with `lock`, which returns a guard holding the lock. By default the whole
+/// file is locked exclusively, blocking until the lock is available:
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::time::Duration;
+/// use fs2::{LockMode, LockOptions};
+///
+/// let file = File::open("data.db").unwrap();
+/// let guard = LockOptions::new()
+///     .mode(LockMode::Shared)
+///     .range(0..512)
+///     .timeout(Duration::from_secs(5))
+///     .lock(&file)
+///     .unwrap();
+/// ```
+#[cfg(feature = "locks")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockOptions {
+    mode: LockMode,
+    nonblocking: bool,
+    range: Option<Range<u64>>,
+    timeout: Option<Duration>,
+    backend: LockBackend,
+    network_fs: NetworkFsPolicy,
+}
+
+#[cfg(feature = "locks")]
+impl LockOptions {
+    /// Creates a new set of options with the default configuration.
+    pub fn new() -> LockOptions {
+        LockOptions {
+            mode: LockMode::Exclusive,
+            nonblocking: false,
+            range: None,
+            timeout: None,
+            backend: LockBackend::Auto,
+            network_fs: NetworkFsPolicy::Allow,
+        }
+    }
+
+    /// Sets the mode of the lock. Defaults to `LockMode::Exclusive`.
+    pub fn mode(&mut self, mode: LockMode) -> &mut LockOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether locking fails immediately with an error (see
+    /// `lock_contended_error`) if the lock is held elsewhere, instead of
+    /// waiting. Takes precedence over `timeout`. Defaults to `false`.
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut LockOptions {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Locks only the given byte range of the file, instead of the whole file.
+    /// The range may extend past the end of the file.
+    ///
+    /// Byte-range locks are separate from whole-file locks on Unix: they are
+    /// open file description locks on Linux and Android, and POSIX record
+    /// locks elsewhere, and do not conflict with locks taken by the other
+    /// methods of `FileExt`. On Windows both kinds of lock conflict.
+    pub fn range(&mut self, range: Range<u64>) -> &mut LockOptions {
+        self.range = Some(range);
+        self
+    }
+
+    /// Waits at most `timeout` for the lock, failing with
+    /// `ErrorKind::TimedOut` if it can not be acquired in time. By default
+    /// locking waits indefinitely.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut LockOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the system call used to lock the file on Unix. Defaults to
+    /// `LockBackend::Auto`.
+    ///
+    /// Locks taken with `LockBackend::Posix` or `LockBackend::Ofd` are not
+    /// seen by the lock registry (see `set_lock_registry`) or by strict mode,
+    /// which track whole-file `flock(2)` locks. `LockBackend::Flock` can not
+    /// lock byte ranges, and fails with `ErrorKind::InvalidInput` if a range
+    /// is set.
+    pub fn backend(&mut self, backend: LockBackend) -> &mut LockOptions {
+        self.backend = backend;
+        self
+    }
+
+    /// Sets what happens when the file is on a network file system, where
+    /// advisory locks may silently fail to exclude other clients. Defaults to
+    /// `NetworkFsPolicy::Allow`, which skips the check.
+    pub fn network_fs(&mut self, policy: NetworkFsPolicy) -> &mut LockOptions {
+        self.network_fs = policy;
+        self
+    }
+
+    /// Locks `file` according to these options. The lock is released when the
+    /// returned guard is dropped.
+    pub fn lock<'a>(&self, file: &'a File) -> io::Result<FileLockGuard<'a>> {
+        let range = match self.range {
+            Some(ref range) if range.start >= range.end => {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "lock range is empty"));
+            }
+            Some(_) if self.backend == LockBackend::Flock => {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "flock(2) can not lock byte ranges"));
+            }
+            Some(ref range) => Some((range.start, range.end - range.start)),
+            None => None,
+        };
+        let unreliable = self.network_fs != NetworkFsPolicy::Allow && sys::is_network_fs_file(file)?;
+        if unreliable && self.network_fs == NetworkFsPolicy::Deny {
+            let mut err = Error::new("lock", io::Error::new(ErrorKind::Unsupported,
+                                                            "advisory locks on network file systems may not \
+                                                             exclude other clients"));
+            if let Ok(path) = sys::path(file) {
+                err = err.with_path(path);
+            }
+            return Err(err.into());
+        }
+        let backend = sys::lock_backend(file, self.backend, range.is_none())?;
+        // Whole files locked with fcntl(2) are locked from offset 0 to the end of the file.
+        let record = match range {
+            None if backend != LockBackend::Flock => Some((0, 0)),
+            range => range,
+        };
+        let mode = self.mode;
+        let start = Instant::now();
+        let try_lock = || match record {
+            Some((offset, len)) => sys::lock_range_with(file, offset, len, mode, false, backend),
+            None if mode == LockMode::Shared => registry::try_lock_shared(file),
+            None => registry::try_lock_exclusive(file),
+        };
+        let acquire = |_: &File| {
+            if self.nonblocking {
+                try_lock()
+            } else if let Some(timeout) = self.timeout {
+                lock_with_timeout(timeout, try_lock)
+            } else {
+                match record {
+                    Some((offset, len)) => sys::lock_range_with(file, offset, len, mode, true, backend),
+                    None if mode == LockMode::Shared => registry::lock_shared(file),
+                    None => registry::lock_exclusive(file),
+                }
+            }
+        };
+        match record {
+            Some(_) => acquire(file)?,
+            None => strict::lock(file, mode, acquire)?,
+        }
+        let mut guard = FileLockGuard::with_backend(file, mode, range, backend, start);
+        guard.unreliable = unreliable;
+        Ok(guard)
+    }
+}
+
+#[cfg(feature = "locks")]
+impl Default for LockOptions {
+    fn default() -> LockOptions {
+        LockOptions::new()
+    }
+}
+
+/// The mode of a file lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    /// A shared (read) lock, which may be held by many owners at once.
+    Shared,
+    /// An exclusive (read-write) lock, which may be held by a single owner.
+    Exclusive,
+}
+
+/// The system call used to lock files on Unix, as selected with
+/// `LockOptions::backend`.
+///
+/// Windows has a single kind of lock, taken with `LockFileEx`, and ignores
+/// the backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LockBackend {
+    /// `flock(2)`, which locks whole files. Locks belong to the open file and
+    /// its duplicates. This is the backend used by `FileExt`.
+    Flock,
+    /// POSIX record locks, taken with `fcntl(2)`. Locks belong to the
+    /// process: files opened by the same process do not conflict, and closing
+    /// any descriptor for the file releases all of the process's locks on it.
+    /// Record locks work across NFS, and interoperate with programs using
+    /// `fcntl(2)` or `lockf(3)`.
+    Posix,
+    /// Open file description locks, taken with `fcntl(2)`. Locks belong to
+    /// the open file like `flock(2)` locks, but conflict with POSIX record
+    /// locks held by other processes. Only available on Linux and Android;
+    /// elsewhere locking fails with `ErrorKind::Unsupported`.
+    Ofd,
+    /// `Flock` for whole files, except on NFS, where `flock(2)` is emulated
+    /// with or unsupported in favor of record locks, so `Posix` is used.
+    /// Byte ranges use `Ofd` on Linux and Android, and `Posix` elsewhere.
+    #[default]
+    Auto,
+}
+
+/// The error from a failed lock upgrade, and whether the shared lock is still
+/// held afterwards.
+#[cfg(feature = "locks")]
+type UpgradeError = (io::Error, bool);
+
+/// What `LockOptions::lock` does when the file is on a network file system,
+/// as detected by `is_network_fs`.
+///
+/// Advisory locks are only as reliable as the protocol carrying them. NFSv3
+/// locks are held by separate lock daemons, and are silently lost if either
+/// side restarts or the daemons are not running; SMB and 9p mounts may only
+/// lock files on the local machine. Two clients may then both believe they
+/// hold an exclusive lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum NetworkFsPolicy {
+    /// Lock the file without checking its file system.
+    #[default]
+    Allow,
+    /// Lock the file, and mark the guard as unreliable (see
+    /// `FileLockGuard::is_unreliable`).
+    Warn,
+    /// Fail with `ErrorKind::Unsupported` without locking the file. The error
+    /// carries the file's path, which can be retrieved with `Error::of`.
+    Deny,
+}
+
+/// A holder of a lock on a file, as returned by `FileExt::lock_holder`.
+///
+/// Formats as, for example, `pid 4242 (postgres)`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockHolder {
+    pid: Option<u32>,
+    mode: LockMode,
+    process_name: Option<String>,
+}
+
+impl LockHolder {
+    /// Returns the ID of the process holding the lock, if it is known.
+    ///
+    /// Locks which belong to an open file rather than a process, such as
+    /// Linux open file description locks, may have no process ID.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Returns the mode of the held lock.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns the name of the process holding the lock, if it is known.
+    pub fn process_name(&self) -> Option<&str> {
+        self.process_name.as_ref().map(|name| &name[..])
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "pid {}", pid)?,
+            None => f.write_str("unknown process")?,
+        }
+        match self.process_name {
+            Some(ref name) => write!(f, " ({})", name),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Attempts to lock each file in `files` with its mode, without blocking.
+///
+/// Either every lock is acquired, or none are: if any file cannot be locked,
+/// the locks already acquired by this call are released in reverse order and
+/// the error for the failing file is returned. Files which are contended fail
+/// with `lock_contended_error`.
+///
+/// The files must not already be locked by the caller, since releasing the
+/// acquired locks would release those too. Fails with
+/// `ErrorKind::InvalidInput`, before locking anything, if a file appears more
+/// than once in `files`, or if the lock registry or the `strict` feature
+/// recorded a lock held through one of them. Locks which were not recorded
+/// can not be detected.
+#[cfg(feature = "locks")]
+pub fn try_lock_many(files: &[(&File, LockMode)]) -> io::Result<()> {
+    for (i, &(file, _)) in files.iter().enumerate() {
+        if files[..i].iter().any(|&(other, _)| ptr::eq(other, file)) {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                                      "a file appears more than once in the files to lock"));
+        }
+        if let Some(held) = strict::mode(file).or_else(|| registry::mode(file)) {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                                      format!("a file to lock is already locked ({:?}) through its handle", held)));
+        }
+    }
+    for (i, &(file, mode)) in files.iter().enumerate() {
+        let ret = match mode {
+            LockMode::Shared => strict::lock(file, mode, registry::try_lock_shared),
+            LockMode::Exclusive => strict::lock(file, mode, registry::try_lock_exclusive),
+        };
+        if let Err(err) = ret {
+            for &(file, _) in files[..i].iter().rev() {
+                let _ = strict::unlock(file, registry::unlock);
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the error that a call to a try lock method on a contended file will
+/// return.
+///
+/// The error is of kind `ErrorKind::WouldBlock` on every platform, so
+/// contention can be detected by matching on `kind()`. On Windows it carries
+/// no raw OS error code.
+#[cfg(feature = "locks")]
+pub fn lock_contended_error() -> io::Error {
+    sys::lock_error()
+}
+
+/// Sends up to `len` bytes of `file`, starting at `offset`, to `socket`.
+///
+/// Returns the number of bytes sent, which may be less than `len` if the end
+/// of the file is reached, or if the socket is non-blocking and would block.
+/// The file's position is not changed.
+///
+/// # Notes
+///
+/// This is implemented with
+/// [`sendfile(2)`](http://man7.org/linux/man-pages/man2/sendfile.2.html) on
+/// Linux, macOS and FreeBSD, and
+/// [`TransmitFile`](https://msdn.microsoft.com/en-us/library/windows/desktop/ms740565(v=vs.85).aspx)
+/// on Windows. Other platforms copy through a userspace buffer.
+#[cfg(all(feature = "utilities", unix))]
+pub fn send_to_socket<S>(file: &File, socket: &S, offset: u64, len: u64) -> io::Result<u64>
+where S: std::os::unix::io::AsRawFd {
+    send_all(file, socket.as_raw_fd(), offset, len)
+}
+
+/// Sends up to `len` bytes of `file`, starting at `offset`, to `socket`.
+///
+/// Returns the number of bytes sent, which may be less than `len` if the end
+/// of the file is reached, or if the socket is non-blocking and would block.
+/// The file's position is not changed.
+///
+/// # Notes
+///
+/// This is implemented with
+/// [`sendfile(2)`](http://man7.org/linux/man-pages/man2/sendfile.2.html) on
+/// Linux, macOS and FreeBSD, and
+/// [`TransmitFile`](https://msdn.microsoft.com/en-us/library/windows/desktop/ms740565(v=vs.85).aspx)
+/// on Windows. Other platforms copy through a userspace buffer.
+#[cfg(all(feature = "utilities", windows))]
+pub fn send_to_socket<S>(file: &File, socket: &S, offset: u64, len: u64) -> io::Result<u64>
+where S: std::os::windows::io::AsRawSocket {
+    send_all(file, socket.as_raw_socket(), offset, len)
+}
+
+#[cfg(all(feature = "utilities", any(unix, windows)))]
+fn send_all(file: &File, socket: sys::RawSocket, offset: u64, len: u64) -> io::Result<u64> {
+    let mut sent = 0;
+    while sent < len {
+        let position = offset.checked_add(sent).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "offset overflows the file position")
+        })?;
+        match sys::send_to_socket(file, socket, position, len - sent) {
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock && sent > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(sent)
+}
+
+/// Describes which operations provided by this crate are supported on the
+/// current platform.
+///
+/// Operations which are not supported fail at runtime with an error of kind
+/// `ErrorKind::Unsupported` unless noted otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    duplicate: bool,
+    lock: bool,
+    allocate: bool,
+    extents: bool,
+    zero_copy_send: bool,
+    statvfs: bool,
+}
+
+impl Capabilities {
+    /// Returns `true` if files can be duplicated with `FileExt::duplicate`.
+    pub fn duplicate(&self) -> bool {
+        self.duplicate
+    }
+
+    /// Returns `true` if files can be locked.
+    pub fn lock(&self) -> bool {
+        self.lock
+    }
+
+    /// Returns `true` if `FileExt::allocate` reserves disk space.
+    ///
+    /// On some platforms without a preallocation API `allocate` only extends
+    /// the length of the file, rather than failing.
+    pub fn allocate(&self) -> bool {
+        self.allocate
+    }
+
+    /// Returns `true` if `FileExt::extents` reports holes. Otherwise the whole
+    /// file is reported as a single data extent.
+    pub fn extents(&self) -> bool {
+        self.extents
+    }
+
+    /// Returns `true` if `send_to_socket` transfers data without copying it
+    /// through userspace.
+    pub fn zero_copy_send(&self) -> bool {
+        self.zero_copy_send
+    }
+
+    /// Returns `true` if filesystem stats can be retrieved with `statvfs` and
+    /// the related space functions.
+    pub fn statvfs(&self) -> bool {
+        self.statvfs
+    }
+}
+
+/// Returns the capabilities of the current platform.
+pub fn capabilities() -> Capabilities {
+    sys::capabilities()
+}
+
+/// A contiguous segment of a file, as returned by `FileExt::extents`.
+#[cfg(feature = "allocate")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Extent {
+    /// The offset of the segment from the start of the file.
+    pub offset: u64,
+    /// The length of the segment in bytes.
+    pub len: u64,
+    /// Whether the segment is a hole, i.e. it has no allocated disk space and
+    /// reads as zeros.
+    pub is_hole: bool,
+}
+
+/// An iterator over the data and hole segments of a file.
+///
+/// This struct is created by `FileExt::extents`.
+#[cfg(feature = "allocate")]
+#[derive(Debug)]
+pub struct Extents {
+    inner: std::vec::IntoIter<Extent>,
+}
+
+#[cfg(feature = "allocate")]
+impl Iterator for Extents {
+    type Item = Extent;
+
+    fn next(&mut self) -> Option<Extent> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// `FsStats` contains some common stats about a file system.
+#[cfg(feature = "statvfs")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FsStats {
+    free_space: u64,
+    available_space: u64,
+    total_space: u64,
+    allocation_granularity: u64,
+    filesystem_id: Option<u64>,
+    kind: Option<FsKind>,
+}
+
+#[cfg(feature = "statvfs")]
+impl FsStats {
+    /// Returns the number of free bytes in the file system containing the provided
+    /// path.
+    pub fn free_space(&self) -> u64 {
+        self.free_space
+    }
+
+    /// Returns the available space in bytes to non-priveleged users in the file
+    /// system containing the provided path.
+    ///
+    /// On Windows, this is the space available to the calling user, which
+    /// accounts for disk quotas.
+    pub fn available_space(&self) -> u64 {
+        self.available_space
+    }
+
+    /// Returns the total space in bytes in the file system containing the provided
+    /// path.
+    pub fn total_space(&self) -> u64 {
+        self.total_space
+    }
+
+    /// Returns the filesystem's disk space allocation granularity in bytes.
+    /// The provided path may be for any file in the filesystem.
+    ///
+    /// On Posix, this is equivalent to the filesystem's block size.
+    /// On Windows, this is equivalent to the filesystem's cluster size.
+    pub fn allocation_granularity(&self) -> u64 {
+        self.allocation_granularity
+    }
+
+    /// Returns the file system ID (`f_fsid`) on Unix, or the volume serial
+    /// number on Windows.
+    ///
+    /// The ID distinguishes mounted file systems, but is not guaranteed to be
+    /// stable across reboots or remounts.
+    pub fn filesystem_id(&self) -> Option<u64> {
+        self.filesystem_id
+    }
+
+    /// Returns the type of the file system, or `None` if it can not be
+    /// determined on this platform, or the query for it failed.
+    ///
+    /// On Unix platforms other than Linux and Android the type takes a second
+    /// system call, so it is only determined by `statvfs_ext`.
+    pub fn kind(&self) -> Option<&FsKind> {
+        self.kind.as_ref()
+    }
+
+    /// Returns the number of bytes in use in the file system.
+    ///
+    /// This is the total space less the free space. Space reserved for
+    /// privileged users is free but not available, so the used and available
+    /// space may not add up to the total space.
+    pub fn used_space(&self) -> u64 {
+        self.total_space.saturating_sub(self.free_space)
+    }
+
+    /// Returns the percentage of the file system's total space which is in
+    /// use, or 0 if the total space is unknown.
+    pub fn percent_used(&self) -> f64 {
+        percent(self.used_space(), self.total_space)
+    }
+
+    /// Returns the percentage of the file system's total space which is
+    /// available to non-privileged users, or 0 if the total space is unknown.
+    pub fn percent_available(&self) -> f64 {
+        percent(self.available_space, self.total_space)
+    }
+
+    /// Returns the change in these stats since `earlier`, which should be a
+    /// snapshot of the same file system.
+    pub fn delta(&self, earlier: &FsStats) -> FsStatsDelta {
+        FsStatsDelta {
+            free_space: difference(self.free_space, earlier.free_space),
+            available_space: difference(self.available_space, earlier.available_space),
+            total_space: difference(self.total_space, earlier.total_space),
+            used_space: difference(self.used_space(), earlier.used_space()),
+        }
+    }
+}
+
+/// The change in a file system's stats between two snapshots, as returned by
+/// `FsStats::delta`. Each field is positive if it grew, and negative if it
+/// shrank.
+#[cfg(feature = "statvfs")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FsStatsDelta {
+    free_space: i64,
+    available_space: i64,
+    total_space: i64,
+    used_space: i64,
+}
+
+#[cfg(feature = "statvfs")]
+impl FsStatsDelta {
+    /// Returns the change in free bytes.
+    pub fn free_space(&self) -> i64 {
+        self.free_space
+    }
+
+    /// Returns the change in bytes available to non-privileged users.
+    pub fn available_space(&self) -> i64 {
+        self.available_space
+    }
+
+    /// Returns the change in total bytes, for example after the file system
+    /// is resized.
+    pub fn total_space(&self) -> i64 {
+        self.total_space
+    }
+
+    /// Returns the change in bytes in use. Positive if space was consumed,
+    /// and negative if it was freed.
+    pub fn used_space(&self) -> i64 {
+        self.used_space
+    }
+}
+
+/// Returns `later - earlier`, saturating at the bounds of `i64`.
+#[cfg(feature = "statvfs")]
+fn difference(later: u64, earlier: u64) -> i64 {
+    if later >= earlier {
+        i64::try_from(later - earlier).unwrap_or(i64::MAX)
+    } else {
+        i64::try_from(earlier - later).map(|d| -d).unwrap_or(i64::MIN)
+    }
+}
+
+#[cfg(feature = "statvfs")]
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+}
+
+#[cfg(feature = "statvfs")]
+impl fmt::Display for FsStats {
+    /// Formats the stats for humans, e.g.
+    /// `40.0 GiB used, 55.0 GiB available of 100.0 GiB (40.0% used)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} used, {} available of {} ({:.1}% used)",
+               Bytes::new(self.used_space()),
+               Bytes::new(self.available_space),
+               Bytes::new(self.total_space),
+               self.percent_used())
+    }
+}
+
+/// Extended stats about a file system, as returned by `statvfs_ext`.
+///
+/// Each field beyond the common `FsStats` is `None` on platforms which do not
+/// report it.
+#[cfg(feature = "statvfs")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FsStatsExt {
+    stats: FsStats,
+    max_name_len: Option<u64>,
+    max_file_size: Option<u64>,
+    flags: Option<u64>,
+    fragment_size: Option<u64>,
+    total_inodes: Option<u64>,
+    free_inodes: Option<u64>,
+    available_inodes: Option<u64>,
+}
+
+#[cfg(feature = "statvfs")]
+impl FsStatsExt {
+    /// Returns the common stats of the file system.
+    pub fn stats(&self) -> &FsStats {
+        &self.stats
+    }
+
+    /// Returns the file system ID (`f_fsid`) on Unix, or the volume serial
+    /// number on Windows.
+    pub fn filesystem_id(&self) -> Option<u64> {
+        self.stats.filesystem_id
+    }
+
+    /// Returns the maximum length in bytes (Unix) or UTF-16 code units
+    /// (Windows) of a file name.
+    pub fn max_name_len(&self) -> Option<u64> {
+        self.max_name_len
+    }
+
+    /// Returns the maximum size in bytes of a file on the file system, e.g.
+    /// 4 GiB - 1 on FAT32, or `None` if it is not known.
+    ///
+    /// On Unix this is derived from `pathconf(_PC_FILESIZEBITS)`. On Windows
+    /// only the limits of FAT volumes are known.
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    /// Returns the platform's mount flags (`f_flag`) on Unix, or file system
+    /// flags (`FILE_*`) on Windows.
+    pub fn flags(&self) -> Option<u64> {
+        self.flags
+    }
+
+    /// Returns the fragment size (`f_frsize`) of the file system. Available on
+    /// Unix.
+    pub fn fragment_size(&self) -> Option<u64> {
+        self.fragment_size
+    }
+
+    /// Returns the total number of inodes in the file system. Available on
+    /// Unix.
+    pub fn total_inodes(&self) -> Option<u64> {
+        self.total_inodes
+    }
+
+    /// Returns the number of free inodes in the file system. Available on
+    /// Unix.
+    pub fn free_inodes(&self) -> Option<u64> {
+        self.free_inodes
+    }
+
+    /// Returns the number of inodes available to non-privileged users in the
+    /// file system. Available on Unix.
+    pub fn available_inodes(&self) -> Option<u64> {
+        self.available_inodes
+    }
+}
+
+/// Get the extended stats of the file system containing the provided path.
+#[cfg(feature = "statvfs")]
+pub fn statvfs_ext<P>(path: P) -> io::Result<FsStatsExt> where P: AsRef<Path> {
+    let path = path.as_ref();
+    sys::statvfs_ext(path).map_err(with_path("statvfs_ext", path))
+}
+
+/// Returns `true` if the path is on a network file system, such as NFS, SMB
+/// or 9p, where files are shared with other machines.
+///
+/// File system types are read with `statfs(2)` on Linux, Android, macOS and
+/// the BSDs; on Windows, drives and shares of type `DRIVE_REMOTE` are network
+/// file systems. Fails with `ErrorKind::Unsupported` on other platforms.
+#[cfg(feature = "statvfs")]
+pub fn is_network_fs<P>(path: P) -> io::Result<bool> where P: AsRef<Path> {
+    let path = path.as_ref();
+    sys::is_network_fs(path).map_err(with_path("is_network_fs", path))
+}
+
+/// Get the stats of the file system containing the provided path.
+///
+/// On Windows, the path may be longer than `MAX_PATH`, a UNC share
+/// (`\\server\share`), or a volume GUID path (`\\?\Volume{...}\`).
+///
+/// Errors carry the path, which can be retrieved with `Error::of`.
+#[cfg(feature = "statvfs")]
+pub fn statvfs<P>(path: P) -> io::Result<FsStats> where P: AsRef<Path> {
+    let path = path.as_ref();
+    sys::statvfs(path).map_err(with_path("statvfs", path))
+}
+
+/// Options which can be used to configure which file system `statvfs`
+/// queries.
+///
+/// By default the stats are of the file system which actually contains the
+/// path, after following symlinks, junctions, bind mounts, and volumes
+/// mounted on directories. On Unix the kernel resolves these itself; on
+/// Windows the path is opened to find its final location first, falling back
+/// to the path as written if it can not be opened.
+#[cfg(feature = "statvfs")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatvfsOptions {
+    outer: bool,
+}
+
+#[cfg(feature = "statvfs")]
+impl StatvfsOptions {
+    /// Creates a new set of options with the default configuration.
+    pub fn new() -> StatvfsOptions {
+        StatvfsOptions {
+            outer: false,
+        }
+    }
+
+    /// Sets whether to query the outer file system, which contains the
+    /// directory entry for the path, rather than the file system the path
+    /// resolves to. Defaults to `false`.
+    ///
+    /// For a mount point, junction, or symlink this is the file system it is
+    /// located on rather than the one it leads to. For other paths both are
+    /// the same.
+    pub fn outer(&mut self, outer: bool) -> &mut StatvfsOptions {
+        self.outer = outer;
+        self
+    }
+
+    /// Get the stats of the file system containing `path` according to these
+    /// options.
+    pub fn statvfs<P>(&self, path: P) -> io::Result<FsStats> where P: AsRef<Path> {
+        let path = path.as_ref();
+        if self.outer {
+            sys::statvfs(&outer_path(path))
+        } else {
+            sys::statvfs(path)
+        }.map_err(with_path("statvfs", path))
+    }
+}
+
+#[cfg(feature = "statvfs")]
+impl Default for StatvfsOptions {
+    fn default() -> StatvfsOptions {
+        StatvfsOptions::new()
+    }
+}
+
+/// Returns the directory containing the entry for `path`, without following
+/// the entry itself.
+#[cfg(feature = "statvfs")]
+fn outer_path(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(_)) if parent.as_os_str().is_empty() => PathBuf::from("."),
+        (Some(parent), Some(_)) => parent.to_path_buf(),
+        // Roots, and paths ending in `..`.
+        _ => path.join(".."),
+    }
+}
+
+/// Get the stats of the file system containing the provided nul-terminated
+/// path.
+///
+/// Unlike `statvfs`, this does not need to convert the path, so repeated
+/// queries may avoid allocating by encoding the path once.
+#[cfg(feature = "statvfs")]
+#[cfg(unix)]
+pub fn statvfs_cstr(path: &CStr) -> io::Result<FsStats> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    sys::statvfs_cstr(path).map_err(with_path("statvfs", Path::new(OsStr::from_bytes(path.to_bytes()))))
+}
+
+/// Returns the number of free bytes in the file system containing the provided
+/// path.
+#[cfg(feature = "statvfs")]
+pub fn free_space<P>(path: P) -> io::Result<u64> where P: AsRef<Path> {
+    statvfs(path).map(|stat| stat.free_space)
+}
+
+/// Returns the available space in bytes to non-priveleged users in the file
+/// system containing the provided path.
+#[cfg(feature = "statvfs")]
+pub fn available_space<P>(path: P) -> io::Result<u64> where P: AsRef<Path> {
+    statvfs(path).map(|stat| stat.available_space)
+}
+
+/// Returns the total space in bytes in the file system containing the provided
+/// path.
+#[cfg(feature = "statvfs")]
+pub fn total_space<P>(path: P) -> io::Result<u64> where P: AsRef<Path> {
+    statvfs(path).map(|stat| stat.total_space)
+}
+
+/// Returns the filesystem's disk space allocation granularity in bytes.
+/// The provided path may be for any file in the filesystem.
+///
+/// On Posix, this is equivalent to the filesystem's block size.
+/// On Windows, this is equivalent to the filesystem's cluster size.
+///
+/// The granularity is cached per volume, keyed by device ID on Unix and by
+/// volume root on Windows; see `clear_granularity_cache`.
+#[cfg(feature = "statvfs")]
+pub fn allocation_granularity<P>(path: P) -> io::Result<u64> where P: AsRef<Path> {
+    granularity::allocation_granularity(path.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+    #[cfg(feature = "unstable")]
+    extern crate test;
+
+    #[cfg(any(feature = "locks", feature = "statvfs"))]
+    use std::env;
+    use std::fs;
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    #[cfg(feature = "utilities")]
+    use std::net::{TcpListener, TcpStream};
+    #[cfg(feature = "locks")]
+    use std::panic;
+    #[cfg(feature = "locks")]
+    use std::path::Path;
+    #[cfg(feature = "locks")]
+    use std::sync::Arc;
+
+    /// Set in the process which runs a test body, to tell it from the test
+    /// harness process which spawned it.
+    #[cfg(feature = "locks")]
+    const IN_PROCESS_VAR: &str = "FS2_OWN_PROCESS_TEST";
+
+    /// Runs the test `name` in a process of its own, for tests which change
+    /// global policies while the harness runs other tests on other threads.
+    /// Returns `true` in that process, where the caller runs the test body.
+    #[cfg(feature = "locks")]
+    pub fn in_own_process(name: &str) -> bool {
+        use std::process::{Command, Stdio};
+
+        if env::var_os(IN_PROCESS_VAR).is_some() {
+            return true;
+        }
+        let status = Command::new(env::current_exe().unwrap())
+            .args([name, "--exact", "--nocapture", "--test-threads", "1"])
+            .env(IN_PROCESS_VAR, "1")
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed in its own process: {}", name, status);
+        false
+    }
+
+    /// Tests file duplication.
+    #[cfg(feature = "duplicate")]
+    #[test]
+    fn duplicate() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file1 =
+            fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut file2 = file1.duplicate().unwrap();
+
+        // Write into the first file and then drop it.
+        file1.write_all(b"foo").unwrap();
+        drop(file1);
+
+        let mut buf = vec![];
+
+        // Read from the second file; since the position is shared it will already be at EOF.
+        file2.read_to_end(&mut buf).unwrap();
+        assert_eq!(0, buf.len());
+
+        // Rewind and read.
+        file2.seek(SeekFrom::Start(0)).unwrap();
+        file2.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, &b"foo");
+    }
+
+    /// Tests shared file lock operations.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_shared() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file3 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        // Concurrent shared access is OK, but not shared and exclusive.
+        FileExt::lock_shared(&file1).unwrap();
+        FileExt::lock_shared(&file2).unwrap();
+        assert_eq!(file3.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        FileExt::unlock(&file1).unwrap();
+        assert_eq!(file3.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
+
+        // Once all shared file locks are dropped, an exclusive lock may be created;
+        FileExt::unlock(&file2).unwrap();
+        file3.lock_exclusive().unwrap();
+    }
+
+    /// Tests exclusive file lock operations.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_exclusive() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        // No other access is possible once an exclusive lock is created.
+        file1.lock_exclusive().unwrap();
+        assert_eq!(file2.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        assert_eq!(FileExt::try_lock_shared(&file2).unwrap_err().kind(),
+                   lock_contended_error().kind());
+
+        // Once the exclusive lock is dropped, the second file is able to create a lock.
+        FileExt::unlock(&file1).unwrap();
+        file2.lock_exclusive().unwrap();
+    }
+
+    /// Tests selecting the system call used to lock files.
+    #[cfg(all(unix, feature = "locks"))]
+    #[test]
+    fn lock_backend() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        // Record locks belong to the process, so files in the same process do not conflict.
+        let posix = LockOptions::new().backend(LockBackend::Posix).lock(&file1).unwrap();
+        LockOptions::new().backend(LockBackend::Posix).nonblocking(true).lock(&file2).unwrap();
+        drop(posix);
+
+        // Locks taken with flock(2), as on local filesystems by default, conflict between files.
+        let auto = LockOptions::new().lock(&file1).unwrap();
+        assert_eq!(file2.try_lock_exclusive().unwrap_err().kind(), lock_contended_error().kind());
+        drop(auto);
+
+        let err = LockOptions::new().backend(LockBackend::Flock).range(0..10).lock(&file1).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            let ofd = LockOptions::new().backend(LockBackend::Ofd).lock(&file1).unwrap();
+            let mut shared = LockOptions::new();
+            shared.backend(LockBackend::Ofd).mode(LockMode::Shared).nonblocking(true);
+            assert_eq!(shared.lock(&file2).unwrap_err().kind(), lock_contended_error().kind());
+            // Open file description locks are separate from flock(2) locks.
+            file2.try_lock_exclusive().unwrap();
+            FileExt::unlock(&file2).unwrap();
+
+            let _ofd = ofd.downgrade().unwrap();
+            shared.lock(&file2).unwrap();
+        } else {
+            let err = LockOptions::new().backend(LockBackend::Ofd).lock(&file1).unwrap_err();
+            assert_eq!(ErrorKind::Unsupported, err.kind());
+        }
+    }
+
+    /// Tests network file system detection, and lock policies on local file
+    /// systems.
+    #[cfg(all(feature = "locks", feature = "statvfs"))]
+    #[test]
+    fn network_fs() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let network = is_network_fs(tempdir.path()).unwrap();
+        assert_eq!(network, is_network_fs(&path).unwrap());
+        assert!(is_network_fs(tempdir.path().join("missing")).is_err());
+
+        let guard = LockOptions::new().network_fs(NetworkFsPolicy::Warn).lock(&file).unwrap();
+        assert_eq!(network, guard.is_unreliable());
+        drop(guard);
+        match LockOptions::new().network_fs(NetworkFsPolicy::Deny).lock(&file) {
+            Ok(guard) => assert!(!network && !guard.is_unreliable()),
+            Err(err) => {
+                assert!(network);
+                assert_eq!(Some(&*path), Error::of(&err).unwrap().path());
+            }
+        };
+    }
+
+    /// Tests that lock contention is reported as `WouldBlock`.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_contended_would_block() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert_eq!(ErrorKind::WouldBlock, lock_contended_error().kind());
+        file1.lock_exclusive().unwrap();
+        assert_eq!(ErrorKind::WouldBlock, file2.try_lock_exclusive().unwrap_err().kind());
+        assert_eq!(ErrorKind::WouldBlock, FileExt::try_lock_shared(&file2).unwrap_err().kind());
+    }
+
+    /// Tests that a reopened file has an independent position.
+    #[test]
+    fn reopen() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(b"hello world").unwrap();
+
+        let mut reopened = file.reopen(fs::OpenOptions::new().read(true)).unwrap();
+        let mut contents = String::new();
+        reopened.read_to_string(&mut contents).unwrap();
+        assert_eq!("hello world", contents);
+        assert_eq!(11, file.stream_position().unwrap());
+
+        // The reopened file is read-only.
+        reopened.seek(SeekFrom::Start(0)).unwrap();
+        assert!(reopened.write_all(b"goodbye").is_err());
+        assert_eq!(0, reopened.stream_position().unwrap());
+    }
+
+    /// Tests retrieving the path of a file.
+    #[test]
+    fn path() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert_eq!(fs::canonicalize(&path).unwrap(),
+                   fs::canonicalize(file.path().unwrap()).unwrap());
+
+        // The path follows the file when it is renamed.
+        let renamed = tempdir.path().join("fs2-renamed");
+        fs::rename(&path, &renamed).unwrap();
+        assert_eq!(fs::canonicalize(&renamed).unwrap(),
+                   fs::canonicalize(file.path().unwrap()).unwrap());
+    }
+
+    /// Tests file identity through different handles and links.
+    #[cfg(feature = "duplicate")]
+    #[test]
+    fn file_id() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path1 = tempdir.path().join("fs2-1");
+        let path2 = tempdir.path().join("fs2-2");
+        let link = tempdir.path().join("fs2-link");
+        let file1 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path1).unwrap();
+        let file2 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path2).unwrap();
+        fs::hard_link(&path1, &link).unwrap();
+        let linked = fs::OpenOptions::new().read(true).open(&link).unwrap();
+
+        assert_eq!(file1.file_id().unwrap(), linked.file_id().unwrap());
+        assert_ne!(file1.file_id().unwrap(), file2.file_id().unwrap());
+        assert!(is_same_file(&file1, &file1.duplicate().unwrap()).unwrap());
+        assert!(is_same_file(&file1, &linked).unwrap());
+        assert!(!is_same_file(&file1, &file2).unwrap());
+    }
+
+    /// Tests that a lock is released after the file that owns it is dropped.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_cleanup() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        file1.lock_exclusive().unwrap();
+        assert_eq!(FileExt::try_lock_shared(&file2).unwrap_err().kind(),
+                   lock_contended_error().kind());
+
+        // Drop file1; the lock should be released.
+        drop(file1);
+        FileExt::lock_shared(&file2).unwrap();
+    }
+
+    /// Tests that a guard holds its lock until it is dropped.
+    #[cfg(feature = "locks")]
+    #[test]
+    #[cfg_attr(feature = "strict", ignore = "locks a handle which already holds a lock, which strict mode rejects")]
+    fn lock_guard() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let guard = file1.lock_exclusive_guard().unwrap();
+        assert_eq!(file2.try_lock_shared_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        assert_eq!(0, guard.metadata().unwrap().len());
+
+        // Drop the guard; the lock should be released.
+        drop(guard);
+        let guard1 = file1.try_lock_shared_guard().unwrap();
+        let guard2 = file2.lock_shared_guard().unwrap();
+        assert_eq!(file2.try_lock_exclusive_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        drop(guard1);
+        drop(guard2);
+        file2.try_lock_exclusive_guard().unwrap();
+    }
+
+    /// Tests acquiring locks with a timeout.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_timeout() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file3 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let timeout = Duration::from_millis(20);
+
+        file1.lock_shared_timeout(timeout).unwrap();
+        let guard = file2.lock_shared_guard_timeout(timeout).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(ErrorKind::TimedOut, file3.lock_exclusive_timeout(timeout).unwrap_err().kind());
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(ErrorKind::TimedOut,
+                   file3.lock_exclusive_guard_timeout(Duration::from_millis(0)).unwrap_err().kind());
+
+        // The lock is acquired once it is released, before the timeout.
+        drop(guard);
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            FileExt::unlock(&file1).unwrap();
+        });
+        file3.lock_exclusive_guard_timeout(Duration::from_secs(10)).unwrap();
+        release.join().unwrap();
+    }
+
+    /// Tests that `FileExt` can be implemented outside of this crate with only
+    /// the methods which are always present, whatever features are enabled.
+    #[test]
+    fn file_ext_defaults() {
+        struct Handle(File);
+
+        impl FileExt for Handle {
+            fn set_cloexec(&self, cloexec: bool) -> io::Result<()> {
+                self.0.set_cloexec(cloexec)
+            }
+            fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+                self.0.set_inheritable(inheritable)
+            }
+            fn reopen(&self, options: &OpenOptions) -> io::Result<File> {
+                self.0.reopen(options)
+            }
+            fn path(&self) -> io::Result<PathBuf> {
+                self.0.path()
+            }
+            fn file_id(&self) -> io::Result<FileId> {
+                self.0.file_id()
+            }
+            #[cfg(feature = "locks")]
+            fn try_lock_exclusive(&self) -> io::Result<()> {
+                FileExt::try_lock_exclusive(&self.0)
+            }
+            #[cfg(feature = "locks")]
+            fn lock_exclusive(&self) -> io::Result<()> {
+                FileExt::lock_exclusive(&self.0)
+            }
+            #[cfg(feature = "locks")]
+            fn unlock(&self) -> io::Result<()> {
+                FileExt::unlock(&self.0)
+            }
+        }
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let handle: Box<dyn FileExt> = Box::new(Handle(file.try_clone().unwrap()));
+        assert_eq!(file.file_id().unwrap(), handle.file_id().unwrap());
+        #[cfg(feature = "allocate")]
+        assert_eq!(ErrorKind::Unsupported, handle.allocate(1).unwrap_err().kind());
+        #[cfg(feature = "statvfs")]
+        assert_eq!(ErrorKind::Unsupported, handle.available_space().unwrap_err().kind());
+        #[cfg(feature = "locks")]
+        {
+            let other = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+            assert_eq!(ErrorKind::Unsupported, handle.lock_shared().unwrap_err().kind());
+            assert!(handle.lock_exclusive_guard().is_err());
+
+            let handle = Handle(file);
+            handle.with_exclusive_lock(|_| {
+                assert_eq!(FileExt::try_lock_shared(&other).unwrap_err().kind(), lock_contended_error().kind());
+            }).unwrap();
+            FileExt::lock_shared(&other).unwrap();
+            assert_eq!(ErrorKind::TimedOut,
+                       handle.lock_exclusive_timeout(Duration::from_millis(10)).unwrap_err().kind());
+            FileExt::unlock(&other).unwrap();
+            handle.lock_exclusive_timeout(Duration::from_millis(10)).unwrap();
+        }
+    }
+
+    /// Tests downgrading an exclusive guard to a shared guard.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_guard_downgrade() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let guard = file1.lock_exclusive_guard().unwrap();
+        assert_eq!(file2.try_lock_shared_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+
+        // Other readers may join after the downgrade, but not writers.
+        assert!(guard.is_exclusive());
+        let guard = guard.downgrade().unwrap();
+        assert_eq!(LockMode::Shared, guard.mode());
+        let reader = file2.try_lock_shared_guard().unwrap();
+        drop(reader);
+        assert_eq!(file2.try_lock_exclusive_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+
+        // Downgrading a shared guard does nothing.
+        let guard = guard.downgrade().unwrap();
+        FileExt::try_lock_shared(&file2).unwrap();
+        FileExt::unlock(&file2).unwrap();
+        drop(guard);
+        file2.try_lock_exclusive_guard().unwrap();
+    }
+
+    /// Tests that a failed upgrade keeps the shared lock.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_guard_try_upgrade() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file3 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let guard = file1.lock_shared_guard().unwrap();
+        let reader = file2.lock_shared_guard().unwrap();
+        let (guard, error) = guard.try_upgrade().unwrap_err();
+        assert_eq!(error.kind(), lock_contended_error().kind());
+        assert!(!guard.is_released());
+
+        // The shared lock is still held, so writers are kept out.
+        drop(reader);
+        assert_eq!(file3.try_lock_exclusive_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+
+        // Without other readers the upgrade succeeds.
+        let guard = guard.try_upgrade().unwrap();
+        assert_eq!(LockMode::Exclusive, guard.mode());
+        assert_eq!(file3.try_lock_shared_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        drop(guard);
+        file3.try_lock_exclusive_guard().unwrap();
     }
-}
 
-/// Returns the error that a call to a try lock method on a contended file will
-/// return.
-pub fn lock_contended_error() -> Error {
-    sys::lock_error()
-}
+    /// Tests releasing a guard explicitly.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_guard_unlock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
-/// `FsStats` contains some common stats about a file system.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct FsStats {
-    free_space: u64,
-    available_space: u64,
-    total_space: u64,
-    allocation_granularity: u64,
-}
+        let guard = file1.lock_exclusive_guard().unwrap();
+        assert_eq!(file2.try_lock_exclusive_guard().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        guard.unlock().unwrap();
+        file2.try_lock_exclusive_guard().unwrap();
+    }
 
-impl FsStats {
-    /// Returns the number of free bytes in the file system containing the provided
-    /// path.
-    pub fn free_space(&self) -> u64 {
-        self.free_space
+    /// Tests that closure helpers hold the lock while the closure runs, and
+    /// release it when the closure returns or panics.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn with_lock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let len = file1.with_exclusive_lock(|file| {
+            assert_eq!(FileExt::try_lock_shared(&file2).unwrap_err().kind(),
+                       lock_contended_error().kind());
+            file.metadata().unwrap().len()
+        }).unwrap();
+        assert_eq!(0, len);
+
+        file1.with_shared_lock(|_| FileExt::try_lock_shared(&file2).unwrap()).unwrap();
+        FileExt::unlock(&file2).unwrap();
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            file1.with_exclusive_lock(|_| panic!("boom")).unwrap();
+        }));
+        assert!(result.is_err());
+        file2.try_lock_exclusive().unwrap();
     }
 
-    /// Returns the available space in bytes to non-priveleged users in the file
-    /// system containing the provided path.
-    pub fn available_space(&self) -> u64 {
-        self.available_space
+    /// Tests punching holes in a file.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn punch_hole() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(tempdir.path()).unwrap();
+
+        file.mark_sparse().unwrap();
+        file.write_all(&vec![0xff; blksize as usize * 4]).unwrap();
+        let allocated = file.allocated_size().unwrap();
+        file.punch_hole(blksize, blksize * 2).unwrap();
+        assert_eq!(blksize * 4, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() <= allocated - blksize * 2);
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let blksize = blksize as usize;
+        assert!(contents[..blksize].iter().all(|&b| b == 0xff));
+        assert!(contents[blksize..blksize * 3].iter().all(|&b| b == 0));
+        assert!(contents[blksize * 3..].iter().all(|&b| b == 0xff));
     }
 
-    /// Returns the total space in bytes in the file system containing the provided
-    /// path.
-    pub fn total_space(&self) -> u64 {
-        self.total_space
+    /// Tests deallocating the zero blocks of a file.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn sparsify() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(tempdir.path()).unwrap() as usize;
+
+        let mut contents = vec![0; blksize * 6];
+        contents[..blksize].iter_mut().for_each(|b| *b = 1);
+        contents[blksize * 4] = 1;
+        file.write_all(&contents).unwrap();
+        file.sync_all().unwrap();
+
+        let reclaimed = super::sparsify(&file, blksize as u64).unwrap();
+        assert!(reclaimed >= blksize as u64 * 4, "reclaimed {} bytes", reclaimed);
+        assert_eq!(contents, fs::read(&path).unwrap());
+        assert_eq!(0, super::sparsify(&file, blksize as u64).unwrap());
+        assert_eq!(ErrorKind::InvalidInput, super::sparsify(&file, 0).unwrap_err().kind());
     }
 
-    /// Returns the filesystem's disk space allocation granularity in bytes.
-    /// The provided path may be for any file in the filesystem.
-    ///
-    /// On Posix, this is equivalent to the filesystem's block size.
-    /// On Windows, this is equivalent to the filesystem's cluster size.
-    pub fn allocation_granularity(&self) -> u64 {
-        self.allocation_granularity
+    /// Tests allocating with verification.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn allocate_strict() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(tempdir.path()).unwrap();
+
+        file.allocate_strict(blksize * 4).unwrap();
+        assert!(file.allocated_size().unwrap() >= blksize * 4);
+
+        // Touching preserves the file's contents.
+        fs::write(&path, vec![7; blksize as usize * 2]).unwrap();
+        AllocateOptions::new().verify(true).touch(true).allocate(&file, blksize * 8).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(blksize as usize * 8, contents.len());
+        assert!(contents[..blksize as usize * 2].iter().all(|&b| b == 7));
+        assert!(contents[blksize as usize * 2..].iter().all(|&b| b == 0));
+
+        // Allocating with progress touches and verifies too, from the start of
+        // the file.
+        let mut reported = Vec::new();
+        AllocateOptions::new().verify(true).touch(true)
+                              .allocate_with_progress(&file, blksize * 16, Progress::new().on_progress(|done, _| {
+            reported.push(done);
+        })).unwrap();
+        assert_eq!(vec![0, blksize * 16], reported);
+        assert!(file.allocated_size().unwrap() >= blksize * 16);
+        let contents = fs::read(&path).unwrap();
+        assert!(contents[..blksize as usize * 2].iter().all(|&b| b == 7));
     }
-}
 
-/// Get the stats of the file system containing the provided path.
-pub fn statvfs<P>(path: P) -> Result<FsStats> where P: AsRef<Path> {
-    sys::statvfs(path.as_ref())
-}
+    /// Tests allocating with zero filling as a fallback.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn allocate_zeroed() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        fs::write(&path, b"fs2").unwrap();
+
+        file.allocate_zeroed(4 << 20).unwrap();
+        assert!(file.allocated_size().unwrap() >= 4 << 20);
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(4 << 20, contents.len());
+        assert_eq!(b"fs2", &contents[..3]);
+        assert!(contents[3..].iter().all(|&b| b == 0));
+
+        let mut updates = 0;
+        AllocateOptions::new().mode(AllocateMode::ZeroFill)
+                              .allocate_with_progress(&file, 100 << 20, Progress::new().on_progress(|_, _| {
+            updates += 1;
+        })).unwrap();
+        assert_eq!(3, updates);
+        assert_eq!(100 << 20, file.metadata().unwrap().len());
+    }
 
-/// Returns the number of free bytes in the file system containing the provided
-/// path.
-pub fn free_space<P>(path: P) -> Result<u64> where P: AsRef<Path> {
-    statvfs(path).map(|stat| stat.free_space)
-}
+    /// Tests that allocations beyond the maximum writable size fail up front.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn allocate_too_large() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let max = max_writable_size(&file).unwrap();
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert!(max.is_some());
+        }
+        if let Some(max) = max {
+            assert_eq!(ErrorKind::FileTooLarge, file.allocate(max + 1).unwrap_err().kind());
+            let err = AllocateOptions::new().allocate_with_progress(&file, max + 1, &mut Progress::new()).unwrap_err();
+            assert_eq!(ErrorKind::FileTooLarge, err.kind());
+            assert_eq!(0, file.metadata().unwrap().len());
+        }
+    }
 
-/// Returns the available space in bytes to non-priveleged users in the file
-/// system containing the provided path.
-pub fn available_space<P>(path: P) -> Result<u64> where P: AsRef<Path> {
-    statvfs(path).map(|stat| stat.available_space)
-}
+    /// Tests allocating with progress reporting and cancellation.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn allocate_progress() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let mut updates = Vec::new();
+        AllocateOptions::new().allocate_with_progress(&file, 100 << 20, Progress::new().on_progress(|done, total| {
+            updates.push((done, total));
+        })).unwrap();
+        assert_eq!(vec![(0, 100 << 20), (64 << 20, 100 << 20), (100 << 20, 100 << 20)], updates);
+        assert_eq!(100 << 20, file.metadata().unwrap().len());
+
+        let cancel = ::std::sync::atomic::AtomicBool::new(true);
+        let err = AllocateOptions::new()
+            .allocate_with_progress(&file, 200 << 20, Progress::new().cancel_flag(&cancel))
+            .unwrap_err();
+        assert!(err.get_ref().unwrap().is::<Cancelled>());
+        assert_eq!(100 << 20, file.metadata().unwrap().len());
+    }
 
-/// Returns the total space in bytes in the file system containing the provided
-/// path.
-pub fn total_space<P>(path: P) -> Result<u64> where P: AsRef<Path> {
-    statvfs(path).map(|stat| stat.total_space)
-}
+    /// Tests that shrinking a file releases its space.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn shrink_to() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(tempdir.path()).unwrap();
 
-/// Returns the filesystem's disk space allocation granularity in bytes.
-/// The provided path may be for any file in the filesystem.
-///
-/// On Posix, this is equivalent to the filesystem's block size.
-/// On Windows, this is equivalent to the filesystem's cluster size.
-pub fn allocation_granularity<P>(path: P) -> Result<u64> where P: AsRef<Path> {
-    statvfs(path).map(|stat| stat.allocation_granularity)
-}
+        file.allocate(blksize * 8).unwrap();
+        file.shrink_to(blksize).unwrap();
+        assert_eq!(blksize, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() <= blksize * 2);
 
-#[cfg(test)]
-mod test {
+        // Shrinking never extends the file.
+        file.shrink_to(blksize * 4).unwrap();
+        assert_eq!(blksize, file.metadata().unwrap().len());
+    }
 
-    extern crate tempdir;
-    extern crate test;
+    /// Tests that shrinking a sparse file releases space preallocated past
+    /// its end, when less space is allocated than its length.
+    #[cfg(all(any(target_os = "linux", target_os = "android"), feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn shrink_to_sparse_keep_size() {
+        extern crate libc;
+        use std::os::unix::io::AsRawFd;
 
-    use std::fs;
-    use super::*;
-    use std::io::{Read, Seek, SeekFrom, Write};
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(tempdir.path()).unwrap();
+
+        file.set_len(blksize * 64).unwrap();
+        let ret = unsafe {
+            libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE,
+                            (blksize * 128) as libc::off_t, (blksize * 8) as libc::off_t)
+        };
+        if ret == -1 {
+            // The file system can not preallocate past the end of a file.
+            return;
+        }
+        assert_eq!(blksize * 64, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() >= blksize * 8);
+
+        // The length is kept, which must still release the space.
+        file.shrink_to(blksize * 64).unwrap();
+        assert_eq!(blksize * 64, file.metadata().unwrap().len());
+        assert_eq!(0, file.allocated_size().unwrap());
+    }
 
-    /// Tests file duplication.
+    /// Tests creating preallocated files.
+    #[cfg(all(feature = "allocate", feature = "locks", feature = "statvfs"))]
     #[test]
-    fn duplicate() {
+    fn create_sized() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let mut file1 =
-            fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-        let mut file2 = file1.duplicate().unwrap();
-
-        // Write into the first file and then drop it.
-        file1.write_all(b"foo").unwrap();
-        drop(file1);
+        let blksize = allocation_granularity(tempdir.path()).unwrap();
+
+        let file = super::create_sized(&path, blksize * 4, CreateOptions::new().lock(true)).unwrap();
+        assert_eq!(blksize * 4, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() >= blksize * 4);
+        let other = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        assert_eq!(other.try_lock_shared_guard().unwrap_err().kind(), lock_contended_error().kind());
+        FileExt::unlock(&file).unwrap();
+        other.try_lock_shared().unwrap();
+
+        assert_eq!(ErrorKind::AlreadyExists,
+                   super::create_sized(&path, 1, &CreateOptions::new()).unwrap_err().kind());
+        // Only the created file is left in the directory.
+        assert_eq!(1, fs::read_dir(tempdir.path()).unwrap().count());
+    }
 
-        let mut buf = vec![];
+    /// Tests that guards record how long acquiring them took, and that slow
+    /// acquisitions are reported.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_wait_duration() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let id = file1.file_id().unwrap();
+
+        let reported = Arc::new(::std::sync::Mutex::new(Vec::new()));
+        let handler_reported = reported.clone();
+        set_slow_lock_handler(Duration::from_millis(20), move |guard| {
+            // Other tests may take slow locks concurrently.
+            if guard.file().file_id().unwrap() == id {
+                handler_reported.lock().unwrap().push(guard.wait_duration());
+            }
+        });
 
-        // Read from the second file; since the position is shared it will already be at EOF.
-        file2.read_to_end(&mut buf).unwrap();
-        assert_eq!(0, buf.len());
+        let guard = file1.try_lock_exclusive_guard().unwrap();
+        assert!(guard.wait_duration() < Duration::from_millis(20));
+        mem::forget(guard);
+        let unlocker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            FileExt::unlock(&file1).unwrap();
+        });
+        let guard = file2.lock_exclusive_guard().unwrap();
+        unlocker.join().unwrap();
+        clear_slow_lock_handler();
 
-        // Rewind and read.
-        file2.seek(SeekFrom::Start(0)).unwrap();
-        file2.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, &b"foo");
+        assert!(guard.wait_duration() >= Duration::from_millis(50));
+        assert_eq!(vec![guard.wait_duration()], *reported.lock().unwrap());
     }
 
-    /// Tests shared file lock operations.
+    /// Tests locking files with `LockOptions`.
+    #[cfg(feature = "locks")]
     #[test]
-    fn lock_shared() {
+    #[cfg_attr(feature = "strict", ignore = "locks a handle which already holds a lock, which strict mode rejects")]
+    fn lock_options() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-        let file3 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-
-        // Concurrent shared access is OK, but not shared and exclusive.
-        file1.lock_shared().unwrap();
-        file2.lock_shared().unwrap();
-        assert_eq!(file3.try_lock_exclusive().unwrap_err().kind(),
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let guard = LockOptions::new().mode(LockMode::Shared).lock(&file1).unwrap();
+        assert_eq!(LockMode::Shared, guard.mode());
+        assert_eq!(None, guard.range());
+        let _reader = LockOptions::new().mode(LockMode::Shared).nonblocking(true).lock(&file2).unwrap();
+        assert_eq!(LockOptions::new().nonblocking(true).lock(&file2).unwrap_err().kind(),
                    lock_contended_error().kind());
-        file1.unlock().unwrap();
-        assert_eq!(file3.try_lock_exclusive().unwrap_err().kind(),
+        assert_eq!(LockOptions::new().timeout(Duration::from_millis(10)).lock(&file2).unwrap_err().kind(),
+                   ErrorKind::TimedOut);
+        drop(guard);
+
+        // Disjoint ranges do not conflict; overlapping ranges do.
+        let guard = LockOptions::new().range(0..10).lock(&file1).unwrap();
+        assert_eq!(Some(0..10), guard.range());
+        let _other = LockOptions::new().range(10..20).nonblocking(true).lock(&file2).unwrap();
+        assert_eq!(LockOptions::new().range(5..15).nonblocking(true).lock(&file2).unwrap_err().kind(),
                    lock_contended_error().kind());
+        let guard = guard.downgrade().unwrap();
+        LockOptions::new().mode(LockMode::Shared).range(5..15).nonblocking(true).lock(&file2).unwrap();
+        drop(guard);
+        LockOptions::new().range(0..5).nonblocking(true).lock(&file2).unwrap();
 
-        // Once all shared file locks are dropped, an exclusive lock may be created;
-        file2.unlock().unwrap();
-        file3.lock_exclusive().unwrap();
+        assert_eq!(LockOptions::new().range(5..5).lock(&file1).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
     }
 
-    /// Tests exclusive file lock operations.
+    /// Tests that `FileExt` can be used as a trait object.
+    #[cfg(all(feature = "allocate", feature = "locks"))]
     #[test]
-    fn lock_exclusive() {
+    fn dyn_file_ext() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-
-        // No other access is possible once an exclusive lock is created.
-        file1.lock_exclusive().unwrap();
-        assert_eq!(file2.try_lock_exclusive().unwrap_err().kind(),
-                   lock_contended_error().kind());
-        assert_eq!(file2.try_lock_shared().unwrap_err().kind(),
-                   lock_contended_error().kind());
-
-        // Once the exclusive lock is dropped, the second file is able to create a lock.
-        file1.unlock().unwrap();
-        file2.lock_exclusive().unwrap();
+        let open = || fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let files: Vec<Box<dyn FileExt>> = vec![Box::new(open()), Box::new(open())];
+
+        files[0].allocate(1024).unwrap();
+        assert!(files[1].allocated_size().unwrap() >= 1024);
+        let guard = files[0].lock_exclusive_guard().unwrap();
+        assert!(guard.is_exclusive());
+        assert_eq!(files[1].try_lock_shared().unwrap_err().kind(), lock_contended_error().kind());
+        drop(guard);
+        files[1].try_lock_shared_guard().unwrap();
+
+        let file: &dyn FileExt = &*files[0];
+        assert_eq!(files[1].file_id().unwrap(), file.file_id().unwrap());
     }
 
-    /// Tests that a lock is released after the file that owns it is dropped.
+    /// Tests that guards can be taken on files shared through an `Arc`.
+    #[cfg(feature = "locks")]
     #[test]
-    fn lock_cleanup() {
+    fn lock_guard_arc() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file1 = Arc::new(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap());
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
-        file1.lock_exclusive().unwrap();
-        assert_eq!(file2.try_lock_shared().unwrap_err().kind(),
+        let shared = file1.clone();
+        let guard = shared.lock_exclusive_guard().unwrap();
+        assert_eq!(file2.try_lock_shared_guard().unwrap_err().kind(),
                    lock_contended_error().kind());
+        drop(guard);
+        file2.try_lock_shared_guard().unwrap();
+    }
 
-        // Drop file1; the lock should be released.
-        drop(file1);
-        file2.lock_shared().unwrap();
+    /// Tests that locking many files either locks all of them or none.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_many() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path1 = tempdir.path().join("fs2-1");
+        let path2 = tempdir.path().join("fs2-2");
+        let open = |path: &Path| fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path).unwrap();
+        let file1 = open(&path1);
+        let file2 = open(&path2);
+        let other1 = open(&path1);
+        let other2 = open(&path2);
+
+        try_lock_many(&[(&file1, LockMode::Exclusive), (&file2, LockMode::Shared)]).unwrap();
+        assert_eq!(FileExt::try_lock_shared(&other1).unwrap_err().kind(),
+                   lock_contended_error().kind());
+        FileExt::try_lock_shared(&other2).unwrap();
+        FileExt::unlock(&file1).unwrap();
+        FileExt::unlock(&file2).unwrap();
+
+        // other2 holds a shared lock, so an exclusive lock on file2 fails and
+        // the lock on file1 is rolled back.
+        assert_eq!(try_lock_many(&[(&file1, LockMode::Exclusive), (&file2, LockMode::Exclusive)])
+                       .unwrap_err().kind(),
+                   lock_contended_error().kind());
+        FileExt::try_lock_exclusive(&other1).unwrap();
+        FileExt::unlock(&other1).unwrap();
+
+        // A file listed twice is rejected before anything is locked.
+        let files = [(&file1, LockMode::Shared), (&file2, LockMode::Shared), (&file1, LockMode::Exclusive)];
+        assert_eq!(try_lock_many(&files).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        FileExt::try_lock_exclusive(&other1).unwrap();
     }
 
     /// Tests file allocation.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
     #[test]
     fn allocate() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
         let blksize = allocation_granularity(&path).unwrap();
 
         // New files are created with no allocated size.
@@ -316,23 +3284,445 @@ mod test {
         file.set_len(blksize + 1).unwrap();
         assert_eq!(2 * blksize, file.allocated_size().unwrap());
         assert_eq!(blksize + 1, file.metadata().unwrap().len());
+
+        // Allocating leaves the file position alone.
+
+        file.write_all(b"fs2").unwrap();
+        file.allocate(4 * blksize).unwrap();
+        assert_eq!(3, file.stream_position().unwrap());
+        assert_eq!(4 * blksize, file.metadata().unwrap().len());
+    }
+
+    /// Tests file allocation with non-default options.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn allocate_options() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(&path).unwrap();
+
+        AllocateOptions::new().contiguous(false).allocate(&file, blksize).unwrap();
+        assert_eq!(blksize, file.allocated_size().unwrap());
+        assert_eq!(blksize, file.metadata().unwrap().len());
+
+        AllocateOptions::new().contiguous(true).allocate(&file, 2 * blksize).unwrap();
+        assert_eq!(2 * blksize, file.allocated_size().unwrap());
+        assert_eq!(2 * blksize, file.metadata().unwrap().len());
+
+        for &mode in &[AllocateMode::Fast, AllocateMode::Portable, AllocateMode::ZeroFill] {
+            let len = file.metadata().unwrap().len() + blksize;
+            match AllocateOptions::new().mode(mode).allocate(&file, len) {
+                Err(ref err) if mode == AllocateMode::Fast && err.kind() == ErrorKind::Unsupported => continue,
+                result => result.unwrap(),
+            }
+            assert!(file.allocated_size().unwrap() >= len);
+            assert_eq!(len, file.metadata().unwrap().len());
+        }
+    }
+
+    /// Tests that zero filling extends a file with allocated zeros, and leaves
+    /// its contents alone.
+    #[cfg(all(unix, feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn zero_fill() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(&path).unwrap();
+        file.write_all(b"fs2").unwrap();
+
+        let len = (2 << 20) + 5;
+        positional::zero_fill(&file, len).unwrap();
+        assert_eq!(len, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() >= len / blksize * blksize);
+        assert_eq!(3, file.stream_position().unwrap());
+
+        let mut contents = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(b"fs2", &contents[..3]);
+        assert!(contents[3..].iter().all(|&b| b == 0));
+
+        // Shorter lengths do not truncate the file.
+        positional::zero_fill(&file, 1).unwrap();
+        assert_eq!(len, file.metadata().unwrap().len());
+    }
+
+    /// Tests that allocation and range operations leave the file position
+    /// alone, so they can be interleaved with buffered writes.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn allocate_preserves_position() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(&path).unwrap();
+
+        let mut writer = io::BufWriter::new(&file);
+        writer.write_all(b"hello ").unwrap();
+        writer.flush().unwrap();
+
+        file.allocate(4 * blksize).unwrap();
+        AllocateOptions::new().touch(true).allocate(&file, 8 * blksize).unwrap();
+        file.allocated_size().unwrap();
+        file.extents().unwrap().count();
+        match file.punch_hole(2 * blksize, blksize) {
+            Err(ref err) if err.kind() == ErrorKind::Unsupported => (),
+            result => result.unwrap(),
+        }
+        file.shrink_to(6 * blksize).unwrap();
+        assert_eq!(6, (&file).stream_position().unwrap());
+
+        writer.write_all(b"world").unwrap();
+        drop(writer);
+        let mut contents = vec![0; 11];
+        (&file).seek(SeekFrom::Start(0)).unwrap();
+        (&file).read_exact(&mut contents).unwrap();
+        assert_eq!(b"hello world", &contents[..]);
+    }
+
+    /// Tests that file extents cover the whole file, and that written data is
+    /// never reported as a hole.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn extents() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file =
+            fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(&path).unwrap();
+
+        // An empty file has no extents.
+        assert_eq!(0, file.extents().unwrap().count());
+
+        // Write a block of data after a (potential) hole of four blocks.
+        file.seek(SeekFrom::Start(4 * blksize)).unwrap();
+        file.write_all(&vec![1; blksize as usize]).unwrap();
+        file.set_len(8 * blksize).unwrap();
+
+        let extents = file.extents().unwrap().collect::<Vec<_>>();
+        let mut offset = 0;
+        for extent in &extents {
+            assert_eq!(offset, extent.offset);
+            assert!(extent.len > 0);
+            if extent.offset <= 4 * blksize && 4 * blksize < extent.offset + extent.len {
+                assert!(!extent.is_hole);
+            }
+            offset += extent.len;
+        }
+        assert_eq!(8 * blksize, offset);
+
+        // The file position is preserved.
+        assert_eq!(5 * blksize, file.stream_position().unwrap());
+    }
+
+    /// Tests files larger than 4 GiB, which require 64-bit file offsets and
+    /// sizes on 32-bit targets.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn large_file() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file =
+            fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let len = 5 * 1024 * 1024 * 1024;
+
+        // Extend the file sparsely, and write a byte past the 4 GiB mark.
+        file.set_len(len).unwrap();
+        file.seek(SeekFrom::Start(len - 1)).unwrap();
+        file.write_all(&[1]).unwrap();
+        assert_eq!(len, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() < len);
+        assert!(file.is_sparse().unwrap());
+
+        let extents = file.extents().unwrap().collect::<Vec<_>>();
+        assert_eq!(len, extents.iter().map(|extent| extent.len).sum::<u64>());
+        assert!(!extents.last().unwrap().is_hole);
+        assert_eq!(len, file.stream_position().unwrap());
+    }
+
+    /// Tests sparse file detection.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn sparse() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = allocation_granularity(&path).unwrap();
+
+        // New files are not sparse.
+        assert!(!file.is_sparse().unwrap());
+
+        // Extending a sparse file leaves a hole.
+        file.mark_sparse().unwrap();
+        file.set_len(16 * blksize).unwrap();
+        assert!(file.is_sparse().unwrap());
+    }
+
+    /// Tests sending a file range to a socket.
+    #[cfg(feature = "utilities")]
+    #[test]
+    fn send_to_socket() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file =
+            fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(b"hello, world").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // Requesting more than the file holds stops at the end of the file.
+        assert_eq!(5, super::send_to_socket(&file, &server, 7, 100).unwrap());
+        assert_eq!(5, super::send_to_socket(&file, &server, 0, 5).unwrap());
+        drop(server);
+
+        let mut buf = String::new();
+        (&client).read_to_string(&mut buf).unwrap();
+        assert_eq!("worldhello", buf);
+        assert_eq!(12, file.stream_position().unwrap());
+    }
+
+    /// Tests that the reported capabilities match the platform.
+    #[test]
+    fn capabilities() {
+        let capabilities = super::capabilities();
+        assert_eq!(cfg!(any(unix, windows)), capabilities.lock());
+        assert_eq!(cfg!(any(unix, windows)), capabilities.duplicate());
+        assert_eq!(cfg!(any(unix, windows)), capabilities.statvfs());
+        // The platforms with a native preallocation API; see `sys::allocate`.
+        assert_eq!(cfg!(any(windows,
+                            target_os = "linux",
+                            target_os = "freebsd",
+                            target_os = "android",
+                            target_os = "emscripten",
+                            target_os = "fuchsia",
+                            target_os = "haiku",
+                            target_os = "nto",
+                            target_os = "aix",
+                            target_os = "macos",
+                            target_os = "ios",
+                            target_os = "tvos",
+                            target_os = "watchos",
+                            target_os = "visionos")),
+                   capabilities.allocate());
     }
 
     /// Checks filesystem space methods.
+    #[cfg(feature = "statvfs")]
     #[test]
     fn filesystem_space() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
-        let total_space = total_space(&tempdir.path()).unwrap();
-        let free_space = free_space(&tempdir.path()).unwrap();
-        let available_space = available_space(&tempdir.path()).unwrap();
+        let total_space = total_space(tempdir.path()).unwrap();
+        let free_space = free_space(tempdir.path()).unwrap();
+        let available_space = available_space(tempdir.path()).unwrap();
 
         assert!(total_space > free_space);
         assert!(total_space > available_space);
         assert!(available_space <= free_space);
     }
 
+    /// Tests that stats are of the file system a symlink leads to, unless the
+    /// outer file system is requested.
+    #[cfg(all(target_os = "linux", feature = "statvfs"))]
+    #[test]
+    fn statvfs_outer() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let link = tempdir.path().join("proc");
+        ::std::os::unix::fs::symlink("/proc", &link).unwrap();
+
+        // procfs reports no space at all.
+        assert_eq!(0, statvfs(&link).unwrap().total_space());
+        assert_eq!(0, StatvfsOptions::new().statvfs(&link).unwrap().total_space());
+
+        let outer = StatvfsOptions::new().outer(true).statvfs(&link).unwrap();
+        assert_eq!(total_space(tempdir.path()).unwrap(), outer.total_space());
+
+        // The outer file system of a mount point is the one it is mounted on.
+        let root = StatvfsOptions::new().outer(true).statvfs("/proc").unwrap();
+        assert_eq!(total_space("/").unwrap(), root.total_space());
+    }
+
+    /// Tests that statvfs errors carry the path they occurred on.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn statvfs_error_path() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let missing = tempdir.path().join("missing");
+
+        let err = free_space(&missing).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().contains("missing"), "{}", err);
+        let context = Error::of(&err).unwrap();
+        assert_eq!("statvfs", context.operation());
+        assert_eq!(Some(missing.as_path()), context.path());
+        assert!(context.raw_os_error().is_some());
+    }
+
+    /// Tests that the raw OS error code of the space functions' errors is kept
+    /// in their context.
+    #[cfg(all(unix, feature = "statvfs"))]
+    #[test]
+    fn statvfs_raw_os_error() {
+        extern crate libc;
+
+        let raw_os_error = |err: io::Error| Error::of(&err).and_then(Error::raw_os_error);
+        assert_eq!(Some(libc::ENOENT), raw_os_error(statvfs("/missing").unwrap_err()));
+        assert_eq!(Some(libc::ENOENT), raw_os_error(free_space("/missing").unwrap_err()));
+        assert_eq!(Some(libc::ENOENT), raw_os_error(available_space("/missing").unwrap_err()));
+        assert_eq!(Some(libc::ENOENT), raw_os_error(total_space("/missing").unwrap_err()));
+        assert_eq!(Some(libc::ENOENT), raw_os_error(allocation_granularity("/missing").unwrap_err()));
+    }
+
+    /// Tests querying the available space through an open file.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn file_available_space() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let available = file.available_space().unwrap();
+        assert!(available > 0);
+        assert!(available <= total_space(&path).unwrap());
+    }
+
+    /// Tests querying space through an open file whose path has been renamed
+    /// away.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn file_space_renamed() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        fs::rename(&path, tempdir.path().join("renamed")).unwrap();
+
+        assert_eq!(total_space(tempdir.path()).unwrap(), file.total_space().unwrap());
+        let free = file.free_space().unwrap();
+        assert!(free > 0);
+        assert!(free >= file.available_space().unwrap());
+        assert!(free <= file.total_space().unwrap());
+    }
+
+    /// Tests finding the holder of a lock.
+    #[cfg(all(any(target_os = "linux", target_os = "android"), feature = "locks"))]
+    #[test]
+    fn lock_holder() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert_eq!(None, file2.lock_holder().unwrap());
+
+        file1.lock_exclusive().unwrap();
+        let holder = file2.lock_holder().unwrap().unwrap();
+        assert_eq!(Some(std::process::id()), holder.pid());
+        assert_eq!(LockMode::Exclusive, holder.mode());
+        assert!(holder.process_name().is_some());
+        assert!(holder.to_string().starts_with(&format!("pid {} (", std::process::id())));
+
+        FileExt::unlock(&file1).unwrap();
+        FileExt::lock_shared(&file1).unwrap();
+        assert_eq!(LockMode::Shared, file2.lock_holder().unwrap().unwrap().mode());
+    }
+
+    /// Tests formatting lock holders.
+    #[test]
+    fn lock_holder_display() {
+        let holder = LockHolder { pid: Some(4242), mode: LockMode::Exclusive, process_name: Some("postgres".to_owned()) };
+        assert_eq!("pid 4242 (postgres)", holder.to_string());
+        let holder = LockHolder { pid: None, mode: LockMode::Shared, process_name: None };
+        assert_eq!("unknown process", holder.to_string());
+    }
+
+    /// Tests retrieving extended file system stats.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn fs_stats_ext() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let stats = statvfs_ext(tempdir.path()).unwrap();
+
+        assert_eq!(statvfs(tempdir.path()).unwrap().total_space(), stats.stats().total_space());
+        assert!(stats.max_name_len().unwrap() > 0);
+        assert!(stats.filesystem_id().is_some());
+        assert!(stats.flags().is_some());
+        if cfg!(any(target_os = "linux", target_os = "macos")) {
+            assert!(stats.max_file_size().unwrap() >= u32::MAX as u64);
+        }
+        if cfg!(unix) {
+            assert_eq!(Some(stats.stats().allocation_granularity()), stats.fragment_size());
+            assert!(stats.free_inodes() <= stats.total_inodes());
+            assert!(stats.available_inodes() <= stats.free_inodes());
+        } else {
+            assert_eq!(None, stats.total_inodes());
+        }
+    }
+
+    /// Tests the file system ID and type reported with the stats.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn fs_stats_kind() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let stats = statvfs(tempdir.path()).unwrap();
+
+        assert!(stats.filesystem_id().is_some());
+        assert_eq!(stats.filesystem_id(), statvfs_ext(tempdir.path()).unwrap().filesystem_id());
+        assert_eq!(stats.filesystem_id(), sys::fstatvfs(&file).unwrap().filesystem_id());
+        assert_eq!(stats.kind(), sys::fstatvfs(&file).unwrap().kind());
+        if cfg!(any(target_os = "linux", windows)) {
+            assert_eq!(stats.kind(), statvfs_ext(tempdir.path()).unwrap().stats().kind());
+        }
+        if cfg!(any(target_os = "linux", target_os = "macos", windows)) {
+            let kind = statvfs_ext(tempdir.path()).unwrap().stats().kind().cloned().unwrap();
+            assert_eq!(kind.is_network(), is_network_fs(tempdir.path()).unwrap());
+        }
+    }
+
+    /// Tests the space computations and formatting of file system stats.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn fs_stats_usage() {
+        let gib = 1024 * 1024 * 1024;
+        let stats = FsStats {
+            free_space: 60 * gib,
+            available_space: 55 * gib,
+            total_space: 100 * gib,
+            allocation_granularity: 4096,
+            filesystem_id: None,
+            kind: None,
+        };
+        assert_eq!(40 * gib, stats.used_space());
+        assert_eq!(40.0, stats.percent_used());
+        assert_eq!(55.0, stats.percent_available());
+        assert_eq!("40.0 GiB used, 55.0 GiB available of 100.0 GiB (40.0% used)",
+                   stats.to_string());
+
+        let empty = FsStats {
+            free_space: 0,
+            available_space: 0,
+            total_space: 0,
+            allocation_granularity: 0,
+            filesystem_id: None,
+            kind: None,
+        };
+        assert_eq!(0, empty.used_space());
+        assert_eq!(0.0, empty.percent_used());
+        assert_eq!("0 B used, 0 B available of 0 B (0.0% used)", empty.to_string());
+
+        let stats = statvfs(env::temp_dir()).unwrap();
+        assert!(stats.used_space() <= stats.total_space());
+        assert!(stats.percent_used() >= 0.0 && stats.percent_used() <= 100.0);
+        assert!(stats.percent_available() >= 0.0 && stats.percent_available() <= 100.0);
+    }
+
     /// Benchmarks creating and removing a file. This is a baseline benchmark
     /// for comparing against the truncate and allocate benchmarks.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_file_create(b: &mut test::Bencher) {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
@@ -343,6 +3733,7 @@ mod test {
                             .read(true)
                             .write(true)
                             .create(true)
+                            .truncate(true)
                             .open(&path)
                             .unwrap();
             fs::remove_file(&path).unwrap();
@@ -350,6 +3741,7 @@ mod test {
     }
 
     /// Benchmarks creating a file, truncating it to 32MiB, and deleting it.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_file_truncate(b: &mut test::Bencher) {
         let size = 32 * 1024 * 1024;
@@ -361,6 +3753,7 @@ mod test {
                                        .read(true)
                                        .write(true)
                                        .create(true)
+                                       .truncate(true)
                                        .open(&path)
                                        .unwrap();
             file.set_len(size).unwrap();
@@ -369,6 +3762,7 @@ mod test {
     }
 
     /// Benchmarks creating a file, allocating 32MiB for it, and deleting it.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_file_allocate(b: &mut test::Bencher) {
         let size = 32 * 1024 * 1024;
@@ -380,6 +3774,7 @@ mod test {
                                        .read(true)
                                        .write(true)
                                        .create(true)
+                                       .truncate(true)
                                        .open(&path)
                                        .unwrap();
             file.allocate(size).unwrap();
@@ -388,6 +3783,7 @@ mod test {
     }
 
     /// Benchmarks creating a file, allocating 32MiB for it, and deleting it.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_allocated_size(b: &mut test::Bencher) {
         let size = 32 * 1024 * 1024;
@@ -397,6 +3793,7 @@ mod test {
                                    .read(true)
                                    .write(true)
                                    .create(true)
+                                   .truncate(true)
                                    .open(&path)
                                    .unwrap();
         file.allocate(size).unwrap();
@@ -407,52 +3804,57 @@ mod test {
     }
 
     /// Benchmarks duplicating a file descriptor or handle.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_duplicate(b: &mut test::Bencher) {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
         b.iter(|| test::black_box(file.duplicate().unwrap()));
     }
 
     /// Benchmarks locking and unlocking a file lock.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_lock_unlock(b: &mut test::Bencher) {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
         b.iter(|| {
             file.lock_exclusive().unwrap();
-            file.unlock().unwrap();
+            FileExt::unlock(&file).unwrap();
         });
     }
 
     /// Benchmarks the free space method.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_free_space(b: &mut test::Bencher) {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         b.iter(|| {
-            test::black_box(free_space(&tempdir.path()).unwrap());
+            test::black_box(free_space(tempdir.path()).unwrap());
         });
     }
 
     /// Benchmarks the available space method.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_available_space(b: &mut test::Bencher) {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         b.iter(|| {
-            test::black_box(available_space(&tempdir.path()).unwrap());
+            test::black_box(available_space(tempdir.path()).unwrap());
         });
     }
 
     /// Benchmarks the total space method.
+    #[cfg(feature = "unstable")]
     #[bench]
     fn bench_total_space(b: &mut test::Bencher) {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         b.iter(|| {
-            test::black_box(total_space(&tempdir.path()).unwrap());
+            test::black_box(total_space(tempdir.path()).unwrap());
         });
     }
 }
@@ -0,0 +1,468 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use {positional, sys, HolderState, LockMetadata};
+
+/// When `LockFile::break_lock` may remove a lock file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BreakPolicy {
+    /// Only if the lock is not held and the recorded holder is verified to
+    /// have exited, with `LockMetadata::holder_state`.
+    #[default]
+    IfDead,
+    /// Whether or not the lock is held or its holder is running. A holder
+    /// which is still running keeps its lock on the removed file, and no
+    /// longer excludes processes which acquire the new one.
+    Force,
+}
+
+/// An exclusively locked file describing its owner, as used by daemons to
+/// ensure that only one instance runs.
+///
+/// The file holds the owner's `LockMetadata`: its process ID, when it took
+/// the lock, its host and user names, and an optional purpose. Read it with
+/// `LockFile::read_metadata`.
+///
+/// The lock is released when the `LockFile` is dropped or the process exits,
+/// and the file is left in place; `LockFile::remove` also removes it. A
+/// process may open the file just before its holder removes it, and lock it
+/// once it is released, while another process creates and locks a new file
+/// at the path. To keep this from producing two holders, acquiring checks
+/// that the locked file is still the one at the path, and starts over if it
+/// is not.
+///
+/// The lock is taken directly rather than through `FileExt`, so it is neither
+/// recorded in the lock registry nor affected by `fork_policy`.
+#[derive(Debug)]
+pub struct LockFile {
+    file: File,
+    path: PathBuf,
+    metadata: LockMetadata,
+}
+
+impl LockFile {
+    /// Opens the lock file at `path`, creating it if necessary, locks it
+    /// exclusively and writes the metadata of the current process to it.
+    ///
+    /// Fails with the error of `lock_contended_error` if another process holds
+    /// the lock.
+    pub fn acquire<P>(path: P) -> Result<LockFile> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let file = lock(path)?;
+        let lock_file = LockFile { file, path: path.to_owned(), metadata: LockMetadata::current() };
+        lock_file.write_metadata()?;
+        Ok(lock_file)
+    }
+
+    /// Detaches the process as a daemon and acquires the lock file at `path`
+    /// in the daemon.
+    ///
+    /// The process forks, the child starts a new session with `setsid`, and
+    /// forks again so that the daemon can never acquire a controlling
+    /// terminal; the daemon then changes directory to `/`. The lock is first
+    /// taken in the original process, so that an instance which is already
+    /// running is reported there, and taken again in the daemon, which keeps
+    /// `flock` locks and acquires record locks, which are not inherited. The
+    /// daemon then checks that its descriptor still refers to the file at
+    /// `path`, and writes its metadata.
+    ///
+    /// This function returns only in the daemon, or with an error in the
+    /// original process if the daemon could not be started or could not take
+    /// the lock; once the daemon holds it, the original process exits with
+    /// status 0. Standard input and output are left as they are.
+    ///
+    /// `fork` copies only the calling thread, so this should be called before
+    /// the process starts any threads. A relative `path` is resolved against
+    /// the current directory before it changes.
+    #[cfg(unix)]
+    pub fn acquire_for_daemon<P>(path: P) -> Result<LockFile> where P: AsRef<Path> {
+        let path = ::std::env::current_dir()?.join(path);
+        let file = lock(&path)?;
+        daemon::detach(file, path)
+    }
+
+    /// Returns the path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the metadata written to the file.
+    pub fn metadata(&self) -> &LockMetadata {
+        &self.metadata
+    }
+
+    /// Records what the lock is held for, rewriting the file.
+    pub fn set_purpose(&mut self, purpose: &str) -> Result<()> {
+        self.metadata.set_purpose(purpose);
+        self.write_metadata()
+    }
+
+    /// Records the current process ID, rewriting the file, such as after the
+    /// process forked while holding the lock.
+    pub fn rewrite_pid(&mut self) -> Result<()> {
+        self.metadata.set_pid(process::id());
+        self.write_metadata()
+    }
+
+    /// Removes the lock file and releases the lock.
+    ///
+    /// The file is removed while it is still locked, so a process waiting
+    /// for it finds that it was removed and creates a new one.
+    pub fn remove(self) -> Result<()> {
+        if is_current(&self.file, &self.path)? {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the metadata of the lock file at `path`, such as to report who
+    /// holds it. The lock is not taken.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the file's contents can not be
+    /// parsed; a file holding only a process ID is accepted.
+    pub fn read_metadata<P>(path: P) -> Result<LockMetadata> where P: AsRef<Path> {
+        LockMetadata::read(path)
+    }
+
+    /// Removes the lock file at `path` left behind by a holder which is gone,
+    /// so that it can be acquired again, and returns the holder's metadata.
+    /// Returns `None` if there is no lock file.
+    ///
+    /// Unless `policy` is `BreakPolicy::Force`, fails with
+    /// `ErrorKind::ResourceBusy` if a process holds the lock, or if the
+    /// recorded holder is running or can not be checked, such as when it ran
+    /// on another host. `audit` is called with the holder's metadata and
+    /// state just before the file is removed, so that breaking a lock can be
+    /// logged.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the file's contents can not be
+    /// parsed as `LockMetadata`.
+    pub fn break_lock<P, F>(path: P, policy: BreakPolicy, audit: F) -> Result<Option<LockMetadata>>
+    where P: AsRef<Path>, F: FnOnce(&LockMetadata, HolderState) {
+        let path = path.as_ref();
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            result => result?,
+        };
+        let locked = match sys::try_lock_exclusive(&file) {
+            Ok(()) => true,
+            Err(ref err) if err.kind() == sys::lock_error().kind() => false,
+            Err(err) => return Err(err),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let metadata = LockMetadata::parse(&contents)?;
+        let state = metadata.holder_state();
+        if policy != BreakPolicy::Force {
+            let reason = if !locked {
+                Some("is locked")
+            } else if state == HolderState::Running {
+                Some("is running")
+            } else if state == HolderState::Unknown {
+                Some("can not be checked")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(Error::new(ErrorKind::ResourceBusy,
+                                      format!("lock file {} held by pid {} {}", path.display(), metadata.pid(), reason)));
+            }
+        }
+        if !is_current(&file, path)? {
+            return Err(Error::new(ErrorKind::ResourceBusy, format!("lock file {} was replaced", path.display())));
+        }
+        audit(&metadata, state);
+        fs::remove_file(path)?;
+        Ok(Some(metadata))
+    }
+
+    fn write_metadata(&self) -> Result<()> {
+        self.file.set_len(0)?;
+        positional::write_at(&self.file, self.metadata.to_string().as_bytes(), 0)?;
+        self.file.sync_data()
+    }
+}
+
+/// Opens and locks the file at `path`, creating it if necessary, until the
+/// locked file is the one at the path.
+fn lock(path: &Path) -> Result<File> {
+    loop {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        sys::try_lock_exclusive(&file)?;
+        // The holder may have removed the file between opening and locking it.
+        if is_current(&file, path)? {
+            return Ok(file);
+        }
+    }
+}
+
+/// Returns whether `file` is still the file at `path`, which may have been
+/// removed or replaced since it was opened.
+fn is_current(file: &File, path: &Path) -> Result<bool> {
+    let current = match OpenOptions::new().read(true).open(path) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        result => result?,
+    };
+    Ok(sys::file_id(file)? == sys::file_id(&current)?)
+}
+
+#[cfg(unix)]
+mod daemon {
+    extern crate libc;
+
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{Error, ErrorKind, Read, Result, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use super::{is_current, LockFile};
+    use {sys, LockMetadata};
+
+    /// Reported by the daemon in place of an error without an OS error code.
+    const OTHER_ERROR: i32 = -1;
+
+    fn cvt(ret: libc::c_int) -> Result<libc::c_int> {
+        if ret == -1 { Err(Error::last_os_error()) } else { Ok(ret) }
+    }
+
+    pub fn detach(file: File, path: PathBuf) -> Result<LockFile> {
+        let root = CString::new(&b"/"[..]).unwrap();
+        let (mut status_read, mut status_write) = pipe()?;
+
+        let child = cvt(unsafe { libc::fork() })?;
+        if child != 0 {
+            // Closing the parent's descriptor releases a record lock, which
+            // the daemon takes again; a `flock` lock is held on by the child's
+            // copy of the descriptor.
+            drop(file);
+            drop(status_write);
+            let mut status = 0;
+            unsafe { libc::waitpid(child, &mut status, 0) };
+            return match read_status(&mut status_read) {
+                Some(0) => process::exit(0),
+                Some(OTHER_ERROR) => Err(Error::other("the daemon failed to acquire the lock file")),
+                Some(code) => Err(Error::from_raw_os_error(code)),
+                None => Err(Error::other("the daemon exited before acquiring the lock file")),
+            };
+        }
+
+        drop(status_read);
+        if unsafe { libc::setsid() } == -1 || unsafe { libc::fork() } != 0 {
+            // The intermediate process exits as soon as the daemon is forked,
+            // and the original process reaps it. If the daemon could not be
+            // started, the closed pipe reports the failure.
+            unsafe { libc::_exit(0) };
+        }
+
+        let result = unsafe { cvt(libc::chdir(root.as_ptr())) }
+            .and_then(|_| sys::try_lock_exclusive(&file))
+            .and_then(|_| verify(&file, &path))
+            .map(|_| LockFile { file, path, metadata: LockMetadata::current() });
+        let result = result.and_then(|lock_file| lock_file.write_metadata().map(|_| lock_file));
+        let code = match result {
+            Ok(_) => 0,
+            Err(ref err) => err.raw_os_error().unwrap_or(OTHER_ERROR),
+        };
+        let _ = status_write.write_all(&code.to_ne_bytes());
+        match result {
+            Ok(lock_file) => Ok(lock_file),
+            // The original process reports the error.
+            Err(_) => unsafe { libc::_exit(1) },
+        }
+    }
+
+    /// Checks that the lock's descriptor is still open and refers to the file
+    /// at `path`, which another process may have replaced in the meantime.
+    fn verify(file: &File, path: &Path) -> Result<()> {
+        cvt(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) })?;
+        if !is_current(file, path)? {
+            return Err(Error::new(ErrorKind::NotFound,
+                                  format!("lock file {} was replaced", path.display())));
+        }
+        Ok(())
+    }
+
+    /// Creates a pipe, returning its read and write ends, which are not
+    /// inherited across `exec`.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",
+              target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd",
+              target_os = "illumos", target_os = "solaris"))]
+    pub fn pipe() -> Result<(File, File)> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+    }
+
+    /// Creates a pipe, returning its read and write ends, which are not
+    /// inherited across `exec`.
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd",
+                  target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd",
+                  target_os = "illumos", target_os = "solaris")))]
+    pub fn pipe() -> Result<(File, File)> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+        let pipe = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        for fd in &fds {
+            cvt(unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+        }
+        Ok(pipe)
+    }
+
+    fn read_status(pipe: &mut File) -> Option<i32> {
+        let mut buf = [0; 4];
+        pipe.read_exact(&mut buf).ok().map(|_| i32::from_ne_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::env;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::process;
+
+    use super::{BreakPolicy, LockFile};
+    use {lock_contended_error, FileExt, HolderState};
+
+    /// Tests that a lock file excludes a second holder and records the
+    /// holder's metadata.
+    #[test]
+    fn acquire() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2.pid");
+        fs::write(&path, "stale contents\n").unwrap();
+
+        let mut lock_file = LockFile::acquire(&path).unwrap();
+        lock_file.set_purpose("testing").unwrap();
+        let metadata = LockFile::read_metadata(&path).unwrap();
+        assert_eq!(process::id(), metadata.pid());
+        assert_eq!(Some("testing"), metadata.purpose());
+        assert!(metadata.started().is_some());
+        assert_eq!(lock_contended_error().kind(), LockFile::acquire(&path).unwrap_err().kind());
+
+        drop(lock_file);
+        LockFile::acquire(&path).unwrap();
+    }
+
+    /// Tests that a lock file removed by its holder is created again, and
+    /// that a handle to the removed file is not taken for the lock.
+    #[test]
+    fn remove() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2.pid");
+        let lock_file = LockFile::acquire(&path).unwrap();
+        // Opened by a process which has yet to lock it.
+        let stale = fs::File::open(&path).unwrap();
+        lock_file.remove().unwrap();
+        assert!(!path.exists());
+
+        FileExt::try_lock_exclusive(&stale).unwrap();
+        assert!(!super::is_current(&stale, &path).unwrap());
+        let lock_file = LockFile::acquire(&path).unwrap();
+        assert!(super::is_current(lock_file.file(), &path).unwrap());
+    }
+
+    /// Tests that only the locks of holders which have exited are broken,
+    /// unless forced.
+    #[test]
+    fn break_lock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2.pid");
+        let never_called = |_: &_, _| panic!("audited a lock which was not broken");
+        assert_eq!(None, LockFile::break_lock(&path, BreakPolicy::IfDead, never_called).unwrap());
+
+        let lock_file = LockFile::acquire(&path).unwrap();
+        let err = LockFile::break_lock(&path, BreakPolicy::IfDead, never_called).unwrap_err();
+        assert_eq!(ErrorKind::ResourceBusy, err.kind());
+        drop(lock_file);
+        // The holder is this process, which is still running.
+        let err = LockFile::break_lock(&path, BreakPolicy::IfDead, never_called).unwrap_err();
+        assert_eq!(ErrorKind::ResourceBusy, err.kind());
+
+        let mut child = process::Command::new(env::current_exe().unwrap()).arg("--list").stdout(process::Stdio::null()).spawn().unwrap();
+        child.wait().unwrap();
+        fs::write(&path, format!("pid = {}\n", child.id())).unwrap();
+        let mut audited = None;
+        let broken = LockFile::break_lock(&path, BreakPolicy::IfDead, |metadata, state| audited = Some((metadata.pid(), state)));
+        assert_eq!(child.id(), broken.unwrap().unwrap().pid());
+        assert_eq!(Some((child.id(), HolderState::Dead)), audited);
+        assert!(!path.exists());
+
+        let _lock_file = LockFile::acquire(&path).unwrap();
+        let broken = LockFile::break_lock(&path, BreakPolicy::Force, |_, state| assert_eq!(HolderState::Running, state));
+        assert_eq!(process::id(), broken.unwrap().unwrap().pid());
+        LockFile::acquire(&path).unwrap();
+    }
+
+    /// Tests that a daemon holds the lock file and writes its own process ID,
+    /// and that a second daemon is refused in the original process.
+    #[cfg(unix)]
+    #[test]
+    fn acquire_for_daemon() {
+        extern crate libc;
+
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::thread;
+        use std::time::Duration;
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2.pid");
+        // The daemon runs until the write end is closed, which happens when
+        // the test ends, even if it fails.
+        let (keep_alive, keep_alive_write) = super::daemon::pipe().unwrap();
+
+        // Exits with 0 once the daemon holds the lock, or 1 if it could not
+        // be started.
+        let start_daemon = || match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                unsafe { libc::close(keep_alive_write.as_raw_fd()) };
+                if let Ok(_lock_file) = LockFile::acquire_for_daemon(&path) {
+                    // The daemon must not keep the test harness's output open.
+                    let null = fs::OpenOptions::new().write(true).open("/dev/null").unwrap();
+                    unsafe {
+                        libc::dup2(null.as_raw_fd(), 1);
+                        libc::dup2(null.as_raw_fd(), 2);
+                    }
+                    let _ = (&keep_alive).read(&mut [0]);
+                    unsafe { libc::_exit(0) }
+                }
+                unsafe { libc::_exit(1) }
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(pid, unsafe { libc::waitpid(pid, &mut status, 0) });
+                (pid, libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0)
+            }
+        };
+
+        let (child, started) = start_daemon();
+        assert!(started);
+        let daemon = LockFile::read_metadata(&path).unwrap().pid() as libc::pid_t;
+        assert!(daemon != child);
+        assert_eq!(lock_contended_error().kind(), LockFile::acquire(&path).unwrap_err().kind());
+        assert!(!start_daemon().1);
+
+        drop(keep_alive_write);
+        for _ in 0..500 {
+            if LockFile::acquire(&path).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the lock was not released when the daemon exited");
+    }
+}
@@ -0,0 +1,315 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sys;
+
+/// A description of the holder of a `LockFile`, which it stores as the file's
+/// contents so that other tools, and people, can tell who owns the lock.
+///
+/// The contents are a small TOML document of `key = value` lines, with the
+/// start time in seconds since the Unix epoch:
+///
+/// ```toml
+/// pid = 4242
+/// started = 1760000000
+/// hostname = "build-1"
+/// user = "alice"
+/// purpose = "nightly backup"
+/// ```
+///
+/// Only `pid` is required. When parsing, keys this crate does not know are
+/// ignored, and a file holding nothing but a process ID, as written by most
+/// other programs, is accepted too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockMetadata {
+    pid: u32,
+    started: Option<SystemTime>,
+    hostname: Option<String>,
+    user: Option<String>,
+    purpose: Option<String>,
+}
+
+impl LockMetadata {
+    /// Describes the current process, started now, without a purpose.
+    pub fn current() -> LockMetadata {
+        LockMetadata {
+            pid: process::id(),
+            started: Some(SystemTime::now()),
+            hostname: sys::hostname(),
+            user: sys::user_name(),
+            purpose: None,
+        }
+    }
+
+    /// Reads the metadata from the lock file at `path`.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the contents can not be parsed.
+    pub fn read<P>(path: P) -> Result<LockMetadata> where P: AsRef<Path> {
+        LockMetadata::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of a lock file, failing with
+    /// `ErrorKind::InvalidData` if they are not valid.
+    pub fn parse(contents: &str) -> Result<LockMetadata> {
+        if let Ok(pid) = contents.trim().parse() {
+            return Ok(LockMetadata { pid, started: None, hostname: None, user: None, purpose: None });
+        }
+        let mut pid = None;
+        let mut metadata = LockMetadata { pid: 0, started: None, hostname: None, user: None, purpose: None };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| {
+                Error::new(ErrorKind::InvalidData, format!("lock file line {}: {}", number + 1, message))
+            };
+            let (key, value) = match line.find('=') {
+                Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+                None => return Err(invalid("expected `key = value`")),
+            };
+            let value = parse_value(value).ok_or_else(|| invalid("expected an integer or a string"))?;
+            match (key, value) {
+                ("pid", Value::Integer(value)) => {
+                    pid = Some(u32::try_from(value).map_err(|_| invalid("process ID out of range"))?);
+                }
+                ("started", Value::Integer(value)) => {
+                    let started = UNIX_EPOCH.checked_add(Duration::from_secs(value));
+                    metadata.started = Some(started.ok_or_else(|| invalid("start time out of range"))?);
+                }
+                ("hostname", Value::String(value)) => metadata.hostname = Some(value),
+                ("user", Value::String(value)) => metadata.user = Some(value),
+                ("purpose", Value::String(value)) => metadata.purpose = Some(value),
+                ("pid", _) | ("started", _) => return Err(invalid("expected an integer")),
+                ("hostname", _) | ("user", _) | ("purpose", _) => return Err(invalid("expected a string")),
+                _ => (),
+            }
+        }
+        metadata.pid = pid.ok_or_else(|| Error::new(ErrorKind::InvalidData, "lock file has no process ID"))?;
+        Ok(metadata)
+    }
+
+    /// Returns the ID of the process holding the lock.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns when the holder acquired the lock, to a second, if recorded.
+    pub fn started(&self) -> Option<SystemTime> {
+        self.started
+    }
+
+    /// Returns the host name of the holder's machine, if recorded.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_ref().map(|hostname| &hostname[..])
+    }
+
+    /// Returns the name of the user running the holder, if recorded.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| &user[..])
+    }
+
+    /// Returns what the holder uses the lock for, if recorded.
+    pub fn purpose(&self) -> Option<&str> {
+        self.purpose.as_ref().map(|purpose| &purpose[..])
+    }
+
+    /// Checks whether the recorded holder is still running.
+    ///
+    /// The holder is `Dead` if it ran on this host and either no process with
+    /// its ID exists, or that process started after the lock was taken, so
+    /// the ID was reused. The start time of other processes is only known on
+    /// Linux, Android, Apple platforms and Windows.
+    pub fn holder_state(&self) -> HolderState {
+        if let Some(ref hostname) = self.hostname {
+            if sys::hostname().as_ref() != Some(hostname) {
+                return HolderState::Unknown;
+            }
+        }
+        match sys::process_start_time(self.pid) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => HolderState::Dead,
+            Err(_) => HolderState::Unknown,
+            // The recorded time is truncated to a second.
+            Ok(Some(start)) => match self.started.and_then(|started| started.checked_add(Duration::from_secs(1))) {
+                Some(latest) if start > latest => HolderState::Dead,
+                _ => HolderState::Running,
+            },
+            Ok(None) => HolderState::Running,
+        }
+    }
+
+    /// Sets the process ID.
+    pub fn set_pid(&mut self, pid: u32) -> &mut LockMetadata {
+        self.pid = pid;
+        self
+    }
+
+    /// Sets what the holder uses the lock for.
+    pub fn set_purpose(&mut self, purpose: &str) -> &mut LockMetadata {
+        self.purpose = Some(purpose.to_owned());
+        self
+    }
+}
+
+/// Formats the metadata as the contents of a lock file.
+impl fmt::Display for LockMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "pid = {}", self.pid)?;
+        if let Some(started) = self.started {
+            let secs = started.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+            writeln!(f, "started = {}", secs)?;
+        }
+        let strings = [("hostname", &self.hostname), ("user", &self.user), ("purpose", &self.purpose)];
+        for &(key, value) in &strings {
+            if let Some(ref value) = *value {
+                write!(f, "{} = ", key)?;
+                write_string(f, value)?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the holder recorded in a lock file is running, as returned by
+/// `LockMetadata::holder_state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HolderState {
+    /// The holder has exited.
+    Dead,
+    /// A process with the holder's ID is running, and may be the holder.
+    Running,
+    /// The holder ran on another host, or could not be checked.
+    Unknown,
+}
+
+enum Value {
+    Integer(u64),
+    String(String),
+}
+
+/// Parses a TOML non-negative decimal integer or basic string, followed by an
+/// optional comment.
+fn parse_value(value: &str) -> Option<Value> {
+    if !value.starts_with('"') {
+        let end = value.find('#').unwrap_or(value.len());
+        return value[..end].trim().replace('_', "").parse().ok().map(Value::Integer);
+    }
+    let mut string = String::new();
+    let mut chars = value[1..].char_indices();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '"' => {
+                let rest = chars.as_str().trim_start();
+                return if rest.is_empty() || rest.starts_with('#') { Some(Value::String(string)) } else { None };
+            }
+            '\\' => {
+                let escaped = match chars.next()?.1 {
+                    'b' => '\u{8}',
+                    't' => '\t',
+                    'n' => '\n',
+                    'f' => '\u{c}',
+                    'r' => '\r',
+                    '"' => '"',
+                    '\\' => '\\',
+                    'u' => unicode_escape(&mut chars, 4)?,
+                    'U' => unicode_escape(&mut chars, 8)?,
+                    _ => return None,
+                };
+                string.push(escaped);
+            }
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+fn unicode_escape(chars: &mut ::std::str::CharIndices, digits: usize) -> Option<char> {
+    let hex: String = chars.by_ref().take(digits).map(|(_, c)| c).collect();
+    if hex.len() != digits {
+        return None;
+    }
+    u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32)
+}
+
+/// Writes a TOML basic string, escaping quotes, backslashes and control
+/// characters.
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            c if c.is_control() => write!(f, "\\u{:04X}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::ErrorKind;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{HolderState, LockMetadata};
+
+    /// Tests that metadata survives formatting and parsing, including strings
+    /// which need escaping.
+    #[test]
+    fn round_trip() {
+        let mut metadata = LockMetadata::current();
+        metadata.set_purpose("backup of \"/srv\"\n\tnightly \u{7f} \u{e9}");
+        let parsed = LockMetadata::parse(&metadata.to_string()).unwrap();
+        assert_eq!(metadata.pid(), parsed.pid());
+        assert_eq!(metadata.hostname(), parsed.hostname());
+        assert_eq!(metadata.user(), parsed.user());
+        assert_eq!(metadata.purpose(), parsed.purpose());
+        let started = metadata.started().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_secs(started)), parsed.started());
+    }
+
+    /// Tests parsing bare process IDs, comments, unknown keys and invalid
+    /// contents.
+    #[test]
+    fn parse() {
+        assert_eq!(4242, LockMetadata::parse("4242\n").unwrap().pid());
+
+        let metadata = LockMetadata::parse("# owner\npid = 4_242 # comment\nhost = \"x\"\nuser = \"a\\u00e9\"\n").unwrap();
+        assert_eq!(4242, metadata.pid());
+        assert_eq!(Some("a\u{e9}"), metadata.user());
+        assert_eq!(None, metadata.hostname());
+        assert_eq!(None, metadata.started());
+
+        for contents in &["", "user = \"a\"", "pid = \"4242\"", "pid = 4294967296", "pid", "pid = 1\nuser = \"a",
+                          "pid = 1\nstarted = 18446744073709551615"] {
+            assert_eq!(ErrorKind::InvalidData, LockMetadata::parse(contents).unwrap_err().kind());
+        }
+    }
+
+    /// Tests that the current process is running, and that a process which
+    /// started after the lock was taken is not the holder.
+    #[test]
+    fn holder_state() {
+        let metadata = LockMetadata::current();
+        assert_eq!(HolderState::Running, metadata.holder_state());
+
+        let other_host = format!("pid = {}\nhostname = \"fs2.invalid\"\n", metadata.pid());
+        assert_eq!(HolderState::Unknown, LockMetadata::parse(&other_host).unwrap().holder_state());
+
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", windows))]
+        {
+            let reused = format!("pid = {}\nstarted = 1000\n", metadata.pid());
+            assert_eq!(HolderState::Dead, LockMetadata::parse(&reused).unwrap().holder_state());
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Deref;
+use std::slice;
+
+use {sys, FileExt, FileLockGuard};
+
+/// How a file is mapped by `mmap_locked`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MapMode {
+    /// The file is locked shared and mapped read-only.
+    Read,
+    /// The file is locked exclusively and mapped for reading and writing.
+    /// The file must be opened for reading and writing.
+    Write,
+}
+
+/// A memory mapping of a file which holds a lock on the file for as long as
+/// it exists.
+///
+/// Created by `mmap_locked`. The mapping is unmapped before the lock is
+/// released, so it can not be read or written while another process holds
+/// a conflicting lock. Writes are shared with other mappings of the file and
+/// eventually reach the file; call `flush` to write them back immediately.
+#[derive(Debug)]
+pub struct LockedMap<'a> {
+    // Dropped before the guard, so the lock outlives the mapping.
+    map: Option<sys::Mmap>,
+    len: usize,
+    guard: FileLockGuard<'a>,
+}
+
+impl<'a> LockedMap<'a> {
+    /// Returns the guard holding the lock.
+    pub fn guard(&self) -> &FileLockGuard<'a> {
+        &self.guard
+    }
+
+    /// Returns the mapped bytes for writing, or `None` if the file was mapped
+    /// with `MapMode::Read`.
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        if !self.guard.is_exclusive() {
+            return None;
+        }
+        Some(match self.map {
+            Some(ref map) => unsafe { slice::from_raw_parts_mut(map.as_ptr(), self.len) },
+            None => &mut [],
+        })
+    }
+
+    /// Writes modified pages back to the file, and waits for them to be
+    /// written.
+    pub fn flush(&self) -> Result<()> {
+        match self.map {
+            Some(ref map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Unmaps the file and releases the lock, returning any error from
+    /// unlocking the file.
+    pub fn unlock(self) -> Result<()> {
+        let LockedMap { map, guard, .. } = self;
+        drop(map);
+        guard.unlock()
+    }
+}
+
+impl<'a> Deref for LockedMap<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.map {
+            Some(ref map) => unsafe { slice::from_raw_parts(map.as_ptr(), self.len) },
+            None => &[],
+        }
+    }
+}
+
+/// Locks `file` according to `mode`, blocking until the lock is available,
+/// and maps its contents into memory.
+///
+/// The mapping covers the length of the file once it is locked, and the lock
+/// is held until the returned `LockedMap` is dropped.
+///
+/// # Safety
+///
+/// The lock is advisory, so it does not stop the file from being modified
+/// while it is mapped. The caller must ensure that for as long as the
+/// `LockedMap` exists, the file is neither truncated nor written through
+/// any other handle or mapping, whether by this process (including through
+/// `file` itself, such as with `set_len` or `write`) or by another process
+/// which does not take the lock. Otherwise the mapped bytes may change
+/// while they are borrowed, and accesses beyond a new end of the file may
+/// crash the process with `SIGBUS` on Unix.
+///
+/// ```no_run
+/// use std::fs::OpenOptions;
+/// use fs2::{mmap_locked, MapMode};
+///
+/// let file = OpenOptions::new().read(true).write(true).open("counters").unwrap();
+/// // Safety: every process using `counters` maps it through `mmap_locked`,
+/// // and nothing resizes it.
+/// let mut map = unsafe { mmap_locked(&file, MapMode::Write) }.unwrap();
+/// map.as_mut_slice().unwrap()[0] += 1;
+/// map.flush().unwrap();
+/// ```
+pub unsafe fn mmap_locked(file: &File, mode: MapMode) -> Result<LockedMap<'_>> {
+    let guard = match mode {
+        MapMode::Read => file.lock_shared_guard()?,
+        MapMode::Write => file.lock_exclusive_guard()?,
+    };
+    let len = usize::try_from(file.metadata()?.len()).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, "file is too large to be mapped")
+    })?;
+    // Empty mappings are not allowed, and there is nothing to map.
+    let map = if len == 0 { None } else { Some(sys::map(file, len, mode == MapMode::Write)?) };
+    Ok(LockedMap { map, len, guard })
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{Read, Write};
+
+    use {lock_contended_error, mmap_locked, FileExt, MapMode};
+
+    /// Tests that a mapping holds the lock, and that writes reach the file.
+    #[test]
+    fn mmap_locked_write() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        file1.write_all(b"hello").unwrap();
+
+        let mut map = unsafe { mmap_locked(&file1, MapMode::Write) }.unwrap();
+        assert_eq!(b"hello", &map[..]);
+        assert_eq!(FileExt::try_lock_shared(&file2).unwrap_err().kind(),
+                   lock_contended_error().kind());
+        map.as_mut_slice().unwrap()[0] = b'j';
+        map.flush().unwrap();
+        map.unlock().unwrap();
+
+        let map = unsafe { mmap_locked(&file2, MapMode::Read) }.unwrap();
+        assert_eq!(b"jello", &map[..]);
+        assert_eq!(file1.try_lock_exclusive().unwrap_err().kind(), lock_contended_error().kind());
+        drop(map);
+
+        let mut contents = String::new();
+        file2.read_to_string(&mut contents).unwrap();
+        assert_eq!("jello", contents);
+    }
+
+    /// Tests that empty files can be mapped, and read-only mappings can not
+    /// be written.
+    #[test]
+    fn mmap_locked_empty() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let mut map = unsafe { mmap_locked(&file, MapMode::Read) }.unwrap();
+        assert!(map.is_empty());
+        assert!(map.as_mut_slice().is_none());
+        map.flush().unwrap();
+        drop(map);
+        let mut map = unsafe { mmap_locked(&file, MapMode::Write) }.unwrap();
+        assert!(map.as_mut_slice().unwrap().is_empty());
+    }
+}
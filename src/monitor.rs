@@ -0,0 +1,240 @@
+use std::fmt;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use error::with_path;
+use {sys, FsStats};
+
+/// The level of available space below which a `SpaceMonitor` reports the file
+/// system as low on space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    /// An absolute number of available bytes.
+    Bytes(u64),
+    /// A fraction of the total space, between 0 and 1. For example `0.1`
+    /// reports when less than 10% of the file system is available.
+    Ratio(f64),
+}
+
+impl Threshold {
+    fn is_low(&self, stats: &FsStats) -> bool {
+        match *self {
+            Threshold::Bytes(bytes) => stats.available_space() < bytes,
+            Threshold::Ratio(ratio) => {
+                (stats.available_space() as f64) < ratio * stats.total_space() as f64
+            }
+        }
+    }
+}
+
+/// A change in the available space observed by a `SpaceMonitor`.
+#[derive(Debug)]
+pub enum SpaceEvent {
+    /// The available space fell below the threshold. This is also reported
+    /// if the space is already low when monitoring starts.
+    Low(FsStats),
+    /// The available space rose back to or above the threshold.
+    Recovered(FsStats),
+    /// The file system stats could not be queried. Reported once until a
+    /// query succeeds again.
+    Failed(Error),
+}
+
+/// Options for watching the available space of a file system, and reporting
+/// when it crosses a threshold.
+///
+/// The file system is polled from a background thread, which is stopped when
+/// the returned `SpaceWatcher` is dropped. Events are only reported when the
+/// state changes, so a file system which stays low on space is reported once.
+///
+/// ```no_run
+/// use fs2::{SpaceEvent, SpaceMonitor, Threshold};
+///
+/// let (_watcher, events) = SpaceMonitor::new("/var/lib", Threshold::Ratio(0.1)).start().unwrap();
+/// for event in events {
+///     if let SpaceEvent::Low(stats) = event {
+///         eprintln!("only {} bytes available", stats.available_space());
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SpaceMonitor {
+    path: PathBuf,
+    threshold: Threshold,
+    interval: Duration,
+}
+
+impl SpaceMonitor {
+    /// Creates options for watching the file system containing `path`,
+    /// polling every 5 seconds.
+    pub fn new<P>(path: P, threshold: Threshold) -> SpaceMonitor where P: AsRef<Path> {
+        SpaceMonitor {
+            path: path.as_ref().to_path_buf(),
+            threshold,
+            interval: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets how often the file system is polled. Defaults to 5 seconds.
+    pub fn interval(&mut self, interval: Duration) -> &mut SpaceMonitor {
+        self.interval = interval;
+        self
+    }
+
+    /// Starts watching, delivering events on the returned channel.
+    pub fn start(&self) -> Result<(SpaceWatcher, Receiver<SpaceEvent>)> {
+        let (sender, receiver) = mpsc::channel();
+        let watcher = self.start_with(move |event| {
+            let _ = sender.send(event);
+        })?;
+        Ok((watcher, receiver))
+    }
+
+    /// Starts watching, calling `callback` on the watching thread with each
+    /// event.
+    pub fn start_with<F>(&self, mut callback: F) -> Result<SpaceWatcher>
+    where F: FnMut(SpaceEvent) + Send + 'static {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let monitor = self.clone();
+        let thread = thread::Builder::new().name("fs2-space-monitor".to_string()).spawn(move || {
+            let (ref stopped, ref condvar) = *thread_stop;
+            let mut state = State::Unknown;
+            let mut guard = stopped.lock().unwrap();
+            while !*guard {
+                drop(guard);
+                if let Some(event) = monitor.poll(&mut state) {
+                    callback(event);
+                }
+                guard = stopped.lock().unwrap();
+                if !*guard {
+                    guard = condvar.wait_timeout(guard, monitor.interval).unwrap().0;
+                }
+            }
+        })?;
+        Ok(SpaceWatcher { stop, thread: Some(thread) })
+    }
+
+    /// Queries the file system, returning an event if the state changed.
+    fn poll(&self, state: &mut State) -> Option<SpaceEvent> {
+        match sys::statvfs(&self.path).map_err(with_path("statvfs", &self.path)) {
+            Ok(stats) => {
+                let low = self.threshold.is_low(&stats);
+                let previous = *state;
+                *state = if low { State::Low } else { State::Ok };
+                match (previous, low) {
+                    (State::Low, true) => None,
+                    (_, true) => Some(SpaceEvent::Low(stats)),
+                    (State::Low, false) => Some(SpaceEvent::Recovered(stats)),
+                    (_, false) => None,
+                }
+            }
+            Err(_) if *state == State::Failed => None,
+            Err(err) => {
+                *state = State::Failed;
+                Some(SpaceEvent::Failed(err))
+            }
+        }
+    }
+}
+
+/// The last observed state of the file system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Unknown,
+    Ok,
+    Low,
+    Failed,
+}
+
+/// A running `SpaceMonitor`. Watching stops when this is dropped.
+pub struct SpaceWatcher {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SpaceWatcher {
+    /// Stops watching, waiting for the watching thread to exit.
+    pub fn stop(self) {}
+}
+
+impl fmt::Debug for SpaceWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpaceWatcher").finish()
+    }
+}
+
+impl Drop for SpaceWatcher {
+    fn drop(&mut self) {
+        let (ref stopped, ref condvar) = *self.stop;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+    use std::time::Duration;
+
+    use super::{SpaceEvent, SpaceMonitor, Threshold};
+
+    /// Tests that low space is reported once, and that dropping the watcher
+    /// stops it.
+    #[test]
+    fn space_monitor_low() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let (watcher, events) = SpaceMonitor::new(tempdir.path(), Threshold::Ratio(1.5))
+                                             .interval(Duration::from_millis(1))
+                                             .start()
+                                             .unwrap();
+        match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+            SpaceEvent::Low(stats) => assert!(stats.total_space() > 0),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(RecvTimeoutError::Timeout,
+                   events.recv_timeout(Duration::from_millis(50)).unwrap_err());
+
+        watcher.stop();
+        assert_eq!(TryRecvError::Disconnected, events.try_recv().unwrap_err());
+    }
+
+    /// Tests that nothing is reported while there is enough space.
+    #[test]
+    fn space_monitor_ok() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let (_watcher, events) = SpaceMonitor::new(tempdir.path(), Threshold::Bytes(0))
+                                              .interval(Duration::from_millis(1))
+                                              .start()
+                                              .unwrap();
+        assert_eq!(RecvTimeoutError::Timeout,
+                   events.recv_timeout(Duration::from_millis(50)).unwrap_err());
+    }
+
+    /// Tests that a failure to query the file system is reported once.
+    #[test]
+    fn space_monitor_failed() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("missing");
+        let (_watcher, events) = SpaceMonitor::new(&path, Threshold::Bytes(0))
+                                              .interval(Duration::from_millis(1))
+                                              .start()
+                                              .unwrap();
+        match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+            SpaceEvent::Failed(_) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(RecvTimeoutError::Timeout,
+                   events.recv_timeout(Duration::from_millis(50)).unwrap_err());
+    }
+}
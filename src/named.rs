@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use {FileExt, FileLockGuard};
+
+/// A mutex shared between processes by name.
+///
+/// Each name maps to a lock file in a directory private to the user. On Unix
+/// this is `$XDG_RUNTIME_DIR` if it is set and private, and otherwise an
+/// `fs2-<uid>` directory in the temporary directory, created with mode `0700`
+/// and checked to be owned by the user and inaccessible to others; the lock
+/// file is not opened through a symbolic link. On Windows it is the user's
+/// temporary directory. Every `NamedMutex` with the same name, in any process
+/// run by the same user, excludes the others while locked.
+///
+/// The lock file is left in place after the mutex is dropped, since removing
+/// it could race with another process opening it.
+#[derive(Debug)]
+pub struct NamedMutex {
+    file: File,
+    path: PathBuf,
+}
+
+impl NamedMutex {
+    /// Opens the mutex with the given name, creating its lock file if
+    /// necessary. The mutex is not locked.
+    ///
+    /// Names must be non-empty and may not contain path separators or nul
+    /// bytes. Fails with `ErrorKind::PermissionDenied` on Unix if the lock
+    /// directory exists but is not private to the user.
+    pub fn new(name: &str) -> Result<NamedMutex> {
+        if name.is_empty() || name.contains(['/', '\\', '\0']) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid mutex name: {:?}", name)));
+        }
+        let path = dir::lock_dir()?.join(format!("fs2-{}.lock", name));
+        let file = dir::open(&path)?;
+        Ok(NamedMutex { file, path })
+    }
+
+    /// Returns the path of the mutex's lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Locks the mutex, blocking until it is available. The mutex is unlocked
+    /// when the returned guard is dropped.
+    pub fn lock(&self) -> Result<FileLockGuard<'_>> {
+        self.file.lock_exclusive_guard()
+    }
+
+    /// Locks the mutex, or returns an error if it is currently locked (see
+    /// `lock_contended_error`). The mutex is unlocked when the returned guard
+    /// is dropped.
+    pub fn try_lock(&self) -> Result<FileLockGuard<'_>> {
+        self.file.try_lock_exclusive_guard()
+    }
+}
+
+#[cfg(unix)]
+mod dir {
+    extern crate libc;
+
+    use std::env;
+    use std::fs::{self, DirBuilder, File, OpenOptions};
+    use std::io::{Error, ErrorKind, Result};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+    use std::path::{Path, PathBuf};
+
+    /// Returns `$XDG_RUNTIME_DIR` if it is set and private, and otherwise
+    /// `fs2-<uid>` in the temporary directory, created if necessary.
+    pub fn lock_dir() -> Result<PathBuf> {
+        if let Some(dir) = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+            if check_private(&dir).is_ok() {
+                return Ok(dir);
+            }
+        }
+        let dir = env::temp_dir().join(format!("fs2-{}", unsafe { libc::getuid() }));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+            result => result?,
+        }
+        check_private(&dir)?;
+        Ok(dir)
+    }
+
+    /// Checks that `dir` is a directory rather than a symbolic link, owned by
+    /// the current user, and inaccessible to other users, so that they can
+    /// neither create the lock files nor replace them.
+    pub fn check_private(dir: &Path) -> Result<()> {
+        let metadata = fs::symlink_metadata(dir)?;
+        if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o077 != 0 {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("lock directory {} is not private to the current user", dir.display())));
+        }
+        Ok(())
+    }
+
+    pub fn open(path: &Path) -> Result<File> {
+        OpenOptions::new().read(true).write(true).create(true).truncate(false)
+                          .mode(0o600)
+                          .custom_flags(libc::O_NOFOLLOW)
+                          .open(path)
+    }
+}
+
+#[cfg(not(unix))]
+mod dir {
+    use std::env;
+    use std::fs::{File, OpenOptions};
+    use std::io::Result;
+    use std::path::{Path, PathBuf};
+
+    pub fn lock_dir() -> Result<PathBuf> {
+        Ok(env::temp_dir())
+    }
+
+    pub fn open(path: &Path) -> Result<File> {
+        OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::ErrorKind;
+    use std::process;
+
+    use {NamedMutex, lock_contended_error};
+
+    /// Tests that mutexes with the same name exclude each other.
+    #[test]
+    fn named_mutex() {
+        let name = format!("named-mutex-test-{}", process::id());
+        let mutex1 = NamedMutex::new(&name).unwrap();
+        let mutex2 = NamedMutex::new(&name).unwrap();
+        let other = NamedMutex::new(&format!("{}-other", name)).unwrap();
+        assert_eq!(mutex1.path(), mutex2.path());
+
+        let guard = mutex1.lock().unwrap();
+        assert_eq!(mutex2.try_lock().unwrap_err().kind(), lock_contended_error().kind());
+        other.try_lock().unwrap();
+        drop(guard);
+        mutex2.try_lock().unwrap();
+
+        let _ = ::std::fs::remove_file(mutex1.path());
+        let _ = ::std::fs::remove_file(other.path());
+    }
+
+    /// Tests that names which are not valid file names are rejected.
+    #[test]
+    fn named_mutex_invalid_name() {
+        for name in &["", "a/b", "a\\b", "a\0b"] {
+            assert_eq!(ErrorKind::InvalidInput, NamedMutex::new(name).unwrap_err().kind());
+        }
+    }
+
+    /// Tests that lock directories other users can write to are refused, and
+    /// that a symbolic link planted as the lock file is not followed.
+    #[cfg(unix)]
+    #[test]
+    fn named_mutex_private() {
+        extern crate tempdir;
+
+        use std::fs;
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        fs::set_permissions(tempdir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(ErrorKind::PermissionDenied, super::dir::check_private(tempdir.path()).unwrap_err().kind());
+        fs::set_permissions(tempdir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        super::dir::check_private(tempdir.path()).unwrap();
+
+        let target = tempdir.path().join("target");
+        fs::write(&target, "contents").unwrap();
+        let name = format!("named-mutex-symlink-test-{}", process::id());
+        let path = super::dir::lock_dir().unwrap().join(format!("fs2-{}.lock", name));
+        symlink(&target, &path).unwrap();
+        let result = NamedMutex::new(&name);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!("contents", fs::read_to_string(&target).unwrap());
+    }
+}
@@ -0,0 +1,162 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use positional::{read_at, write_at};
+use sys;
+
+/// The bounds of the polling interval, on platforms where the file can not
+/// be watched.
+const MIN_POLL: Duration = Duration::from_millis(1);
+const MAX_POLL: Duration = Duration::from_millis(50);
+
+/// A cross-process notification, in the style of a condition variable.
+///
+/// Processes which open a `Notify` on the same path can signal each other
+/// that some shared state changed: `notify` wakes every process blocked in
+/// `wait`, which should then re-check the state. The file holds a
+/// little-endian `u64` generation, which `notify` increments under an
+/// exclusive lock. Each `Notify` remembers the last generation it saw, so a
+/// notification sent after it was opened, or after its last `wait` returned,
+/// is never missed even if it arrives before `wait` is called.
+///
+/// On Linux and Android waiters sleep on an inotify watch of the file.
+/// Elsewhere they poll the generation, backing off to every 50 milliseconds.
+/// The file must not be replaced while it is in use.
+///
+/// ```no_run
+/// use fs2::Notify;
+///
+/// let mut notify = Notify::open("/var/run/jobs.notify").unwrap();
+/// loop {
+///     // Check the job status, then sleep until it changes.
+///     notify.wait().unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Notify {
+    path: PathBuf,
+    file: File,
+    watch: Option<sys::Watch>,
+    seen: u64,
+}
+
+impl Notify {
+    /// Opens the notification file at `path`, creating it if it does not
+    /// exist.
+    pub fn open<P>(path: P) -> Result<Notify> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        // Watch before reading the generation, so that no notification falls between them.
+        let watch = sys::watch(path)?;
+        let mut notify = Notify { path: path.to_path_buf(), file, watch, seen: 0 };
+        notify.seen = notify.generation()?;
+        Ok(notify)
+    }
+
+    /// Returns the path of the notification file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the current generation, which is incremented by every
+    /// notification.
+    pub fn generation(&self) -> Result<u64> {
+        sys::lock_shared(&self.file)?;
+        let generation = read_generation(&self.file);
+        sys::unlock(&self.file)?;
+        generation
+    }
+
+    /// Wakes every process waiting on the file.
+    pub fn notify(&self) -> Result<()> {
+        sys::lock_exclusive(&self.file)?;
+        let result = read_generation(&self.file).and_then(|generation| {
+            write_at(&self.file, &generation.wrapping_add(1).to_le_bytes(), 0)
+        });
+        sys::unlock(&self.file)?;
+        result
+    }
+
+    /// Blocks until a notification is sent after the last one this `Notify`
+    /// saw, and returns the new generation.
+    pub fn wait(&mut self) -> Result<u64> {
+        self.wait_until(None).map(|generation| generation.unwrap())
+    }
+
+    /// Blocks until a notification is sent after the last one this `Notify`
+    /// saw, or `timeout` elapses. Returns the new generation, or `None` on
+    /// timeout.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<u64>> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&mut self, deadline: Option<Instant>) -> Result<Option<u64>> {
+        let mut interval = MIN_POLL;
+        loop {
+            let generation = self.generation()?;
+            if generation != self.seen {
+                self.seen = generation;
+                return Ok(Some(generation));
+            }
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            match self.watch {
+                Some(ref watch) => watch.wait(remaining)?,
+                None => {
+                    thread::sleep(remaining.map_or(interval, |remaining| cmp::min(interval, remaining)));
+                    interval = cmp::min(interval * 2, MAX_POLL);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the generation; a file too short to hold one has not been notified.
+fn read_generation(file: &File) -> Result<u64> {
+    let mut buf = [0; 8];
+    Ok(if read_at(file, &mut buf, 0)? == buf.len() { u64::from_le_bytes(buf) } else { 0 })
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use Notify;
+
+    /// Tests that a notification wakes a waiter, and is not missed when sent
+    /// before the wait.
+    #[test]
+    fn notify() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut waiter = Notify::open(&path).unwrap();
+        let notifier = Notify::open(&path).unwrap();
+
+        assert_eq!(None, waiter.wait_timeout(Duration::from_millis(10)).unwrap());
+        notifier.notify().unwrap();
+        assert_eq!(Some(1), waiter.wait_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(None, waiter.wait_timeout(Duration::from_millis(10)).unwrap());
+
+        let handle = thread::spawn(move || waiter.wait().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        notifier.notify().unwrap();
+        assert_eq!(2, handle.join().unwrap());
+        assert_eq!(2, notifier.generation().unwrap());
+    }
+}
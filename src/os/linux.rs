@@ -0,0 +1,556 @@
+//! Linux-specific extensions.
+
+extern crate libc;
+
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use {sys, LockMode};
+
+/// The kind of a lock listed in `/proc/locks`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LockKind {
+    /// A whole-file `flock(2)` lock, as taken by `FileExt`.
+    Flock,
+    /// A POSIX record lock, taken with `fcntl(2)` or `lockf(3)`.
+    Posix,
+    /// An open file description record lock.
+    OpenFileDescription,
+    /// A lease, taken with `F_SETLEASE` (see `set_lease`).
+    Lease,
+    /// An NFS delegation granted by the kernel's NFS server.
+    Delegation,
+    /// Another kind of lock.
+    Other(String),
+}
+
+impl LockKind {
+    /// Returns `true` for leases and delegations, which do not exclude other
+    /// lockers, but are broken when the file is opened in a conflicting mode.
+    pub fn is_lease(&self) -> bool {
+        matches!(*self, LockKind::Lease | LockKind::Delegation)
+    }
+}
+
+/// A lock listed in `/proc/locks`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockEntry {
+    kind: LockKind,
+    blocked: bool,
+    mandatory: bool,
+    mode: LockMode,
+    pid: Option<u32>,
+    major: u64,
+    minor: u64,
+    inode: u64,
+    start: u64,
+    end: Option<u64>,
+}
+
+impl LockEntry {
+    /// Returns the kind of the lock.
+    pub fn kind(&self) -> &LockKind {
+        &self.kind
+    }
+
+    /// Returns `true` if this entry is a request blocked waiting for the lock,
+    /// rather than a held lock.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Returns `true` if the lock is mandatory rather than advisory.
+    pub fn is_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    /// Returns the mode of the lock.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns the ID of the process which took the lock, or `None` for open
+    /// file description locks, which belong to no process.
+    ///
+    /// For `flock(2)` locks this is the process which created the lock; it
+    /// may have since exited, leaving the lock held by a child.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Returns the major number of the device containing the locked file.
+    pub fn major(&self) -> u64 {
+        self.major
+    }
+
+    /// Returns the minor number of the device containing the locked file.
+    pub fn minor(&self) -> u64 {
+        self.minor
+    }
+
+    /// Returns the inode number of the locked file.
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+
+    /// Returns the offset of the first locked byte.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the offset of the last locked byte, or `None` if the lock
+    /// extends to the end of the file, however long it grows.
+    pub fn end(&self) -> Option<u64> {
+        self.end
+    }
+
+    /// Returns `true` if the lock is on the given file.
+    pub fn is_on(&self, file: &File) -> Result<bool> {
+        let metadata = file.metadata()?;
+        let (major, minor) = split_dev(metadata.dev());
+        Ok(self.major == major && self.minor == minor && self.inode == metadata.ino())
+    }
+
+    /// Parses a line of `/proc/locks`, such as
+    /// `1: FLOCK  ADVISORY  WRITE 1234 08:02:131090 0 EOF`. Returns `None` for
+    /// entries without a read or write mode, such as breaking leases, and for
+    /// malformed lines.
+    fn parse(line: &str) -> Option<LockEntry> {
+        let mut fields = line.split_whitespace().skip(1).peekable();
+        let blocked = fields.peek() == Some(&"->");
+        if blocked {
+            fields.next();
+        }
+        let kind = match fields.next()? {
+            "FLOCK" => LockKind::Flock,
+            "POSIX" => LockKind::Posix,
+            "OFDLCK" => LockKind::OpenFileDescription,
+            "LEASE" => LockKind::Lease,
+            "DELEG" => LockKind::Delegation,
+            other => LockKind::Other(other.to_owned()),
+        };
+        let mandatory = fields.next()? == "MANDATORY";
+        let mode = match fields.next()? {
+            "READ" => LockMode::Shared,
+            "WRITE" => LockMode::Exclusive,
+            _ => return None,
+        };
+        // Open file description locks have no owning process, and are listed with pid -1.
+        let pid = fields.next()?.parse::<u32>().ok();
+        let mut id = fields.next()?.split(':');
+        let major = u64::from_str_radix(id.next()?, 16).ok()?;
+        let minor = u64::from_str_radix(id.next()?, 16).ok()?;
+        let inode = id.next()?.parse().ok()?;
+        let start = fields.next()?.parse().ok()?;
+        let end = match fields.next()? {
+            "EOF" => None,
+            end => Some(end.parse().ok()?),
+        };
+        Some(LockEntry { kind, blocked, mandatory, mode, pid, major, minor, inode, start, end })
+    }
+}
+
+/// Splits a device number into its major and minor numbers, as glibc's `major` and `minor` do.
+fn split_dev(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000);
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xffff_ff00);
+    (major, minor)
+}
+
+/// Returns the locks held and requested on the system, as listed in
+/// `/proc/locks`.
+///
+/// Only locks on files in the caller's mount namespace are listed, and
+/// entries without a read or write mode, such as breaking leases, are
+/// omitted. Leases and delegations are listed with their own `LockKind`;
+/// unlike locks, they do not exclude other lockers.
+pub fn active_locks() -> Result<Vec<LockEntry>> {
+    let locks = fs::read_to_string("/proc/locks")?;
+    Ok(locks.lines().filter_map(LockEntry::parse).collect())
+}
+
+/// Returns the locks held and requested on the given file, as listed in
+/// `/proc/locks`.
+pub fn file_locks(file: &File) -> Result<Vec<LockEntry>> {
+    let metadata = file.metadata()?;
+    let (major, minor) = split_dev(metadata.dev());
+    let mut locks = active_locks()?;
+    locks.retain(|lock| lock.major == major && lock.minor == minor && lock.inode == metadata.ino());
+    Ok(locks)
+}
+
+/// `_IOWR('f', 11, struct fiemap)`.
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+/// Flushes dirty pages before mapping, so that delayed allocations are mapped.
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+/// How many extents are requested by each call.
+const FIEMAP_BATCH: usize = 256;
+
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x8;
+const FIEMAP_EXTENT_NOT_ALIGNED: u32 = 0x100;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawFiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct FiemapBuffer {
+    header: Fiemap,
+    extents: [RawFiemapExtent; FIEMAP_BATCH],
+}
+
+/// The physical location of a segment of a file, as returned by `fiemap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    len: u64,
+    flags: u32,
+}
+
+impl FiemapExtent {
+    /// Returns the offset of the segment from the start of the file.
+    pub fn logical(&self) -> u64 {
+        self.logical
+    }
+
+    /// Returns the offset of the segment from the start of the device, in
+    /// bytes. Meaningless if `is_unknown` is `true`.
+    pub fn physical(&self) -> u64 {
+        self.physical
+    }
+
+    /// Returns the length of the segment in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the segment is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the raw `FIEMAP_EXTENT_*` flags of the segment.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns `true` if this is the last segment of the file.
+    pub fn is_last(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_LAST != 0
+    }
+
+    /// Returns `true` if the location of the data is not known, for example
+    /// because it has not been allocated yet.
+    pub fn is_unknown(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_UNKNOWN != 0
+    }
+
+    /// Returns `true` if the segment is awaiting delayed allocation.
+    pub fn is_delalloc(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_DELALLOC != 0
+    }
+
+    /// Returns `true` if the data is compressed or encrypted, so the physical
+    /// length may differ from `len`.
+    pub fn is_encoded(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_ENCODED != 0
+    }
+
+    /// Returns `true` if the data is stored inline with metadata, or is
+    /// otherwise not block aligned.
+    pub fn is_inline(&self) -> bool {
+        self.flags & (FIEMAP_EXTENT_DATA_INLINE | FIEMAP_EXTENT_NOT_ALIGNED) != 0
+    }
+
+    /// Returns `true` if the segment is allocated but not written, and reads
+    /// as zeros.
+    pub fn is_unwritten(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_UNWRITTEN != 0
+    }
+
+    /// Returns `true` if the segment's blocks are shared with another file,
+    /// for example after a reflink copy.
+    pub fn is_shared(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_SHARED != 0
+    }
+}
+
+/// Returns the physical layout of the file's allocated segments, in order of
+/// their offset in the file, using the `FS_IOC_FIEMAP` ioctl. Holes are not
+/// listed.
+///
+/// Dirty pages are flushed first, so that data awaiting delayed allocation
+/// is mapped. Fails with `ErrorKind::Unsupported` on file systems without
+/// FIEMAP support, such as tmpfs.
+pub fn fiemap(file: &File) -> Result<Vec<FiemapExtent>> {
+    let mut buffer = Box::new(FiemapBuffer {
+        header: Fiemap::default(),
+        extents: [RawFiemapExtent::default(); FIEMAP_BATCH],
+    });
+    let mut extents = Vec::new();
+    let mut start = 0u64;
+    loop {
+        buffer.header = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            extent_count: FIEMAP_BATCH as u32,
+            ..Fiemap::default()
+        };
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut *buffer as *mut FiemapBuffer) };
+        if ret != 0 {
+            let err = Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => {
+                    Error::new(ErrorKind::Unsupported, "the file system does not support FIEMAP")
+                }
+                _ => err,
+            });
+        }
+        let mapped = (buffer.header.mapped_extents as usize).min(FIEMAP_BATCH);
+        for raw in &buffer.extents[..mapped] {
+            extents.push(FiemapExtent {
+                logical: raw.logical,
+                physical: raw.physical,
+                len: raw.length,
+                flags: raw.flags,
+            });
+        }
+        match extents.last() {
+            Some(last) if mapped == FIEMAP_BATCH && !last.is_last() => start = last.logical + last.len,
+            _ => return Ok(extents),
+        }
+    }
+}
+
+/// Locks the byte range `offset..offset + len` of the file with an open file
+/// description lock, blocking until the lock is available. A `len` of 0
+/// extends the range to the end of the file.
+///
+/// Open file description locks belong to the `File` and its duplicates, like
+/// `flock(2)` locks, but cover byte ranges like POSIX record locks, and
+/// conflict with record locks held by other processes. These are the locks
+/// taken by the range locking methods of `LockOptions` on Linux.
+///
+/// # Notes
+///
+/// This is implemented with `F_OFD_SETLKW`.
+pub fn lock_ofd(file: &File, offset: u64, len: u64, mode: LockMode) -> Result<()> {
+    sys::lock_range(file, offset, len, mode, true)
+}
+
+/// Locks the byte range `offset..offset + len` of the file with an open file
+/// description lock, failing with the error returned by
+/// `lock_contended_error` if a conflicting lock is held.
+pub fn try_lock_ofd(file: &File, offset: u64, len: u64, mode: LockMode) -> Result<()> {
+    sys::lock_range(file, offset, len, mode, false)
+}
+
+/// Releases the file's open file description locks on the byte range
+/// `offset..offset + len`.
+pub fn unlock_ofd(file: &File, offset: u64, len: u64) -> Result<()> {
+    sys::unlock_range(file, offset, len)
+}
+
+/// Takes, changes, or with `None` releases a lease on the file.
+///
+/// A shared (read) lease may be taken on a file opened read-only which no
+/// process has open for writing, and an exclusive (write) lease on a file no
+/// other process has open. The holder is sent `SIGIO` when another process
+/// opens the file in a conflicting way, and must release or downgrade the
+/// lease within `/proc/sys/fs/lease-break-time` seconds. Only the file's
+/// owner, or a process with `CAP_LEASE`, may take a lease.
+///
+/// # Notes
+///
+/// This is implemented with `F_SETLEASE`.
+pub fn set_lease(file: &File, lease: Option<LockMode>) -> Result<()> {
+    let arg = match lease {
+        None => libc::F_UNLCK,
+        Some(LockMode::Shared) => libc::F_RDLCK,
+        Some(LockMode::Exclusive) => libc::F_WRLCK,
+    };
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLEASE, arg as libc::c_int) } == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the lease held on the file, if any.
+///
+/// # Notes
+///
+/// This is implemented with `F_GETLEASE`.
+pub fn lease(file: &File) -> Result<Option<LockMode>> {
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLEASE) };
+    match ret {
+        -1 => Err(Error::last_os_error()),
+        t if t == libc::F_RDLCK as libc::c_int => Ok(Some(LockMode::Shared)),
+        t if t == libc::F_WRLCK as libc::c_int => Ok(Some(LockMode::Exclusive)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{ErrorKind, Write};
+    #[cfg(feature = "locks")]
+    use std::process;
+
+    use LockMode;
+    #[cfg(feature = "locks")]
+    use {lock_contended_error, FileExt};
+    use super::{LockEntry, LockKind, split_dev, fiemap, lease, set_lease};
+    #[cfg(feature = "locks")]
+    use super::{file_locks, try_lock_ofd, unlock_ofd};
+
+    /// Tests parsing `/proc/locks` entries.
+    #[test]
+    fn parse_lock_entry() {
+        let entry = LockEntry::parse("1: FLOCK  ADVISORY  WRITE 1234 08:02:131090 0 EOF").unwrap();
+        assert_eq!(LockKind::Flock, *entry.kind());
+        assert!(!entry.is_blocked());
+        assert!(!entry.is_mandatory());
+        assert_eq!(LockMode::Exclusive, entry.mode());
+        assert_eq!(Some(1234), entry.pid());
+        assert_eq!((8, 2, 131090), (entry.major(), entry.minor(), entry.inode()));
+        assert_eq!((0, None), (entry.start(), entry.end()));
+
+        let entry = LockEntry::parse("2: -> OFDLCK ADVISORY  READ  -1 fd:01:42 100 199").unwrap();
+        assert_eq!(LockKind::OpenFileDescription, *entry.kind());
+        assert!(entry.is_blocked());
+        assert_eq!(LockMode::Shared, entry.mode());
+        assert_eq!(None, entry.pid());
+        assert_eq!((0xfd, 1, 42), (entry.major(), entry.minor(), entry.inode()));
+        assert_eq!((100, Some(199)), (entry.start(), entry.end()));
+
+        let entry = LockEntry::parse("3: LEASE  ACTIVE    READ  1 08:02:1 0 EOF").unwrap();
+        assert_eq!(LockKind::Lease, *entry.kind());
+        assert!(entry.kind().is_lease());
+        let entry = LockEntry::parse("4: DELEG  ACTIVE    READ  1 08:02:1 0 EOF").unwrap();
+        assert_eq!(LockKind::Delegation, *entry.kind());
+        assert!(entry.kind().is_lease());
+        assert!(!LockKind::Posix.is_lease());
+
+        assert_eq!(None, LockEntry::parse("5: LEASE  BREAKING  UNLCK 1 08:02:1 0 EOF"));
+        assert_eq!(None, LockEntry::parse("garbage"));
+    }
+
+    /// Tests splitting device numbers, including ones with bits above the
+    /// legacy 8-bit minor and 12-bit major.
+    #[test]
+    fn split_dev_numbers() {
+        assert_eq!((8, 2), split_dev(0x802));
+        assert_eq!((0x103, 0x45), split_dev(0x10345));
+        assert_eq!((0x1234_5678, 0x9abc_def0), split_dev(0x1234_59ab_cde6_78f0));
+        for &dev in &[0x802u64, 0x10345, 0x1234_59ab_cde6_78f0] {
+            assert_eq!((libc::major(dev) as u64, libc::minor(dev) as u64), split_dev(dev));
+        }
+    }
+
+    /// Tests listing the locks on a file.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn list_file_locks() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert!(file_locks(&file).unwrap().is_empty());
+        file.lock_exclusive().unwrap();
+        let locks = file_locks(&file).unwrap();
+        assert_eq!(1, locks.len());
+        assert_eq!(LockKind::Flock, *locks[0].kind());
+        assert_eq!(Some(process::id()), locks[0].pid());
+        assert!(locks[0].is_on(&file).unwrap());
+    }
+
+    /// Tests mapping the physical extents of a file.
+    #[test]
+    fn fiemap_extents() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        match fiemap(&file) {
+            Ok(extents) => assert!(extents.is_empty()),
+            Err(ref err) if err.kind() == ErrorKind::Unsupported => return,
+            Err(err) => panic!("{}", err),
+        }
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        let extents = fiemap(&file).unwrap();
+        assert!(!extents.is_empty());
+        assert!(extents.last().unwrap().is_last());
+        assert!(extents.iter().map(|extent| extent.len()).sum::<u64>() >= 64 * 1024);
+        assert!(extents.iter().all(|extent| !extent.is_unknown() && extent.physical() > 0));
+    }
+
+    /// Tests that open file description locks belong to the file, so files in
+    /// the same process conflict.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn ofd_locks() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        try_lock_ofd(&file1, 0, 10, LockMode::Exclusive).unwrap();
+        assert_eq!(lock_contended_error().kind(),
+                   try_lock_ofd(&file2, 5, 10, LockMode::Shared).unwrap_err().kind());
+        try_lock_ofd(&file2, 10, 10, LockMode::Shared).unwrap();
+        unlock_ofd(&file1, 0, 10).unwrap();
+        try_lock_ofd(&file2, 0, 0, LockMode::Shared).unwrap();
+    }
+
+    /// Tests taking and releasing a lease.
+    #[test]
+    fn leases() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::write(&path, b"fs2").unwrap();
+        let file = fs::File::open(&path).unwrap();
+
+        assert_eq!(None, lease(&file).unwrap());
+        match set_lease(&file, Some(LockMode::Shared)) {
+            // Leases may be disabled, or unsupported by the file system.
+            Err(ref err) if err.kind() == ErrorKind::PermissionDenied || err.raw_os_error() == Some(super::libc::EINVAL) => return,
+            result => result.unwrap(),
+        }
+        assert_eq!(Some(LockMode::Shared), lease(&file).unwrap());
+        set_lease(&file, None).unwrap();
+        assert_eq!(None, lease(&file).unwrap());
+    }
+}
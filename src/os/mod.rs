@@ -0,0 +1,23 @@
+//! Platform-specific extensions.
+//!
+//! Like `std::os`, each module is only available on its platform, and exposes
+//! calls which have no portable equivalent in `FileExt`.
+
+#[cfg(unix)]
+pub mod unix;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod linux;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "tvos",
+          target_os = "watchos",
+          target_os = "visionos"))]
+pub mod macos;
+
+#[cfg(windows)]
+pub mod windows;
@@ -0,0 +1,187 @@
+//! Unix-specific extensions.
+
+use std::fs::File;
+use std::io::Result;
+use std::os::unix::net::UnixStream;
+
+use {sys, LockBackend, LockMode};
+#[cfg(feature = "locks")]
+use {registry, strict};
+
+/// The byte of data sent with a descriptor when the sender holds no lock
+/// recorded through it.
+const UNLOCKED: u8 = 0;
+/// The byte of data sent with a descriptor holding a recorded shared lock.
+#[cfg(feature = "locks")]
+const SHARED: u8 = 1;
+/// The byte of data sent with a descriptor holding a recorded exclusive lock.
+#[cfg(feature = "locks")]
+const EXCLUSIVE: u8 = 2;
+
+/// Locks the byte range `offset..offset + len` of the file with a POSIX
+/// record lock, blocking until the lock is available. A `len` of 0 extends
+/// the range to the end of the file, however large it grows.
+///
+/// Unlike the default range locks of `LockOptions`, which are open file
+/// description locks on Linux and Android, record locks belong to the process: ranges
+/// locked through different files in the same process do not conflict, and
+/// closing any file descriptor for the file releases all of the process's
+/// locks on it. Use them to interoperate with programs which lock with
+/// `fcntl(2)` or `lockf(3)`. These are the locks taken by `LockOptions` with
+/// `LockBackend::Posix`.
+///
+/// # Notes
+///
+/// This is implemented with `F_SETLKW`.
+pub fn lock_record(file: &File, offset: u64, len: u64, mode: LockMode) -> Result<()> {
+    sys::lock_range_with(file, offset, len, mode, true, LockBackend::Posix)
+}
+
+/// Locks the byte range `offset..offset + len` of the file with a POSIX
+/// record lock, failing with the error returned by `lock_contended_error` if
+/// another process holds a conflicting lock.
+///
+/// See `lock_record` for how record locks behave.
+pub fn try_lock_record(file: &File, offset: u64, len: u64, mode: LockMode) -> Result<()> {
+    sys::lock_range_with(file, offset, len, mode, false, LockBackend::Posix)
+}
+
+/// Releases the process's POSIX record locks on the byte range
+/// `offset..offset + len` of the file.
+pub fn unlock_record(file: &File, offset: u64, len: u64) -> Result<()> {
+    sys::unlock_range_with(file, offset, len, LockBackend::Posix)
+}
+
+/// Sends the descriptor of `file` to the process at the other end of the Unix
+/// socket, which receives it with `recv_fd`.
+///
+/// The receiver gets a new descriptor for the same open file description, so
+/// the description's `flock` and open file description locks, which are
+/// the locks taken by `FileExt` and `LockOptions` on Linux and Android, are
+/// shared by both processes: the sender may then close its file without
+/// releasing them, handing the lock over without a window in which another
+/// process could take it. POSIX record locks belong to the sending process
+/// and are not passed on.
+///
+/// # Notes
+///
+/// This is implemented with `sendmsg(2)` and an `SCM_RIGHTS` control message,
+/// along with one byte of data, which `recv_fd` consumes. The byte carries
+/// the mode of the lock held through `file`, if the `strict` feature or the
+/// lock registry recorded one.
+pub fn send_fd(socket: &UnixStream, file: &File) -> Result<()> {
+    sys::send_fd(socket, file, lock_tag(file))
+}
+
+#[cfg(feature = "locks")]
+fn lock_tag(file: &File) -> u8 {
+    match strict::mode(file).or_else(|| registry::mode(file)) {
+        Some(LockMode::Shared) => SHARED,
+        Some(LockMode::Exclusive) => EXCLUSIVE,
+        None => UNLOCKED,
+    }
+}
+
+#[cfg(not(feature = "locks"))]
+fn lock_tag(_file: &File) -> u8 {
+    UNLOCKED
+}
+
+/// Receives a descriptor sent with `send_fd` over the Unix socket, blocking
+/// until one arrives. The returned file is close-on-exec.
+///
+/// Fails with `ErrorKind::UnexpectedEof` if the socket is closed first, and
+/// with `ErrorKind::InvalidData` if a message arrives without a descriptor.
+///
+/// If the sender recorded a lock held through the file with the `strict`
+/// feature or the lock registry, the received file is recorded as holding it
+/// too, so that it can be unlocked through `FileExt::unlock`. A lock the
+/// sender took while neither was enabled is not recorded, and with `strict`
+/// the received file can then not be unlocked through `FileExt`.
+pub fn recv_fd(socket: &UnixStream) -> Result<File> {
+    let (file, tag) = sys::recv_fd(socket)?;
+    record_lock(&file, tag);
+    Ok(file)
+}
+
+#[cfg(feature = "locks")]
+fn record_lock(file: &File, tag: u8) {
+    let mode = match tag {
+        SHARED => LockMode::Shared,
+        EXCLUSIVE => LockMode::Exclusive,
+        _ => return,
+    };
+    strict::received(file, mode);
+    registry::set_mode(file, mode);
+}
+
+#[cfg(not(feature = "locks"))]
+fn record_lock(_file: &File, _tag: u8) {}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    #[cfg(feature = "locks")]
+    use std::io::{ErrorKind, Read};
+    #[cfg(feature = "locks")]
+    use std::os::unix::net::UnixStream;
+
+    use LockMode;
+    #[cfg(feature = "locks")]
+    use {lock_contended_error, FileExt};
+    use super::{lock_record, try_lock_record, unlock_record};
+    #[cfg(feature = "locks")]
+    use super::{recv_fd, send_fd};
+
+    /// Tests that record locks belong to the process, so files in the same
+    /// process do not conflict.
+    #[test]
+    fn record_locks() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        lock_record(&file1, 0, 10, LockMode::Exclusive).unwrap();
+        try_lock_record(&file2, 5, 10, LockMode::Exclusive).unwrap();
+        try_lock_record(&file1, 0, 0, LockMode::Shared).unwrap();
+        unlock_record(&file1, 0, 0).unwrap();
+
+        // Ranges past 4 GiB can be locked on 32-bit platforms too.
+        try_lock_record(&file1, 1 << 32, 10, LockMode::Exclusive).unwrap();
+        unlock_record(&file1, 1 << 32, 10).unwrap();
+    }
+
+    /// Tests that a passed descriptor shares the sender's lock, which stays
+    /// held after the sender closes its file.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn pass_fd() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::write(&path, "fs2").unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        FileExt::lock_exclusive(&file).unwrap();
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        send_fd(&sender, &file).unwrap();
+        let mut received = recv_fd(&receiver).unwrap();
+        // Opened before the sender's file is closed, so that it does not reuse the sender's
+        // descriptor, which `strict` still records as locked.
+        let other = fs::File::open(&path).unwrap();
+        drop(file);
+
+        assert_eq!(lock_contended_error().kind(), FileExt::try_lock_shared(&other).unwrap_err().kind());
+        let mut contents = String::new();
+        received.read_to_string(&mut contents).unwrap();
+        assert_eq!("fs2", contents);
+        FileExt::unlock(&received).unwrap();
+        FileExt::try_lock_shared(&other).unwrap();
+
+        drop(sender);
+        assert_eq!(ErrorKind::UnexpectedEof, recv_fd(&receiver).unwrap_err().kind());
+    }
+}
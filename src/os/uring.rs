@@ -0,0 +1,448 @@
+//! Allocation and sync operations submitted through Linux `io_uring`, enabled
+//! with the `io-uring` feature.
+//!
+//! Each operation is built as an `Entry`, laid out as the kernel's
+//! `struct io_uring_sqe`. Programs which already run a ring can copy entries
+//! into its submission queue with `Entry::into_raw`, so that fs2's operations
+//! are batched and completed alongside their own. Programs without a ring can
+//! submit entries through a `Ring`.
+//!
+//! An entry refers to the file by descriptor only, so the file must stay open
+//! until its completion has been reaped: if it is closed and the descriptor
+//! reused, the operation applies to whichever file then has it. Handing an
+//! entry to the kernel is therefore `unsafe`. Completions report the raw result of
+//! the operation, with none of the fallbacks of the blocking calls: an
+//! allocation on a file system without native allocation fails with
+//! `EOPNOTSUPP`, as with `AllocateMode::Fast`.
+
+extern crate libc;
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_SYNC_FILE_RANGE: u8 = 8;
+const IORING_OP_FALLOCATE: u8 = 17;
+
+const IORING_FSYNC_DATASYNC: u32 = 1;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x0800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+/// An operation to submit to an `io_uring`, laid out as `struct io_uring_sqe`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Entry {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+impl Entry {
+    fn new(opcode: u8, file: &File) -> Entry {
+        Entry {
+            opcode,
+            flags: 0,
+            ioprio: 0,
+            fd: file.as_raw_fd(),
+            off: 0,
+            addr: 0,
+            len: 0,
+            op_flags: 0,
+            user_data: 0,
+            pad: [0; 3],
+        }
+    }
+
+    /// Ensures that at least `len` bytes of disk space are allocated for the
+    /// file, and the file size is at least `len` bytes, like
+    /// `FileExt::allocate`.
+    pub fn allocate(file: &File, len: u64) -> Entry {
+        Entry { addr: len, ..Entry::new(IORING_OP_FALLOCATE, file) }
+    }
+
+    /// Deallocates the byte range `offset..offset + len` of the file, which
+    /// then reads as zeros, without changing the file's length.
+    pub fn punch_hole(file: &File, offset: u64, len: u64) -> Entry {
+        Entry {
+            off: offset,
+            addr: len,
+            len: (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+            ..Entry::new(IORING_OP_FALLOCATE, file)
+        }
+    }
+
+    /// Flushes the file's data and metadata to disk, like `File::sync_all`.
+    pub fn sync_all(file: &File) -> Entry {
+        Entry::new(IORING_OP_FSYNC, file)
+    }
+
+    /// Flushes the file's data to disk, and only the metadata needed to read
+    /// it back, like `File::sync_data`.
+    pub fn sync_data(file: &File) -> Entry {
+        Entry { op_flags: IORING_FSYNC_DATASYNC, ..Entry::new(IORING_OP_FSYNC, file) }
+    }
+
+    /// Syncs the byte range `offset..offset + len` of the file with
+    /// `sync_file_range(2)` and the given `SYNC_FILE_RANGE_*` flags. A `len`
+    /// of 0 extends the range to the end of the file.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if `len` does not fit in 32 bits,
+    /// the limit of the `io_uring` operation.
+    pub fn sync_range(file: &File, offset: u64, len: u64, flags: u32) -> Result<Entry> {
+        if len > u64::from(u32::MAX) {
+            return Err(Error::new(ErrorKind::InvalidInput, "io_uring sync_file_range is limited to 4 GiB"));
+        }
+        Ok(Entry { off: offset, len: len as u32, op_flags: flags, ..Entry::new(IORING_OP_SYNC_FILE_RANGE, file) })
+    }
+
+    /// Sets the value returned with the entry's completion, to tell
+    /// completions apart. Defaults to 0.
+    pub fn user_data(mut self, user_data: u64) -> Entry {
+        self.user_data = user_data;
+        self
+    }
+
+    /// Links the next entry submitted to this one, so that it only starts
+    /// once this one has completed successfully, such as a sync after an
+    /// allocation.
+    pub fn link(mut self) -> Entry {
+        self.flags |= IOSQE_IO_LINK;
+        self
+    }
+
+    /// Returns the entry as the bytes of a `struct io_uring_sqe`, to copy into
+    /// another ring's submission queue.
+    ///
+    /// # Safety
+    ///
+    /// The file the entry was built from must stay open until the entry's
+    /// completion has been reaped, or the entry must never be submitted.
+    pub unsafe fn into_raw(self) -> [u8; 64] {
+        mem::transmute(self)
+    }
+}
+
+/// The result of an operation, reaped from a `Ring`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Completion {
+    user_data: u64,
+    res: i32,
+}
+
+impl Completion {
+    /// Returns the value set with `Entry::user_data`.
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// Returns the result of the operation. Entries which were cancelled
+    /// because an entry linked before them failed report `ECANCELED`.
+    pub fn result(&self) -> Result<u32> {
+        if self.res < 0 {
+            Err(Error::from_raw_os_error(-self.res))
+        } else {
+            Ok(self.res as u32)
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+struct RawCompletion {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory mapped region of the ring, unmapped on drop.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: libc::c_int, len: usize, offset: libc::off_t) -> Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(Error::last_os_error())
+        } else {
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+    }
+
+    /// Returns a pointer to the value at `offset` bytes into the region.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + mem::size_of::<T>() <= self.len);
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// An `io_uring` instance, for submitting entries without a ring of one's
+/// own.
+///
+/// Entries are queued with `push`, and handed to the kernel in one system
+/// call by `submit`, which may also wait for completions. Completions are
+/// reaped with `completions`, in the order the operations complete.
+pub struct Ring {
+    fd: libc::c_int,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    params: Params,
+    /// Entries pushed but not yet submitted.
+    pending: u32,
+}
+
+impl Ring {
+    /// Creates a ring with room for at least `entries` queued entries.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the kernel does not support
+    /// `io_uring`, or it is disabled, as by the `kernel.io_uring_disabled`
+    /// sysctl or a seccomp filter.
+    pub fn new(entries: u32) -> Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+        if fd < 0 {
+            let err = Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EPERM) => {
+                    Error::new(ErrorKind::Unsupported, "io_uring is not available")
+                }
+                _ => err,
+            });
+        }
+        let fd = fd as libc::c_int;
+        match map_ring(fd, &params) {
+            Ok((sq, cq, sqes)) => Ok(Ring { fd, sq, cq, sqes, params, pending: 0 }),
+            Err(err) => {
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Queues an entry for the next `submit`.
+    ///
+    /// Fails with `ErrorKind::WouldBlock` if the submission queue is full.
+    ///
+    /// # Safety
+    ///
+    /// The file the entry was built from must stay open until the entry's
+    /// completion has been reaped with `completions`.
+    pub unsafe fn push(&mut self, entry: Entry) -> Result<()> {
+        let off = &self.params.sq_off;
+        let head = unsafe { (*self.sq.at::<AtomicU32>(off.head)).load(Ordering::Acquire) };
+        let tail = unsafe { (*self.sq.at::<AtomicU32>(off.tail)).load(Ordering::Relaxed) };
+        if tail.wrapping_sub(head) >= self.params.sq_entries {
+            return Err(Error::new(ErrorKind::WouldBlock, "the io_uring submission queue is full"));
+        }
+        let index = tail & unsafe { *self.sq.at::<u32>(off.ring_mask) };
+        unsafe {
+            *self.sqes.at::<Entry>(index * mem::size_of::<Entry>() as u32) = entry;
+            *self.sq.at::<u32>(off.array + index * 4) = index;
+            (*self.sq.at::<AtomicU32>(off.tail)).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Submits the queued entries, then waits until at least `wait`
+    /// operations have completed and not been reaped. Returns the number of
+    /// entries submitted.
+    pub fn submit(&mut self, wait: u32) -> Result<u32> {
+        let flags = if wait > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        loop {
+            let ret = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd, self.pending, wait, flags,
+                              ptr::null::<libc::sigset_t>(), 0usize)
+            };
+            if ret >= 0 {
+                let submitted = ret as u32;
+                self.pending -= submitted;
+                return Ok(submitted);
+            }
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Reaps the operations which have completed.
+    pub fn completions(&mut self) -> Vec<Completion> {
+        let off = &self.params.cq_off;
+        let mut completions = Vec::new();
+        unsafe {
+            let head = &*self.cq.at::<AtomicU32>(off.head);
+            let tail = (*self.cq.at::<AtomicU32>(off.tail)).load(Ordering::Acquire);
+            let mask = *self.cq.at::<u32>(off.ring_mask);
+            let mut next = head.load(Ordering::Relaxed);
+            while next != tail {
+                let raw = &*self.cq.at::<RawCompletion>(off.cqes + (next & mask) * mem::size_of::<RawCompletion>() as u32);
+                completions.push(Completion { user_data: raw.user_data, res: raw.res });
+                next = next.wrapping_add(1);
+            }
+            head.store(next, Ordering::Release);
+        }
+        completions
+    }
+}
+
+/// Maps the submission queue, completion queue and entries of the ring.
+fn map_ring(fd: libc::c_int, params: &Params) -> Result<(Mapping, Mapping, Mapping)> {
+    let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+    let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<RawCompletion>();
+    let sqes_len = params.sq_entries as usize * mem::size_of::<Entry>();
+    Ok((Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+        Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+        Mapping::new(fd, sqes_len, IORING_OFF_SQES)?))
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate libc;
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::mem;
+
+    #[cfg(feature = "allocate")]
+    use FileExt;
+    use super::Entry;
+    #[cfg(feature = "allocate")]
+    use super::Ring;
+
+    /// Tests the layout of entries against `struct io_uring_sqe`.
+    #[test]
+    fn entry_layout() {
+        assert_eq!(64, mem::size_of::<Entry>());
+        let file = fs::File::open("/dev/null").unwrap();
+        let raw = unsafe { Entry::punch_hole(&file, 1, 2).user_data(3).into_raw() };
+        let field = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&raw[offset..offset + 8]);
+            u64::from_ne_bytes(bytes)
+        };
+        assert_eq!(17, raw[0]);
+        assert_eq!((1, 2, 3), (field(8), field(16), field(32)));
+        assert_eq!(ErrorKind::InvalidInput, Entry::sync_range(&file, 0, 1 << 32, 0).unwrap_err().kind());
+    }
+
+    /// Tests allocating, syncing and punching a hole through a ring.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn ring() {
+        let mut ring = match Ring::new(8) {
+            Ok(ring) => ring,
+            Err(ref err) if err.kind() == ErrorKind::Unsupported => return,
+            Err(err) => panic!("{}", err),
+        };
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        unsafe { ring.push(Entry::allocate(&file, 1 << 20).user_data(1).link()) }.unwrap();
+        unsafe { ring.push(Entry::sync_data(&file).user_data(2)) }.unwrap();
+        assert_eq!(2, ring.submit(2).unwrap());
+        let mut completions = ring.completions();
+        completions.sort_by_key(|completion| completion.user_data());
+        assert_eq!(vec![1, 2], completions.iter().map(|completion| completion.user_data()).collect::<Vec<_>>());
+        match completions[0].result() {
+            // The file system may not support native allocation.
+            Err(ref err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+            result => result.unwrap(),
+        };
+        completions[1].result().unwrap();
+        assert_eq!(1 << 20, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() >= 1 << 20);
+
+        unsafe { ring.push(Entry::punch_hole(&file, 0, 1 << 20).user_data(3)) }.unwrap();
+        unsafe { ring.push(Entry::sync_range(&file, 0, 0, libc::SYNC_FILE_RANGE_WRITE).unwrap().user_data(4)) }.unwrap();
+        unsafe { ring.push(Entry::sync_all(&file).user_data(5)) }.unwrap();
+        assert_eq!(3, ring.submit(3).unwrap());
+        for completion in ring.completions() {
+            completion.result().unwrap();
+        }
+        assert_eq!(1 << 20, file.metadata().unwrap().len());
+        assert!(file.allocated_size().unwrap() < 1 << 20);
+        assert!(ring.completions().is_empty());
+    }
+}
@@ -0,0 +1,732 @@
+//! Windows-specific extensions.
+
+#[cfg(any(feature = "allocate", feature = "locks"))]
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Error, Result};
+use std::mem;
+#[cfg(feature = "allocate")]
+use std::ops::Range;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+
+use winapi::shared::minwindef::{DWORD, UINT};
+use winapi::shared::winerror::{ERROR_MORE_DATA, ERROR_SUCCESS};
+use winapi::um::handleapi::GetHandleInformation;
+use winapi::um::restartmanager::{RM_PROCESS_INFO, RmEndSession, RmGetList, RmRegisterResources,
+                                 RmStartSession};
+use winapi::um::winbase::HANDLE_FLAG_INHERIT;
+
+#[cfg(feature = "allocate")]
+use winapi::um::ioapiset::DeviceIoControl;
+
+#[cfg(feature = "locks")]
+use std::fmt;
+#[cfg(feature = "locks")]
+use std::ops::BitOr;
+#[cfg(feature = "locks")]
+use std::os::windows::io::RawHandle;
+#[cfg(feature = "locks")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "locks")]
+use winapi::shared::minwindef::{FALSE, TRUE};
+#[cfg(feature = "locks")]
+use winapi::shared::winerror::{ERROR_IO_INCOMPLETE, ERROR_IO_PENDING, WAIT_TIMEOUT};
+#[cfg(feature = "locks")]
+use winapi::um::fileapi::{LockFileEx, UnlockFile};
+#[cfg(feature = "locks")]
+use winapi::um::handleapi::CloseHandle;
+#[cfg(feature = "locks")]
+use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+#[cfg(feature = "locks")]
+use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+#[cfg(feature = "locks")]
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+#[cfg(feature = "locks")]
+use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+
+#[cfg(feature = "locks")]
+use {sys, FileLockGuard, LockMode};
+
+/// A process which has a file open, as returned by `processes_using`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ProcessUsingFile {
+    pid: u32,
+    name: String,
+}
+
+impl ProcessUsingFile {
+    /// Returns the ID of the process.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns the display name of the application, as reported by the
+    /// Restart Manager.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Returns the processes which have the file at `path` open.
+///
+/// This is intended for diagnostics when a lock attempt fails, and is
+/// best-effort: Windows does not report which process holds a lock, so every
+/// process with the file open is returned, and processes may open or close
+/// the file at any time.
+///
+/// # Notes
+///
+/// This is implemented with the
+/// [Restart Manager](https://msdn.microsoft.com/en-us/library/windows/desktop/aa373524(v=vs.85).aspx).
+pub fn processes_using<P>(path: P) -> Result<Vec<ProcessUsingFile>> where P: AsRef<Path> {
+    let mut session: DWORD = 0;
+    // CCH_RM_SESSION_KEY + 1.
+    let mut key = [0u16; 33];
+    check(unsafe { RmStartSession(&mut session, 0, key.as_mut_ptr()) })?;
+    let processes = session_processes(session, path.as_ref());
+    unsafe {
+        RmEndSession(session);
+    }
+    processes
+}
+
+fn session_processes(session: DWORD, path: &Path) -> Result<Vec<ProcessUsingFile>> {
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut files = [path.as_ptr()];
+    check(unsafe {
+        RmRegisterResources(session, 1, files.as_mut_ptr(), 0, ptr::null_mut(), 0, ptr::null_mut())
+    })?;
+
+    let mut infos: Vec<RM_PROCESS_INFO> = Vec::new();
+    loop {
+        let mut needed: UINT = 0;
+        let mut count = infos.len() as UINT;
+        let mut reasons: DWORD = 0;
+        let ret = unsafe {
+            RmGetList(session, &mut needed, &mut count, infos.as_mut_ptr(), &mut reasons)
+        };
+        if ret == ERROR_MORE_DATA {
+            // Processes may open the file between calls, so retry until the list fits.
+            infos = vec![unsafe { mem::zeroed() }; needed as usize];
+            continue;
+        }
+        check(ret)?;
+        infos.truncate(count as usize);
+        break;
+    }
+
+    Ok(infos.iter().map(|info| {
+        let name = &info.strAppName;
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        ProcessUsingFile {
+            pid: info.Process.dwProcessId,
+            name: OsString::from_wide(&name[..len]).to_string_lossy().into_owned(),
+        }
+    }).collect())
+}
+
+/// Sets whether the file's handle is inherited by child processes.
+///
+/// Handles duplicated with `FileExt::duplicate` are inheritable, while files
+/// opened by the standard library are not.
+pub fn set_inheritable(file: &File, inheritable: bool) -> Result<()> {
+    ::sys::set_inheritable(file, inheritable)
+}
+
+/// Opens a new handle to the file with the access rights, share mode, and
+/// flags given, such as `GENERIC_READ`, `FILE_SHARE_READ`, and
+/// `FILE_FLAG_SEQUENTIAL_SCAN`.
+///
+/// Unlike `FileExt::reopen`, which opens the file's current path with the
+/// `OpenOptions` given, the file is reopened through its handle, so this
+/// works even if the file has been deleted while open.
+///
+/// # Notes
+///
+/// This is implemented with
+/// [`ReOpenFile`](https://msdn.microsoft.com/en-us/library/windows/desktop/aa365497(v=vs.85).aspx).
+pub fn reopen(file: &File, access: u32, share_mode: u32, flags: u32) -> Result<File> {
+    ::sys::reopen_handle(file, access, share_mode, flags)
+}
+
+/// Returns `true` if the file's handle is inherited by child processes.
+pub fn is_inheritable(file: &File) -> Result<bool> {
+    let mut flags = 0;
+    let ret = unsafe { GetHandleInformation(file.as_raw_handle(), &mut flags) };
+    if ret == 0 { Err(Error::last_os_error()) } else { Ok(flags & HANDLE_FLAG_INHERIT != 0) }
+}
+
+/// A whole-file lock requested with `lock_overlapped`, which may not have been
+/// granted yet.
+///
+/// Waiting locks do not occupy a thread: the request is queued in the kernel,
+/// and its completion signals an event, which can be waited on with `wait`,
+/// `wait_timeout`, or together with other handles through `event`. Dropping a
+/// pending lock cancels the request, releasing the lock if it was granted.
+#[cfg(feature = "locks")]
+pub struct PendingLock<'a> {
+    file: &'a File,
+    mode: LockMode,
+    // Boxed so that its address is stable while the kernel owns it.
+    overlapped: Box<OVERLAPPED>,
+    granted: bool,
+    finished: bool,
+    requested: Instant,
+}
+
+/// Requests a whole-file lock without blocking the calling thread.
+///
+/// The file must have been opened with `FILE_FLAG_OVERLAPPED` (through
+/// `std::os::windows::fs::OpenOptionsExt::custom_flags`), otherwise
+/// `LockFileEx` completes synchronously and this blocks like
+/// `FileExt::lock_exclusive`.
+#[cfg(feature = "locks")]
+pub fn lock_overlapped(file: &File, mode: LockMode) -> Result<PendingLock<'_>> {
+    unsafe {
+        let event = CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null());
+        if event.is_null() {
+            return Err(Error::last_os_error());
+        }
+        let mut overlapped: Box<OVERLAPPED> = Box::new(mem::zeroed());
+        overlapped.hEvent = event;
+        let mut pending = PendingLock {
+            file, mode, overlapped, granted: false, finished: false, requested: Instant::now(),
+        };
+
+        let flags = if mode == LockMode::Exclusive { LOCKFILE_EXCLUSIVE_LOCK } else { 0 };
+        let ret = LockFileEx(file.as_raw_handle(), flags, 0, !0, !0, &mut *pending.overlapped);
+        if ret != 0 {
+            pending.granted = true;
+        } else {
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                pending.finished = true;
+                return Err(err);
+            }
+        }
+        Ok(pending)
+    }
+}
+
+#[cfg(feature = "locks")]
+impl<'a> PendingLock<'a> {
+    /// Returns the mode of the requested lock.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns the manual-reset event which is signaled when the request
+    /// completes, for use with `WaitForMultipleObjects` or
+    /// `RegisterWaitForSingleObject`. The event is owned by the pending lock.
+    pub fn event(&self) -> RawHandle {
+        self.overlapped.hEvent as RawHandle
+    }
+
+    /// Returns `true` if the lock has been granted, without blocking.
+    pub fn poll(&mut self) -> Result<bool> {
+        self.complete(false)
+    }
+
+    /// Waits up to `timeout` for the lock, returning `true` if it was
+    /// granted.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        if self.granted {
+            return Ok(true);
+        }
+        let millis = cmp::min(timeout.as_millis(), (INFINITE - 1) as u128) as DWORD;
+        match unsafe { WaitForSingleObject(self.overlapped.hEvent, millis) } {
+            WAIT_OBJECT_0 => self.complete(false),
+            WAIT_TIMEOUT => Ok(false),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    /// Waits until the lock is granted, returning a guard which releases it.
+    pub fn wait(mut self) -> Result<FileLockGuard<'a>> {
+        self.complete(true)?;
+        self.finished = true;
+        Ok(FileLockGuard::new(self.file, self.mode, None, self.requested))
+    }
+
+    fn complete(&mut self, block: bool) -> Result<bool> {
+        if self.granted {
+            return Ok(true);
+        }
+        let mut transferred = 0;
+        let ret = unsafe {
+            GetOverlappedResult(self.file.as_raw_handle(), &mut *self.overlapped, &mut transferred,
+                                if block { TRUE } else { FALSE })
+        };
+        if ret != 0 {
+            self.granted = true;
+            return Ok(true);
+        }
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_IO_INCOMPLETE as i32) {
+            Ok(false)
+        } else {
+            self.finished = true;
+            Err(err)
+        }
+    }
+}
+
+#[cfg(feature = "locks")]
+impl<'a> fmt::Debug for PendingLock<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PendingLock")
+         .field("file", &self.file)
+         .field("mode", &self.mode)
+         .field("granted", &self.granted)
+         .finish()
+    }
+}
+
+#[cfg(feature = "locks")]
+impl<'a> Drop for PendingLock<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            let handle = self.file.as_raw_handle();
+            if !self.finished {
+                if !self.granted {
+                    // The kernel owns the OVERLAPPED until the request completes, so wait for the
+                    // cancellation to take effect. The lock may have been granted in the meantime.
+                    CancelIoEx(handle, &mut *self.overlapped);
+                    let mut transferred = 0;
+                    self.granted = GetOverlappedResult(handle, &mut *self.overlapped,
+                                                       &mut transferred, TRUE) != 0;
+                }
+                if self.granted {
+                    UnlockFile(handle, 0, 0, !0, !0);
+                }
+            }
+            CloseHandle(self.overlapped.hEvent);
+        }
+    }
+}
+
+/// `FSCTL_GET_RETRIEVAL_POINTERS`.
+#[cfg(feature = "allocate")]
+const FSCTL_GET_RETRIEVAL_POINTERS: DWORD = 0x0009_0073;
+/// Returned when the file has no clusters, for example because it is empty
+/// or its data is resident in the MFT record.
+#[cfg(feature = "allocate")]
+const ERROR_HANDLE_EOF: DWORD = 38;
+/// How many extents are requested by each call.
+#[cfg(feature = "allocate")]
+const RETRIEVAL_BATCH: usize = 256;
+
+#[cfg(feature = "allocate")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawRetrievalExtent {
+    next_vcn: i64,
+    lcn: i64,
+}
+
+#[cfg(feature = "allocate")]
+#[repr(C)]
+struct RetrievalPointersBuffer {
+    extent_count: DWORD,
+    starting_vcn: i64,
+    extents: [RawRetrievalExtent; RETRIEVAL_BATCH],
+}
+
+/// The physical location of a run of a file's clusters, as returned by
+/// `retrieval_pointers`.
+#[cfg(feature = "allocate")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RetrievalPointer {
+    vcn: u64,
+    lcn: Option<u64>,
+    clusters: u64,
+    cluster_size: u64,
+}
+
+#[cfg(feature = "allocate")]
+impl RetrievalPointer {
+    /// Returns the virtual cluster number of the run's first cluster, i.e.
+    /// its cluster offset within the file.
+    pub fn vcn(&self) -> u64 {
+        self.vcn
+    }
+
+    /// Returns the logical cluster number of the run's first cluster on the
+    /// volume, or `None` if the run is a hole in a sparse file or is
+    /// otherwise unallocated.
+    pub fn lcn(&self) -> Option<u64> {
+        self.lcn
+    }
+
+    /// Returns the number of clusters in the run.
+    pub fn clusters(&self) -> u64 {
+        self.clusters
+    }
+
+    /// Returns the offset of the run from the start of the file, in bytes.
+    pub fn logical(&self) -> u64 {
+        self.vcn * self.cluster_size
+    }
+
+    /// Returns the offset of the run from the start of the volume, in bytes,
+    /// or `None` if the run is unallocated.
+    pub fn physical(&self) -> Option<u64> {
+        self.lcn.map(|lcn| lcn * self.cluster_size)
+    }
+
+    /// Returns the length of the run in bytes.
+    pub fn len(&self) -> u64 {
+        self.clusters * self.cluster_size
+    }
+
+    /// Returns `true` if the run is empty.
+    pub fn is_empty(&self) -> bool {
+        self.clusters == 0
+    }
+}
+
+/// Returns the runs of clusters making up the file, in order of their offset
+/// in the file, using the `FSCTL_GET_RETRIEVAL_POINTERS` control code.
+///
+/// Unallocated runs of sparse files are listed with no `lcn`. Files small
+/// enough to be stored in their MFT record have no runs. Compressed files
+/// are listed as stored, so runs may be shorter than the data they hold.
+#[cfg(feature = "allocate")]
+pub fn retrieval_pointers(file: &File) -> Result<Vec<RetrievalPointer>> {
+    let cluster_size = cmp::max(::sys::allocation_granularity(file)?, 1);
+    let mut buffer = Box::new(RetrievalPointersBuffer {
+        extent_count: 0,
+        starting_vcn: 0,
+        extents: [RawRetrievalExtent::default(); RETRIEVAL_BATCH],
+    });
+    let mut pointers = Vec::new();
+    let mut starting_vcn = 0i64;
+    loop {
+        let mut bytes_returned = 0;
+        let ret = unsafe {
+            DeviceIoControl(file.as_raw_handle(),
+                            FSCTL_GET_RETRIEVAL_POINTERS,
+                            &mut starting_vcn as *mut i64 as *mut _,
+                            mem::size_of::<i64>() as DWORD,
+                            &mut *buffer as *mut RetrievalPointersBuffer as *mut _,
+                            mem::size_of::<RetrievalPointersBuffer>() as DWORD,
+                            &mut bytes_returned,
+                            ptr::null_mut())
+        };
+        let more_data = if ret == 0 {
+            let err = Error::last_os_error();
+            match err.raw_os_error() {
+                Some(code) if code == ERROR_MORE_DATA as i32 => true,
+                Some(code) if code == ERROR_HANDLE_EOF as i32 => return Ok(pointers),
+                _ => return Err(err),
+            }
+        } else {
+            false
+        };
+
+        let count = cmp::min(buffer.extent_count as usize, RETRIEVAL_BATCH);
+        let mut vcn = buffer.starting_vcn;
+        for extent in &buffer.extents[..count] {
+            pointers.push(RetrievalPointer {
+                vcn: vcn as u64,
+                lcn: if extent.lcn < 0 { None } else { Some(extent.lcn as u64) },
+                clusters: (extent.next_vcn - vcn) as u64,
+                cluster_size,
+            });
+            vcn = extent.next_vcn;
+        }
+        if !more_data || count == 0 {
+            return Ok(pointers);
+        }
+        starting_vcn = vcn;
+    }
+}
+
+/// `FSCTL_SET_SPARSE`.
+#[cfg(feature = "allocate")]
+const FSCTL_SET_SPARSE: DWORD = 0x0009_00c4;
+
+/// `FILE_SET_SPARSE_BUFFER`.
+#[cfg(feature = "allocate")]
+#[repr(C)]
+struct FileSetSparseBuffer {
+    set_sparse: u8,
+}
+
+/// Sets or clears the file's sparse attribute.
+///
+/// `FileExt::mark_sparse` can only set the attribute. Clearing it allocates
+/// clusters for every hole in the file, and fails if the volume lacks the
+/// space.
+///
+/// # Notes
+///
+/// This is implemented with `FSCTL_SET_SPARSE`.
+#[cfg(feature = "allocate")]
+pub fn set_sparse(file: &File, sparse: bool) -> Result<()> {
+    let buffer = FileSetSparseBuffer { set_sparse: sparse as u8 };
+    let mut bytes_returned = 0;
+    let ret = unsafe {
+        DeviceIoControl(file.as_raw_handle(),
+                        FSCTL_SET_SPARSE,
+                        &buffer as *const _ as *mut _,
+                        mem::size_of::<FileSetSparseBuffer>() as DWORD,
+                        ptr::null_mut(),
+                        0,
+                        &mut bytes_returned,
+                        ptr::null_mut())
+    };
+    if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+/// Returns the byte ranges of the file which have clusters allocated, in
+/// order. Ranges are not merged across calls to the file system, so adjacent
+/// ranges may be listed separately.
+///
+/// # Notes
+///
+/// This is implemented with `FSCTL_QUERY_ALLOCATED_RANGES`.
+#[cfg(feature = "allocate")]
+pub fn allocated_ranges(file: &File) -> Result<Vec<Range<u64>>> {
+    Ok(::sys::extents(file)?
+        .into_iter()
+        .filter(|extent| !extent.is_hole)
+        .map(|extent| extent.offset..extent.offset + extent.len)
+        .collect())
+}
+
+/// Flags for `FileRegionExt::lock_region`, combined with `|`. The default
+/// flags request a shared lock, and wait for it.
+#[cfg(feature = "locks")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LockFlags(DWORD);
+
+#[cfg(feature = "locks")]
+impl LockFlags {
+    /// Requests a shared lock; the absence of `EXCLUSIVE`.
+    pub const SHARED: LockFlags = LockFlags(0);
+    /// Requests an exclusive lock (`LOCKFILE_EXCLUSIVE_LOCK`).
+    pub const EXCLUSIVE: LockFlags = LockFlags(LOCKFILE_EXCLUSIVE_LOCK);
+    /// Fails with the error returned by `lock_contended_error` rather than
+    /// waiting for a conflicting lock (`LOCKFILE_FAIL_IMMEDIATELY`).
+    pub const FAIL_IMMEDIATELY: LockFlags = LockFlags(LOCKFILE_FAIL_IMMEDIATELY);
+
+    /// Returns the flags as passed to `LockFileEx`.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns `true` if all of `other`'s flags are set.
+    pub fn contains(&self, other: LockFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+#[cfg(feature = "locks")]
+impl BitOr for LockFlags {
+    type Output = LockFlags;
+
+    fn bitor(self, other: LockFlags) -> LockFlags {
+        LockFlags(self.0 | other.0)
+    }
+}
+
+/// Byte range locking with explicit 64-bit offsets.
+///
+/// Region locks are mandatory: while one handle holds an exclusive lock on a
+/// region, reads and writes of it through other handles fail. Locks through
+/// the same handle stack, and each must be unlocked with the same offset and
+/// length it was locked with.
+#[cfg(feature = "locks")]
+pub trait FileRegionExt {
+    /// Locks the byte range `offset..offset + len` of the file. The range may
+    /// extend past the end of the file.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented with `LockFileEx`, passing the offset in the
+    /// `OVERLAPPED` structure.
+    fn lock_region(&self, offset: u64, len: u64, flags: LockFlags) -> Result<()>;
+
+    /// Unlocks the byte range `offset..offset + len` of the file, which must
+    /// exactly match a locked range.
+    ///
+    /// # Notes
+    ///
+    /// This is implemented with `UnlockFileEx`.
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()>;
+}
+
+#[cfg(feature = "locks")]
+impl FileRegionExt for File {
+    fn lock_region(&self, offset: u64, len: u64, flags: LockFlags) -> Result<()> {
+        sys::lock_file_range(self, flags.bits(), offset, len)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        sys::unlock_range(self, offset, len)
+    }
+}
+
+/// Converts a Restart Manager result code to a `Result`.
+fn check(ret: DWORD) -> Result<()> {
+    if ret == ERROR_SUCCESS { Ok(()) } else { Err(Error::from_raw_os_error(ret as i32)) }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::process;
+
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::time::Duration;
+
+    use winapi::um::winbase::FILE_FLAG_OVERLAPPED;
+
+    use super::{allocated_ranges, is_inheritable, lock_overlapped, processes_using, reopen, retrieval_pointers,
+                set_inheritable, set_sparse, FileRegionExt, LockFlags};
+    use {lock_contended_error, DuplicateOptions, FileExt, LockMode};
+
+    /// Tests that a process with a file open is listed as using it.
+    #[test]
+    fn processes_using_file() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        assert!(processes_using(&path).unwrap().is_empty());
+
+        let _file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let processes = processes_using(&path).unwrap();
+        assert!(processes.iter().any(|p| p.pid() == process::id()));
+    }
+
+    /// Tests waiting for an overlapped lock.
+    #[test]
+    fn overlapped_lock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let holder = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let waiter = fs::OpenOptions::new().read(true).write(true).custom_flags(FILE_FLAG_OVERLAPPED)
+                                           .open(&path).unwrap();
+
+        holder.lock_exclusive().unwrap();
+        let mut pending = lock_overlapped(&waiter, LockMode::Shared).unwrap();
+        assert!(!pending.poll().unwrap());
+        assert!(!pending.wait_timeout(Duration::from_millis(10)).unwrap());
+
+        // Dropping a pending lock cancels it.
+        drop(lock_overlapped(&waiter, LockMode::Exclusive).unwrap());
+
+        FileExt::unlock(&holder).unwrap();
+        let guard = pending.wait().unwrap();
+        assert_eq!(LockMode::Shared, guard.mode());
+        FileExt::try_lock_shared(&holder).unwrap();
+        holder.try_lock_exclusive().unwrap_err();
+    }
+
+    /// Tests controlling handle inheritance.
+    #[test]
+    fn inheritable() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        assert!(!is_inheritable(&file).unwrap());
+
+        assert!(is_inheritable(&file.duplicate().unwrap()).unwrap());
+        assert!(!is_inheritable(&DuplicateOptions::new().duplicate(&file).unwrap()).unwrap());
+        let inherited = DuplicateOptions::new().inheritable(true).duplicate(&file).unwrap();
+        assert!(is_inheritable(&inherited).unwrap());
+
+        set_inheritable(&inherited, false).unwrap();
+        assert!(!is_inheritable(&inherited).unwrap());
+    }
+
+    /// Tests reopening a file through its handle with explicit access rights.
+    #[test]
+    fn reopen_handle() {
+        use winapi::um::winbase::FILE_FLAG_SEQUENTIAL_SCAN;
+        use winapi::um::winnt::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ};
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                                             .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+                                             .open(&path).unwrap();
+        file.write_all(b"fs2").unwrap();
+
+        let mut reopened = reopen(&file, GENERIC_READ, FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                                  FILE_FLAG_SEQUENTIAL_SCAN).unwrap();
+        let mut contents = String::new();
+        reopened.read_to_string(&mut contents).unwrap();
+        assert_eq!("fs2", contents);
+        // The reopened handle is read-only.
+        assert!(reopened.write_all(b"fs2").is_err());
+    }
+
+    /// Tests mapping the clusters of a file.
+    #[test]
+    fn retrieval_pointers_runs() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert!(retrieval_pointers(&file).unwrap().is_empty());
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        file.sync_all().unwrap();
+        let pointers = retrieval_pointers(&file).unwrap();
+        assert!(!pointers.is_empty());
+        assert!(pointers.iter().map(|pointer| pointer.len()).sum::<u64>() >= 64 * 1024);
+        assert!(pointers.iter().all(|pointer| pointer.physical().is_some()));
+    }
+
+    /// Tests setting and clearing the sparse attribute.
+    #[test]
+    fn sparse_ranges() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = ::allocation_granularity(&path).unwrap();
+
+        set_sparse(&file, true).unwrap();
+        assert!(file.is_sparse().unwrap());
+        file.set_len(64 * blksize).unwrap();
+        assert!(allocated_ranges(&file).unwrap().is_empty());
+
+        set_sparse(&file, false).unwrap();
+        assert!(!file.is_sparse().unwrap());
+        let ranges = allocated_ranges(&file).unwrap();
+        assert_eq!(64 * blksize, ranges.iter().map(|range| range.end - range.start).sum::<u64>());
+    }
+
+    /// Tests locking regions past 4 GiB.
+    #[test]
+    fn lock_regions() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let offset = 5 << 32;
+
+        file1.lock_region(offset, 10, LockFlags::EXCLUSIVE).unwrap();
+        assert!(LockFlags::EXCLUSIVE.contains(LockFlags::SHARED));
+        let flags = LockFlags::SHARED | LockFlags::FAIL_IMMEDIATELY;
+        assert_eq!(file2.lock_region(offset + 5, 10, flags).unwrap_err().kind(),
+                   lock_contended_error().kind());
+        file2.lock_region(offset + 10, 10, flags).unwrap();
+        file2.lock_region(offset - 10, 10, flags).unwrap();
+
+        file1.unlock_region(offset, 10).unwrap();
+        file2.lock_region(offset, 10, flags).unwrap();
+        file2.unlock_region(offset, 10).unwrap();
+        file2.unlock_region(offset, 5).unwrap_err();
+    }
+}
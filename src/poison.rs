@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::Result;
+use std::ops::Deref;
+use std::sync::{LockResult, PoisonError};
+use std::thread;
+
+use positional::{read_at, write_at};
+use {FileExt, FileLockGuard, LockMode};
+
+const CLEAN: u8 = 0;
+const DIRTY: u8 = 1;
+
+/// A file lock which records whether a writer failed to release it cleanly.
+///
+/// This extends `std::sync::Mutex` poisoning across processes. Acquiring the
+/// lock exclusively sets a flag byte in the file, which is cleared when the
+/// guard is dropped normally. If the writer's process dies, or its thread
+/// panics, while holding the lock, the flag is left set, and later
+/// acquisitions (shared or exclusive) return a `PoisonError` so the caller
+/// knows the data may be half-updated. The guard can still be recovered with
+/// `PoisonError::into_inner`; the file stays poisoned until an exclusive
+/// guard is released cleanly.
+///
+/// The flag is a single byte at an offset chosen by the caller, which must not
+/// be used for anything else. Setting the flag is flushed to disk with
+/// `File::sync_data` before the exclusive guard is returned, so that the flag
+/// survives a system crash in the middle of an update; clearing it is not
+/// flushed, which at worst reports a spurious poisoning after a crash.
+#[derive(Debug)]
+pub struct PoisonFileLock {
+    file: File,
+    offset: u64,
+}
+
+impl PoisonFileLock {
+    /// Wraps a file to be locked, keeping the poison flag at `offset`. The
+    /// file must be opened for reading and writing, and is made
+    /// non-inheritable.
+    pub fn new(file: File, offset: u64) -> PoisonFileLock {
+        let _ = file.set_inheritable(false);
+        PoisonFileLock { file, offset }
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the offset of the poison flag.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Unwraps the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Returns `true` if the file is poisoned. The file may be poisoned or
+    /// cleaned by another process as soon as this returns, unless a lock is
+    /// held.
+    pub fn is_poisoned(&self) -> Result<bool> {
+        let mut flag = [CLEAN];
+        // A file too short to hold the flag has never been locked exclusively.
+        read_at(&self.file, &mut flag, self.offset)?;
+        Ok(flag[0] != CLEAN)
+    }
+
+    /// Locks the file for shared usage, blocking if the file is currently
+    /// locked exclusively.
+    pub fn lock_shared(&self) -> Result<LockResult<PoisonGuard<'_>>> {
+        let guard = self.file.lock_shared_guard()?;
+        self.acquired(guard)
+    }
+
+    /// Locks the file for exclusive usage, blocking if the file is currently
+    /// locked.
+    pub fn lock_exclusive(&self) -> Result<LockResult<PoisonGuard<'_>>> {
+        let guard = self.file.lock_exclusive_guard()?;
+        self.acquired(guard)
+    }
+
+    /// Locks the file for shared usage, or returns an error if the file is
+    /// currently locked exclusively (see `lock_contended_error`).
+    pub fn try_lock_shared(&self) -> Result<LockResult<PoisonGuard<'_>>> {
+        let guard = self.file.try_lock_shared_guard()?;
+        self.acquired(guard)
+    }
+
+    /// Locks the file for exclusive usage, or returns an error if the file is
+    /// currently locked (see `lock_contended_error`).
+    pub fn try_lock_exclusive(&self) -> Result<LockResult<PoisonGuard<'_>>> {
+        let guard = self.file.try_lock_exclusive_guard()?;
+        self.acquired(guard)
+    }
+
+    fn acquired<'a>(&'a self, guard: FileLockGuard<'a>) -> Result<LockResult<PoisonGuard<'a>>> {
+        let poisoned = self.is_poisoned()?;
+        if guard.is_exclusive() && !poisoned {
+            write_at(&self.file, &[DIRTY], self.offset)?;
+            self.file.sync_data()?;
+        }
+        let guard = PoisonGuard { lock: self, guard };
+        Ok(if poisoned { Err(PoisonError::new(guard)) } else { Ok(guard) })
+    }
+}
+
+/// A lock held on a `PoisonFileLock`, which is released when dropped.
+///
+/// Dropping an exclusive guard clears the poison flag, unless the thread is
+/// panicking.
+#[derive(Debug)]
+pub struct PoisonGuard<'a> {
+    lock: &'a PoisonFileLock,
+    guard: FileLockGuard<'a>,
+}
+
+impl<'a> PoisonGuard<'a> {
+    /// Returns the mode the file is locked in.
+    pub fn mode(&self) -> LockMode {
+        self.guard.mode()
+    }
+}
+
+impl<'a> Deref for PoisonGuard<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock.file
+    }
+}
+
+impl<'a> Drop for PoisonGuard<'a> {
+    fn drop(&mut self) {
+        if self.guard.is_exclusive() && !thread::panicking() {
+            let _ = write_at(&self.lock.file, &[CLEAN], self.lock.offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    use {LockMode, PoisonFileLock};
+
+    /// Tests that a writer which panics while holding the lock poisons it, and
+    /// that a clean exclusive release recovers it.
+    #[test]
+    fn poison_lock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let lock = Arc::new(PoisonFileLock::new(file, 0));
+
+        // Clean acquisitions are not poisoned.
+        drop(lock.lock_exclusive().unwrap().unwrap());
+        drop(lock.lock_shared().unwrap().unwrap());
+        assert!(!lock.is_poisoned().unwrap());
+
+        let writer = lock.clone();
+        thread::spawn(move || {
+            let _guard = writer.lock_exclusive().unwrap().unwrap();
+            panic!("writer failed mid-update");
+        }).join().unwrap_err();
+        assert!(lock.is_poisoned().unwrap());
+
+        // Readers see the poisoning, but can still get at the data.
+        let guard = lock.lock_shared().unwrap().unwrap_err().into_inner();
+        assert_eq!(LockMode::Shared, guard.mode());
+        drop(guard);
+        assert!(lock.is_poisoned().unwrap());
+
+        // A writer which finishes cleanly recovers the lock.
+        drop(lock.lock_exclusive().unwrap().unwrap_err().into_inner());
+        assert!(!lock.is_poisoned().unwrap());
+        lock.try_lock_shared().unwrap().unwrap();
+    }
+}
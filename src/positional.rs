@@ -0,0 +1,98 @@
+use std::fs::File;
+#[cfg(feature = "allocate")]
+use std::io::{Error, ErrorKind};
+use std::io::Result;
+
+/// Fills `buf` from the file at `offset`, failing if the file ends first.
+#[cfg(feature = "allocate")]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, offset)? {
+            0 => return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extends the file to `len` bytes by writing zeros past its end, so that
+/// the file system backs every block with disk space. Files already at least
+/// `len` bytes long are left alone.
+///
+/// Writes are aligned to 1 MiB, and flushed to disk every 64 MiB.
+#[cfg(all(feature = "allocate", unix))]
+pub fn zero_fill(file: &File, len: u64) -> Result<()> {
+    const CHUNK: u64 = 1 << 20;
+    const SYNC_INTERVAL: u64 = 64 << 20;
+    static ZEROS: [u8; CHUNK as usize] = [0; CHUNK as usize];
+
+    let mut offset = file.metadata()?.len();
+    let mut unsynced = 0;
+    while offset < len {
+        let end = ::std::cmp::min((offset / CHUNK + 1) * CHUNK, len);
+        write_at(file, &ZEROS[..(end - offset) as usize], offset)?;
+        unsynced += end - offset;
+        offset = end;
+        if unsynced >= SYNC_INTERVAL {
+            file.sync_data()?;
+            unsynced = 0;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    ::std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+pub fn write_at(file: &File, buf: &[u8], offset: u64) -> Result<()> {
+    ::std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    preserving_position(file, || ::std::os::windows::fs::FileExt::seek_read(file, buf, offset))
+}
+
+#[cfg(windows)]
+pub fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> Result<()> {
+    preserving_position(file, || {
+        while !buf.is_empty() {
+            let written = ::std::os::windows::fs::FileExt::seek_write(file, buf, offset)?;
+            buf = &buf[written..];
+            offset += written as u64;
+        }
+        Ok(())
+    })
+}
+
+/// Calls `f`, then moves the file position back to where it was before the
+/// call if `f` moved it. `seek_read` and `seek_write` leave the position after
+/// the bytes they transfer, unlike `pread` and `pwrite`.
+#[cfg(windows)]
+pub fn preserving_position<T, F>(file: &File, f: F) -> Result<T> where F: FnOnce() -> Result<T> {
+    use std::io::{Seek, SeekFrom};
+
+    let mut file = file;
+    let position = file.stream_position()?;
+    let result = f();
+    if file.stream_position()? != position {
+        file.seek(SeekFrom::Start(position))?;
+    }
+    result
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn read_at(_file: &File, _buf: &mut [u8], _offset: u64) -> Result<usize> {
+    Ok(0)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn write_at(_file: &File, _buf: &[u8], _offset: u64) -> Result<()> {
+    Ok(())
+}
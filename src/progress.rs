@@ -0,0 +1,81 @@
+use std::error;
+use std::fmt;
+use std::io::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Progress reporting and cancellation for long-running operations, such as
+/// `copy_fast_with_progress` and `AllocateOptions::allocate_with_progress`.
+///
+/// Operations work in chunks. After each chunk the progress callback is called
+/// with the number of bytes done and the total, and then the cancellation flag
+/// is checked. If the flag is set the operation stops and fails with an error
+/// wrapping `Cancelled`; the work done so far is not undone.
+///
+/// ```
+/// use std::sync::atomic::AtomicBool;
+/// use fs2::Progress;
+///
+/// let cancel = AtomicBool::new(false);
+/// let mut progress = Progress::new();
+/// progress.on_progress(|done, total| println!("{}/{} bytes", done, total))
+///         .cancel_flag(&cancel);
+/// ```
+#[derive(Default)]
+pub struct Progress<'a> {
+    callback: Option<Box<dyn FnMut(u64, u64) + 'a>>,
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> Progress<'a> {
+    /// Creates a new set of options, which neither reports progress nor can be
+    /// cancelled.
+    pub fn new() -> Progress<'a> {
+        Progress { callback: None, cancel: None }
+    }
+
+    /// Sets the callback which receives the bytes done and the total.
+    pub fn on_progress<F>(&mut self, callback: F) -> &mut Progress<'a> where F: FnMut(u64, u64) + 'a {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets the flag which cancels the operation when set.
+    pub fn cancel_flag(&mut self, cancel: &'a AtomicBool) -> &mut Progress<'a> {
+        self.cancel = Some(cancel);
+        self
+    }
+}
+
+impl<'a> fmt::Debug for Progress<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress")
+         .field("callback", &self.callback.is_some())
+         .field("cancel", &self.cancel)
+         .finish()
+    }
+}
+
+/// Reports progress, then fails if the operation has been cancelled.
+pub fn update(progress: &mut Progress, done: u64, total: u64) -> Result<()> {
+    if let Some(ref mut callback) = progress.callback {
+        callback(done, total);
+    }
+    match progress.cancel {
+        Some(cancel) if cancel.load(Ordering::Relaxed) => Err(Error::other(Cancelled)),
+        _ => Ok(()),
+    }
+}
+
+/// The error wrapped by the `io::Error` returned from a cancelled operation.
+///
+/// Check for it with `err.get_ref().map_or(false, |err| err.is::<Cancelled>())`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("operation was cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
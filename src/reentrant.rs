@@ -0,0 +1,210 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Result;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use {lock_unpoisoned, sys};
+
+/// The thread which holds a `ReentrantFileLock`, and how many times.
+#[derive(Debug, Default)]
+struct Owner {
+    thread: Option<ThreadId>,
+    depth: usize,
+}
+
+/// An exclusive file lock which the holding thread may lock again.
+///
+/// Locking a file twice through the same handle replaces the lock on Unix,
+/// but blocks forever on Windows, so library code which may be called while
+/// its caller already holds the lock deadlocks on Windows. A
+/// `ReentrantFileLock` counts how many times the holding thread has locked it:
+/// nested `lock_exclusive` calls on that thread succeed immediately, and only
+/// the outermost guard releases the file lock when it is dropped.
+///
+/// Other threads in the process block until the lock is fully released, and
+/// other processes are excluded by an exclusive lock on the file.
+pub struct ReentrantFileLock {
+    file: File,
+    owner: Mutex<Owner>,
+    released: Condvar,
+}
+
+impl ReentrantFileLock {
+    /// Wraps a file to be locked, and makes it non-inheritable.
+    pub fn new(file: File) -> ReentrantFileLock {
+        let _ = sys::set_inheritable(&file, false);
+        ReentrantFileLock { file, owner: Mutex::new(Owner::default()), released: Condvar::new() }
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns how many guards the current thread holds, or 0 if it does not
+    /// hold the lock.
+    pub fn depth(&self) -> usize {
+        let owner = self.owner();
+        if owner.thread == Some(thread::current().id()) { owner.depth } else { 0 }
+    }
+
+    /// Locks the file exclusively, blocking until it is available. If the
+    /// current thread already holds the lock, this succeeds immediately.
+    pub fn lock_exclusive(&self) -> Result<ReentrantGuard<'_>> {
+        self.lock(sys::lock_exclusive, true)
+    }
+
+    /// Locks the file exclusively, or returns an error if it is held by
+    /// another thread or process (see `lock_contended_error`). If the current
+    /// thread already holds the lock, this succeeds.
+    pub fn try_lock_exclusive(&self) -> Result<ReentrantGuard<'_>> {
+        self.lock(sys::try_lock_exclusive, false)
+    }
+
+    fn lock(&self, lock: fn(&File) -> Result<()>, block: bool) -> Result<ReentrantGuard<'_>> {
+        let current = thread::current().id();
+        let mut owner = self.owner();
+        loop {
+            match owner.thread {
+                Some(thread) if thread == current => {
+                    owner.depth += 1;
+                    return Ok(ReentrantGuard { lock: self, not_send: PhantomData });
+                }
+                Some(_) if block => {
+                    owner = self.released.wait(owner).unwrap_or_else(|err| err.into_inner());
+                }
+                Some(_) => return Err(sys::lock_error()),
+                None => break,
+            }
+        }
+        // Claim the lock before taking the file lock, so that other threads
+        // wait on the condition variable rather than the file.
+        owner.thread = Some(current);
+        drop(owner);
+        if let Err(err) = lock(&self.file) {
+            let mut owner = self.owner();
+            owner.thread = None;
+            self.released.notify_all();
+            return Err(err);
+        }
+        self.owner().depth = 1;
+        Ok(ReentrantGuard { lock: self, not_send: PhantomData })
+    }
+
+    fn owner(&self) -> MutexGuard<'_, Owner> {
+        lock_unpoisoned(&self.owner)
+    }
+}
+
+impl fmt::Debug for ReentrantFileLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReentrantFileLock")
+         .field("file", &self.file)
+         .field("owner", &*self.owner())
+         .finish()
+    }
+}
+
+/// One level of a held `ReentrantFileLock`. The file lock is released when
+/// the outermost guard is dropped.
+///
+/// Guards belong to the thread which took them, and can not be sent to
+/// another thread.
+pub struct ReentrantGuard<'a> {
+    lock: &'a ReentrantFileLock,
+    not_send: PhantomData<*const ()>,
+}
+
+impl<'a> Deref for ReentrantGuard<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock.file
+    }
+}
+
+impl<'a> fmt::Debug for ReentrantGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReentrantGuard").field("file", &self.lock.file).finish()
+    }
+}
+
+impl<'a> Drop for ReentrantGuard<'a> {
+    fn drop(&mut self) {
+        let mut owner = self.lock.owner();
+        owner.depth -= 1;
+        if owner.depth == 0 {
+            let _ = sys::unlock(&self.lock.file);
+            owner.thread = None;
+            self.lock.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    use {FileExt, ReentrantFileLock, lock_contended_error};
+
+    /// Tests that nested locks succeed, and only the outermost guard releases
+    /// the file lock.
+    #[test]
+    fn reentrant_lock() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let other = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let lock = Arc::new(ReentrantFileLock::new(file));
+
+        let outer = lock.lock_exclusive().unwrap();
+        let inner = lock.lock_exclusive().unwrap();
+        assert_eq!(2, lock.depth());
+        assert!(lock.try_lock_exclusive().is_ok());
+
+        // Other threads are excluded until the outermost guard is dropped.
+        let contender = lock.clone();
+        thread::spawn(move || {
+            assert_eq!(0, contender.depth());
+            assert_eq!(contender.try_lock_exclusive().unwrap_err().kind(),
+                       lock_contended_error().kind());
+        }).join().unwrap();
+
+        drop(inner);
+        assert_eq!(1, lock.depth());
+        assert_eq!(FileExt::try_lock_shared(&other).unwrap_err().kind(),
+                   lock_contended_error().kind());
+        drop(outer);
+        assert_eq!(0, lock.depth());
+        FileExt::try_lock_shared(&other).unwrap();
+        assert_eq!(lock.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
+    }
+
+    /// Tests that a thread blocked on the lock acquires it once released.
+    #[test]
+    fn reentrant_lock_blocking() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let lock = Arc::new(ReentrantFileLock::new(file));
+
+        let guard = lock.lock_exclusive().unwrap();
+        let waiter = lock.clone();
+        let handle = thread::spawn(move || {
+            let _guard = waiter.lock_exclusive().unwrap();
+            waiter.depth()
+        });
+        thread::sleep(::std::time::Duration::from_millis(50));
+        drop(guard);
+        assert_eq!(1, handle.join().unwrap());
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use {lock_unpoisoned, sys, AllocateOptions, LockMode};
+
+/// Plain data which can be stored in a `SharedRegion`.
+///
+/// # Safety
+///
+/// Every bit pattern of the type's size must be a valid value, and the type
+/// must have no padding and no pointers, since values are read back from
+/// bytes written by other processes. `#[repr(C)]` structs and arrays of the
+/// primitive integer and float types implementing `Pod` qualify.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! pod {
+    ($($ty:ty)*) => { $(unsafe impl Pod for $ty {})* };
+}
+
+pod!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize f32 f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A locked range of a `SharedRegion`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Held {
+    offset: u64,
+    len: u64,
+    mode: LockMode,
+}
+
+impl Held {
+    fn overlaps(&self, offset: u64, len: u64) -> bool {
+        self.offset < offset.saturating_add(len) && offset < self.offset.saturating_add(self.len)
+    }
+}
+
+/// A fixed-size region of memory shared between processes through a mapped
+/// file, with access coordinated by byte-range locks.
+///
+/// Ranges of the region are locked shared with `read` or exclusively with
+/// `write`, which exclude both the other threads of this process and other
+/// processes mapping the same file. `load`, `store`, and `update` read and
+/// write `Pod` values under the same locks, which suits caches and counters
+/// with a fixed layout.
+///
+/// ```no_run
+/// use fs2::SharedRegion;
+///
+/// // Safety: the file is only accessed through `SharedRegion`s of this size.
+/// let region = unsafe { SharedRegion::create("/dev/shm/hits", 4096) }.unwrap();
+/// let hits = region.update(0, |hits: &mut u64| { *hits += 1; *hits }).unwrap();
+/// ```
+///
+/// # Notes
+///
+/// The locks are open file description locks on Linux and Android, and
+/// `LockFileEx` locks on Windows, which belong to the `SharedRegion`. On
+/// other Unix platforms they are `fcntl(2)` record locks, which belong to the
+/// process: two `SharedRegion`s for the same file in one process do not
+/// exclude each other there, and dropping either one, or closing any other
+/// descriptor for the file, releases the locks held through the other. Use a
+/// single `SharedRegion` per file in each process, shared between threads.
+#[derive(Debug)]
+pub struct SharedRegion {
+    // Unmapped before the file is closed.
+    map: sys::Mmap,
+    file: File,
+    len: usize,
+    held: Mutex<Vec<Held>>,
+    released: Condvar,
+}
+
+// The mapping is only accessed through guards, which hold locks on the ranges they access.
+unsafe impl Send for SharedRegion {}
+unsafe impl Sync for SharedRegion {}
+
+impl SharedRegion {
+    /// Opens the region backed by the file at `path`, creating the file if
+    /// necessary and allocating at least `size` bytes for it, and maps the
+    /// first `size` bytes into memory. A new file is zero filled.
+    ///
+    /// # Safety
+    ///
+    /// The locks are advisory, so they do not stop the file from being
+    /// modified while it is mapped. The caller must ensure that for as long
+    /// as the region exists, the file is neither truncated nor written other
+    /// than through a `SharedRegion` of the same size, by this process
+    /// (including through `file`) or any other. Otherwise bytes may change
+    /// while a guard borrows them, and accesses beyond a new end of the file
+    /// may crash the process with `SIGBUS` on Unix.
+    pub unsafe fn create<P>(path: P, size: usize) -> Result<SharedRegion> where P: AsRef<Path> {
+        if size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "a shared region must not be empty"));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        AllocateOptions::new().allocate(&file, size as u64)?;
+        let map = sys::map(&file, size, true)?;
+        Ok(SharedRegion { map, file, len: size, held: Mutex::new(Vec::new()), released: Condvar::new() })
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the file backing the region.
+    ///
+    /// The file must not be resized or written through while the region
+    /// exists; see `create`.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Locks `len` bytes at `offset` for shared access, blocking while
+    /// another thread or process holds an overlapping write lock.
+    pub fn read(&self, offset: usize, len: usize) -> Result<RegionReadGuard<'_>> {
+        self.acquire(offset, len, LockMode::Shared)?;
+        Ok(RegionReadGuard { region: self, offset, len })
+    }
+
+    /// Locks `len` bytes at `offset` for exclusive access, blocking while
+    /// another thread or process holds an overlapping lock.
+    pub fn write(&self, offset: usize, len: usize) -> Result<RegionWriteGuard<'_>> {
+        self.acquire(offset, len, LockMode::Exclusive)?;
+        Ok(RegionWriteGuard { region: self, offset, len })
+    }
+
+    /// Reads the value at `offset` under a shared lock.
+    pub fn load<T>(&self, offset: usize) -> Result<T> where T: Pod {
+        let guard = self.read(offset, mem::size_of::<T>())?;
+        Ok(unsafe { ptr::read_unaligned(guard.as_ptr() as *const T) })
+    }
+
+    /// Writes `value` at `offset` under an exclusive lock.
+    pub fn store<T>(&self, offset: usize, value: T) -> Result<()> where T: Pod {
+        let mut guard = self.write(offset, mem::size_of::<T>())?;
+        unsafe { ptr::write_unaligned(guard.as_mut_ptr() as *mut T, value) };
+        Ok(())
+    }
+
+    /// Calls `f` with the value at `offset` under an exclusive lock, and
+    /// writes the modified value back.
+    pub fn update<T, R, F>(&self, offset: usize, f: F) -> Result<R> where T: Pod, F: FnOnce(&mut T) -> R {
+        let mut guard = self.write(offset, mem::size_of::<T>())?;
+        let ptr = guard.as_mut_ptr() as *mut T;
+        let mut value = unsafe { ptr::read_unaligned(ptr) };
+        let result = f(&mut value);
+        unsafe { ptr::write_unaligned(ptr, value) };
+        Ok(result)
+    }
+
+    /// Writes modified pages back to the file, and waits for them to be
+    /// written.
+    pub fn flush(&self) -> Result<()> {
+        self.map.flush()
+    }
+
+    fn acquire(&self, offset: usize, len: usize, mode: LockMode) -> Result<()> {
+        if len == 0 || offset.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(Error::new(ErrorKind::InvalidInput, "range is empty or outside the shared region"));
+        }
+        let range = Held { offset: offset as u64, len: len as u64, mode };
+        // Byte-range locks do not exclude threads sharing a file, so threads
+        // wait for conflicting ranges here first.
+        let mut held = self.held();
+        while held.iter().any(|other| {
+            other.overlaps(range.offset, range.len)
+                && (other.mode == LockMode::Exclusive || mode == LockMode::Exclusive)
+        }) {
+            held = self.released.wait(held).unwrap_or_else(|err| err.into_inner());
+        }
+        held.push(range);
+        drop(held);
+
+        if let Err(err) = sys::lock_range(&self.file, range.offset, range.len, mode, true) {
+            let mut held = self.held();
+            self.remove(&mut held, range);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn release(&self, offset: usize, len: usize, mode: LockMode) {
+        let range = Held { offset: offset as u64, len: len as u64, mode };
+        // The list stays locked while unlocking, so that no overlapping
+        // shared lock is taken and then released with this one.
+        let mut held = self.held();
+        self.remove(&mut held, range);
+        let _ = self.unlock(&held, range);
+    }
+
+    fn remove(&self, held: &mut Vec<Held>, range: Held) {
+        if let Some(index) = held.iter().position(|other| *other == range) {
+            held.swap_remove(index);
+        }
+        self.released.notify_all();
+    }
+
+    /// Unlocks `range`, except where it overlaps the ranges still `held`.
+    /// Record locks merge, so those parts would be unlocked for the other
+    /// guards too.
+    #[cfg(unix)]
+    fn unlock(&self, held: &[Held], range: Held) -> Result<()> {
+        let end = range.offset + range.len;
+        let mut covered: Vec<(u64, u64)> = held.iter()
+            .filter(|other| other.overlaps(range.offset, range.len))
+            .map(|other| (other.offset.max(range.offset), (other.offset + other.len).min(end)))
+            .collect();
+        covered.sort();
+        let mut start = range.offset;
+        for (covered_start, covered_end) in covered {
+            if covered_start > start {
+                sys::unlock_range(&self.file, start, covered_start - start)?;
+            }
+            start = start.max(covered_end);
+        }
+        if start < end {
+            sys::unlock_range(&self.file, start, end - start)?;
+        }
+        Ok(())
+    }
+
+    /// Unlocks `range`. Overlapping shared locks stack on Windows, and each
+    /// is released separately.
+    #[cfg(not(unix))]
+    fn unlock(&self, _held: &[Held], range: Held) -> Result<()> {
+        sys::unlock_range(&self.file, range.offset, range.len)
+    }
+
+    fn held(&self) -> MutexGuard<'_, Vec<Held>> {
+        lock_unpoisoned(&self.held)
+    }
+
+    fn bytes(&self, offset: usize) -> *mut u8 {
+        unsafe { self.map.as_ptr().add(offset) }
+    }
+}
+
+/// Shared access to a range of a `SharedRegion`, released when dropped.
+#[derive(Debug)]
+pub struct RegionReadGuard<'a> {
+    region: &'a SharedRegion,
+    offset: usize,
+    len: usize,
+}
+
+impl<'a> Deref for RegionReadGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.region.bytes(self.offset), self.len) }
+    }
+}
+
+impl<'a> Drop for RegionReadGuard<'a> {
+    fn drop(&mut self) {
+        self.region.release(self.offset, self.len, LockMode::Shared);
+    }
+}
+
+/// Exclusive access to a range of a `SharedRegion`, released when dropped.
+#[derive(Debug)]
+pub struct RegionWriteGuard<'a> {
+    region: &'a SharedRegion,
+    offset: usize,
+    len: usize,
+}
+
+impl<'a> Deref for RegionWriteGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.region.bytes(self.offset), self.len) }
+    }
+}
+
+impl<'a> DerefMut for RegionWriteGuard<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.region.bytes(self.offset), self.len) }
+    }
+}
+
+impl<'a> Drop for RegionWriteGuard<'a> {
+    fn drop(&mut self) {
+        self.region.release(self.offset, self.len, LockMode::Exclusive);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::thread;
+
+    use SharedRegion;
+
+    /// Tests typed access, and that two regions on the same file share memory.
+    /// The regions are only used in turn, since their locks do not exclude
+    /// each other outside Linux, Android and Windows.
+    #[test]
+    fn shared_region() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let region1 = unsafe { SharedRegion::create(&path, 64) }.unwrap();
+        let region2 = unsafe { SharedRegion::create(&path, 64) }.unwrap();
+
+        assert_eq!(0u64, region1.load::<u64>(8).unwrap());
+        region1.store(8, 5u64).unwrap();
+        assert_eq!(5u64, region2.load::<u64>(8).unwrap());
+        assert_eq!(6, region2.update(8, |value: &mut u64| { *value += 1; *value }).unwrap());
+        assert_eq!([6, 0, 0, 0], region1.load::<[u8; 4]>(8).unwrap());
+
+        region1.write(60, 4).unwrap().copy_from_slice(b"fs2!");
+        assert_eq!(b"fs2!", &region2.read(60, 4).unwrap()[..]);
+
+        assert_eq!(ErrorKind::InvalidInput, region1.load::<u64>(60).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, region1.read(0, 0).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, unsafe { SharedRegion::create(&path, 0) }.unwrap_err().kind());
+    }
+
+    /// Tests that updates from several threads are not lost, and that
+    /// overlapping shared guards release their locks independently.
+    #[test]
+    fn shared_region_threads() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let region = Arc::new(unsafe { SharedRegion::create(&path, 16) }.unwrap());
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let region = region.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    region.update(0, |count: &mut u32| *count += 1).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(400u32, region.load::<u32>(0).unwrap());
+
+        let outer = region.read(0, 16).unwrap();
+        drop(region.read(4, 4).unwrap());
+        assert_eq!(400, u32::from_le_bytes([outer[0], outer[1], outer[2], outer[3]]));
+        drop(outer);
+        region.store(0, 0u32).unwrap();
+    }
+}
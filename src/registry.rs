@@ -0,0 +1,472 @@
+//! An optional in-process registry of whole-file locks, which detects two
+//! handles in the same process locking the same file.
+//!
+//! Whole-file locks belong to the handle, so two `File`s opened on the same
+//! path in one process conflict with each other just as if they were in
+//! different processes. A thread which locks a file that another part of the
+//! program already holds through a different handle deadlocks, and the cause
+//! is hard to find. With the registry enabled (see `set_lock_registry`),
+//! locks taken through `FileExt`, its guards, `TrackedFile`, and
+//! `LockOptions` are recorded by file identity, and conflicting locks from
+//! this process are either reported or serialized. The recorded locks can be listed with
+//! `held_locks`.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use {fork, lock_unpoisoned, sys, FileId, LockMode};
+
+/// What the lock registry does when a handle would conflict with a lock held
+/// by another handle in the same process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegistryPolicy {
+    /// Locks are not recorded. This is the default.
+    Off,
+    /// Fail with a descriptive error instead of blocking. Non-blocking locks
+    /// fail with the usual contended error kind (see `lock_contended_error`),
+    /// and blocking locks with `ErrorKind::Other`.
+    Error,
+    /// Blocking locks wait until the other handle releases its lock, without
+    /// blocking in the operating system. Non-blocking locks fail as usual.
+    Serialize,
+    /// Locks are recorded, so that `held_locks` can list them, but conflicts
+    /// are left to the operating system.
+    Track,
+}
+
+const OFF: usize = 0;
+const ERROR: usize = 1;
+const SERIALIZE: usize = 2;
+const TRACK: usize = 3;
+
+static POLICY: AtomicUsize = AtomicUsize::new(OFF);
+
+/// The holders of whole-file locks, by file identity.
+static HOLDERS: Mutex<Option<HashMap<FileId, Vec<Holder>>>> = Mutex::new(None);
+
+/// Signalled when a lock is released.
+static RELEASED: Condvar = Condvar::new();
+
+/// How often a serialized lock checks whether the holder closed its file
+/// without unlocking it.
+const STALE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct Holder {
+    pub handle: u64,
+    pub mode: LockMode,
+    pub acquired: SystemTime,
+    /// The path of the file when the lock was taken, if it could be
+    /// determined.
+    pub path: Option<PathBuf>,
+    /// Whether the handle is still waiting for the lock in the operating
+    /// system. Pending holders conflict with other handles like held ones, so
+    /// that two handles can not both pass the registry and then block on each
+    /// other.
+    pub pending: bool,
+}
+
+/// A whole-file lock held by this process, as listed by `held_locks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeldLock {
+    id: FileId,
+    handle: u64,
+    mode: LockMode,
+    acquired: SystemTime,
+    path: Option<PathBuf>,
+}
+
+impl HeldLock {
+    /// Returns the identity of the locked file.
+    pub fn file_id(&self) -> FileId {
+        self.id
+    }
+
+    /// Returns the handle holding the lock: the file descriptor on Unix, or
+    /// the `HANDLE` on Windows.
+    pub fn handle(&self) -> u64 {
+        self.handle
+    }
+
+    /// Returns the mode of the lock.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns when the lock was acquired.
+    pub fn acquired(&self) -> SystemTime {
+        self.acquired
+    }
+
+    /// Returns the path of the locked file when the lock was taken, if it
+    /// could be determined (see `FileExt::path`).
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+/// Sets the policy of the in-process lock registry.
+///
+/// The registry is global, and should be configured once at startup. Locks
+/// taken while it is `Off` are not recorded.
+pub fn set_lock_registry(policy: RegistryPolicy) {
+    let policy = match policy {
+        RegistryPolicy::Off => OFF,
+        RegistryPolicy::Error => ERROR,
+        RegistryPolicy::Serialize => SERIALIZE,
+        RegistryPolicy::Track => TRACK,
+    };
+    POLICY.store(policy, Ordering::SeqCst);
+}
+
+/// Returns the policy of the in-process lock registry.
+pub fn lock_registry() -> RegistryPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        ERROR => RegistryPolicy::Error,
+        SERIALIZE => RegistryPolicy::Serialize,
+        TRACK => RegistryPolicy::Track,
+        _ => RegistryPolicy::Off,
+    }
+}
+
+/// Lists the whole-file locks this process holds through `FileExt`, its
+/// guards, `TrackedFile`, and `LockOptions` without a range, oldest first.
+///
+/// Only locks recorded by the lock registry are listed, so the registry must
+/// not be `Off` when they are taken; `RegistryPolicy::Track` records locks
+/// without changing how they behave. The file's identity and path are those
+/// recorded when the lock was taken, and the handles are not accessed, so a
+/// lock whose handle was closed without unlocking is still listed. This is
+/// meant for logging what the process holds, such as at shutdown or in a
+/// crash report.
+pub fn held_locks() -> Vec<HeldLock> {
+    let mut locks = Vec::new();
+    for (&id, file_holders) in holders().iter().flat_map(|holders| holders.iter()) {
+        for holder in file_holders.iter().filter(|holder| !holder.pending) {
+            locks.push(HeldLock {
+                id,
+                handle: holder.handle,
+                mode: holder.mode,
+                acquired: holder.acquired,
+                path: holder.path.clone(),
+            });
+        }
+    }
+    locks.sort_by_key(|lock| lock.acquired);
+    locks
+}
+
+pub fn holders() -> MutexGuard<'static, Option<HashMap<FileId, Vec<Holder>>>> {
+    lock_unpoisoned(&HOLDERS)
+}
+
+#[cfg(unix)]
+fn handle(file: &File) -> u64 {
+    use std::os::unix::io::AsRawFd;
+    file.as_raw_fd() as u64
+}
+
+#[cfg(windows)]
+fn handle(file: &File) -> u64 {
+    use std::os::windows::io::AsRawHandle;
+    file.as_raw_handle() as usize as u64
+}
+
+#[cfg(not(any(unix, windows)))]
+fn handle(_file: &File) -> u64 {
+    0
+}
+
+/// Returns whether no handle holds a lock on `file`, meaning that the
+/// registry's holders closed their files without unlocking them.
+fn is_stale(file: &File) -> bool {
+    let probe = match sys::reopen(file, OpenOptions::new().read(true)) {
+        Ok(probe) => probe,
+        Err(_) => return false,
+    };
+    sys::try_lock_exclusive(&probe).and_then(|()| sys::unlock(&probe)).is_ok()
+}
+
+fn lock(policy: usize, file: &File, mode: LockMode, block: bool, lock: fn(&File) -> Result<()>)
+        -> Result<()> {
+    if policy == OFF || policy == TRACK {
+        lock(file)?;
+        record(policy, file, mode);
+        return Ok(());
+    }
+    let id = match sys::file_id(file) {
+        Ok(id) => id,
+        Err(_) => return lock(file),
+    };
+    let handle = handle(file);
+    let mut holders = holders();
+    loop {
+        let conflict = holders.as_ref()
+                              .and_then(|holders| holders.get(&id))
+                              .and_then(|file_holders| {
+                                  file_holders.iter().find(|holder| {
+                                      holder.handle != handle &&
+                                          (holder.mode == LockMode::Exclusive || mode == LockMode::Exclusive)
+                                  }).cloned()
+                              });
+        let conflict = match conflict {
+            Some(conflict) => conflict,
+            None => break,
+        };
+        // A pending holder has not locked the file yet, so it can not be stale.
+        if !conflict.pending && is_stale(file) {
+            if let Some(ref mut holders) = *holders {
+                if let Some(file_holders) = holders.get_mut(&id) {
+                    file_holders.retain(|holder| holder.pending);
+                }
+            }
+            continue;
+        }
+        if policy == ERROR || !block {
+            let message = format!("file {:?} is already locked ({:?}) through another handle ({}) in this \
+                                   process; locking it ({:?}) would {}",
+                                  id, conflict.mode, conflict.handle, mode,
+                                  if block { "deadlock if both are on this thread" } else { "conflict" });
+            return Err(if block {
+                Error::other(message)
+            } else if policy == ERROR {
+                Error::new(sys::lock_error().kind(), message)
+            } else {
+                sys::lock_error()
+            });
+        }
+        holders = RELEASED.wait_timeout(holders, STALE_CHECK_INTERVAL)
+                          .unwrap_or_else(|err| err.into_inner())
+                          .0;
+    }
+    // Record the lock as pending before blocking without the registry, so that other handles
+    // conflict with it until it is taken or fails.
+    let file_holders = holders.get_or_insert_with(HashMap::new).entry(id).or_default();
+    let pending = !file_holders.iter().any(|holder| holder.handle == handle);
+    if pending {
+        file_holders.push(Holder { handle, mode, acquired: SystemTime::now(), path: None, pending: true });
+    }
+    drop(holders);
+
+    if let Err(err) = lock(file) {
+        if pending {
+            remove(id, handle);
+        }
+        return Err(err);
+    }
+    record(policy, file, mode);
+    Ok(())
+}
+
+/// Forgets the holder of the lock on the file identified by `id` through `handle`.
+fn remove(id: FileId, handle: u64) {
+    let mut holders = holders();
+    if let Some(ref mut holders) = *holders {
+        let now_empty = holders.get_mut(&id).map(|file_holders| {
+            file_holders.retain(|holder| holder.handle != handle);
+            file_holders.is_empty()
+        });
+        if now_empty == Some(true) {
+            holders.remove(&id);
+        }
+    }
+    RELEASED.notify_all();
+}
+
+/// Records that `file` now holds a lock in `mode`, for example after a guard
+/// is upgraded or downgraded.
+pub fn set_mode(file: &File, mode: LockMode) {
+    record(POLICY.load(Ordering::SeqCst), file, mode)
+}
+
+/// Returns the mode of the lock recorded for `file`'s handle.
+pub fn mode(file: &File) -> Option<LockMode> {
+    let id = sys::file_id(file).ok()?;
+    let handle = handle(file);
+    let holders = holders();
+    holders.as_ref()?
+           .get(&id)?
+           .iter()
+           .find(|holder| holder.handle == handle)
+           .map(|holder| holder.mode)
+}
+
+fn record(policy: usize, file: &File, mode: LockMode) {
+    // Held locks are also tracked for the fork handlers while the registry is off.
+    if policy == OFF && !fork::is_tracking() {
+        return;
+    }
+    if let Ok(id) = sys::file_id(file) {
+        let handle = handle(file);
+        let path = sys::path(file).ok();
+        let mut holders = holders();
+        let file_holders = holders.get_or_insert_with(HashMap::new).entry(id).or_default();
+        // A lock which changes mode keeps its acquisition time.
+        match file_holders.iter_mut().find(|holder| holder.handle == handle) {
+            Some(holder) if holder.pending => {
+                *holder = Holder { handle, mode, acquired: SystemTime::now(), path, pending: false };
+            }
+            Some(holder) => holder.mode = mode,
+            None => file_holders.push(Holder { handle, mode, acquired: SystemTime::now(), path, pending: false }),
+        }
+        RELEASED.notify_all();
+    }
+}
+
+pub fn lock_shared(file: &File) -> Result<()> {
+    lock(POLICY.load(Ordering::SeqCst), file, LockMode::Shared, true, sys::lock_shared)
+}
+
+pub fn lock_exclusive(file: &File) -> Result<()> {
+    lock(POLICY.load(Ordering::SeqCst), file, LockMode::Exclusive, true, sys::lock_exclusive)
+}
+
+pub fn try_lock_shared(file: &File) -> Result<()> {
+    lock(POLICY.load(Ordering::SeqCst), file, LockMode::Shared, false, sys::try_lock_shared)
+}
+
+pub fn try_lock_exclusive(file: &File) -> Result<()> {
+    lock(POLICY.load(Ordering::SeqCst), file, LockMode::Exclusive, false, sys::try_lock_exclusive)
+}
+
+pub fn unlock(file: &File) -> Result<()> {
+    unlock_with(POLICY.load(Ordering::SeqCst), file)
+}
+
+fn unlock_with(policy: usize, file: &File) -> Result<()> {
+    sys::unlock(file)?;
+    if policy == OFF && !fork::is_tracking() {
+        return Ok(());
+    }
+    if let Ok(id) = sys::file_id(file) {
+        remove(id, handle(file));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::{self, ErrorKind};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{held_locks, ERROR, SERIALIZE, TRACK, lock, unlock_with};
+    use {lock_contended_error, sys, FileExt, LockMode};
+
+    // The policy is global, so the tests pass it explicitly rather than
+    // changing it under concurrently running tests.
+
+    /// Tests that conflicting locks from two handles in this process are
+    /// reported.
+    #[test]
+    fn registry_error() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        fn exclusive(file: &fs::File, block: bool) -> io::Result<()> {
+            lock(ERROR, file, LockMode::Exclusive, block, sys::lock_exclusive)
+        }
+        fn shared(file: &fs::File) -> io::Result<()> {
+            lock(ERROR, file, LockMode::Shared, true, sys::lock_shared)
+        }
+
+        exclusive(&file1, true).unwrap();
+        let err = exclusive(&file2, true).unwrap_err();
+        assert_eq!(ErrorKind::Other, err.kind());
+        assert!(err.to_string().contains("another handle"), "{}", err);
+        assert_eq!(lock_contended_error().kind(), exclusive(&file2, false).unwrap_err().kind());
+        unlock_with(ERROR, &file1).unwrap();
+
+        // Shared locks do not conflict.
+        shared(&file1).unwrap();
+        shared(&file2).unwrap();
+        unlock_with(ERROR, &file1).unwrap();
+        unlock_with(ERROR, &file2).unwrap();
+
+        // A holder which closed its file without unlocking is forgotten.
+        exclusive(&file1, true).unwrap();
+        drop(file1);
+        exclusive(&file2, true).unwrap();
+        unlock_with(ERROR, &file2).unwrap();
+    }
+
+    /// Tests that a conflicting lock waits for the other handle in this
+    /// process to unlock.
+    #[test]
+    fn registry_serialize() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        lock(SERIALIZE, &file1, LockMode::Exclusive, true, sys::lock_exclusive).unwrap();
+        assert_eq!(lock_contended_error().kind(),
+                   lock(SERIALIZE, &file2, LockMode::Shared, false, sys::try_lock_shared).unwrap_err().kind());
+        let unlocker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            unlock_with(SERIALIZE, &file1).unwrap();
+        });
+        lock(SERIALIZE, &file2, LockMode::Exclusive, true, sys::lock_exclusive).unwrap();
+        unlocker.join().unwrap();
+        unlock_with(SERIALIZE, &file2).unwrap();
+    }
+
+    /// Tests that a handle waiting for a lock in the operating system
+    /// conflicts with other handles in this process, but is not listed.
+    #[test]
+    fn registry_pending() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        // Locked without the registry, as if by another process.
+        let outside = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let id = FileExt::file_id(&file1).unwrap();
+        sys::lock_exclusive(&outside).unwrap();
+
+        let waiter = thread::spawn(move || {
+            lock(SERIALIZE, &file1, LockMode::Exclusive, true, sys::lock_exclusive).unwrap();
+            unlock_with(SERIALIZE, &file1).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        let err = lock(ERROR, &file2, LockMode::Exclusive, true, sys::try_lock_exclusive).unwrap_err();
+        assert!(err.to_string().contains("another handle"), "{}", err);
+        assert!(held_locks().iter().all(|held| held.file_id() != id));
+
+        sys::unlock(&outside).unwrap();
+        waiter.join().unwrap();
+    }
+
+    /// Tests that tracked locks are listed with their mode and path until
+    /// they are released.
+    #[test]
+    fn registry_track() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let id = FileExt::file_id(&file).unwrap();
+        let held = || held_locks().into_iter().filter(|held| held.file_id() == id).collect::<Vec<_>>();
+
+        lock(TRACK, &file, LockMode::Shared, true, sys::lock_shared).unwrap();
+        lock(TRACK, &file, LockMode::Exclusive, true, sys::lock_exclusive).unwrap();
+        let locks = held();
+        assert_eq!(1, locks.len());
+        assert_eq!(LockMode::Exclusive, locks[0].mode());
+        if let Some(held_path) = locks[0].path() {
+            assert_eq!(fs::canonicalize(&path).unwrap(), fs::canonicalize(held_path).unwrap());
+        }
+
+        unlock_with(TRACK, &file).unwrap();
+        assert!(held().is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::Result;
+
+use {sys, LockMode};
+
+/// The byte locked exclusively by writers waiting for the lock.
+const INTENT: u64 = 0;
+/// The byte locked by holders of the lock.
+const DATA: u64 = 1;
+
+/// A shared/exclusive file lock which prefers writers.
+///
+/// Plain file locks grant shared locks whenever no exclusive lock is held, so
+/// a steady stream of readers can starve a writer indefinitely. A writer
+/// waiting for a `PoliteRwFileLock` first locks an intent marker, which new
+/// readers wait on before taking their shared lock; once the readers already
+/// holding the lock drain, the writer is granted the lock.
+///
+/// Every process using the lock must use a `PoliteRwFileLock`, since the
+/// marker and lock are byte-range locks on the file rather than whole-file
+/// locks. As with `FileExt` locks, locks are held by the file rather than by
+/// a thread. See `FileSemaphore` for the platform notes on byte-range locks.
+#[derive(Debug)]
+pub struct PoliteRwFileLock {
+    file: File,
+}
+
+impl PoliteRwFileLock {
+    /// Wraps a file to be locked, and makes it non-inheritable.
+    pub fn new(file: File) -> PoliteRwFileLock {
+        let _ = sys::set_inheritable(&file, false);
+        PoliteRwFileLock { file }
+    }
+
+    /// Returns the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Unwraps the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Locks the file for shared usage, blocking if the file is locked
+    /// exclusively or a writer is waiting for it.
+    pub fn lock_shared(&self) -> Result<()> {
+        // Wait for pending writers to pass through.
+        sys::lock_range(&self.file, INTENT, 1, LockMode::Shared, true)?;
+        sys::unlock_range(&self.file, INTENT, 1)?;
+        sys::lock_range(&self.file, DATA, 1, LockMode::Shared, true)
+    }
+
+    /// Locks the file for exclusive usage, blocking if the file is currently
+    /// locked. New shared lockers wait while this call is blocked.
+    pub fn lock_exclusive(&self) -> Result<()> {
+        sys::lock_range(&self.file, INTENT, 1, LockMode::Exclusive, true)?;
+        let ret = sys::lock_range(&self.file, DATA, 1, LockMode::Exclusive, true);
+        // The result of locking decides whether the caller holds the lock, so it must not be
+        // hidden by a failure to release the intent marker.
+        let _ = sys::unlock_range(&self.file, INTENT, 1);
+        ret
+    }
+
+    /// Locks the file for shared usage, or returns an error if the file is
+    /// locked exclusively or a writer is waiting for it (see
+    /// `lock_contended_error`).
+    pub fn try_lock_shared(&self) -> Result<()> {
+        sys::lock_range(&self.file, INTENT, 1, LockMode::Shared, false)?;
+        sys::unlock_range(&self.file, INTENT, 1)?;
+        sys::lock_range(&self.file, DATA, 1, LockMode::Shared, false)
+    }
+
+    /// Locks the file for exclusive usage, or returns an error if the file is
+    /// currently locked or another writer is waiting for it (see
+    /// `lock_contended_error`).
+    pub fn try_lock_exclusive(&self) -> Result<()> {
+        sys::lock_range(&self.file, INTENT, 1, LockMode::Exclusive, false)?;
+        let ret = sys::lock_range(&self.file, DATA, 1, LockMode::Exclusive, false);
+        let _ = sys::unlock_range(&self.file, INTENT, 1);
+        ret
+    }
+
+    /// Unlocks the file.
+    pub fn unlock(&self) -> Result<()> {
+        sys::unlock_range(&self.file, DATA, 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    use {LockMode, PoliteRwFileLock, lock_contended_error, sys};
+
+    fn open(path: &Path) -> File {
+        fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+    }
+
+    /// Tests that readers share the lock and writers exclude everyone.
+    #[cfg(any(windows, target_os = "linux", target_os = "android"))]
+    #[test]
+    fn polite_lock_modes() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let lock1 = PoliteRwFileLock::new(open(&path));
+        let lock2 = PoliteRwFileLock::new(open(&path));
+
+        lock1.lock_shared().unwrap();
+        lock2.try_lock_shared().unwrap();
+        assert_eq!(lock2.try_lock_exclusive().unwrap_err().kind(), lock_contended_error().kind());
+        lock1.unlock().unwrap();
+        lock2.unlock().unwrap();
+
+        lock1.try_lock_exclusive().unwrap();
+        assert_eq!(lock2.try_lock_shared().unwrap_err().kind(), lock_contended_error().kind());
+        lock1.unlock().unwrap();
+        lock2.lock_exclusive().unwrap();
+    }
+
+    /// Tests that new readers are refused while a writer is waiting.
+    #[cfg(any(windows, target_os = "linux", target_os = "android"))]
+    #[test]
+    fn polite_lock_waiting_writer() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let reader = PoliteRwFileLock::new(open(&path));
+        let writer = open(&path);
+        let late_reader = PoliteRwFileLock::new(open(&path));
+
+        reader.lock_shared().unwrap();
+        // Simulate a writer blocked on the lock, holding the intent marker.
+        sys::lock_range(&writer, 0, 1, LockMode::Exclusive, false).unwrap();
+
+        assert_eq!(late_reader.try_lock_shared().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        sys::unlock_range(&writer, 0, 1).unwrap();
+        late_reader.try_lock_shared().unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use error::with_path;
+use {sys, FsStats, FsStatsDelta};
+
+/// Records periodic snapshots of a file system's stats, and computes how
+/// quickly space is being consumed or freed.
+///
+/// The sampler does not poll on its own: call `sample` at whatever interval
+/// suits the application. Only the most recent `capacity` snapshots are kept,
+/// and rates are computed over the window they span.
+///
+/// The path is encoded for the platform on the first `sample`, and reused by
+/// later samples. On Windows this includes resolving the volume containing
+/// the path, so volumes mounted over it afterwards are not noticed.
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+/// use fs2::StatsSampler;
+///
+/// let mut sampler = StatsSampler::new("/var/lib", 60);
+/// loop {
+///     sampler.sample().unwrap();
+///     if let Some(until_full) = sampler.time_until_full() {
+///         println!("full in {}s", until_full.as_secs());
+///     }
+///     thread::sleep(Duration::from_secs(60));
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct StatsSampler {
+    path: PathBuf,
+    encoded: Option<sys::StatvfsPath>,
+    capacity: usize,
+    samples: VecDeque<(Instant, FsStats)>,
+}
+
+impl StatsSampler {
+    /// Creates a sampler for the file system containing `path`, which keeps up
+    /// to `capacity` snapshots. At least two snapshots are always kept.
+    pub fn new<P>(path: P, capacity: usize) -> StatsSampler where P: AsRef<Path> {
+        let capacity = capacity.max(2);
+        StatsSampler {
+            path: path.as_ref().to_path_buf(),
+            encoded: None,
+            capacity,
+            samples: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Takes a snapshot of the file system's stats now, and returns it.
+    pub fn sample(&mut self) -> Result<&FsStats> {
+        let stats = self.stats().map_err(with_path("statvfs", &self.path))?;
+        self.record(Instant::now(), stats);
+        Ok(&self.samples.back().unwrap().1)
+    }
+
+    fn stats(&mut self) -> Result<FsStats> {
+        if self.encoded.is_none() {
+            self.encoded = Some(sys::statvfs_path(&self.path)?);
+        }
+        sys::statvfs_encoded(self.encoded.as_ref().unwrap())
+    }
+
+    /// Records a snapshot taken at `at`, evicting the oldest snapshot if the
+    /// sampler is full. Snapshots must be recorded in chronological order.
+    pub fn record(&mut self, at: Instant, stats: FsStats) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, stats));
+    }
+
+    /// Returns the recorded snapshots, oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = &(Instant, FsStats)> {
+        self.samples.iter()
+    }
+
+    /// Returns the change between the oldest and newest snapshots, and the
+    /// time between them, or `None` if fewer than two have been recorded.
+    pub fn delta(&self) -> Option<(FsStatsDelta, Duration)> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+        let &(first_at, ref first) = self.samples.front()?;
+        let &(last_at, ref last) = self.samples.back()?;
+        Some((last.delta(first), last_at.duration_since(first_at)))
+    }
+
+    /// Returns the rate at which space is being consumed, in bytes per second.
+    /// The rate is negative if space is being freed.
+    ///
+    /// Returns `None` if fewer than two snapshots have been recorded, or they
+    /// were all taken at the same instant.
+    pub fn consumption_rate(&self) -> Option<f64> {
+        let (delta, elapsed) = self.delta()?;
+        let secs = elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return None;
+        }
+        Some(-delta.available_space() as f64 / secs)
+    }
+
+    /// Estimates how long until no space is available, if space continues to
+    /// be consumed at the current rate.
+    ///
+    /// Returns `None` if the rate is unknown, or space is not being consumed.
+    pub fn time_until_full(&self) -> Option<Duration> {
+        let rate = self.consumption_rate()?;
+        if rate <= 0.0 {
+            return None;
+        }
+        let available = self.samples.back()?.1.available_space();
+        Some(Duration::from_secs_f64(available as f64 / rate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::time::{Duration, Instant};
+
+    use super::StatsSampler;
+    use FsStats;
+
+    fn stats(available: u64) -> FsStats {
+        FsStats {
+            free_space: available,
+            available_space: available,
+            total_space: 1000,
+            allocation_granularity: 1,
+            filesystem_id: None,
+            kind: None,
+        }
+    }
+
+    /// Tests computing rates over the sampling window.
+    #[test]
+    fn stats_sampler_rates() {
+        let mut sampler = StatsSampler::new("/", 3);
+        let start = Instant::now();
+        assert_eq!(None, sampler.consumption_rate());
+
+        sampler.record(start, stats(900));
+        assert_eq!(None, sampler.delta());
+        sampler.record(start + Duration::from_secs(10), stats(800));
+        assert_eq!(Some(10.0), sampler.consumption_rate());
+        assert_eq!(Some(Duration::from_secs(80)), sampler.time_until_full());
+
+        let (delta, elapsed) = sampler.delta().unwrap();
+        assert_eq!(Duration::from_secs(10), elapsed);
+        assert_eq!(-100, delta.available_space());
+        assert_eq!(100, delta.used_space());
+        assert_eq!(0, delta.total_space());
+
+        // The oldest snapshot is evicted, and freed space gives a negative rate.
+        sampler.record(start + Duration::from_secs(20), stats(1000));
+        sampler.record(start + Duration::from_secs(30), stats(1000));
+        assert_eq!(3, sampler.samples().count());
+        assert_eq!(Some(-10.0), sampler.consumption_rate());
+        assert_eq!(None, sampler.time_until_full());
+    }
+
+    /// Tests sampling a real file system.
+    #[test]
+    fn stats_sampler_sample() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let mut sampler = StatsSampler::new(tempdir.path(), 2);
+        assert!(sampler.sample().unwrap().total_space() > 0);
+        assert!(sampler.encoded.is_some());
+        sampler.sample().unwrap();
+        sampler.sample().unwrap();
+        assert_eq!(2, sampler.samples().count());
+        assert!(sampler.delta().is_some());
+    }
+}
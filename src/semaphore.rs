@@ -0,0 +1,165 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use {lock_unpoisoned, sys, LockMode};
+
+/// The longest pause between attempts of a blocking `acquire`.
+const MAX_BACKOFF: Duration = Duration::from_millis(64);
+
+/// A counting semaphore shared between processes through a file.
+///
+/// Each of the semaphore's permits is an exclusive lock on one byte of the
+/// file, so at most `permits` holders may exist at once across every process
+/// which opens the semaphore with the same path and permit count.
+///
+/// # Notes
+///
+/// The permits are byte-range locks: open file description locks on Linux
+/// and Android, `fcntl(2)` record locks on other Unix platforms, and
+/// `LockFileEx` locks on Windows. Record locks belong to the process, so on
+/// Unix platforms other than Linux and Android two `FileSemaphore` instances
+/// for the same file in one process do not exclude each other, and closing
+/// any other descriptor for the file releases the process's permits. Within a
+/// single instance permits are always exclusive.
+#[derive(Debug)]
+pub struct FileSemaphore {
+    file: File,
+    held: Mutex<Vec<bool>>,
+}
+
+impl FileSemaphore {
+    /// Opens the semaphore at `path`, creating the file if necessary, with
+    /// `permits` permits. No permits are acquired.
+    pub fn new<P>(path: P, permits: usize) -> Result<FileSemaphore> where P: AsRef<Path> {
+        if permits == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "a semaphore requires at least one permit"));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        Ok(FileSemaphore { file, held: Mutex::new(vec![false; permits]) })
+    }
+
+    /// Returns the total number of permits.
+    pub fn permits(&self) -> usize {
+        self.state().len()
+    }
+
+    /// Acquires a permit, blocking until one is available. The permit is
+    /// released when it is dropped.
+    ///
+    /// Permits are polled for, with a backoff of up to 64ms between attempts.
+    pub fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            match self.try_acquire() {
+                Err(ref err) if err.kind() == sys::lock_error().kind() => (),
+                result => return result,
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    /// Acquires a permit, or returns an error if none are available (see
+    /// `lock_contended_error`). The permit is released when it is dropped.
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>> {
+        let mut held = self.state();
+        for slot in 0..held.len() {
+            if held[slot] {
+                continue;
+            }
+            match sys::lock_range(&self.file, slot as u64, 1, LockMode::Exclusive, false) {
+                Ok(()) => {
+                    held[slot] = true;
+                    return Ok(SemaphorePermit { semaphore: self, slot });
+                }
+                Err(ref err) if err.kind() == sys::lock_error().kind() => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(sys::lock_error())
+    }
+
+    fn state(&self) -> MutexGuard<'_, Vec<bool>> {
+        lock_unpoisoned(&self.held)
+    }
+}
+
+/// A permit acquired from a `FileSemaphore`, which is released when dropped.
+#[derive(Debug)]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a FileSemaphore,
+    slot: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Returns the index of the permit, which is less than the semaphore's
+    /// number of permits.
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        let mut held = self.semaphore.state();
+        let _ = sys::unlock_range(&self.semaphore.file, self.slot as u64, 1);
+        held[self.slot] = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::io::ErrorKind;
+
+    use {FileSemaphore, lock_contended_error};
+
+    /// Tests that a semaphore hands out at most its number of permits.
+    #[test]
+    fn semaphore_permits() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let semaphore = FileSemaphore::new(&path, 2).unwrap();
+        assert_eq!(2, semaphore.permits());
+
+        let permit1 = semaphore.acquire().unwrap();
+        let permit2 = semaphore.try_acquire().unwrap();
+        assert_ne!(permit1.slot(), permit2.slot());
+        assert_eq!(semaphore.try_acquire().unwrap_err().kind(), lock_contended_error().kind());
+
+        // Releasing a permit makes its slot available again.
+        let slot = permit1.slot();
+        drop(permit1);
+        assert_eq!(slot, semaphore.try_acquire().unwrap().slot());
+    }
+
+    /// Tests that permits are exclusive between semaphore instances.
+    #[cfg(any(windows, target_os = "linux", target_os = "android"))]
+    #[test]
+    fn semaphore_instances() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let semaphore1 = FileSemaphore::new(&path, 1).unwrap();
+        let semaphore2 = FileSemaphore::new(&path, 1).unwrap();
+
+        let permit = semaphore1.try_acquire().unwrap();
+        assert_eq!(semaphore2.try_acquire().unwrap_err().kind(), lock_contended_error().kind());
+        drop(permit);
+        semaphore2.try_acquire().unwrap();
+    }
+
+    /// A semaphore must have at least one permit.
+    #[test]
+    fn semaphore_no_permits() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        assert_eq!(ErrorKind::InvalidInput, FileSemaphore::new(&path, 0).unwrap_err().kind());
+    }
+}
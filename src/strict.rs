@@ -0,0 +1,265 @@
+//! Detection of lock usage whose behavior differs between platforms, enabled
+//! with the `strict` feature.
+//!
+//! Whole-file locks taken through `FileExt`, its guards, `TrackedFile`, and
+//! `LockOptions` are recorded per handle. With the feature enabled, the following fail with a
+//! descriptive error instead of doing whatever the platform does:
+//!
+//! * locking a handle which already holds a lock (Unix replaces the lock,
+//!   Windows stacks it),
+//! * unlocking a handle which does not hold a lock (Unix succeeds, Windows
+//!   fails), and
+//! * locking or unlocking a handle while a duplicate of it, made with
+//!   `FileExt::duplicate` or `DuplicateOptions`, holds a lock (Unix
+//!   duplicates share their locks, Windows duplicates do not).
+//!
+//! Handles are identified by their descriptor number and file identity.
+//! Closing a file can not be observed, so before reporting an error for a
+//! handle recorded as locked, the file is probed through a new handle to check
+//! that a lock is really held. Files should still be unlocked explicitly
+//! before they are closed while the feature is enabled. Without the feature
+//! every check compiles to nothing.
+
+#[cfg(not(feature = "strict"))]
+use std::fs::File;
+#[cfg(not(feature = "strict"))]
+use std::io::Result;
+
+#[cfg(not(feature = "strict"))]
+use LockMode;
+
+#[cfg(feature = "strict")]
+pub use self::imp::{lock, mode, received, unlock};
+#[cfg(all(feature = "strict", feature = "duplicate"))]
+pub use self::imp::duplicated;
+
+#[cfg(not(feature = "strict"))]
+pub fn lock<F>(file: &File, _mode: LockMode, lock: F) -> Result<()> where F: FnOnce(&File) -> Result<()> {
+    lock(file)
+}
+
+#[cfg(not(feature = "strict"))]
+pub fn unlock<F>(file: &File, unlock: F) -> Result<()> where F: FnOnce(&File) -> Result<()> {
+    unlock(file)
+}
+
+#[cfg(all(not(feature = "strict"), feature = "duplicate"))]
+pub fn duplicated(_original: &File, _duplicate: &File) {}
+
+#[cfg(not(feature = "strict"))]
+pub fn mode(_file: &File) -> Option<LockMode> {
+    None
+}
+
+#[cfg(not(feature = "strict"))]
+pub fn received(_file: &File, _mode: LockMode) {}
+
+#[cfg(feature = "strict")]
+mod imp {
+    use std::collections::HashMap;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Error, Result};
+    use std::sync::{Mutex, MutexGuard};
+    use std::time::SystemTime;
+
+    use {lock_unpoisoned, sys, FileId, LockMode};
+
+    /// A handle, identified by its descriptor number and the file it refers to.
+    /// The file's creation time, where available, tells apart files which
+    /// reuse the identity of a deleted file.
+    type Handle = (u64, FileId, Option<SystemTime>);
+
+    #[derive(Default)]
+    struct Registry {
+        /// The handles holding whole-file locks.
+        locked: HashMap<Handle, LockMode>,
+        /// The duplicate group of each duplicated handle.
+        groups: HashMap<Handle, usize>,
+        #[cfg(feature = "duplicate")]
+        next_group: usize,
+    }
+
+    static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+    fn registry() -> MutexGuard<'static, Option<Registry>> {
+        lock_unpoisoned(&REGISTRY)
+    }
+
+    #[cfg(unix)]
+    fn raw(file: &File) -> u64 {
+        use std::os::unix::io::AsRawFd;
+        file.as_raw_fd() as u64
+    }
+
+    #[cfg(windows)]
+    fn raw(file: &File) -> u64 {
+        use std::os::windows::io::AsRawHandle;
+        file.as_raw_handle() as usize as u64
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn raw(_file: &File) -> u64 {
+        0
+    }
+
+    /// Returns the handle of `file`, or `None` if its identity is unknown, in
+    /// which case it is not checked.
+    fn handle(file: &File) -> Option<Handle> {
+        let created = file.metadata().and_then(|metadata| metadata.created()).ok();
+        sys::file_id(file).ok().map(|id| (raw(file), id, created))
+    }
+
+    impl Registry {
+        /// Returns a duplicate of `handle` which holds a lock, if any.
+        fn locked_duplicate(&self, handle: &Handle) -> Option<LockMode> {
+            let group = self.groups.get(handle)?;
+            self.groups.iter()
+                .filter(|&(other, other_group)| other != handle && other_group == group)
+                .filter_map(|(other, _)| self.locked.get(other).cloned())
+                .next()
+        }
+    }
+
+    /// Returns whether the registry's record that `file` is locked is stale,
+    /// because no handle holds a lock on it. This happens when a locked file
+    /// is closed, and its descriptor number and file identity are reused.
+    fn is_stale(file: &File) -> bool {
+        let probe = match sys::reopen(file, OpenOptions::new().read(true)) {
+            Ok(probe) => probe,
+            Err(_) => return false,
+        };
+        sys::try_lock_exclusive(&probe).and_then(|()| sys::unlock(&probe)).is_ok()
+    }
+
+    /// Forgets the locks recorded for the file identified by `id`.
+    fn forget(id: FileId) {
+        if let Some(ref mut registry) = *registry() {
+            registry.locked.retain(|&(_, locked_id, _), _| locked_id != id);
+        }
+    }
+
+    /// Returns an error if locking `handle` would behave differently across
+    /// platforms.
+    fn check_lock(handle: &Handle, mode: LockMode) -> Result<()> {
+        let mut registry = registry();
+        let registry = registry.get_or_insert_with(Registry::default);
+        if let Some(held) = registry.locked.get(handle) {
+            return Err(Error::other(format!(
+                "strict: the file is already locked ({:?}) through this handle; unlock it before \
+                 locking it again ({:?} requested)", held, mode)));
+        }
+        if let Some(held) = registry.locked_duplicate(handle) {
+            return Err(Error::other(format!(
+                "strict: a duplicate of this handle holds a {:?} lock; duplicates share locks on \
+                 Unix but not on Windows", held)));
+        }
+        Ok(())
+    }
+
+    pub fn lock<F>(file: &File, mode: LockMode, lock: F) -> Result<()> where F: FnOnce(&File) -> Result<()> {
+        let handle = match handle(file) {
+            Some(handle) => handle,
+            None => return lock(file),
+        };
+        if let Err(err) = check_lock(&handle, mode) {
+            if !is_stale(file) {
+                return Err(err);
+            }
+            forget(handle.1);
+        }
+        // The registry is not held while blocking, so other threads may lock other files.
+        lock(file)?;
+        registry().get_or_insert_with(Registry::default).locked.insert(handle, mode);
+        Ok(())
+    }
+
+    pub fn unlock<F>(file: &File, unlock: F) -> Result<()> where F: FnOnce(&File) -> Result<()> {
+        let handle = match handle(file) {
+            Some(handle) => handle,
+            None => return unlock(file),
+        };
+        let mut registry = registry();
+        let registry = registry.get_or_insert_with(Registry::default);
+        if !registry.locked.contains_key(&handle) {
+            return Err(match registry.locked_duplicate(&handle) {
+                Some(held) => Error::other(format!(
+                    "strict: the {:?} lock is held through a duplicate of this handle, and must be \
+                     unlocked through it", held)),
+                None => Error::other("strict: the file is not locked through this handle"),
+            });
+        }
+        unlock(file)?;
+        registry.locked.remove(&handle);
+        Ok(())
+    }
+
+    /// Returns the mode of the lock held through `file` or a duplicate of it.
+    pub fn mode(file: &File) -> Option<LockMode> {
+        let handle = handle(file)?;
+        let registry = registry();
+        let registry = registry.as_ref()?;
+        registry.locked.get(&handle).cloned().or_else(|| registry.locked_duplicate(&handle))
+    }
+
+    /// Records that `file`, received from another handle, holds a lock in
+    /// `mode`.
+    pub fn received(file: &File, mode: LockMode) {
+        if let Some(handle) = handle(file) {
+            registry().get_or_insert_with(Registry::default).locked.insert(handle, mode);
+        }
+    }
+
+    /// Records that `duplicate` was duplicated from `original`.
+    #[cfg(feature = "duplicate")]
+    pub fn duplicated(original: &File, duplicate: &File) {
+        let (original, duplicate) = match (handle(original), handle(duplicate)) {
+            (Some(original), Some(duplicate)) => (original, duplicate),
+            _ => return,
+        };
+        let mut registry = registry();
+        let registry = registry.get_or_insert_with(Registry::default);
+        let group = match registry.groups.get(&original) {
+            Some(&group) => group,
+            None => {
+                registry.next_group += 1;
+                registry.next_group
+            }
+        };
+        registry.groups.insert(original, group);
+        registry.groups.insert(duplicate, group);
+    }
+}
+
+#[cfg(all(test, feature = "strict", feature = "duplicate"))]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+
+    use FileExt;
+
+    /// Tests that re-locking, unlocking an unlocked file, and locking through a
+    /// duplicate fail.
+    #[test]
+    fn strict_locking() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let duplicate = file.duplicate().unwrap();
+
+        assert!(FileExt::unlock(&file).unwrap_err().to_string().contains("not locked"));
+        file.lock_exclusive().unwrap();
+        assert!(FileExt::lock_shared(&file).unwrap_err().to_string().contains("already locked"));
+        assert!(duplicate.lock_exclusive().unwrap_err().to_string().contains("duplicate"));
+        assert!(FileExt::unlock(&duplicate).unwrap_err().to_string().contains("duplicate"));
+        FileExt::unlock(&file).unwrap();
+
+        // Guards are checked too.
+        let guard = file.lock_shared_guard().unwrap();
+        assert!(file.try_lock_exclusive_guard().is_err());
+        drop(guard);
+        file.lock_exclusive().unwrap();
+        FileExt::unlock(&file).unwrap();
+    }
+}
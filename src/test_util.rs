@@ -0,0 +1,402 @@
+//! Helpers for testing file locks across processes.
+//!
+//! Locks are only meaningful between processes, but test harnesses run tests
+//! as threads of one process. These helpers re-run the current test binary as
+//! a child process which takes a lock, holds it while the parent makes its
+//! assertions, and releases it on request. The parent and child talk over the
+//! child's standard input and output, which works the same way on Unix and
+//! Windows.
+//!
+//! A test which spawns children must call `child_main` first: in the child,
+//! it runs the requested scenario and exits instead of returning.
+//!
+//! ```no_run
+//! extern crate fs2;
+//!
+//! use fs2::test_util::{self, LockScenario};
+//! use fs2::LockMode;
+//!
+//! #[test]
+//! fn exclusive_across_processes() {
+//!     test_util::child_main();
+//!
+//!     let path = std::env::temp_dir().join("fs2-test.lock");
+//!     std::fs::write(&path, b"").unwrap();
+//!     let child = LockScenario::new(&path).mode(LockMode::Exclusive)
+//!                                         .spawn("exclusive_across_processes")
+//!                                         .unwrap();
+//!     test_util::assert_locked_elsewhere(&path, LockMode::Shared);
+//!     child.release().unwrap();
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! Available with the `test-util` feature.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use {LockMode, LockOptions};
+
+/// Set in the environment of a child to the scenario it runs.
+const SCENARIO_VAR: &str = "FS2_TEST_UTIL_SCENARIO";
+/// Set in the environment of a child to the path of the file it locks.
+const PATH_VAR: &str = "FS2_TEST_UTIL_PATH";
+/// Prefixes the lines of the protocol, to tell them apart from the test
+/// harness's own output.
+const PREFIX: &str = "fs2-test-util: ";
+
+/// A lock to take in a child process.
+///
+/// By default the child blocks until it holds an exclusive lock on the whole
+/// file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockScenario {
+    path: PathBuf,
+    mode: LockMode,
+    range: Option<Range<u64>>,
+    nonblocking: bool,
+}
+
+impl LockScenario {
+    /// Creates a scenario which locks the existing file at `path`.
+    pub fn new<P>(path: P) -> LockScenario where P: AsRef<Path> {
+        LockScenario {
+            path: path.as_ref().to_path_buf(),
+            mode: LockMode::Exclusive,
+            range: None,
+            nonblocking: false,
+        }
+    }
+
+    /// Sets the mode of the lock. Defaults to `LockMode::Exclusive`.
+    pub fn mode(&mut self, mode: LockMode) -> &mut LockScenario {
+        self.mode = mode;
+        self
+    }
+
+    /// Locks only the byte range `range`. Defaults to the whole file.
+    pub fn range(&mut self, range: Range<u64>) -> &mut LockScenario {
+        self.range = Some(range);
+        self
+    }
+
+    /// Sets whether the child fails rather than waits if the lock is held.
+    /// Defaults to `false`.
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut LockScenario {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Spawns a child which takes the lock, by re-running the current
+    /// executable with only the test `test_name` selected.
+    ///
+    /// `test_name` must be the full name of the running test as the harness
+    /// knows it, e.g. `locks::exclusive_across_processes`, and that test must
+    /// call `child_main` before anything else. Executables with a custom
+    /// harness receive `test_name` followed by libtest's `--exact`,
+    /// `--nocapture` and `--test-threads 1` arguments.
+    pub fn spawn(&self, test_name: &str) -> Result<LockChild> {
+        let mut child = Command::new(env::current_exe()?)
+            .args([test_name, "--exact", "--nocapture", "--test-threads", "1"])
+            .env(SCENARIO_VAR, self.encode())
+            .env(PATH_VAR, &self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().map(BufReader::new);
+        Ok(LockChild { child, stdin, stdout, locked: false })
+    }
+
+    fn encode(&self) -> String {
+        let mode = match self.mode {
+            LockMode::Shared => "shared",
+            LockMode::Exclusive => "exclusive",
+        };
+        let range = match self.range {
+            Some(ref range) => format!("{}..{}", range.start, range.end),
+            None => "-".to_owned(),
+        };
+        format!("{};{};{}", mode, range, self.nonblocking)
+    }
+
+    fn decode(path: PathBuf, scenario: &str) -> Option<LockScenario> {
+        let mut parts = scenario.split(';');
+        let mode = match parts.next()? {
+            "shared" => LockMode::Shared,
+            "exclusive" => LockMode::Exclusive,
+            _ => return None,
+        };
+        let range = match parts.next()? {
+            "-" => None,
+            range => {
+                let mut bounds = range.split("..");
+                let start = bounds.next()?.parse().ok()?;
+                let end = bounds.next()?.parse().ok()?;
+                Some(start..end)
+            }
+        };
+        let nonblocking = parts.next()?.parse().ok()?;
+        Some(LockScenario { path, mode, range, nonblocking })
+    }
+}
+
+/// A child process running a `LockScenario`.
+///
+/// The child is killed when dropped, unless it has been released.
+#[derive(Debug)]
+pub struct LockChild {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: Option<BufReader<ChildStdout>>,
+    locked: bool,
+}
+
+impl LockChild {
+    /// Waits until the child holds the lock.
+    ///
+    /// If the child fails to lock, the error it reported is returned with the
+    /// same kind, e.g. `ErrorKind::WouldBlock` for a nonblocking scenario
+    /// whose lock is held elsewhere.
+    pub fn wait_locked(&mut self) -> Result<()> {
+        if self.locked {
+            return Ok(());
+        }
+        let line = self.read_message()?;
+        if line == "locked" {
+            self.locked = true;
+            return Ok(());
+        }
+        let (kind, message) = match line.strip_prefix("failed ") {
+            Some(failure) => failure.split_once(' ').unwrap_or((failure, "")),
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("unexpected message from lock child: {}", line))),
+        };
+        Err(Error::new(decode_kind(kind), message.to_owned()))
+    }
+
+    /// Releases the lock, and waits for the child to exit.
+    pub fn release(mut self) -> Result<()> {
+        self.wait_locked()?;
+        if let Some(mut stdin) = self.stdin.take() {
+            stdin.write_all(b"release\n")?;
+        }
+        let line = self.read_message()?;
+        if line != "released" {
+            return Err(Error::new(ErrorKind::InvalidData, format!("unexpected message from lock child: {}", line)));
+        }
+        let status = self.child.wait()?;
+        self.locked = false;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!("lock child failed: {}", status)))
+        }
+    }
+
+    /// Returns the process ID of the child.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Reads the next message of the protocol, skipping the test harness's
+    /// output.
+    fn read_message(&mut self) -> Result<String> {
+        let stdout = match self.stdout {
+            Some(ref mut stdout) => stdout,
+            None => return Err(Error::new(ErrorKind::BrokenPipe, "lock child has no output")),
+        };
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stdout.read_line(&mut line)? == 0 {
+                let status = self.child.wait()?;
+                return Err(Error::new(ErrorKind::UnexpectedEof, format!("lock child exited early: {}", status)));
+            }
+            // The harness may not have ended its own line before the child wrote.
+            if let Some(start) = line.find(PREFIX) {
+                return Ok(line[start + PREFIX.len()..].trim_end().to_owned());
+            }
+        }
+    }
+}
+
+impl Drop for LockChild {
+    fn drop(&mut self) {
+        if self.child.try_wait().ok().and_then(|status| status).is_none() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Runs the lock scenario if this process is a child spawned by
+/// `LockScenario::spawn`, and exits; otherwise returns immediately.
+///
+/// Call this first in every test which spawns lock children.
+pub fn child_main() {
+    let scenario = match env::var(SCENARIO_VAR) {
+        Ok(scenario) => scenario,
+        Err(_) => return,
+    };
+    let path = PathBuf::from(env::var_os(PATH_VAR).unwrap_or_default());
+    let scenario = match LockScenario::decode(path, &scenario) {
+        Some(scenario) => scenario,
+        None => {
+            eprintln!("invalid lock scenario: {}", scenario);
+            process::exit(2);
+        }
+    };
+    process::exit(match run(&scenario) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("lock child failed: {}", err);
+            1
+        }
+    });
+}
+
+/// Takes the scenario's lock, reports it, and holds it until the parent asks
+/// for it to be released or goes away.
+fn run(scenario: &LockScenario) -> Result<()> {
+    let mut stdout = ::std::io::stdout();
+    let file = OpenOptions::new().read(true).write(true).open(&scenario.path)?;
+    let mut options = LockOptions::new();
+    options.mode(scenario.mode).nonblocking(scenario.nonblocking);
+    if let Some(ref range) = scenario.range {
+        options.range(range.clone());
+    }
+    let guard = match options.lock(&file) {
+        Ok(guard) => guard,
+        Err(err) => {
+            writeln!(stdout, "{}failed {:?} {}", PREFIX, err.kind(), err)?;
+            return stdout.flush();
+        }
+    };
+    writeln!(stdout, "{}locked", PREFIX)?;
+    stdout.flush()?;
+
+    let mut line = String::new();
+    ::std::io::stdin().read_line(&mut line)?;
+    drop(guard);
+    writeln!(stdout, "{}released", PREFIX)?;
+    stdout.flush()
+}
+
+/// Maps the kinds of error a lock child reports back from their `Debug` form.
+fn decode_kind(kind: &str) -> ErrorKind {
+    match kind {
+        "WouldBlock" => ErrorKind::WouldBlock,
+        "TimedOut" => ErrorKind::TimedOut,
+        "NotFound" => ErrorKind::NotFound,
+        "PermissionDenied" => ErrorKind::PermissionDenied,
+        "InvalidInput" => ErrorKind::InvalidInput,
+        "Unsupported" => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    }
+}
+
+/// Opens the file at `path` for a lock attempt from this process.
+fn open(path: &Path) -> File {
+    OpenOptions::new().read(true).write(true).open(path)
+        .unwrap_or_else(|err| panic!("failed to open {}: {}", path.display(), err))
+}
+
+/// Asserts that a `mode` lock on the whole file at `path` can not be taken
+/// without blocking, because another process holds a conflicting lock.
+pub fn assert_locked_elsewhere<P>(path: P, mode: LockMode) where P: AsRef<Path> {
+    let path = path.as_ref();
+    let file = open(path);
+    match LockOptions::new().mode(mode).nonblocking(true).lock(&file) {
+        Ok(_) => panic!("{:?} lock on {} was not excluded by another process", mode, path.display()),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+        Err(err) => panic!("{:?} lock on {} failed: {}", mode, path.display(), err),
+    };
+}
+
+/// Asserts that a `mode` lock on the whole file at `path` can be taken
+/// without blocking. The lock is released before returning.
+pub fn assert_lockable<P>(path: P, mode: LockMode) where P: AsRef<Path> {
+    let path = path.as_ref();
+    let file = open(path);
+    if let Err(err) = LockOptions::new().mode(mode).nonblocking(true).lock(&file) {
+        panic!("{:?} lock on {} failed: {}", mode, path.display(), err);
+    };
+}
+
+/// Asserts the basic guarantees of file locks across processes, using the
+/// existing file at `path`:
+///
+/// - an exclusive lock held by another process excludes shared and exclusive
+///   locks;
+/// - a shared lock held by another process admits shared locks and excludes
+///   exclusive locks;
+/// - locks are available again once the other process releases them.
+///
+/// `test_name` is the name of the running test, as for `LockScenario::spawn`.
+pub fn assert_cross_process_exclusion<P>(test_name: &str, path: P) where P: AsRef<Path> {
+    let path = path.as_ref();
+
+    let mut child = LockScenario::new(path).mode(LockMode::Exclusive).spawn(test_name).unwrap();
+    child.wait_locked().unwrap();
+    assert_locked_elsewhere(path, LockMode::Shared);
+    assert_locked_elsewhere(path, LockMode::Exclusive);
+    child.release().unwrap();
+    assert_lockable(path, LockMode::Exclusive);
+
+    let mut child = LockScenario::new(path).mode(LockMode::Shared).spawn(test_name).unwrap();
+    child.wait_locked().unwrap();
+    assert_lockable(path, LockMode::Shared);
+    assert_locked_elsewhere(path, LockMode::Exclusive);
+    child.release().unwrap();
+    assert_lockable(path, LockMode::Exclusive);
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::ErrorKind;
+
+    use super::{assert_cross_process_exclusion, child_main, LockScenario};
+    use {LockMode, LockOptions};
+
+    /// Tests the exclusion assertions against real child processes.
+    #[test]
+    fn cross_process_exclusion() {
+        child_main();
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::write(&path, b"").unwrap();
+
+        assert_cross_process_exclusion("test_util::test::cross_process_exclusion", &path);
+    }
+
+    /// Tests that a nonblocking child reports contention with its error kind.
+    #[test]
+    fn child_contention() {
+        child_main();
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::write(&path, b"").unwrap();
+
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let guard = LockOptions::new().mode(LockMode::Exclusive).lock(&file).unwrap();
+        let mut child = LockScenario::new(&path).mode(LockMode::Shared)
+                                                .nonblocking(true)
+                                                .spawn("test_util::test::child_contention")
+                                                .unwrap();
+        assert_eq!(ErrorKind::WouldBlock, child.wait_locked().unwrap_err().kind());
+        drop(guard);
+
+        let mut child = LockScenario::new(&path).range(0..1).spawn("test_util::test::child_contention").unwrap();
+        child.wait_locked().unwrap();
+        child.release().unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::{Error, Result};
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard};
+
+use {lock_unpoisoned, registry, strict, LockMode};
+
+/// A file which records whether it holds a lock.
+///
+/// The platform lock APIs differ when a handle which already holds a lock is
+/// locked again: on Unix the existing lock is silently replaced, while on
+/// Windows the locks stack and must each be unlocked. `TrackedFile` gives
+/// consistent semantics by tracking the lock held through it: locking while a
+/// lock is already held fails with an error, and unlocking while no lock is
+/// held is a no-op which does not make a system call.
+///
+/// Only locks taken through the `TrackedFile` are tracked; locking the
+/// underlying file directly, or a duplicate of it, bypasses the tracking.
+/// Locks are taken the same way as through `FileExt`, so they are recorded by
+/// the lock registry and checked by the `strict` feature.
+///
+/// Code which must behave the same on every platform can opt in to these
+/// portable semantics by locking through a `TrackedFile` instead of
+/// `FileExt`. It is available with only the `locks` feature enabled.
+#[doc(alias = "portable")]
+#[derive(Debug)]
+pub struct TrackedFile {
+    file: File,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Unlocked,
+    /// A thread is waiting for a lock, without holding the mutex.
+    Locking(LockMode),
+    Held(LockMode),
+}
+
+impl TrackedFile {
+    /// Wraps a file which does not currently hold a lock.
+    pub fn new(file: File) -> TrackedFile {
+        TrackedFile { file, state: Mutex::new(State::Unlocked) }
+    }
+
+    /// Returns the mode of the lock currently held through this file, if any.
+    pub fn held(&self) -> Option<LockMode> {
+        match *self.state() {
+            State::Held(mode) => Some(mode),
+            State::Unlocked | State::Locking(_) => None,
+        }
+    }
+
+    /// Locks the file for shared usage, blocking if the file is currently
+    /// locked exclusively.
+    ///
+    /// While one thread is blocked, locking through this file from another
+    /// thread fails as if the lock were held.
+    pub fn lock_shared(&self) -> Result<()> {
+        self.lock(LockMode::Shared, registry::lock_shared)
+    }
+
+    /// Locks the file for exclusive usage, blocking if the file is currently
+    /// locked.
+    ///
+    /// While one thread is blocked, locking through this file from another
+    /// thread fails as if the lock were held.
+    pub fn lock_exclusive(&self) -> Result<()> {
+        self.lock(LockMode::Exclusive, registry::lock_exclusive)
+    }
+
+    /// Locks the file for shared usage, or returns an error if the file is
+    /// currently locked (see `lock_contended_error`).
+    pub fn try_lock_shared(&self) -> Result<()> {
+        self.lock(LockMode::Shared, registry::try_lock_shared)
+    }
+
+    /// Locks the file for exclusive usage, or returns an error if the file is
+    /// currently locked (see `lock_contended_error`).
+    pub fn try_lock_exclusive(&self) -> Result<()> {
+        self.lock(LockMode::Exclusive, registry::try_lock_exclusive)
+    }
+
+    /// Unlocks the file. Does nothing if no lock is held.
+    pub fn unlock(&self) -> Result<()> {
+        let mut state = self.state();
+        if let State::Held(_) = *state {
+            strict::unlock(&self.file, registry::unlock)?;
+            *state = State::Unlocked;
+        }
+        Ok(())
+    }
+
+    /// Unwraps the file. Any lock held remains held by the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn lock(&self, mode: LockMode, lock: fn(&File) -> Result<()>) -> Result<()> {
+        {
+            let mut state = self.state();
+            match *state {
+                State::Unlocked => *state = State::Locking(mode),
+                State::Locking(held) | State::Held(held) => {
+                    return Err(Error::other(format!("the file is already locked ({:?}) through this handle",
+                                                    held)));
+                }
+            }
+        }
+        // The mutex is not held while blocking, so that `held` and `unlock`
+        // do not wait for the lock.
+        let result = strict::lock(&self.file, mode, lock);
+        *self.state() = if result.is_ok() { State::Held(mode) } else { State::Unlocked };
+        result
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock_unpoisoned(&self.state)
+    }
+}
+
+impl Deref for TrackedFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl From<File> for TrackedFile {
+    fn from(file: File) -> TrackedFile {
+        TrackedFile::new(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    extern crate tempdir;
+
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::thread;
+
+    use test::in_own_process;
+    use {held_locks, set_lock_registry, FileExt, LockMode, RegistryPolicy, TrackedFile, lock_contended_error};
+
+    /// Tests that locking twice through a tracked file fails, and unlocking an
+    /// unlocked tracked file succeeds.
+    #[test]
+    fn tracked_lock_state() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = TrackedFile::new(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap());
+        let other = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert_eq!(None, file.held());
+        file.unlock().unwrap();
+
+        file.lock_shared().unwrap();
+        assert_eq!(Some(LockMode::Shared), file.held());
+        assert!(file.try_lock_exclusive().is_err());
+        assert!(file.lock_shared().is_err());
+        assert_eq!(Some(LockMode::Shared), file.held());
+
+        file.unlock().unwrap();
+        file.unlock().unwrap();
+        assert_eq!(None, file.held());
+
+        file.try_lock_exclusive().unwrap();
+        assert_eq!(Some(LockMode::Exclusive), file.held());
+        assert_eq!(FileExt::try_lock_shared(&other).unwrap_err().kind(),
+                   lock_contended_error().kind());
+        file.unlock().unwrap();
+        FileExt::try_lock_shared(&other).unwrap();
+    }
+
+    /// Tests the semantics which differ between platforms for plain files: a
+    /// second lock fails without replacing the first (Unix would downgrade
+    /// it), and a single unlock releases it (Windows would have stacked the
+    /// locks, needing two).
+    #[test]
+    fn tracked_portable() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = TrackedFile::new(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap());
+        let other = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        file.lock_exclusive().unwrap();
+        assert_eq!(ErrorKind::Other, file.lock_shared().unwrap_err().kind());
+        assert_eq!(ErrorKind::Other, file.try_lock_exclusive().unwrap_err().kind());
+        assert_eq!(FileExt::try_lock_shared(&other).unwrap_err().kind(), lock_contended_error().kind());
+
+        file.unlock().unwrap();
+        other.try_lock_exclusive().unwrap();
+    }
+
+    /// Tests that a contended lock attempt leaves the tracked state unchanged.
+    #[test]
+    fn tracked_contended() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = TrackedFile::from(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap());
+        let other = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        other.lock_exclusive().unwrap();
+        assert_eq!(file.try_lock_shared().unwrap_err().kind(), lock_contended_error().kind());
+        assert_eq!(None, file.held());
+    }
+
+    /// Tests that a thread blocked locking a tracked file does not block other
+    /// threads using it, which see the lock as taken.
+    #[test]
+    fn tracked_blocking() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = Arc::new(TrackedFile::new(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap()));
+        let other = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        other.lock_exclusive().unwrap();
+        let locker = {
+            let file = file.clone();
+            thread::spawn(move || file.lock_exclusive())
+        };
+
+        // Until the thread is blocked the attempt is contended, after that it
+        // fails because a lock is being taken through the file.
+        while file.try_lock_shared().unwrap_err().kind() != ErrorKind::Other {
+            thread::yield_now();
+        }
+        assert_eq!(None, file.held());
+        file.unlock().unwrap();
+
+        FileExt::unlock(&other).unwrap();
+        locker.join().unwrap().unwrap();
+        assert_eq!(Some(LockMode::Exclusive), file.held());
+    }
+
+    /// Tests that locks taken through a tracked file are recorded by the lock
+    /// registry.
+    #[test]
+    fn tracked_registry() {
+        if !in_own_process("tracked::test::tracked_registry") {
+            return;
+        }
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = TrackedFile::new(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap());
+        let id = file.file_id().unwrap();
+
+        set_lock_registry(RegistryPolicy::Track);
+        file.lock_shared().unwrap();
+        let locks = held_locks();
+        assert_eq!(1, locks.len());
+        assert_eq!(id, locks[0].file_id());
+        assert_eq!(LockMode::Shared, locks[0].mode());
+
+        file.unlock().unwrap();
+        assert!(held_locks().is_empty());
+    }
+}
@@ -1,16 +1,123 @@
 extern crate libc;
 
-use std::ffi::CString;
-use std::fs::File;
+#[cfg(feature = "utilities")]
+use std::cmp;
+use std::convert::TryFrom;
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
+use std::ffi::{CStr, CString};
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
+use std::fs;
+use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
+#[cfg(feature = "locks")]
+use std::io::{Seek, SeekFrom};
 use std::mem;
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+#[cfg(feature = "utilities")]
+pub use std::os::unix::io::RawFd as RawSocket;
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
 use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "utilities")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use FsStats;
+use {Capabilities, LockBackend, FileId, LockMode};
+#[cfg(any(feature = "allocate", feature = "locks", feature = "statvfs"))]
+use FsKind;
+#[cfg(feature = "allocate")]
+use {positional, AllocateMode, AllocateOptions, Extent};
+#[cfg(any(feature = "allocate", feature = "statvfs"))]
+use {fs_kind, Bytes};
+#[cfg(feature = "statvfs")]
+use {FsStats, FsStatsExt};
+#[cfg(feature = "locks")]
+use {LockHolder, UpgradeError};
 
+#[cfg(all(feature = "locks",
+          not(any(target_os = "solaris", target_os = "illumos", target_os = "nto",
+                  target_os = "aix"))))]
+use self::libc::{LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN};
+
+// Solaris, QNX Neutrino and AIX have no usable flock(2), and libc does not define its
+// operations on every such target. These select the fcntl(2) record lock emulated by `flock` below.
+#[cfg(all(feature = "locks",
+          any(target_os = "solaris", target_os = "illumos", target_os = "nto", target_os = "aix")))]
+const LOCK_SH: libc::c_int = 1;
+#[cfg(all(feature = "locks",
+          any(target_os = "solaris", target_os = "illumos", target_os = "nto", target_os = "aix")))]
+const LOCK_EX: libc::c_int = 2;
+#[cfg(all(feature = "locks",
+          any(target_os = "solaris", target_os = "illumos", target_os = "nto", target_os = "aix")))]
+const LOCK_NB: libc::c_int = 4;
+#[cfg(all(feature = "locks",
+          any(target_os = "solaris", target_os = "illumos", target_os = "nto", target_os = "aix")))]
+const LOCK_UN: libc::c_int = 8;
+
+/// Large-file variants of the calls which take or return file offsets and sizes. On 32-bit Linux
+/// and Android `off_t` and the `statvfs` counters may be 32 bits wide, so the 64-bit variants are
+/// needed to handle files and filesystems larger than 4 GiB.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod lfs {
+    pub use super::libc::{flock64 as flock, off64_t as off_t};
+    #[cfg(feature = "allocate")]
+    pub use super::libc::{fallocate64 as fallocate, lseek64 as lseek, posix_fallocate64 as posix_fallocate};
+    #[cfg(any(feature = "allocate", feature = "locks", feature = "statvfs"))]
+    pub use super::libc::{fstatfs64 as fstatfs, statfs64 as statfs};
+    #[cfg(feature = "allocate")]
+    pub use super::libc::fstatvfs64 as fstatvfs;
+    #[cfg(any(feature = "allocate", feature = "statvfs"))]
+    pub use super::libc::statvfs64 as statvfs;
+    #[cfg(feature = "utilities")]
+    pub use super::libc::sendfile64 as sendfile;
+
+    // libc does not declare F_SETLK64 and F_SETLKW64, which take a struct flock64. On 64-bit
+    // targets they are the same as F_SETLK and F_SETLKW.
+    #[cfg(any(target_pointer_width = "64", target_arch = "x86_64"))]
+    pub use super::libc::{F_SETLK, F_SETLKW};
+    #[cfg(all(target_pointer_width = "32", not(target_arch = "x86_64"),
+              any(target_arch = "mips", target_arch = "mips32r6")))]
+    pub const F_SETLK: super::libc::c_int = 34;
+    #[cfg(all(target_pointer_width = "32", not(target_arch = "x86_64"),
+              any(target_arch = "mips", target_arch = "mips32r6")))]
+    pub const F_SETLKW: super::libc::c_int = 35;
+    #[cfg(all(target_pointer_width = "32", not(target_arch = "x86_64"),
+              not(any(target_arch = "mips", target_arch = "mips32r6"))))]
+    pub const F_SETLK: super::libc::c_int = 13;
+    #[cfg(all(target_pointer_width = "32", not(target_arch = "x86_64"),
+              not(any(target_arch = "mips", target_arch = "mips32r6"))))]
+    pub const F_SETLKW: super::libc::c_int = 14;
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod lfs {
+    pub use super::libc::{flock, off_t, F_SETLK, F_SETLKW};
+    #[cfg(feature = "allocate")]
+    pub use super::libc::lseek;
+    #[cfg(any(feature = "allocate", feature = "statvfs"))]
+    pub use super::libc::{fstatvfs, statvfs};
+
+    #[cfg(all(feature = "allocate",
+              any(target_os = "freebsd",
+                  target_os = "emscripten",
+                  target_os = "fuchsia",
+                  target_os = "haiku",
+                  target_os = "nto",
+                  target_os = "aix")))]
+    pub use super::libc::posix_fallocate;
+}
+
+/// Converts a file offset or length to `off_t`, failing rather than wrapping if it is too large.
+fn to_off_t(value: u64) -> Result<lfs::off_t> {
+    lfs::off_t::try_from(value).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, "file offset or length is too large for this platform")
+    })
+}
+
+#[cfg(feature = "duplicate")]
 pub fn duplicate(file: &File) -> Result<File> {
     unsafe {
         let fd = libc::dup(file.as_raw_fd());
@@ -23,60 +130,343 @@ pub fn duplicate(file: &File) -> Result<File> {
     }
 }
 
+#[cfg(feature = "duplicate")]
+pub fn duplicate_with(file: &File, inheritable: bool) -> Result<File> {
+    if inheritable {
+        return duplicate(file);
+    }
+    unsafe {
+        let fd = libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0);
+        if fd < 0 { Err(Error::last_os_error()) } else { Ok(File::from_raw_fd(fd)) }
+    }
+}
+
+pub fn set_inheritable(file: &File, inheritable: bool) -> Result<()> {
+    unsafe {
+        let fd = file.as_raw_fd();
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return Err(Error::last_os_error());
+        }
+        let new_flags = if inheritable { flags & !libc::FD_CLOEXEC } else { flags | libc::FD_CLOEXEC };
+        if new_flags != flags && libc::fcntl(fd, libc::F_SETFD, new_flags) < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn reopen(file: &File, options: &OpenOptions) -> Result<File> {
+    // Opening the descriptor's /proc entry opens the file itself, even if it has been renamed or
+    // unlinked since.
+    options.open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "tvos",
+          target_os = "watchos",
+          target_os = "visionos"))]
+pub fn reopen(file: &File, options: &OpenOptions) -> Result<File> {
+    options.open(path(file)?)
+}
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "tvos",
+              target_os = "watchos",
+              target_os = "visionos")))]
+pub fn reopen(_file: &File, _options: &OpenOptions) -> Result<File> {
+    Err(Error::new(ErrorKind::Unsupported, "reopening a file is not supported on this platform"))
+}
+
+/// Opens a new description of `file` with the same access mode, append flag and offset, which
+/// shares none of its locks.
+#[cfg(feature = "locks")]
+pub fn reopen_description(file: &File) -> Result<File> {
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags == -1 {
+        return Err(Error::last_os_error());
+    }
+    let mut options = OpenOptions::new();
+    match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => options.read(true),
+        libc::O_WRONLY => options.write(true),
+        _ => options.read(true).write(true),
+    };
+    options.append(flags & libc::O_APPEND != 0);
+    let mut replacement = reopen(file, &options)?;
+    let offset = (&*file).stream_position()?;
+    replacement.seek(SeekFrom::Start(offset))?;
+    Ok(replacement)
+}
+
+/// Makes `handle` refer to the description of `replacement`, keeping its close-on-exec flag.
+///
+/// Only async-signal-safe calls are made, so this may be used in a forked child.
+#[cfg(feature = "locks")]
+pub fn replace_handle(handle: u64, replacement: &File) -> Result<()> {
+    let fd = handle as libc::c_int;
+    let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if fd_flags == -1 {
+        return Err(Error::last_os_error());
+    }
+    loop {
+        if unsafe { libc::dup2(replacement.as_raw_fd(), fd) } != -1 {
+            break;
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, fd_flags) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The space for a control message carrying one descriptor, aligned for `cmsghdr`.
+#[repr(C)]
+union FdControl {
+    buf: [u8; 64],
+    _align: libc::cmsghdr,
+}
+
+/// Sends `file`'s descriptor over the Unix socket with an `SCM_RIGHTS` control message, along
+/// with a single byte of data, `tag`.
+pub fn send_fd(socket: &UnixStream, file: &File, tag: u8) -> Result<()> {
+    let mut data = [tag];
+    let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: 1 };
+    let mut control = FdControl { buf: [0; 64] };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as libc::c_uint) } as usize;
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = unsafe { control.buf.as_mut_ptr() } as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as libc::c_uint) as _;
+        *(libc::CMSG_DATA(cmsg) as *mut libc::c_int) = file.as_raw_fd();
+    }
+    loop {
+        if unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) } != -1 {
+            return Ok(());
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Receives a descriptor sent with `send_fd` over the Unix socket, as a close-on-exec file,
+/// along with the byte of data sent with it.
+pub fn recv_fd(socket: &UnixStream) -> Result<(File, u8)> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",
+              target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd"))]
+    const FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd",
+                  target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd")))]
+    const FLAGS: libc::c_int = 0;
+
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: 1 };
+    let mut control = FdControl { buf: [0; 64] };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = unsafe { control.buf.as_mut_ptr() } as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of::<FdControl>() as _;
+    let received = loop {
+        let ret = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, FLAGS) };
+        if ret != -1 {
+            break ret;
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    // Take ownership of every descriptor received, so that any beyond the first are closed.
+    let mut files = Vec::new();
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        unsafe {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const libc::c_int;
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..len / mem::size_of::<libc::c_int>() {
+                    files.push(File::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Error::other("descriptors received over the socket were truncated"));
+    }
+    let file = match files.into_iter().next() {
+        Some(file) => file,
+        None if received == 0 => return Err(Error::new(ErrorKind::UnexpectedEof,
+                                                       "the socket was closed before a descriptor was received")),
+        None => return Err(Error::new(ErrorKind::InvalidData, "no descriptor was received over the socket")),
+    };
+    if FLAGS == 0 && unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok((file, data[0]))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn path(file: &File) -> Result<PathBuf> {
+    ::std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "tvos",
+          target_os = "watchos",
+          target_os = "visionos"))]
+pub fn path(file: &File) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETPATH, buf.as_mut_ptr()) };
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    Ok(OsString::from_vec(buf).into())
+}
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "tvos",
+              target_os = "watchos",
+              target_os = "visionos")))]
+pub fn path(_file: &File) -> Result<PathBuf> {
+    Err(Error::new(ErrorKind::Unsupported,
+                   "retrieving the path of a file is not supported on this platform"))
+}
+
+pub fn file_id(file: &File) -> Result<FileId> {
+    file.metadata().map(|m| FileId { device: m.dev(), index: m.ino() })
+}
+
+/// Returns the identity of the file open as `handle`, without taking ownership of it, so that a
+/// recorded handle can be checked before it is used.
+// The widths of the stat fields vary between platforms.
+#[allow(clippy::unnecessary_cast)]
+#[cfg(feature = "locks")]
+pub fn handle_file_id(handle: u64) -> Result<FileId> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(handle as libc::c_int, &mut stat) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(FileId { device: stat.st_dev as u64, index: stat.st_ino as u64 })
+}
+
+#[cfg(feature = "locks")]
 pub fn lock_shared(file: &File) -> Result<()> {
-    flock(file, libc::LOCK_SH)
+    flock(file, LOCK_SH)
 }
 
+#[cfg(feature = "locks")]
 pub fn lock_exclusive(file: &File) -> Result<()> {
-    flock(file, libc::LOCK_EX)
+    flock(file, LOCK_EX)
 }
 
+#[cfg(feature = "locks")]
 pub fn try_lock_shared(file: &File) -> Result<()> {
-    flock(file, libc::LOCK_SH | libc::LOCK_NB)
+    flock(file, LOCK_SH | LOCK_NB)
 }
 
+#[cfg(feature = "locks")]
 pub fn try_lock_exclusive(file: &File) -> Result<()> {
-    flock(file, libc::LOCK_EX | libc::LOCK_NB)
+    flock(file, LOCK_EX | LOCK_NB)
 }
 
+#[cfg(feature = "locks")]
 pub fn unlock(file: &File) -> Result<()> {
-    flock(file, libc::LOCK_UN)
+    flock(file, LOCK_UN)
+}
+
+#[cfg(feature = "locks")]
+pub fn downgrade(file: &File) -> Result<()> {
+    // Locking a file shared while it is held exclusively converts the lock.
+    flock(file, LOCK_SH)
+}
+
+#[cfg(feature = "locks")]
+pub fn try_upgrade(file: &File) -> ::std::result::Result<(), UpgradeError> {
+    flock(file, LOCK_EX | LOCK_NB).map_err(|error| {
+        // A failed conversion may release the shared lock (flock(2) on Linux does), so take it
+        // again. This is a no-op if the shared lock is still held.
+        match flock(file, LOCK_SH) {
+            Ok(()) => (error, true),
+            Err(relock) => (relock, false),
+        }
+    })
+}
+
+#[cfg(feature = "locks")]
+pub fn downgrade_range(file: &File, offset: u64, len: u64, backend: LockBackend) -> Result<()> {
+    // Record locks are converted atomically.
+    lock_range_with(file, offset, len, LockMode::Shared, true, backend)
+}
+
+#[cfg(feature = "locks")]
+pub fn try_upgrade_range(file: &File, offset: u64, len: u64,
+                         backend: LockBackend) -> ::std::result::Result<(), UpgradeError> {
+    // A failed conversion leaves the existing record lock in place.
+    lock_range_with(file, offset, len, LockMode::Exclusive, false, backend).map_err(|error| (error, true))
 }
 
 pub fn lock_error() -> Error {
     Error::from_raw_os_error(libc::EWOULDBLOCK)
 }
 
-#[cfg(not(target_os = "solaris"))]
+#[cfg(all(feature = "locks",
+          not(any(target_os = "solaris", target_os = "illumos", target_os = "nto",
+                  target_os = "aix"))))]
 fn flock(file: &File, flag: libc::c_int) -> Result<()> {
     let ret = unsafe { libc::flock(file.as_raw_fd(), flag) };
     if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
 }
 
-/// Simulate flock() using fcntl(); primarily for Oracle Solaris.
-#[cfg(target_os = "solaris")]
+/// Simulate flock() using fcntl() record locks covering the whole file; primarily for Oracle
+/// Solaris, illumos, QNX Neutrino and AIX.
+#[cfg(all(feature = "locks",
+          any(target_os = "solaris", target_os = "illumos", target_os = "nto", target_os = "aix")))]
 fn flock(file: &File, flag: libc::c_int) -> Result<()> {
-    let mut fl = libc::flock {
-        l_whence: 0,
-        l_start: 0,
-        l_len: 0,
-        l_type: 0,
-        l_pad: [0; 4],
-        l_pid: 0,
-        l_sysid: 0,
-    };
+    // The layout of struct flock varies between platforms; zero it and fill in the fields we
+    // need. A zero l_whence, l_start and l_len covers the whole file.
+    let mut fl: libc::flock = unsafe { mem::zeroed() };
 
     // In non-blocking mode, use F_SETLK for cmd, F_SETLKW otherwise, and don't forget to clear
     // LOCK_NB.
-    let (cmd, operation) = match flag & libc::LOCK_NB {
+    let (cmd, operation) = match flag & LOCK_NB {
         0 => (libc::F_SETLKW, flag),
-        _ => (libc::F_SETLK, flag & !libc::LOCK_NB),
+        _ => (libc::F_SETLK, flag & !LOCK_NB),
     };
 
     match operation {
-        libc::LOCK_SH => fl.l_type |= libc::F_RDLCK,
-        libc::LOCK_EX => fl.l_type |= libc::F_WRLCK,
-        libc::LOCK_UN => fl.l_type |= libc::F_UNLCK,
+        // The lock type constants are not declared as the type of l_type on every platform.
+        LOCK_SH => fl.l_type = libc::F_RDLCK as _,
+        LOCK_EX => fl.l_type = libc::F_WRLCK as _,
+        LOCK_UN => fl.l_type = libc::F_UNLCK as _,
         _ => return Err(Error::from_raw_os_error(libc::EINVAL)),
     }
 
@@ -84,48 +474,329 @@ fn flock(file: &File, flag: libc::c_int) -> Result<()> {
     match ret {
         // Translate EACCES to EWOULDBLOCK
         -1 => match Error::last_os_error().raw_os_error() {
-            Some(libc::EACCES) => return Err(lock_error()),
-            _ => return Err(Error::last_os_error())
+            Some(libc::EACCES) => Err(lock_error()),
+            _ => Err(Error::last_os_error())
         },
         _ => Ok(())
     }
 }
 
+/// Locks the byte range `offset..offset + len` of the file.
+///
+/// On Linux and Android these are open file description locks, which belong to the `File` (and
+/// its duplicates) like flock(2) locks. Elsewhere they are POSIX record locks, which belong to
+/// the process: ranges locked through different files in the same process do not conflict, and
+/// closing any file descriptor for the file releases all of the process's locks on it.
+pub fn lock_range(file: &File, offset: u64, len: u64, mode: LockMode, block: bool) -> Result<()> {
+    let l_type = match mode {
+        LockMode::Shared => libc::F_RDLCK,
+        LockMode::Exclusive => libc::F_WRLCK,
+    };
+    fcntl_range(file, l_type as libc::c_int, offset, len, block)
+}
+
+/// Unlocks the byte range `offset..offset + len` of the file.
+pub fn unlock_range(file: &File, offset: u64, len: u64) -> Result<()> {
+    fcntl_range(file, libc::F_UNLCK as libc::c_int, offset, len, false)
+}
+
+/// Resolves `LockBackend::Auto` for a whole-file or byte-range lock on the file, and checks that
+/// the backend is available.
+#[cfg(feature = "locks")]
+pub fn lock_backend(file: &File, backend: LockBackend, whole_file: bool) -> Result<LockBackend> {
+    let ofd = cfg!(any(target_os = "linux", target_os = "android"));
+    match backend {
+        // Linux emulates flock(2) with record locks on NFS, and other systems may not support it
+        // there at all; use record locks, so that every process sees the same kind of lock.
+        LockBackend::Auto if whole_file => Ok(if is_nfs(file)? { LockBackend::Posix } else { LockBackend::Flock }),
+        LockBackend::Auto if ofd => Ok(LockBackend::Ofd),
+        LockBackend::Auto => Ok(LockBackend::Posix),
+        LockBackend::Ofd if !ofd => {
+            Err(Error::new(ErrorKind::Unsupported,
+                           "open file description locks are only available on Linux and Android"))
+        }
+        backend => Ok(backend),
+    }
+}
+
+/// Locks the byte range `offset..offset + len` of the file with a backend resolved by
+/// `lock_backend`. `lock_range` takes open file description locks where they are available.
+pub fn lock_range_with(file: &File, offset: u64, len: u64, mode: LockMode, block: bool,
+                       backend: LockBackend) -> Result<()> {
+    match backend {
+        LockBackend::Posix => lock_record(file, record_type(mode), offset, len, block),
+        _ => lock_range(file, offset, len, mode, block),
+    }
+}
+
+/// Unlocks the byte range `offset..offset + len` of the file, locked with `backend`.
+pub fn unlock_range_with(file: &File, offset: u64, len: u64, backend: LockBackend) -> Result<()> {
+    match backend {
+        LockBackend::Posix => lock_record(file, libc::F_UNLCK as libc::c_int, offset, len, false),
+        _ => unlock_range(file, offset, len),
+    }
+}
+
+fn record_type(mode: LockMode) -> libc::c_int {
+    match mode {
+        LockMode::Shared => libc::F_RDLCK as libc::c_int,
+        LockMode::Exclusive => libc::F_WRLCK as libc::c_int,
+    }
+}
+
+/// A file or path whose file system is queried with `statfs(2)`.
+#[derive(Clone, Copy)]
+#[cfg(any(feature = "allocate", feature = "locks", feature = "statvfs"))]
+enum StatfsTarget<'a> {
+    File(&'a File),
+    #[cfg(feature = "statvfs")]
+    Path(&'a CStr),
+}
+
+/// Calls `statfs(2)` on the target.
+#[cfg(all(any(feature = "allocate", feature = "locks", feature = "statvfs"),
+          any(target_os = "linux", target_os = "android")))]
+fn statfs(target: StatfsTarget) -> Result<lfs::statfs> {
+    let mut stat: lfs::statfs = unsafe { mem::zeroed() };
+    let ret = match target {
+        StatfsTarget::File(file) => unsafe { lfs::fstatfs(file.as_raw_fd(), &mut stat) },
+        #[cfg(feature = "statvfs")]
+        StatfsTarget::Path(path) => unsafe { lfs::statfs(path.as_ptr(), &mut stat) },
+    };
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Returns the type of the file system containing the target, decoded from its magic number on
+/// Linux or its name on the BSDs and macOS.
+#[cfg(all(any(feature = "allocate", feature = "locks", feature = "statvfs"),
+          any(target_os = "linux", target_os = "android")))]
+fn fs_kind(target: StatfsTarget) -> Result<Option<FsKind>> {
+    statfs(target).map(|stat| Some(FsKind::from_magic(stat.f_type as u32)))
+}
+
+#[cfg(all(any(feature = "allocate", feature = "locks", feature = "statvfs"),
+          any(target_os = "macos",
+              target_os = "ios",
+              target_os = "tvos",
+              target_os = "watchos",
+              target_os = "visionos",
+              target_os = "freebsd",
+              target_os = "openbsd",
+              target_os = "dragonfly")))]
+fn fs_kind(target: StatfsTarget) -> Result<Option<FsKind>> {
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    let ret = match target {
+        StatfsTarget::File(file) => unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) },
+        #[cfg(feature = "statvfs")]
+        StatfsTarget::Path(path) => unsafe { libc::statfs(path.as_ptr(), &mut stat) },
+    };
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    let name = unsafe { ::std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(Some(FsKind::from_name(&name.to_string_lossy())))
+}
+
+#[cfg(all(any(feature = "allocate", feature = "locks", feature = "statvfs"),
+          not(any(target_os = "linux",
+                  target_os = "android",
+                  target_os = "macos",
+                  target_os = "ios",
+                  target_os = "tvos",
+                  target_os = "watchos",
+                  target_os = "visionos",
+                  target_os = "freebsd",
+                  target_os = "openbsd",
+                  target_os = "dragonfly"))))]
+fn fs_kind(_target: StatfsTarget) -> Result<Option<FsKind>> {
+    Ok(None)
+}
+
+/// Returns `true` if the file is on an NFS mount.
+#[cfg(feature = "locks")]
+fn is_nfs(file: &File) -> Result<bool> {
+    Ok(fs_kind(StatfsTarget::File(file))? == Some(FsKind::Nfs))
+}
+
+/// Returns `true` if the file system containing the target is a network file system.
+#[cfg(any(feature = "locks", feature = "statvfs"))]
+fn is_network(target: StatfsTarget) -> Result<bool> {
+    match fs_kind(target)? {
+        Some(kind) => Ok(kind.is_network()),
+        None => Err(Error::new(ErrorKind::Unsupported,
+                               "file system types can not be determined on this platform")),
+    }
+}
+
+#[cfg(feature = "statvfs")]
+pub fn is_network_fs(path: &Path) -> Result<bool> {
+    with_c_path(path, |path| is_network(StatfsTarget::Path(path)))
+}
+
+#[cfg(feature = "locks")]
+pub fn is_network_fs_file(file: &File) -> Result<bool> {
+    is_network(StatfsTarget::File(file))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fcntl_range(file: &File, l_type: libc::c_int, offset: u64, len: u64, block: bool) -> Result<()> {
+    // The kernel reads open file description locks as struct flock64 on every architecture.
+    let mut fl: libc::flock64 = unsafe { mem::zeroed() };
+    fl.l_type = l_type as _;
+    fl.l_whence = libc::SEEK_SET as _;
+    fl.l_start = to_off_t(offset)?;
+    fl.l_len = to_off_t(len)?;
+    let cmd = if block { libc::F_OFD_SETLKW } else { libc::F_OFD_SETLK };
+    fcntl_lock(file, cmd, &fl)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn fcntl_range(file: &File, l_type: libc::c_int, offset: u64, len: u64, block: bool) -> Result<()> {
+    lock_record(file, l_type, offset, len, block)
+}
+
+/// Locks or unlocks the byte range `offset..offset + len` of the file with a POSIX record lock,
+/// which belongs to the process on every platform.
+fn lock_record(file: &File, l_type: libc::c_int, offset: u64, len: u64, block: bool) -> Result<()> {
+    let mut fl: lfs::flock = unsafe { mem::zeroed() };
+    fl.l_type = l_type as _;
+    fl.l_whence = libc::SEEK_SET as _;
+    fl.l_start = to_off_t(offset)?;
+    fl.l_len = to_off_t(len)?;
+    let cmd = if block { lfs::F_SETLKW } else { lfs::F_SETLK };
+    fcntl_lock(file, cmd, &fl)
+}
+
+fn fcntl_lock<T>(file: &File, cmd: libc::c_int, fl: &T) -> Result<()> {
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), cmd, fl as *const T) };
+    if ret == -1 {
+        let err = Error::last_os_error();
+        match err.raw_os_error() {
+            // Contended locks fail with EAGAIN or EACCES, depending on the platform.
+            Some(libc::EAGAIN) | Some(libc::EACCES) => Err(lock_error()),
+            _ => Err(err),
+        }
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "locks", any(target_os = "linux", target_os = "android")))]
+pub fn lock_holder(file: &File) -> Result<Option<LockHolder>> {
+    let locks = ::os::linux::file_locks(file)?;
+    Ok(locks.into_iter().find(|lock| !lock.is_blocked() && !lock.kind().is_lease()).map(|lock| {
+        let process_name = lock.pid().and_then(|pid| {
+            ::std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()
+                .map(|name| name.trim_end().to_owned())
+        });
+        LockHolder { pid: lock.pid(), mode: lock.mode(), process_name }
+    }))
+}
+
+#[cfg(all(feature = "locks", not(any(target_os = "linux", target_os = "android"))))]
+pub fn lock_holder(file: &File) -> Result<Option<LockHolder>> {
+    let mut fl: libc::flock = unsafe { mem::zeroed() };
+    fl.l_type = libc::F_WRLCK as _;
+    fl.l_whence = libc::SEEK_SET as _;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut fl) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    let mode = match fl.l_type as libc::c_int {
+        t if t == libc::F_UNLCK as libc::c_int => return Ok(None),
+        t if t == libc::F_RDLCK as libc::c_int => LockMode::Shared,
+        _ => LockMode::Exclusive,
+    };
+    let pid = if fl.l_pid > 0 { Some(fl.l_pid as u32) } else { None };
+    Ok(Some(LockHolder { pid, mode, process_name: None }))
+}
+
+#[cfg(feature = "allocate")]
 pub fn allocated_size(file: &File) -> Result<u64> {
-    file.metadata().map(|m| m.blocks() as u64 * 512)
+    Ok(Bytes::from_blocks(512, file.metadata()?.blocks())?.as_u64())
+}
+
+#[cfg(all(feature = "allocate", any(target_os = "linux", target_os = "android")))]
+pub fn allocate(file: &File, len: u64, options: &AllocateOptions) -> Result<()> {
+    // Unlike posix_fallocate, fallocate is never emulated by the C library, so it fails on
+    // filesystems which can not allocate natively.
+    if unsafe { lfs::fallocate(file.as_raw_fd(), 0, 0, to_off_t(len)?) } == 0 {
+        return Ok(());
+    }
+    let err = Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+        return Err(err);
+    }
+    match options.mode {
+        AllocateMode::Fast => Err(err),
+        AllocateMode::Portable => posix_fallocate(file, len),
+        AllocateMode::ZeroFill => positional::zero_fill(file, len),
+    }
+}
+
+#[cfg(all(feature = "allocate",
+          any(target_os = "freebsd",
+              target_os = "emscripten",
+              target_os = "fuchsia",
+              target_os = "haiku",
+              target_os = "nto",
+              target_os = "aix")))]
+pub fn allocate(file: &File, len: u64, options: &AllocateOptions) -> Result<()> {
+    match posix_fallocate(file, len) {
+        // ZFS on FreeBSD fails with EINVAL rather than EOPNOTSUPP.
+        Err(ref err) if (err.raw_os_error() == Some(libc::EOPNOTSUPP) || err.raw_os_error() == Some(libc::EINVAL))
+                        && options.mode == AllocateMode::ZeroFill => positional::zero_fill(file, len),
+        result => result,
+    }
 }
 
-#[cfg(any(target_os = "linux",
-          target_os = "freebsd",
-          target_os = "android",
-          target_os = "emscripten",
-          target_os = "nacl"))]
-pub fn allocate(file: &File, len: u64) -> Result<()> {
-    let ret = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) };
-    if ret == 0 { Ok(()) } else { Err(Error::last_os_error()) }
+#[cfg(all(feature = "allocate",
+          any(target_os = "linux",
+              target_os = "freebsd",
+              target_os = "android",
+              target_os = "emscripten",
+              target_os = "fuchsia",
+              target_os = "haiku",
+              target_os = "nto",
+              target_os = "aix")))]
+fn posix_fallocate(file: &File, len: u64) -> Result<()> {
+    // posix_fallocate returns the error number rather than setting errno.
+    let ret = unsafe { lfs::posix_fallocate(file.as_raw_fd(), 0, to_off_t(len)?) };
+    if ret == 0 { Ok(()) } else { Err(Error::from_raw_os_error(ret)) }
 }
 
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-pub fn allocate(file: &File, len: u64) -> Result<()> {
-    let stat = try!(file.metadata());
+#[cfg(all(feature = "allocate",
+          any(target_os = "macos",
+              target_os = "ios",
+              target_os = "tvos",
+              target_os = "watchos",
+              target_os = "visionos")))]
+pub fn allocate(file: &File, len: u64, options: &AllocateOptions) -> Result<()> {
+    let stat = file.metadata()?;
 
-    if len > stat.blocks() as u64 * 512 {
+    if len > Bytes::from_blocks(512, stat.blocks())?.as_u64() {
         let mut fstore = libc::fstore_t {
-            fst_flags: libc::F_ALLOCATECONTIG,
+            fst_flags: if options.contiguous { libc::F_ALLOCATECONTIG } else { libc::F_ALLOCATEALL },
             fst_posmode: libc::F_PEOFPOSMODE,
             fst_offset: 0,
-            fst_length: len as libc::off_t,
+            fst_length: to_off_t(len)?,
             fst_bytesalloc: 0,
         };
 
-        let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &fstore) };
-        if ret == -1 {
+        let mut ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &fstore) };
+        if ret == -1 && options.contiguous {
             // Unable to allocate contiguous disk space; attempt to allocate non-contiguously.
             fstore.fst_flags = libc::F_ALLOCATEALL;
-            let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &fstore) };
-            if ret == -1 {
-                return Err(Error::last_os_error());
+            ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &fstore) };
+        }
+        if ret == -1 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOTSUP) && options.mode == AllocateMode::ZeroFill {
+                return positional::zero_fill(file, len);
             }
+            return Err(err);
         }
     }
 
@@ -136,63 +807,956 @@ pub fn allocate(file: &File, len: u64) -> Result<()> {
     }
 }
 
-#[cfg(any(target_os = "openbsd",
-          target_os = "netbsd",
-          target_os = "dragonfly",
-          target_os = "solaris",
-          target_os = "haiku"))]
-pub fn allocate(file: &File, len: u64) -> Result<()> {
+#[cfg(all(feature = "allocate",
+          any(target_os = "openbsd",
+              target_os = "netbsd",
+              target_os = "dragonfly",
+              target_os = "solaris",
+              target_os = "illumos",
+              target_os = "redox")))]
+pub fn allocate(file: &File, len: u64, options: &AllocateOptions) -> Result<()> {
     // No file allocation API available, just set the length if necessary.
-    if len > try!(file.metadata()).len() as u64 {
+    if options.mode == AllocateMode::ZeroFill {
+        positional::zero_fill(file, len)
+    } else if len > file.metadata()?.len() {
         file.set_len(len)
     } else {
         Ok(())
     }
 }
 
-pub fn statvfs(path: &Path) -> Result<FsStats> {
-    let cstr = match CString::new(path.as_os_str().as_bytes()) {
-        Ok(cstr) => cstr,
-        Err(..) => return Err(Error::new(ErrorKind::InvalidInput, "path contained a null")),
+#[cfg(all(feature = "allocate",
+          not(any(target_os = "linux",
+                  target_os = "freebsd",
+                  target_os = "android",
+                  target_os = "emscripten",
+                  target_os = "fuchsia",
+                  target_os = "haiku",
+                  target_os = "nto",
+                  target_os = "aix",
+                  target_os = "macos",
+                  target_os = "ios",
+                  target_os = "tvos",
+                  target_os = "watchos",
+                  target_os = "visionos",
+                  target_os = "openbsd",
+                  target_os = "netbsd",
+                  target_os = "dragonfly",
+                  target_os = "solaris",
+                  target_os = "illumos",
+                  target_os = "redox"))))]
+pub fn allocate(_file: &File, _len: u64, _options: &AllocateOptions) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "file allocation is not supported on this platform"))
+}
+
+/// Deallocates any disk space allocated for the file past `offset`, which is at or past the end of
+/// the file.
+#[cfg(all(feature = "allocate", any(target_os = "linux", target_os = "android")))]
+pub fn release_tail(file: &File, offset: u64) -> Result<()> {
+    // Space preallocated with FALLOC_FL_KEEP_SIZE may lie anywhere past the end of the file, where
+    // SEEK_DATA does not look, but FIEMAP maps it. File systems without FIEMAP get a hole up to
+    // the maximum file size instead, since ranges ending past it fail with EFBIG.
+    let end = match ::os::linux::fiemap(file) {
+        Ok(extents) => extents.iter().map(|extent| extent.logical() + extent.len()).max().unwrap_or(0),
+        Err(ref err) if err.kind() == ErrorKind::Unsupported => {
+            max_file_size(file)?.unwrap_or(i64::MAX as u64)
+        }
+        Err(err) => return Err(err),
+    };
+    if end <= offset {
+        return Ok(());
+    }
+    match punch_hole(file, offset, end - offset) {
+        // Filesystems which can not punch holes do not keep space past the end of the file.
+        Err(ref err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(all(feature = "allocate", any(target_os = "linux", target_os = "android")))]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let ret = unsafe {
+        lfs::fallocate(file.as_raw_fd(), libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                       to_off_t(offset)?, to_off_t(len)?)
+    };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(all(feature = "allocate",
+          any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos",
+              target_os = "visionos")))]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let args = libc::fpunchhole_t {
+        fp_flags: 0,
+        reserved: 0,
+        fp_offset: to_off_t(offset)?,
+        fp_length: to_off_t(len)?,
+    };
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PUNCHHOLE, &args) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(all(feature = "allocate",
+          not(any(target_os = "linux", target_os = "android", target_os = "macos",
+                  target_os = "ios", target_os = "tvos", target_os = "watchos",
+                  target_os = "visionos"))))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "punching holes is not supported on this platform"))
+}
+
+#[cfg(all(feature = "allocate", not(any(target_os = "linux", target_os = "android"))))]
+pub fn release_tail(_file: &File, _offset: u64) -> Result<()> {
+    // ftruncate(2) releases the space past the end of the file.
+    Ok(())
+}
+
+#[cfg(all(feature = "allocate",
+          any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "tvos", target_os = "watchos", target_os = "visionos",
+              target_os = "freebsd", target_os = "dragonfly", target_os = "solaris",
+              target_os = "illumos")))]
+pub fn extents(file: &File) -> Result<Vec<Extent>> {
+    let fd = file.as_raw_fd();
+    let len = file.metadata()?.len();
+
+    let position = unsafe { lfs::lseek(fd, 0, libc::SEEK_CUR) };
+    if position < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let extents = seek_extents(fd, len);
+
+    if unsafe { lfs::lseek(fd, position, libc::SEEK_SET) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    extents
+}
+
+/// Walks the data and hole segments of the file with `SEEK_DATA` and
+/// `SEEK_HOLE`. Moves the file position.
+#[cfg(all(feature = "allocate",
+          any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "tvos", target_os = "watchos", target_os = "visionos",
+              target_os = "freebsd", target_os = "dragonfly", target_os = "solaris",
+              target_os = "illumos")))]
+fn seek_extents(fd: libc::c_int, len: u64) -> Result<Vec<Extent>> {
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let data = unsafe { lfs::lseek(fd, to_off_t(offset)?, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                // There is no more data before the end of the file.
+                extents.push(Extent { offset, len: len - offset, is_hole: true });
+                break;
+            }
+            return Err(err);
+        }
+        // The file may have grown since its length was read, so data and
+        // holes past `len` are clamped to it.
+        let data = (data as u64).min(len);
+        if data > offset {
+            extents.push(Extent { offset, len: data - offset, is_hole: true });
+        }
+        if data == len {
+            break;
+        }
+
+        let hole = unsafe { lfs::lseek(fd, to_off_t(data)?, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(Error::last_os_error());
+        }
+        let hole = (hole as u64).min(len);
+        extents.push(Extent { offset: data, len: hole.saturating_sub(data), is_hole: false });
+        offset = hole;
+    }
+    Ok(extents)
+}
+
+#[cfg(all(feature = "allocate",
+          not(any(target_os = "linux", target_os = "android", target_os = "macos",
+                  target_os = "ios", target_os = "tvos", target_os = "watchos",
+                  target_os = "visionos", target_os = "freebsd", target_os = "dragonfly",
+                  target_os = "solaris", target_os = "illumos"))))]
+pub fn extents(file: &File) -> Result<Vec<Extent>> {
+    // No hole detection API available, report the whole file as data.
+    let len = file.metadata()?.len();
+    if len == 0 {
+        Ok(Vec::new())
+    } else {
+        Ok(vec![Extent { offset: 0, len, is_hole: false }])
+    }
+}
+
+#[cfg(feature = "allocate")]
+pub fn is_sparse(file: &File) -> Result<bool> {
+    let metadata = file.metadata()?;
+    Ok(Bytes::from_blocks(512, metadata.blocks())?.as_u64() < metadata.size())
+}
+
+#[cfg(feature = "allocate")]
+pub fn mark_sparse(_file: &File) -> Result<()> {
+    // Files on Unix are sparse without any special marking.
+    Ok(())
+}
+
+#[cfg(all(feature = "utilities", any(target_os = "linux", target_os = "android")))]
+pub fn send_to_socket(file: &File, socket: RawSocket, offset: u64, len: u64) -> Result<u64> {
+    let mut offset = to_off_t(offset)?;
+    // Linux transfers at most 0x7ffff000 bytes per call.
+    let count = cmp::min(len, 0x7fff_f000) as libc::size_t;
+    let ret = unsafe { lfs::sendfile(socket, file.as_raw_fd(), &mut offset, count) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(ret as u64) }
+}
+
+#[cfg(all(feature = "utilities",
+          any(target_os = "macos",
+              target_os = "ios",
+              target_os = "tvos",
+              target_os = "watchos",
+              target_os = "visionos")))]
+pub fn send_to_socket(file: &File, socket: RawSocket, offset: u64, len: u64) -> Result<u64> {
+    // A length of 0 means 'until the end of the file', so the caller must not
+    // pass it.
+    let mut sent = cmp::min(len, libc::off_t::MAX as u64) as libc::off_t;
+    let ret = unsafe {
+        libc::sendfile(file.as_raw_fd(), socket, to_off_t(offset)?, &mut sent,
+                       ::std::ptr::null_mut(), 0)
+    };
+    // A partial transfer may be reported alongside EAGAIN or EINTR.
+    if ret < 0 && sent == 0 { Err(Error::last_os_error()) } else { Ok(sent as u64) }
+}
+
+#[cfg(all(feature = "utilities", any(target_os = "freebsd", target_os = "dragonfly")))]
+pub fn send_to_socket(file: &File, socket: RawSocket, offset: u64, len: u64) -> Result<u64> {
+    // A length of 0 means 'until the end of the file', so the caller must not
+    // pass it.
+    let count = cmp::min(len, libc::off_t::MAX as u64) as libc::size_t;
+    let mut sent = 0;
+    let ret = unsafe {
+        libc::sendfile(file.as_raw_fd(), socket, to_off_t(offset)?, count,
+                       ::std::ptr::null_mut(), &mut sent, 0)
+    };
+    // A partial transfer may be reported alongside EAGAIN or EINTR.
+    if ret < 0 && sent == 0 { Err(Error::last_os_error()) } else { Ok(sent as u64) }
+}
+
+#[cfg(all(feature = "utilities",
+          not(any(target_os = "linux",
+                  target_os = "android",
+                  target_os = "macos",
+                  target_os = "ios",
+                  target_os = "tvos",
+                  target_os = "watchos",
+                  target_os = "visionos",
+                  target_os = "freebsd",
+                  target_os = "dragonfly"))))]
+pub fn send_to_socket(file: &File, socket: RawSocket, offset: u64, len: u64) -> Result<u64> {
+    // No zero-copy API available, copy through a userspace buffer.
+    let mut buf = [0u8; 64 * 1024];
+    let count = cmp::min(len, buf.len() as u64) as libc::size_t;
+    let read = unsafe {
+        libc::pread(file.as_raw_fd(), buf.as_mut_ptr() as *mut _, count, to_off_t(offset)?)
+    };
+    if read <= 0 {
+        return if read < 0 { Err(Error::last_os_error()) } else { Ok(0) };
+    }
+    let written = unsafe { libc::write(socket, buf.as_ptr() as *const _, read as libc::size_t) };
+    if written < 0 { Err(Error::last_os_error()) } else { Ok(written as u64) }
+}
+
+/// Clones the contents of `src` into `dst` by sharing extents, as `cp --reflink` does.
+#[cfg(all(feature = "utilities", target_os = "linux"))]
+pub fn reflink(src: &File, dst: &File) -> Result<()> {
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(all(feature = "utilities", not(target_os = "linux")))]
+pub fn reflink(_src: &File, _dst: &File) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "reflinks are not supported on this platform"))
+}
+
+/// Copies up to `len` bytes from `offset` in `src` to the same offset in `dst` within the kernel,
+/// returning the number of bytes copied.
+#[cfg(all(feature = "utilities", target_os = "linux"))]
+pub fn copy_file_range(src: &File, dst: &File, offset: u64, len: u64) -> Result<u64> {
+    let mut off_in = to_off_t(offset)?;
+    let mut off_out = off_in;
+    let count = cmp::min(len, 0x7fff_f000) as libc::size_t;
+    let ret = unsafe {
+        libc::copy_file_range(src.as_raw_fd(), &mut off_in, dst.as_raw_fd(), &mut off_out, count, 0)
     };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(ret as u64) }
+}
+
+#[cfg(all(feature = "utilities", not(target_os = "linux")))]
+pub fn copy_file_range(_src: &File, _dst: &File, _offset: u64, _len: u64) -> Result<u64> {
+    Err(Error::new(ErrorKind::Unsupported, "copy_file_range is not supported on this platform"))
+}
+
+/// Copies up to `len` bytes from `offset` in `src` to the current position of `dst` with
+/// `sendfile(2)`, returning the number of bytes copied.
+#[cfg(all(feature = "utilities", any(target_os = "linux", target_os = "android")))]
+pub fn sendfile_to_file(src: &File, dst: &File, offset: u64, len: u64) -> Result<u64> {
+    // Since Linux 2.6.33 the destination of sendfile may be any file.
+    send_to_socket(src, dst.as_raw_fd(), offset, len)
+}
+
+#[cfg(all(feature = "utilities", not(any(target_os = "linux", target_os = "android"))))]
+pub fn sendfile_to_file(_src: &File, _dst: &File, _offset: u64, _len: u64) -> Result<u64> {
+    // Elsewhere sendfile only sends to sockets.
+    Err(Error::new(ErrorKind::Unsupported, "sendfile between files is not supported on this platform"))
+}
+
+/// Returns `true` if a copy failed because the files or filesystems do not support the method,
+/// rather than because of an I/O error.
+#[cfg(feature = "utilities")]
+pub fn is_copy_unsupported(err: &Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::ENOTTY)
+            | Some(libc::EOPNOTSUPP) | Some(libc::EBADF) | Some(libc::ENOTSOCK) => true,
+        _ => err.kind() == ErrorKind::Unsupported,
+    }
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        duplicate: true,
+        lock: true,
+        allocate: cfg!(any(target_os = "linux",
+                           target_os = "freebsd",
+                           target_os = "android",
+                           target_os = "emscripten",
+                           target_os = "fuchsia",
+                           target_os = "haiku",
+                           target_os = "nto",
+                           target_os = "aix",
+                           target_os = "macos",
+                           target_os = "ios",
+                           target_os = "tvos",
+                           target_os = "watchos",
+                           target_os = "visionos")),
+        extents: cfg!(any(target_os = "linux",
+                          target_os = "android",
+                          target_os = "macos",
+                          target_os = "ios",
+                          target_os = "tvos",
+                          target_os = "watchos",
+                          target_os = "visionos",
+                          target_os = "freebsd",
+                          target_os = "dragonfly",
+                          target_os = "solaris",
+                          target_os = "illumos")),
+        zero_copy_send: cfg!(any(target_os = "linux",
+                                 target_os = "android",
+                                 target_os = "macos",
+                                 target_os = "ios",
+                                 target_os = "tvos",
+                                 target_os = "watchos",
+                                 target_os = "visionos",
+                                 target_os = "freebsd",
+                                 target_os = "dragonfly")),
+        statvfs: true,
+    }
+}
+
+/// Paths shorter than this are converted to C strings on the stack, rather than allocating.
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
+const MAX_STACK_PATH: usize = 384;
+
+/// Calls `f` with `path` converted to a nul-terminated C string.
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
+fn with_c_path<T, F>(path: &Path, f: F) -> Result<T> where F: FnOnce(&CStr) -> Result<T> {
+    let bytes = path.as_os_str().as_bytes();
+    let null_err = || Error::new(ErrorKind::InvalidInput, "path contained a null");
+
+    if bytes.len() >= MAX_STACK_PATH {
+        return match CString::new(bytes) {
+            Ok(cstr) => f(&cstr),
+            Err(..) => Err(null_err()),
+        };
+    }
+
+    let mut buf = [0u8; MAX_STACK_PATH];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    match CStr::from_bytes_with_nul(&buf[..=bytes.len()]) {
+        Ok(cstr) => f(cstr),
+        Err(..) => Err(null_err()),
+    }
+}
+
+/// Opens an unnamed file in `dir` with `O_TMPFILE`, or returns `None` if the filesystem does not
+/// support it.
+#[cfg(all(feature = "utilities", target_os = "linux"))]
+pub fn open_tmpfile(dir: &Path) -> Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match OpenOptions::new().read(true).write(true).mode(0o600).custom_flags(libc::O_TMPFILE).open(dir) {
+        Ok(file) => Ok(Some(file)),
+        // Filesystems without support fail with EOPNOTSUPP. Kernels older than 3.11 ignore the
+        // unknown flag bits and fail to open the directory for writing with EISDIR.
+        Err(ref err) if err.raw_os_error() == Some(libc::EOPNOTSUPP)
+                     || err.raw_os_error() == Some(libc::EISDIR) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(all(feature = "utilities", not(target_os = "linux")))]
+pub fn open_tmpfile(_dir: &Path) -> Result<Option<File>> {
+    Ok(None)
+}
+
+/// Creates a file at `path` which is removed once created, so that it is deleted when closed.
+#[cfg(feature = "utilities")]
+pub fn create_deleted(path: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let file = OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(path)?;
+    fs::remove_file(path)?;
+    Ok(file)
+}
+
+/// Links a file opened with `open_tmpfile` into the filesystem at `path`.
+#[cfg(all(feature = "utilities", target_os = "linux"))]
+pub fn link_tmpfile(file: &File, path: &Path) -> Result<()> {
+    // Linking the /proc/self/fd entry does not require CAP_DAC_READ_SEARCH, unlike AT_EMPTY_PATH.
+    let fd_path = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+    with_c_path(path, |path| {
+        let ret = unsafe {
+            libc::linkat(libc::AT_FDCWD, fd_path.as_ptr(), libc::AT_FDCWD, path.as_ptr(),
+                         libc::AT_SYMLINK_FOLLOW)
+        };
+        if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+    })
+}
+
+#[cfg(all(feature = "utilities", not(target_os = "linux")))]
+pub fn link_tmpfile(_file: &File, _path: &Path) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "O_TMPFILE is not supported on this platform"))
+}
+
+/// Identifies the file system containing a path.
+#[cfg(feature = "statvfs")]
+pub type VolumeKey = u64;
 
+#[cfg(feature = "statvfs")]
+pub fn volume_key(path: &Path) -> Result<VolumeKey> {
+    fs::metadata(path).map(|metadata| metadata.dev())
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs(path: &Path) -> Result<FsStats> {
+    with_c_path(path, statvfs_cstr)
+}
+
+/// A path encoded once for repeated `statvfs` queries.
+#[cfg(feature = "statvfs")]
+pub type StatvfsPath = CString;
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_path(path: &Path) -> Result<StatvfsPath> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contained a null"))
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_encoded(path: &CStr) -> Result<FsStats> {
+    statvfs_cstr(path)
+}
+
+#[cfg(all(feature = "statvfs", any(target_os = "linux", target_os = "android")))]
+pub fn statvfs_cstr(path: &CStr) -> Result<FsStats> {
+    statfs(StatfsTarget::Path(path)).and_then(|stat| statfs_stats(&stat))
+}
+
+#[cfg(all(feature = "statvfs", not(any(target_os = "linux", target_os = "android"))))]
+pub fn statvfs_cstr(path: &CStr) -> Result<FsStats> {
     unsafe {
-        let mut stat: libc::statvfs = mem::zeroed();
+        let mut stat: lfs::statvfs = mem::zeroed();
         // danburkert/fs2-rs#1: cast is necessary for platforms where c_char != u8.
-        if libc::statvfs(cstr.as_ptr() as *const _, &mut stat) != 0 {
+        if lfs::statvfs(path.as_ptr() as *const _, &mut stat) != 0 {
+            Err(Error::last_os_error())
+        } else {
+            // Determining the type takes a second system call, so it is left to `statvfs_ext`.
+            fs_stats(&stat, None)
+        }
+    }
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_ext(path: &Path) -> Result<FsStatsExt> {
+    with_c_path(path, |path| unsafe {
+        let mut stat: lfs::statvfs = mem::zeroed();
+        if lfs::statvfs(path.as_ptr() as *const _, &mut stat) != 0 {
+            Err(Error::last_os_error())
+        } else {
+            let kind = fs_kind(StatfsTarget::Path(path)).ok().flatten();
+            let max_file_size = fs_max_file_size(StatfsTarget::Path(path), kind.as_ref());
+            fs_stats_ext(&stat, kind, max_file_size)
+        }
+    })
+}
+
+#[cfg(all(feature = "statvfs", any(target_os = "linux", target_os = "android")))]
+pub fn fstatvfs(file: &File) -> Result<FsStats> {
+    statfs(StatfsTarget::File(file)).and_then(|stat| statfs_stats(&stat))
+}
+
+#[cfg(all(feature = "statvfs", not(any(target_os = "linux", target_os = "android"))))]
+pub fn fstatvfs(file: &File) -> Result<FsStats> {
+    unsafe {
+        let mut stat: lfs::statvfs = mem::zeroed();
+        if lfs::fstatvfs(file.as_raw_fd(), &mut stat) != 0 {
             Err(Error::last_os_error())
         } else {
-            Ok(FsStats {
-                free_space: stat.f_frsize as u64 * stat.f_bfree as u64,
-                available_space: stat.f_frsize as u64 * stat.f_bavail as u64,
-                total_space: stat.f_frsize as u64 * stat.f_blocks as u64,
-                allocation_granularity: stat.f_frsize as u64,
-            })
+            fs_stats(&stat, None)
         }
     }
 }
 
+// The width of `f_frsize` varies between platforms.
+#[allow(clippy::unnecessary_cast)]
+#[cfg(feature = "allocate")]
+pub fn allocation_granularity(file: &File) -> Result<u64> {
+    unsafe {
+        let mut stat: lfs::statvfs = mem::zeroed();
+        if lfs::fstatvfs(file.as_raw_fd(), &mut stat) != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(stat.f_frsize as u64)
+        }
+    }
+}
+
+/// Returns the maximum size of a file on the file system containing the target, from the number
+/// of bits needed to represent it as a signed integer.
+#[cfg(all(any(feature = "allocate", feature = "statvfs"), not(target_os = "android")))]
+fn fs_max_file_size(target: StatfsTarget, kind: Option<&FsKind>) -> Option<u64> {
+    if let Some(max) = kind.and_then(fs_kind::format_max_file_size) {
+        return Some(max);
+    }
+    let bits = match target {
+        StatfsTarget::File(file) => unsafe { libc::fpathconf(file.as_raw_fd(), libc::_PC_FILESIZEBITS) },
+        #[cfg(feature = "statvfs")]
+        StatfsTarget::Path(path) => unsafe { libc::pathconf(path.as_ptr(), libc::_PC_FILESIZEBITS) },
+    };
+    match bits {
+        bits if bits >= 64 => Some(i64::MAX as u64),
+        bits if bits > 1 => Some((1u64 << (bits - 1)) - 1),
+        // No limit is reported, or the query failed.
+        _ => None,
+    }
+}
+
+#[cfg(all(any(feature = "allocate", feature = "statvfs"), target_os = "android"))]
+fn fs_max_file_size(_target: StatfsTarget, kind: Option<&FsKind>) -> Option<u64> {
+    kind.and_then(fs_kind::format_max_file_size)
+}
+
+#[cfg(feature = "allocate")]
+pub fn max_file_size(file: &File) -> Result<Option<u64>> {
+    let target = StatfsTarget::File(file);
+    Ok(fs_max_file_size(target, fs_kind(target).ok().flatten().as_ref()))
+}
+
+/// Returns the soft `RLIMIT_FSIZE` limit of the process, or `None` if it is unlimited.
+// rlim_t is not 64 bits wide on every platform.
+#[cfg(feature = "allocate")]
+#[allow(clippy::unnecessary_cast)]
+pub fn file_size_limit() -> Result<Option<u64>> {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_FSIZE, &mut limit) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        Ok(None)
+    } else {
+        Ok(Some(limit.rlim_cur as u64))
+    }
+}
+
+/// Returns the host name of the machine, if it can be determined.
+#[cfg(feature = "utilities")]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Returns the name of the process's real user, looked up in the user database, if it can be
+/// determined.
+#[cfg(feature = "utilities")]
+pub fn user_name() -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = ::std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwuid_r(libc::getuid(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if ret != 0 || result.is_null() || passwd.pw_name.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(passwd.pw_name) }.to_str().ok().map(|name| name.to_owned())
+}
+
+/// Returns when the process `pid` started, or `None` if it is running but its start time can
+/// not be determined. Fails with `ErrorKind::NotFound` if there is no such process.
+#[cfg(feature = "utilities")]
+pub fn process_start_time(pid: u32) -> Result<Option<SystemTime>> {
+    let pid = libc::pid_t::try_from(pid).map_err(|_| Error::from(ErrorKind::NotFound))?;
+    // A process which can not be signalled because it belongs to another user still exists.
+    if unsafe { libc::kill(pid, 0) } == -1 {
+        let err = Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ESRCH) => return Err(Error::new(ErrorKind::NotFound, "no such process")),
+            Some(libc::EPERM) => (),
+            _ => return Err(err),
+        }
+    }
+    Ok(process_start_time_of(pid))
+}
+
+#[cfg(all(feature = "utilities", any(target_os = "linux", target_os = "android")))]
+fn process_start_time_of(pid: libc::pid_t) -> Option<SystemTime> {
+    // The start time is the 22nd field of /proc/<pid>/stat, in clock ticks since boot. The
+    // second field is the command name, which may contain spaces and parentheses.
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields = &stat[stat.rfind(')')? + 1..];
+    let ticks: u64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+    let proc_stat = fs::read_to_string("/proc/stat").ok()?;
+    let boot_time: u64 = proc_stat.lines().find(|line| line.starts_with("btime "))?[6..].trim().parse().ok()?;
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(boot_time) + Duration::from_millis(ticks * 1000 / hz as u64))
+}
+
+#[cfg(all(feature = "utilities",
+          any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos",
+              target_os = "visionos")))]
+fn process_start_time_of(pid: libc::pid_t) -> Option<SystemTime> {
+    let mut info: libc::proc_bsdinfo = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let ret = unsafe {
+        libc::proc_pidinfo(pid, libc::PROC_PIDTBSDINFO, 0, &mut info as *mut _ as *mut libc::c_void, size)
+    };
+    if ret != size {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(info.pbi_start_tvsec, info.pbi_start_tvusec as u32 * 1000))
+}
+
+#[cfg(all(feature = "utilities",
+          not(any(target_os = "linux", target_os = "android", target_os = "macos",
+                  target_os = "ios", target_os = "tvos", target_os = "watchos",
+                  target_os = "visionos"))))]
+fn process_start_time_of(_pid: libc::pid_t) -> Option<SystemTime> {
+    None
+}
+
+// The widths of the statvfs fields vary between platforms.
+#[allow(clippy::unnecessary_cast)]
+#[cfg(feature = "statvfs")]
+fn fs_stats(stat: &lfs::statvfs, kind: Option<FsKind>) -> Result<FsStats> {
+    let block_size = stat.f_frsize as u64;
+    Ok(FsStats {
+        free_space: Bytes::from_blocks(block_size, stat.f_bfree as u64)?.as_u64(),
+        available_space: Bytes::from_blocks(block_size, stat.f_bavail as u64)?.as_u64(),
+        total_space: Bytes::from_blocks(block_size, stat.f_blocks as u64)?.as_u64(),
+        allocation_granularity: block_size,
+        filesystem_id: Some(stat.f_fsid as u64),
+        kind,
+    })
+}
+
+/// Returns the stats reported by `statfs(2)`, which has the block counts, fragment size and ID of
+/// the file system like `statvfs(3)`, and its magic number as well, saving a second call for the
+/// type.
+// The widths of the statfs fields vary between platforms.
+#[allow(clippy::unnecessary_cast)]
+#[cfg(all(feature = "statvfs", any(target_os = "linux", target_os = "android")))]
+fn statfs_stats(stat: &lfs::statfs) -> Result<FsStats> {
+    // Kernels before 2.6 report no fragment size; glibc's statvfs falls back to the block size too.
+    let block_size = if stat.f_frsize > 0 { stat.f_frsize as u64 } else { stat.f_bsize as u64 };
+    // `fsid_t` is opaque; statvfs reports the same bytes as an integer.
+    let fsid: [u8; 8] = unsafe { mem::transmute(stat.f_fsid) };
+    Ok(FsStats {
+        free_space: Bytes::from_blocks(block_size, stat.f_bfree as u64)?.as_u64(),
+        available_space: Bytes::from_blocks(block_size, stat.f_bavail as u64)?.as_u64(),
+        total_space: Bytes::from_blocks(block_size, stat.f_blocks as u64)?.as_u64(),
+        allocation_granularity: block_size,
+        filesystem_id: Some(u64::from_ne_bytes(fsid)),
+        kind: Some(FsKind::from_magic(stat.f_type as u32)),
+    })
+}
+
+#[allow(clippy::unnecessary_cast)]
+#[cfg(feature = "statvfs")]
+fn fs_stats_ext(stat: &lfs::statvfs, kind: Option<FsKind>, max_file_size: Option<u64>) -> Result<FsStatsExt> {
+    Ok(FsStatsExt {
+        stats: fs_stats(stat, kind)?,
+        max_name_len: Some(stat.f_namemax as u64),
+        max_file_size,
+        flags: Some(stat.f_flag as u64),
+        fragment_size: Some(stat.f_frsize as u64),
+        total_inodes: Some(stat.f_files as u64),
+        free_inodes: Some(stat.f_ffree as u64),
+        available_inodes: Some(stat.f_favail as u64),
+    })
+}
+
+/// A shared memory mapping of a file, unmapped when dropped.
+#[derive(Debug)]
+#[cfg(feature = "locks")]
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+/// Maps the first `len` bytes of `file` into memory, shared with other
+/// mappings of the file. `len` must not be 0.
+#[cfg(feature = "locks")]
+pub fn map(file: &File, len: usize, writable: bool) -> Result<Mmap> {
+    let prot = if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+    let ptr = unsafe { libc::mmap(::std::ptr::null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    if ptr == libc::MAP_FAILED {
+        Err(Error::last_os_error())
+    } else {
+        Ok(Mmap { ptr, len })
+    }
+}
+
+#[cfg(feature = "locks")]
+impl Mmap {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr as *mut u8
+    }
+
+    /// Writes modified pages back to the file, and waits for them to be written.
+    pub fn flush(&self) -> Result<()> {
+        if unsafe { libc::msync(self.ptr, self.len, libc::MS_SYNC) } != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "locks")]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// An inotify watch for modifications of a file.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug)]
+#[cfg(feature = "utilities")]
+pub struct Watch {
+    inotify: File,
+}
+
+/// Watches `path` for modifications, or returns `None` if the platform can
+/// not watch files.
+#[cfg(all(feature = "utilities", any(target_os = "linux", target_os = "android")))]
+pub fn watch(path: &Path) -> Result<Option<Watch>> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let inotify = unsafe { File::from_raw_fd(fd) };
+    with_c_path(path, |path| {
+        let mask = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_DELETE_SELF;
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    })?;
+    Ok(Some(Watch { inotify }))
+}
+
+#[cfg(all(feature = "utilities", any(target_os = "linux", target_os = "android")))]
+impl Watch {
+    /// Blocks until the file is modified or `timeout` elapses. Spurious
+    /// wakeups are possible, so the caller must check what changed.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<()> {
+        use std::io::Read;
+
+        let timeout = match timeout {
+            // Round up, so that a short timeout does not spin.
+            Some(timeout) => cmp::min(timeout.as_nanos().div_ceil(1_000_000), libc::c_int::MAX as u128)
+                as libc::c_int,
+            None => -1,
+        };
+        let mut pollfd = libc::pollfd { fd: self.inotify.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, timeout) } < 0 {
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        // Drain the queued events.
+        let mut buf = [0u8; 4096];
+        loop {
+            match (&self.inotify).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Files can not be watched on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[derive(Debug)]
+#[cfg(feature = "utilities")]
+pub enum Watch {}
+
+#[cfg(all(feature = "utilities", not(any(target_os = "linux", target_os = "android"))))]
+pub fn watch(_path: &Path) -> Result<Option<Watch>> {
+    Ok(None)
+}
+
+#[cfg(all(feature = "utilities", not(any(target_os = "linux", target_os = "android"))))]
+impl Watch {
+    pub fn wait(&self, _timeout: Option<Duration>) -> Result<()> {
+        match *self {}
+    }
+}
+
 #[cfg(test)]
 mod test {
     extern crate tempdir;
     extern crate libc;
 
+    #[cfg(feature = "statvfs")]
+    use std::ffi::{CStr, OsStr};
     use std::fs::{self, File};
+    #[cfg(any(feature = "allocate", feature = "statvfs"))]
+    use std::io::ErrorKind;
+    #[cfg(feature = "statvfs")]
+    use std::os::unix::ffi::OsStrExt;
     use std::os::unix::io::AsRawFd;
+    #[cfg(feature = "statvfs")]
+    use std::path::{Path, PathBuf};
+
+    use FileExt;
+    #[cfg(feature = "allocate")]
+    use AllocateOptions;
+    #[cfg(all(feature = "duplicate", feature = "locks"))]
+    use DuplicateOptions;
+    #[cfg(feature = "locks")]
+    use lock_contended_error;
+
+    fn is_cloexec(file: &File) -> bool {
+        unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) & libc::FD_CLOEXEC != 0 }
+    }
+
+    /// Tests setting and clearing close-on-exec.
+    #[test]
+    fn set_cloexec() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let file = fs::File::create(tempdir.path().join("fs2")).unwrap();
+        assert!(is_cloexec(&file));
+        file.set_cloexec(false).unwrap();
+        assert!(!is_cloexec(&file));
+        file.set_cloexec(true).unwrap();
+        assert!(is_cloexec(&file));
+    }
+
+    /// Tests that lock types make their files non-inheritable.
+    #[cfg(feature = "utilities")]
+    #[test]
+    fn set_inheritable() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let file = fs::File::create(tempdir.path().join("fs2")).unwrap();
+        file.set_inheritable(true).unwrap();
+        assert!(!is_cloexec(&file));
+        file.set_inheritable(false).unwrap();
+        assert!(is_cloexec(&file));
+
+        file.set_inheritable(true).unwrap();
+        let lock = ::HybridLock::new(file);
+        assert!(is_cloexec(lock.file()));
+    }
 
-    use {FileExt, lock_contended_error};
+    /// Tests that a spawned child does not inherit a locked file.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn spawn_without_locks() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.set_cloexec(false).unwrap();
+        file.lock_exclusive().unwrap();
+
+        let mut command = ::std::process::Command::new("sleep");
+        command.arg("5");
+        let mut child = ::spawn_without_locks(&mut command, &[&file]).unwrap();
+        assert!(is_cloexec(&file));
+
+        // Closing the file releases the lock although the child still runs. Had the child inherited
+        // the file, it would keep the lock.
+        drop(file);
+        let other = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let locked = other.try_lock_exclusive();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        locked.unwrap();
+    }
+
+    /// Duplicates made with `DuplicateOptions` are close-on-exec unless inheritable.
+    #[cfg(all(feature = "duplicate", feature = "locks"))]
+    #[test]
+    #[cfg_attr(feature = "strict", ignore = "unlocks through a duplicate, which strict mode rejects")]
+    fn duplicate_options() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let private = DuplicateOptions::new().duplicate(&file).unwrap();
+        assert!(is_cloexec(&private));
+        let inherited = DuplicateOptions::new().inheritable(true).duplicate(&file).unwrap();
+        assert!(!is_cloexec(&inherited));
+
+        // Duplicates share locks with the original.
+        private.lock_exclusive().unwrap();
+        let other = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        assert_eq!(other.try_lock_shared_guard().unwrap_err().kind(), lock_contended_error().kind());
+        FileExt::unlock(&file).unwrap();
+        other.try_lock_shared_guard().unwrap();
+    }
 
     /// The duplicate method returns a file with a new file descriptor.
+    #[cfg(feature = "duplicate")]
     #[test]
     fn duplicate_new_fd() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
         let file2 = file1.duplicate().unwrap();
         assert!(file1.as_raw_fd() != file2.as_raw_fd());
     }
 
     /// The duplicate method should preservesthe close on exec flag.
+    #[cfg(feature = "duplicate")]
     #[test]
     fn duplicate_cloexec() {
 
@@ -202,7 +1766,7 @@ mod test {
 
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
         let file2 = file1.duplicate().unwrap();
 
         assert_eq!(flags(&file1), flags(&file2));
@@ -210,42 +1774,81 @@ mod test {
 
     /// Tests that locking a file descriptor will replace any existing locks
     /// held on the file descriptor.
+    #[cfg(feature = "locks")]
     #[test]
+    #[cfg_attr(feature = "strict", ignore = "locks a handle which already holds a lock, which strict mode rejects")]
     fn lock_replace() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
-        let file2 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
 
         // Creating a shared lock will drop an exclusive lock.
         file1.lock_exclusive().unwrap();
-        file1.lock_shared().unwrap();
-        file2.lock_shared().unwrap();
+        FileExt::lock_shared(&file1).unwrap();
+        FileExt::lock_shared(&file2).unwrap();
 
         // Attempting to replace a shared lock with an exclusive lock will fail
         // with multiple lock holders, and remove the original shared lock.
         assert_eq!(file2.try_lock_exclusive().unwrap_err().raw_os_error(),
                    lock_contended_error().raw_os_error());
-        file1.lock_shared().unwrap();
+        FileExt::lock_shared(&file1).unwrap();
     }
 
     /// Tests that locks are shared among duplicated file descriptors.
+    #[cfg(all(feature = "duplicate", feature = "locks"))]
     #[test]
+    #[cfg_attr(feature = "strict", ignore = "locks through a duplicate, which strict mode rejects")]
     fn lock_duplicate() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
         let file2 = file1.duplicate().unwrap();
-        let file3 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let file3 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
 
         // Create a lock through fd1, then replace it through fd2.
-        file1.lock_shared().unwrap();
+        FileExt::lock_shared(&file1).unwrap();
         file2.lock_exclusive().unwrap();
-        assert_eq!(file3.try_lock_shared().unwrap_err().raw_os_error(),
+        assert_eq!(FileExt::try_lock_shared(&file3).unwrap_err().raw_os_error(),
                    lock_contended_error().raw_os_error());
 
         // Either of the file descriptors should be able to unlock.
-        file1.unlock().unwrap();
-        file3.lock_shared().unwrap();
+        FileExt::unlock(&file1).unwrap();
+        FileExt::lock_shared(&file3).unwrap();
+    }
+
+    /// Offsets and lengths which do not fit in `off_t` are rejected rather than wrapped.
+    #[cfg(feature = "allocate")]
+    #[test]
+    fn off_t_overflow() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+
+        assert_eq!(0, super::to_off_t(0).unwrap());
+        assert_eq!(ErrorKind::InvalidInput, super::to_off_t(u64::MAX).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, super::allocate(&file, u64::MAX, &AllocateOptions::new()).unwrap_err().kind());
+        // The public API rejects the length before it reaches the system call.
+        assert_eq!(ErrorKind::FileTooLarge, file.allocate(u64::MAX).unwrap_err().kind());
+        assert_eq!(0, file.metadata().unwrap().len());
+    }
+
+    /// Paths are converted to C strings whether or not they fit on the stack,
+    /// and interior nul bytes are rejected.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn c_path_conversion() {
+        let short = Path::new("/tmp");
+        let long = PathBuf::from(format!("/{}", "a".repeat(super::MAX_STACK_PATH)));
+        for path in &[short, long.as_path()] {
+            let converted = super::with_c_path(path, |cstr| Ok(cstr.to_bytes().to_vec())).unwrap();
+            assert_eq!(path.as_os_str().as_bytes(), &converted[..]);
+        }
+
+        let nul = Path::new(OsStr::from_bytes(b"/tmp\0"));
+        assert_eq!(ErrorKind::InvalidInput,
+                   super::with_c_path(nul, |_| Ok(())).unwrap_err().kind());
+        let stats = ::statvfs_cstr(CStr::from_bytes_with_nul(b"/\0").unwrap()).unwrap();
+        assert_eq!(::statvfs("/").unwrap().total_space(), stats.total_space());
     }
 }
@@ -0,0 +1,324 @@
+//! Fallback implementation for platforms without a supported file API. Every
+//! operation fails with `ErrorKind::Unsupported`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+#[cfg(any(feature = "statvfs", feature = "utilities"))]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "utilities")]
+use std::time::{Duration, SystemTime};
+
+use {Capabilities, FileId};
+#[cfg(feature = "allocate")]
+use {AllocateOptions, Extent};
+#[cfg(feature = "statvfs")]
+use {FsStats, FsStatsExt};
+#[cfg(feature = "locks")]
+use {LockBackend, LockHolder, LockMode, UpgradeError};
+
+fn unsupported() -> Error {
+    Error::new(ErrorKind::Unsupported, "operation is not supported on this platform")
+}
+
+#[cfg(feature = "duplicate")]
+pub fn duplicate(_file: &File) -> Result<File> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "duplicate")]
+pub fn duplicate_with(_file: &File, _inheritable: bool) -> Result<File> {
+    Err(unsupported())
+}
+
+pub fn set_inheritable(_file: &File, _inheritable: bool) -> Result<()> {
+    Err(unsupported())
+}
+
+pub fn reopen(_file: &File, _options: &OpenOptions) -> Result<File> {
+    Err(unsupported())
+}
+
+pub fn path(_file: &File) -> Result<PathBuf> {
+    Err(unsupported())
+}
+
+pub fn file_id(_file: &File) -> Result<FileId> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_shared(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_exclusive(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn try_lock_shared(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn try_lock_exclusive(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn unlock(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn lock_range(_file: &File, _offset: u64, _len: u64, _mode: LockMode, _block: bool) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn unlock_range(_file: &File, _offset: u64, _len: u64) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_holder(_file: &File) -> Result<Option<LockHolder>> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn downgrade(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn try_upgrade(_file: &File) -> ::std::result::Result<(), UpgradeError> {
+    Err((unsupported(), true))
+}
+
+#[cfg(feature = "locks")]
+pub fn downgrade_range(_file: &File, _offset: u64, _len: u64, _backend: LockBackend) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn try_upgrade_range(_file: &File, _offset: u64, _len: u64,
+                         _backend: LockBackend) -> ::std::result::Result<(), UpgradeError> {
+    Err((unsupported(), true))
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_backend(_file: &File, _backend: LockBackend, _whole_file: bool) -> Result<LockBackend> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_range_with(_file: &File, _offset: u64, _len: u64, _mode: LockMode, _block: bool,
+                       _backend: LockBackend) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn unlock_range_with(_file: &File, _offset: u64, _len: u64, _backend: LockBackend) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_error() -> Error {
+    Error::new(ErrorKind::WouldBlock, "file is locked")
+}
+
+#[cfg(feature = "allocate")]
+pub fn allocated_size(_file: &File) -> Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn reflink(_src: &File, _dst: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn copy_file_range(_src: &File, _dst: &File, _offset: u64, _len: u64) -> Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn sendfile_to_file(_src: &File, _dst: &File, _offset: u64, _len: u64) -> Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn is_copy_unsupported(err: &Error) -> bool {
+    err.kind() == ErrorKind::Unsupported
+}
+
+#[cfg(feature = "allocate")]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn release_tail(_file: &File, _offset: u64) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn allocate(_file: &File, _len: u64, _options: &AllocateOptions) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn extents(_file: &File) -> Result<Vec<Extent>> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn is_sparse(_file: &File) -> Result<bool> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn mark_sparse(_file: &File) -> Result<()> {
+    Err(unsupported())
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        duplicate: false,
+        lock: false,
+        allocate: false,
+        extents: false,
+        zero_copy_send: false,
+        statvfs: false,
+    }
+}
+
+#[cfg(feature = "utilities")]
+pub fn open_tmpfile(_dir: &Path) -> Result<Option<File>> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn create_deleted(_path: &Path) -> Result<File> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "utilities")]
+pub fn link_tmpfile(_file: &File, _path: &Path) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "statvfs")]
+pub type VolumeKey = ();
+
+#[cfg(feature = "statvfs")]
+pub fn volume_key(_path: &Path) -> Result<VolumeKey> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "statvfs")]
+pub fn is_network_fs(_path: &Path) -> Result<bool> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+pub fn is_network_fs_file(_file: &File) -> Result<bool> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn max_file_size(_file: &File) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(feature = "allocate")]
+pub fn file_size_limit() -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(feature = "utilities")]
+pub fn hostname() -> Option<String> {
+    None
+}
+
+#[cfg(feature = "utilities")]
+pub fn user_name() -> Option<String> {
+    None
+}
+
+#[cfg(feature = "utilities")]
+pub fn process_start_time(_pid: u32) -> Result<Option<SystemTime>> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs(_path: &Path) -> Result<FsStats> {
+    Err(unsupported())
+}
+
+/// A path encoded once for repeated `statvfs` queries.
+#[cfg(feature = "statvfs")]
+pub type StatvfsPath = ();
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_path(_path: &Path) -> Result<StatvfsPath> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_encoded(_path: &StatvfsPath) -> Result<FsStats> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "statvfs")]
+pub fn fstatvfs(_file: &File) -> Result<FsStats> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "allocate")]
+pub fn allocation_granularity(_file: &File) -> Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_ext(_path: &Path) -> Result<FsStatsExt> {
+    Err(unsupported())
+}
+
+#[derive(Debug)]
+#[cfg(feature = "utilities")]
+pub enum Watch {}
+
+#[cfg(feature = "utilities")]
+pub fn watch(_path: &Path) -> Result<Option<Watch>> {
+    Ok(None)
+}
+
+#[cfg(feature = "utilities")]
+impl Watch {
+    pub fn wait(&self, _timeout: Option<Duration>) -> Result<()> {
+        match *self {}
+    }
+}
+
+#[derive(Debug)]
+#[cfg(feature = "locks")]
+pub enum Mmap {}
+
+#[cfg(feature = "locks")]
+pub fn map(_file: &File, _len: usize, _writable: bool) -> Result<Mmap> {
+    Err(unsupported())
+}
+
+#[cfg(feature = "locks")]
+impl Mmap {
+    pub fn as_ptr(&self) -> *mut u8 {
+        match *self {}
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        match *self {}
+    }
+}
@@ -1,25 +1,172 @@
-use std::fs::File;
-use std::io::{Error, Result};
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
 use std::mem;
-use std::os::windows::ffi::OsStrExt;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::fs::MetadataExt;
 use std::os::windows::io::{AsRawHandle, FromRawHandle};
-use std::path::Path;
+#[cfg(feature = "utilities")]
+pub use std::os::windows::io::RawSocket;
+use std::path::{Path, PathBuf};
 use std::ptr;
+#[cfg(feature = "utilities")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use winapi::shared::minwindef::{BOOL, DWORD};
-use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
-use winapi::um::fileapi::{FILE_ALLOCATION_INFO, FILE_STANDARD_INFO, GetDiskFreeSpaceW};
-use winapi::um::fileapi::{GetVolumePathNameW, LockFileEx, UnlockFile, SetFileInformationByHandle};
+use winapi::shared::minwindef::{BOOL, DWORD, MAX_PATH};
+use winapi::um::fileapi::{BY_HANDLE_FILE_INFORMATION, GetFileInformationByHandle};
+use winapi::um::fileapi::{GetFinalPathNameByHandleW, GetFullPathNameW};
+use winapi::um::fileapi::{GetVolumeInformationW, GetVolumePathNameW};
+use winapi::um::fileapi::GetVolumeInformationByHandleW;
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE, SetHandleInformation};
+use winapi::um::winbase::FILE_FLAG_BACKUP_SEMANTICS;
+use winapi::um::winbase::{HANDLE_FLAG_INHERIT, ReOpenFile};
+use winapi::um::winnt::{FILE_READ_ATTRIBUTES, HANDLE};
+
+#[cfg(feature = "allocate")]
+use winapi::shared::winerror::ERROR_MORE_DATA;
+#[cfg(feature = "allocate")]
+use winapi::um::fileapi::{FILE_ALLOCATION_INFO, FILE_END_OF_FILE_INFO, FILE_STANDARD_INFO};
+#[cfg(feature = "allocate")]
+use winapi::um::fileapi::SetFileInformationByHandle;
+#[cfg(feature = "allocate")]
+use winapi::um::minwinbase::{FileAllocationInfo, FileCompressionInfo, FileEndOfFileInfo, FileStandardInfo};
+#[cfg(feature = "allocate")]
+use winapi::um::winbase::GetFileInformationByHandleEx;
+#[cfg(feature = "allocate")]
+use winapi::um::winnt::{FILE_ATTRIBUTE_COMPRESSED, FILE_ATTRIBUTE_SPARSE_FILE};
+#[cfg(any(feature = "allocate", feature = "statvfs", feature = "utilities"))]
+use winapi::um::fileapi::GetDiskFreeSpaceW;
+#[cfg(any(feature = "allocate", feature = "utilities"))]
+use winapi::um::ioapiset::DeviceIoControl;
+#[cfg(feature = "statvfs")]
+use winapi::shared::ntdef::ULARGE_INTEGER;
+#[cfg(feature = "statvfs")]
+use winapi::um::fileapi::GetDiskFreeSpaceExW;
+#[cfg(any(feature = "locks", feature = "utilities"))]
+use winapi::um::minwinbase::OVERLAPPED;
+#[cfg(feature = "utilities")]
+use winapi::shared::winerror::{ERROR_INVALID_FUNCTION, ERROR_INVALID_PARAMETER, ERROR_IO_PENDING};
+#[cfg(feature = "utilities")]
+use winapi::shared::winerror::{ERROR_NOT_SAME_DEVICE, ERROR_NOT_SUPPORTED};
+#[cfg(feature = "utilities")]
+use winapi::um::ioapiset::GetOverlappedResult;
+#[cfg(feature = "utilities")]
+use winapi::um::mswsock::TransmitFile;
+#[cfg(feature = "utilities")]
+use winapi::um::winbase::FILE_FLAG_DELETE_ON_CLOSE;
+#[cfg(feature = "utilities")]
+use winapi::um::winnt::FILE_ATTRIBUTE_TEMPORARY;
+#[cfg(feature = "utilities")]
+use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+#[cfg(feature = "utilities")]
+use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+#[cfg(feature = "utilities")]
+use winapi::um::winsock2::SOCKET;
+#[cfg(any(feature = "locks", feature = "utilities"))]
+use winapi::shared::winerror::ERROR_ACCESS_DENIED;
+#[cfg(any(feature = "duplicate", feature = "locks"))]
 use winapi::um::handleapi::DuplicateHandle;
-use winapi::um::minwinbase::{FileAllocationInfo, FileStandardInfo};
-use winapi::um::minwinbase::{LOCKFILE_FAIL_IMMEDIATELY, LOCKFILE_EXCLUSIVE_LOCK};
+#[cfg(any(feature = "duplicate", feature = "locks"))]
 use winapi::um::processthreadsapi::GetCurrentProcess;
-use winapi::um::winbase::GetFileInformationByHandleEx;
+#[cfg(any(feature = "duplicate", feature = "locks"))]
 use winapi::um::winnt::DUPLICATE_SAME_ACCESS;
+#[cfg(any(feature = "locks", feature = "statvfs"))]
+use winapi::um::fileapi::GetDriveTypeW;
+#[cfg(any(feature = "locks", feature = "statvfs"))]
+use winapi::um::winbase::DRIVE_REMOTE;
+#[cfg(feature = "locks")]
+use winapi::shared::minwindef::LPVOID;
+#[cfg(feature = "locks")]
+use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+#[cfg(feature = "locks")]
+use winapi::um::fileapi::{LockFileEx, UnlockFile, UnlockFileEx};
+#[cfg(feature = "locks")]
+use winapi::um::memoryapi::{CreateFileMappingW, FILE_MAP_READ, FILE_MAP_WRITE, FlushViewOfFile};
+#[cfg(feature = "locks")]
+use winapi::um::memoryapi::{MapViewOfFile, UnmapViewOfFile};
+#[cfg(feature = "locks")]
+use winapi::um::minwinbase::{LOCKFILE_FAIL_IMMEDIATELY, LOCKFILE_EXCLUSIVE_LOCK};
+#[cfg(feature = "locks")]
+use winapi::um::winnt::{PAGE_READONLY, PAGE_READWRITE};
+#[cfg(feature = "utilities")]
+use winapi::shared::minwindef::FILETIME;
+#[cfg(feature = "utilities")]
+use winapi::um::minwinbase::STILL_ACTIVE;
+#[cfg(feature = "utilities")]
+use winapi::um::processthreadsapi::{GetExitCodeProcess, GetProcessTimes, OpenProcess};
+#[cfg(feature = "utilities")]
+use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+use {Capabilities, FileId};
+#[cfg(any(feature = "allocate", feature = "statvfs"))]
+use {fs_kind, FsKind};
+#[cfg(any(feature = "allocate", feature = "utilities"))]
+use Bytes;
+#[cfg(feature = "allocate")]
+use {positional, AllocateOptions, Extent};
+#[cfg(feature = "statvfs")]
+use {FsStats, FsStatsExt};
+#[cfg(feature = "locks")]
+use {LockBackend, LockHolder, LockMode, UpgradeError};
 
-use FsStats;
+#[cfg(feature = "allocate")]
+const FSCTL_QUERY_ALLOCATED_RANGES: DWORD = 0x0009_40cf;
+#[cfg(feature = "allocate")]
+const FSCTL_SET_SPARSE: DWORD = 0x0009_00c4;
+#[cfg(feature = "allocate")]
+const FSCTL_SET_ZERO_DATA: DWORD = 0x0009_80c8;
+#[cfg(feature = "utilities")]
+const FSCTL_DUPLICATE_EXTENTS_TO_FILE: DWORD = 0x0009_8344;
+/// Returned when a cluster would be shared too many times.
+#[cfg(feature = "utilities")]
+const ERROR_BLOCK_TOO_MANY_REFERENCES: DWORD = 347;
+
+#[cfg(feature = "allocate")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileAllocatedRangeBuffer {
+    file_offset: i64,
+    length: i64,
+}
 
+/// `FILE_ZERO_DATA_INFORMATION`.
+#[cfg(feature = "allocate")]
+#[repr(C)]
+struct FileZeroDataInformation {
+    file_offset: i64,
+    beyond_final_zero: i64,
+}
+
+/// `DUPLICATE_EXTENTS_DATA`.
+#[cfg(feature = "utilities")]
+#[repr(C)]
+struct DuplicateExtentsData {
+    file_handle: HANDLE,
+    source_file_offset: i64,
+    target_file_offset: i64,
+    byte_count: i64,
+}
+
+/// `FILE_COMPRESSION_INFO`.
+#[cfg(feature = "allocate")]
+#[repr(C)]
+struct FileCompressionInfoBuffer {
+    compressed_file_size: i64,
+    compression_format: u16,
+    compression_unit_shift: u8,
+    chunk_shift: u8,
+    cluster_shift: u8,
+    reserved: [u8; 3],
+}
+
+#[cfg(any(feature = "duplicate", feature = "locks"))]
 pub fn duplicate(file: &File) -> Result<File> {
+    duplicate_with(file, true)
+}
+
+#[cfg(any(feature = "duplicate", feature = "locks"))]
+pub fn duplicate_with(file: &File, inheritable: bool) -> Result<File> {
     unsafe {
         let mut handle = ptr::null_mut();
         let current_process = GetCurrentProcess();
@@ -28,7 +175,7 @@ pub fn duplicate(file: &File) -> Result<File> {
                                   current_process,
                                   &mut handle,
                                   0,
-                                  true as BOOL,
+                                  inheritable as BOOL,
                                   DUPLICATE_SAME_ACCESS);
         if ret == 0 {
             Err(Error::last_os_error())
@@ -38,62 +185,187 @@ pub fn duplicate(file: &File) -> Result<File> {
     }
 }
 
+pub fn set_inheritable(file: &File, inheritable: bool) -> Result<()> {
+    let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+    let ret = unsafe { SetHandleInformation(file.as_raw_handle(), HANDLE_FLAG_INHERIT, flags) };
+    if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+pub fn reopen(file: &File, options: &OpenOptions) -> Result<File> {
+    // std does not expose the options it opens files with, so they can not be passed to
+    // ReOpenFile, and the file is opened through its current path instead.
+    options.open(path(file)?)
+}
+
+/// Reopens the handle with `ReOpenFile`.
+pub fn reopen_handle(file: &File, access: DWORD, share_mode: DWORD, flags: DWORD) -> Result<File> {
+    let handle = unsafe { ReOpenFile(file.as_raw_handle(), access, share_mode, flags) };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_handle(handle) })
+}
+
+pub fn path(file: &File) -> Result<PathBuf> {
+    let mut buf = vec![0u16; MAX_STACK_PATH];
+    loop {
+        let ret = unsafe {
+            GetFinalPathNameByHandleW(file.as_raw_handle(), buf.as_mut_ptr(), buf.len() as DWORD, 0)
+        } as usize;
+        if ret == 0 {
+            return Err(Error::last_os_error());
+        } else if ret < buf.len() {
+            // On success the returned length excludes the terminating nul.
+            buf.truncate(ret);
+            return Ok(OsString::from_wide(&buf).into());
+        }
+        // The buffer is too small, and the returned length includes the terminating nul.
+        buf.resize(ret, 0);
+    }
+}
+
+pub fn file_id(file: &File) -> Result<FileId> {
+    unsafe {
+        let mut info: BY_HANDLE_FILE_INFORMATION = mem::zeroed();
+        if GetFileInformationByHandle(file.as_raw_handle(), &mut info) == 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(FileId {
+                device: info.dwVolumeSerialNumber as u64,
+                index: ((info.nFileIndexHigh as u64) << 32) | info.nFileIndexLow as u64,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "allocate")]
 pub fn allocated_size(file: &File) -> Result<u64> {
+    // The allocation size of compressed and sparse files counts the clusters spanned by the file,
+    // rather than those stored on disk; the compressed file size reflects actual usage.
+    let attributes = file.metadata()?.file_attributes();
+    if attributes & (FILE_ATTRIBUTE_COMPRESSED | FILE_ATTRIBUTE_SPARSE_FILE) != 0 {
+        return compressed_file_size(file);
+    }
+
+    let info = standard_info(file)?;
+    Ok(Bytes::from_i64(unsafe { *info.AllocationSize.QuadPart() })?.as_u64())
+}
+
+#[cfg(feature = "allocate")]
+fn compressed_file_size(file: &File) -> Result<u64> {
     unsafe {
-        let mut info: FILE_STANDARD_INFO = mem::zeroed();
+        let mut info: FileCompressionInfoBuffer = mem::zeroed();
 
         let ret = GetFileInformationByHandleEx(
             file.as_raw_handle(),
-            FileStandardInfo,
+            FileCompressionInfo,
             &mut info as *mut _ as *mut _,
-            mem::size_of::<FILE_STANDARD_INFO>() as DWORD);
+            mem::size_of::<FileCompressionInfoBuffer>() as DWORD);
 
         if ret == 0 {
             Err(Error::last_os_error())
         } else {
-            Ok(*info.AllocationSize.QuadPart() as u64)
+            Ok(Bytes::from_i64(info.compressed_file_size)?.as_u64())
         }
     }
 }
 
-pub fn allocate(file: &File, len: u64) -> Result<()> {
-    if try!(allocated_size(file)) < len {
+#[cfg(feature = "allocate")]
+pub fn release_tail(file: &File, offset: u64) -> Result<()> {
+    // Setting the allocation size releases clusters reserved past it.
+    set_allocation_size(file, offset)
+}
+
+#[cfg(feature = "allocate")]
+pub fn allocate(file: &File, len: u64, _options: &AllocateOptions) -> Result<()> {
+    // Neither size is set through the file pointer, but restore it should the file system move
+    // it, so that writers sharing the handle are unaffected.
+    positional::preserving_position(file, || reserve(file, len))
+}
+
+/// Reserves clusters for `len` bytes, then extends the end of file to `len`.
+///
+/// Both sizes are only ever grown, and each is read immediately before it is set: an allocation
+/// size below the end of file truncates the file, so a stale size could discard data appended by
+/// a concurrent writer. The valid data length is left alone, so the reserved clusters are not
+/// zeroed until they are written.
+#[cfg(feature = "allocate")]
+fn reserve(file: &File, len: u64) -> Result<()> {
+    let info = standard_info(file)?;
+    let allocation_size = Bytes::from_i64(unsafe { *info.AllocationSize.QuadPart() })?.as_u64();
+    if allocation_size < len {
+        let end = Bytes::from_i64(unsafe { *info.EndOfFile.QuadPart() })?.as_u64();
+        set_allocation_size(file, cmp::max(len, end))?;
+    }
+
+    let info = standard_info(file)?;
+    if Bytes::from_i64(unsafe { *info.EndOfFile.QuadPart() })?.as_u64() < len {
+        let len = Bytes::new(len).to_i64()?;
         unsafe {
-            let mut info: FILE_ALLOCATION_INFO = mem::zeroed();
-            *info.AllocationSize.QuadPart_mut() = len as i64;
+            let mut info: FILE_END_OF_FILE_INFO = mem::zeroed();
+            *info.EndOfFile.QuadPart_mut() = len;
             let ret = SetFileInformationByHandle(
                 file.as_raw_handle(),
-                FileAllocationInfo,
+                FileEndOfFileInfo,
                 &mut info as *mut _ as *mut _,
-                mem::size_of::<FILE_ALLOCATION_INFO>() as DWORD);
+                mem::size_of::<FILE_END_OF_FILE_INFO>() as DWORD);
             if ret == 0 {
                 return Err(Error::last_os_error());
             }
         }
     }
-    if try!(file.metadata()).len() < len {
-        file.set_len(len)
-    } else {
-        Ok(())
+    Ok(())
+}
+
+#[cfg(feature = "allocate")]
+fn standard_info(file: &File) -> Result<FILE_STANDARD_INFO> {
+    unsafe {
+        let mut info: FILE_STANDARD_INFO = mem::zeroed();
+        let ret = GetFileInformationByHandleEx(
+            file.as_raw_handle(),
+            FileStandardInfo,
+            &mut info as *mut _ as *mut _,
+            mem::size_of::<FILE_STANDARD_INFO>() as DWORD);
+        if ret == 0 { Err(Error::last_os_error()) } else { Ok(info) }
+    }
+}
+
+#[cfg(feature = "allocate")]
+fn set_allocation_size(file: &File, len: u64) -> Result<()> {
+    let len = Bytes::new(len).to_i64()?;
+    unsafe {
+        let mut info: FILE_ALLOCATION_INFO = mem::zeroed();
+        *info.AllocationSize.QuadPart_mut() = len;
+        let ret = SetFileInformationByHandle(
+            file.as_raw_handle(),
+            FileAllocationInfo,
+            &mut info as *mut _ as *mut _,
+            mem::size_of::<FILE_ALLOCATION_INFO>() as DWORD);
+        if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
     }
 }
 
+#[cfg(feature = "locks")]
 pub fn lock_shared(file: &File) -> Result<()> {
     lock_file(file, 0)
 }
 
+#[cfg(feature = "locks")]
 pub fn lock_exclusive(file: &File) -> Result<()> {
     lock_file(file, LOCKFILE_EXCLUSIVE_LOCK)
 }
 
+#[cfg(feature = "locks")]
 pub fn try_lock_shared(file: &File) -> Result<()> {
     lock_file(file, LOCKFILE_FAIL_IMMEDIATELY)
 }
 
+#[cfg(feature = "locks")]
 pub fn try_lock_exclusive(file: &File) -> Result<()> {
     lock_file(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY)
 }
 
+#[cfg(feature = "locks")]
 pub fn unlock(file: &File) -> Result<()> {
     unsafe {
         let ret = UnlockFile(file.as_raw_handle(), 0, 0, !0, !0);
@@ -101,56 +373,802 @@ pub fn unlock(file: &File) -> Result<()> {
     }
 }
 
+#[cfg(feature = "locks")]
+pub fn lock_holder(_file: &File) -> Result<Option<LockHolder>> {
+    Err(Error::new(ErrorKind::Unsupported, "lock holders cannot be determined on Windows"))
+}
+
+#[cfg(feature = "locks")]
+pub fn downgrade(file: &File) -> Result<()> {
+    // Windows locks stack: take a shared lock alongside the exclusive lock, then release the
+    // exclusive lock, which is unlocked first.
+    lock_shared(file)?;
+    unlock(file)
+}
+
+#[cfg(feature = "locks")]
+pub fn try_upgrade(file: &File) -> ::std::result::Result<(), UpgradeError> {
+    // An exclusive lock conflicts with a shared lock held through the same handle, so the shared
+    // lock must be released first.
+    unlock(file).map_err(|error| (error, true))?;
+    try_lock_exclusive(file).map_err(|error| match lock_shared(file) {
+        Ok(()) => (error, true),
+        Err(relock) => (relock, false),
+    })
+}
+
+#[cfg(feature = "locks")]
+pub fn downgrade_range(file: &File, offset: u64, len: u64, _backend: LockBackend) -> Result<()> {
+    lock_range(file, offset, len, LockMode::Shared, true)?;
+    unlock_range(file, offset, len)
+}
+
+#[cfg(feature = "locks")]
+pub fn try_upgrade_range(file: &File, offset: u64, len: u64,
+                         _backend: LockBackend) -> ::std::result::Result<(), UpgradeError> {
+    unlock_range(file, offset, len).map_err(|error| (error, true))?;
+    lock_range(file, offset, len, LockMode::Exclusive, false).map_err(|error| {
+        match lock_range(file, offset, len, LockMode::Shared, true) {
+            Ok(()) => (error, true),
+            Err(relock) => (relock, false),
+        }
+    })
+}
+
+/// Returns the error for a contended non-blocking lock. `LockFileEx` fails
+/// with `ERROR_LOCK_VIOLATION`, which the standard library does not
+/// categorize, so the error is given the `WouldBlock` kind, as on Unix.
+#[cfg(feature = "locks")]
 pub fn lock_error() -> Error {
-    Error::from_raw_os_error(ERROR_LOCK_VIOLATION as i32)
+    Error::new(ErrorKind::WouldBlock, "the file is locked (ERROR_LOCK_VIOLATION)")
 }
 
-fn lock_file(file: &File, flags: DWORD) -> Result<()> {
+/// Locks the byte range `offset..offset + len` of the file.
+#[cfg(feature = "locks")]
+pub fn lock_range(file: &File, offset: u64, len: u64, mode: LockMode, block: bool) -> Result<()> {
+    let mut flags = 0;
+    if mode == LockMode::Exclusive {
+        flags |= LOCKFILE_EXCLUSIVE_LOCK;
+    }
+    if !block {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    lock_file_range(file, flags, offset, len)
+}
+
+/// Unlocks the byte range `offset..offset + len` of the file.
+#[cfg(feature = "locks")]
+pub fn unlock_range(file: &File, offset: u64, len: u64) -> Result<()> {
     unsafe {
-        let mut overlapped = mem::zeroed();
-        let ret = LockFileEx(file.as_raw_handle(), flags, 0, !0, !0, &mut overlapped);
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        overlapped.u.s_mut().Offset = offset as DWORD;
+        overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWORD;
+        let ret = UnlockFileEx(file.as_raw_handle(), 0, len as DWORD, (len >> 32) as DWORD, &mut overlapped);
         if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
     }
 }
 
-fn volume_path(path: &Path, volume_path: &mut [u16]) -> Result<()> {
-    let path_utf8: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+/// Windows has a single kind of lock: whole files use the whole-file path, and ranges ignore the
+/// backend.
+#[cfg(feature = "locks")]
+pub fn lock_backend(_file: &File, _backend: LockBackend, whole_file: bool) -> Result<LockBackend> {
+    Ok(if whole_file { LockBackend::Flock } else { LockBackend::Auto })
+}
+
+#[cfg(feature = "locks")]
+pub fn lock_range_with(file: &File, offset: u64, len: u64, mode: LockMode, block: bool,
+                       _backend: LockBackend) -> Result<()> {
+    lock_range(file, offset, len, mode, block)
+}
+
+#[cfg(feature = "locks")]
+pub fn unlock_range_with(file: &File, offset: u64, len: u64, _backend: LockBackend) -> Result<()> {
+    unlock_range(file, offset, len)
+}
+
+#[cfg(feature = "locks")]
+fn lock_file(file: &File, flags: DWORD) -> Result<()> {
+    lock_file_range(file, flags, 0, !0)
+}
+
+/// Locks the byte range `offset..offset + len` of the file with `LockFileEx` and the given flags.
+#[cfg(feature = "locks")]
+pub fn lock_file_range(file: &File, flags: DWORD, offset: u64, len: u64) -> Result<()> {
     unsafe {
-        let ret = GetVolumePathNameW(path_utf8.as_ptr(),
-                                     volume_path.as_mut_ptr(),
-                                     volume_path.len() as DWORD);
-        if ret == 0 { Err(Error::last_os_error()) } else { Ok(())
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        overlapped.u.s_mut().Offset = offset as DWORD;
+        overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWORD;
+        let ret = LockFileEx(file.as_raw_handle(), flags, 0,
+                             len as DWORD, (len >> 32) as DWORD, &mut overlapped);
+        if ret == 0 {
+            let err = Error::last_os_error();
+            // LockFileEx requires read or write data access to the file, which flock(2) does
+            // not. Explain the failure rather than returning a bare access denied error.
+            if err.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) {
+                Err(Error::new(ErrorKind::PermissionDenied,
+                               "file locking requires a handle opened with read or write access"))
+            } else if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+                Err(lock_error())
+            } else {
+                Err(err)
+            }
+        } else {
+            Ok(())
         }
     }
 }
 
-pub fn statvfs(path: &Path) -> Result<FsStats> {
-    let root_path: &mut [u16] = &mut [0; 261];
-    try!(volume_path(path, root_path));
+#[cfg(feature = "allocate")]
+pub fn extents(file: &File) -> Result<Vec<Extent>> {
+    let len = file.metadata()?.len();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    let mut ranges = [FileAllocatedRangeBuffer { file_offset: 0, length: 0 }; 64];
+
+    while offset < len {
+        let query = FileAllocatedRangeBuffer { file_offset: Bytes::new(offset).to_i64()?,
+                                               length: Bytes::new(len - offset).to_i64()? };
+        let mut bytes_returned = 0;
+        let ret = unsafe {
+            DeviceIoControl(file.as_raw_handle(),
+                            FSCTL_QUERY_ALLOCATED_RANGES,
+                            &query as *const _ as *mut _,
+                            mem::size_of::<FileAllocatedRangeBuffer>() as DWORD,
+                            ranges.as_mut_ptr() as *mut _,
+                            mem::size_of_val(&ranges) as DWORD,
+                            &mut bytes_returned,
+                            ptr::null_mut())
+        };
+        let more_data = if ret == 0 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_MORE_DATA as i32) {
+                return Err(err);
+            }
+            true
+        } else {
+            false
+        };
+
+        let count = bytes_returned as usize / mem::size_of::<FileAllocatedRangeBuffer>();
+        for range in &ranges[..count] {
+            let start = Bytes::from_i64(range.file_offset)?;
+            let end = start.checked_add(Bytes::from_i64(range.length)?).map_or(len, |end| end.as_u64().min(len));
+            let start = start.as_u64();
+            if start > offset {
+                extents.push(Extent { offset, len: start - offset, is_hole: true });
+            }
+            extents.push(Extent { offset: start, len: end - start, is_hole: false });
+            offset = end;
+        }
+
+        if !more_data || count == 0 {
+            break;
+        }
+    }
+
+    if offset < len {
+        extents.push(Extent { offset, len: len - offset, is_hole: true });
+    }
+    Ok(extents)
+}
+
+#[cfg(feature = "allocate")]
+pub fn is_sparse(file: &File) -> Result<bool> {
+    file.metadata().map(|m| m.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0)
+}
+
+#[cfg(feature = "allocate")]
+pub fn mark_sparse(file: &File) -> Result<()> {
+    let mut bytes_returned = 0;
+    let ret = unsafe {
+        DeviceIoControl(file.as_raw_handle(),
+                        FSCTL_SET_SPARSE,
+                        ptr::null_mut(),
+                        0,
+                        ptr::null_mut(),
+                        0,
+                        &mut bytes_returned,
+                        ptr::null_mut())
+    };
+    if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(feature = "allocate")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = offset.checked_add(len).ok_or_else(|| {
+        Error::new(ErrorKind::InvalidInput, "file offset or length is too large")
+    })?;
+    let info = FileZeroDataInformation {
+        file_offset: Bytes::new(offset).to_i64()?,
+        beyond_final_zero: Bytes::new(end).to_i64()?,
+    };
+    let mut bytes_returned = 0;
+    let ret = unsafe {
+        DeviceIoControl(file.as_raw_handle(),
+                        FSCTL_SET_ZERO_DATA,
+                        &info as *const _ as *mut _,
+                        mem::size_of::<FileZeroDataInformation>() as DWORD,
+                        ptr::null_mut(),
+                        0,
+                        &mut bytes_returned,
+                        ptr::null_mut())
+    };
+    if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+/// Clones the contents of `src` into `dst` by sharing clusters, which is supported by ReFS.
+#[cfg(feature = "utilities")]
+pub fn reflink(src: &File, dst: &File) -> Result<()> {
+    let len = src.metadata()?.len();
+    // The byte count must be a multiple of the cluster size, and may extend past the end of the
+    // source file. The target must already be long enough to hold the range.
+    let cluster = cmp::max(allocation_granularity(src)?, 1);
+    let byte_count = Bytes::new(len).checked_round_up(cluster).ok_or_else(|| {
+        Error::new(ErrorKind::InvalidInput, "file is too large to clone")
+    })?.as_u64();
+    dst.set_len(byte_count)?;
+    let data = DuplicateExtentsData {
+        file_handle: src.as_raw_handle(),
+        source_file_offset: 0,
+        target_file_offset: 0,
+        byte_count: Bytes::new(byte_count).to_i64()?,
+    };
+    let mut bytes_returned = 0;
+    let ret = unsafe {
+        DeviceIoControl(dst.as_raw_handle(),
+                        FSCTL_DUPLICATE_EXTENTS_TO_FILE,
+                        &data as *const _ as *mut _,
+                        mem::size_of::<DuplicateExtentsData>() as DWORD,
+                        ptr::null_mut(),
+                        0,
+                        &mut bytes_returned,
+                        ptr::null_mut())
+    };
+    let result = if ret == 0 { Err(Error::last_os_error()) } else { Ok(()) };
+    // Trim the cluster padding, or the length set above if the clone failed.
+    dst.set_len(if result.is_ok() { len } else { 0 })?;
+    result
+}
+
+#[cfg(feature = "utilities")]
+pub fn copy_file_range(_src: &File, _dst: &File, _offset: u64, _len: u64) -> Result<u64> {
+    Err(Error::new(ErrorKind::Unsupported, "copy_file_range is not supported on Windows"))
+}
+
+#[cfg(feature = "utilities")]
+pub fn sendfile_to_file(_src: &File, _dst: &File, _offset: u64, _len: u64) -> Result<u64> {
+    Err(Error::new(ErrorKind::Unsupported, "sendfile between files is not supported on Windows"))
+}
+
+/// Returns `true` if a copy failed because the files or volume do not support the method, rather
+/// than because of an I/O error.
+#[cfg(feature = "utilities")]
+pub fn is_copy_unsupported(err: &Error) -> bool {
+    match err.raw_os_error().map(|code| code as DWORD) {
+        Some(ERROR_INVALID_FUNCTION) | Some(ERROR_NOT_SUPPORTED) | Some(ERROR_NOT_SAME_DEVICE)
+            | Some(ERROR_INVALID_PARAMETER) | Some(ERROR_BLOCK_TOO_MANY_REFERENCES) => true,
+        _ => err.kind() == ErrorKind::Unsupported,
+    }
+}
+
+#[cfg(feature = "utilities")]
+pub fn send_to_socket(file: &File, socket: RawSocket, offset: u64, len: u64) -> Result<u64> {
+    let file_len = file.metadata()?.len();
+    if offset >= file_len {
+        return Ok(0);
+    }
+    // TransmitFile transfers at most 2^31 - 2 bytes per call.
+    let count = cmp::min(cmp::min(len, file_len - offset), 0x7fff_fffe);
+
+    // The offset is passed in the OVERLAPPED structure, so the file position,
+    // which other users of the handle may rely on, is never moved.
     unsafe {
+        let event = CreateEventW(ptr::null_mut(), 1, 0, ptr::null());
+        if event.is_null() {
+            return Err(Error::last_os_error());
+        }
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        overlapped.u.s_mut().Offset = offset as DWORD;
+        overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWORD;
+        // Setting the low bit of the event keeps the completion off any I/O
+        // completion port the socket is associated with.
+        overlapped.hEvent = (event as usize | 1) as HANDLE;
+        let ret = TransmitFile(socket as SOCKET,
+                               file.as_raw_handle(),
+                               count as DWORD,
+                               0,
+                               &mut overlapped,
+                               ptr::null_mut(),
+                               0);
+        let mut result = if ret == 0 { Err(Error::last_os_error()) } else { Ok(count) };
+        if let Err(ref err) = result {
+            if err.raw_os_error() == Some(ERROR_IO_PENDING as i32) {
+                result = wait_overlapped(socket as HANDLE, event, &mut overlapped).map(u64::from);
+            }
+        }
+        CloseHandle(event);
+        result
+    }
+}
+
+/// Waits for the overlapped operation on `handle` to complete, signalling
+/// `event`, and returns the number of bytes transferred.
+unsafe fn wait_overlapped(handle: HANDLE, event: HANDLE, overlapped: &mut OVERLAPPED) -> Result<DWORD> {
+    if WaitForSingleObject(event, INFINITE) != WAIT_OBJECT_0 {
+        return Err(Error::last_os_error());
+    }
+    let mut transferred = 0;
+    if GetOverlappedResult(handle, overlapped, &mut transferred, 0) == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(transferred)
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        duplicate: true,
+        lock: true,
+        allocate: true,
+        extents: true,
+        zero_copy_send: true,
+        statvfs: true,
+    }
+}
+
+/// Paths shorter than this are converted to wide strings on the stack, rather than allocating.
+///
+/// This is also `MAX_PATH`, the longest path (including the terminating nul) which Win32
+/// functions accept without the `\\?\` prefix.
+const MAX_STACK_PATH: usize = 260;
+
+/// Calls `f` with `path` converted to a nul-terminated wide string.
+///
+/// Paths too long for the stack buffer are also too long for most Win32 functions, so they are
+/// passed to `f` in their extended-length form.
+fn with_wide_path<T, F>(path: &Path, f: F) -> Result<T> where F: FnOnce(&[u16]) -> Result<T> {
+    let mut buf = [0u16; MAX_STACK_PATH];
+    let mut len = 0;
+    for unit in path.as_os_str().encode_wide() {
+        if len + 1 == MAX_STACK_PATH {
+            return f(&long_path(path)?);
+        }
+        buf[len] = unit;
+        len += 1;
+    }
+    f(&buf[..=len])
+}
 
-        let mut sectors_per_cluster = 0;
-        let mut bytes_per_sector = 0;
-        let mut number_of_free_clusters = 0;
-        let mut total_number_of_clusters = 0;
-        let ret = GetDiskFreeSpaceW(root_path.as_ptr(),
-                                    &mut sectors_per_cluster,
-                                    &mut bytes_per_sector,
-                                    &mut number_of_free_clusters,
-                                    &mut total_number_of_clusters);
+/// Converts `path` to a nul-terminated wide string in extended-length (`\\?\`) form, which is
+/// not subject to `MAX_PATH`.
+///
+/// Extended-length paths are not normalized by Windows, so the path is made absolute and
+/// normalized with `GetFullPathNameW` first.
+fn long_path(path: &Path) -> Result<Vec<u16>> {
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    if is_verbatim(&wide) {
+        return Ok(wide);
+    }
+    let mut full = vec![0u16; wide.len() + MAX_STACK_PATH];
+    loop {
+        let len = unsafe {
+            GetFullPathNameW(wide.as_ptr(), full.len() as DWORD, full.as_mut_ptr(), ptr::null_mut())
+        } as usize;
+        if len == 0 {
+            return Err(Error::last_os_error());
+        } else if len < full.len() {
+            full.truncate(len);
+            return Ok(verbatim(&full));
+        }
+        // The buffer was too small, and `len` is the required size including the nul.
+        full.resize(len, 0);
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().collect()
+}
+
+/// Returns whether the wide path is already in extended-length (`\\?\`) or device (`\\.\`)
+/// form.
+fn is_verbatim(path: &[u16]) -> bool {
+    path.starts_with(&to_wide(r"\\?\")) || path.starts_with(&to_wide(r"\\.\"))
+}
+
+/// Converts an absolute, normalized wide path to a nul-terminated extended-length path.
+///
+/// Drive paths (`C:\dir`) gain the `\\?\` prefix, and UNC paths (`\\server\share\dir`)
+/// become `\\?\UNC\server\share\dir`.
+fn verbatim(full: &[u16]) -> Vec<u16> {
+    let mut path = Vec::with_capacity(full.len() + 8);
+    if full.starts_with(&to_wide(r"\\")) {
+        path.extend(to_wide(r"\\?\UNC\"));
+        path.extend_from_slice(&full[2..]);
+    } else {
+        path.extend(to_wide(r"\\?\"));
+        path.extend_from_slice(full);
+    }
+    path.push(0);
+    path
+}
+
+/// Converts an extended-length volume root back to its ordinary form if it is short enough, since
+/// some volume functions reject the `\\?\` prefix on drive and UNC roots. Volume GUID paths
+/// (`\\?\Volume{...}\`) are left unchanged. The root must be nul-terminated.
+fn simplify_root(root: Vec<u16>) -> Vec<u16> {
+    let unc = to_wide(r"\\?\UNC\");
+    let drive = to_wide(r"\\?\");
+    if root.len() > MAX_STACK_PATH {
+        root
+    } else if root.starts_with(&unc) {
+        let mut simple = to_wide(r"\\");
+        simple.extend_from_slice(&root[unc.len()..]);
+        simple
+    } else if root.starts_with(&drive) && root.get(drive.len() + 1) == Some(&(b':' as u16)) {
+        root[drive.len()..].to_vec()
+    } else {
+        root
+    }
+}
+
+/// Returns the final location of `path`, after following junctions and symlinks, or `path` itself
+/// if it can not be opened.
+fn resolve(path: &Path) -> PathBuf {
+    use std::os::windows::fs::OpenOptionsExt;
+    // Directories can only be opened with backup semantics.
+    OpenOptions::new().access_mode(FILE_READ_ATTRIBUTES)
+                      .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+                      .open(path)
+                      .and_then(|file| self::path(&file))
+                      .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns the nul-terminated root of the volume containing `path`. The root may be a drive
+/// (`C:\`), a UNC share (`\\server\share\`), a volume GUID path, or a mounted folder.
+///
+/// Junctions and symlinks are followed, so the root is of the volume the path actually leads to.
+fn volume_path(path: &Path) -> Result<Vec<u16>> {
+    with_wide_path(&resolve(path), |wide| unsafe {
+        // The root of a relative path may be longer than the path itself.
+        let mut root = vec![0u16; cmp::max(wide.len(), MAX_STACK_PATH) + 1];
+        let ret = GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as DWORD);
         if ret == 0 {
+            return Err(Error::last_os_error());
+        }
+        let len = root.iter().position(|&c| c == 0).unwrap_or(root.len());
+        root.truncate(len + 1);
+        Ok(simplify_root(root))
+    })
+}
+
+#[cfg(feature = "utilities")]
+pub fn open_tmpfile(_dir: &Path) -> Result<Option<File>> {
+    Ok(None)
+}
+
+/// Creates a file at `path` which is deleted when closed.
+#[cfg(feature = "utilities")]
+pub fn create_deleted(path: &Path) -> Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    OpenOptions::new().read(true).write(true).create_new(true)
+                      .attributes(FILE_ATTRIBUTE_TEMPORARY)
+                      .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+                      .open(path)
+}
+
+#[cfg(feature = "utilities")]
+pub fn link_tmpfile(_file: &File, _path: &Path) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported,
+                   "files opened with FILE_FLAG_DELETE_ON_CLOSE can not be persisted"))
+}
+
+/// Identifies the volume containing a path, by its root.
+#[cfg(feature = "statvfs")]
+pub type VolumeKey = Vec<u16>;
+
+#[cfg(feature = "statvfs")]
+pub fn volume_key(path: &Path) -> Result<VolumeKey> {
+    volume_path(path)
+}
+
+#[cfg(any(feature = "locks", feature = "statvfs"))]
+pub fn is_network_fs(path: &Path) -> Result<bool> {
+    let root = volume_path(path)?;
+    Ok(unsafe { GetDriveTypeW(root.as_ptr()) } == DRIVE_REMOTE)
+}
+
+#[cfg(feature = "locks")]
+pub fn is_network_fs_file(file: &File) -> Result<bool> {
+    is_network_fs(&self::path(file)?)
+}
+
+/// A path encoded once for repeated `statvfs` queries: the nul-terminated root of its volume.
+#[cfg(feature = "statvfs")]
+pub type StatvfsPath = Vec<u16>;
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_path(path: &Path) -> Result<StatvfsPath> {
+    volume_path(path)
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs(path: &Path) -> Result<FsStats> {
+    statvfs_encoded(&volume_path(path)?)
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_encoded(root_path: &[u16]) -> Result<FsStats> {
+    unsafe {
+        // The space available to the caller is reduced by any disk quota, so it comes from
+        // `GetDiskFreeSpaceExW`; the cluster counts are only used for the allocation granularity.
+        let mut available_space: ULARGE_INTEGER = mem::zeroed();
+        let mut total_space: ULARGE_INTEGER = mem::zeroed();
+        let mut free_space: ULARGE_INTEGER = mem::zeroed();
+        if GetDiskFreeSpaceExW(root_path.as_ptr(),
+                               &mut available_space,
+                               &mut total_space,
+                               &mut free_space) == 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // Not every volume reports its information (e.g. some network shares), so the ID
+        // and type are best effort.
+        let info = volume_information(root_path).ok();
+        Ok(FsStats {
+            free_space: *free_space.QuadPart(),
+            available_space: *available_space.QuadPart(),
+            total_space: *total_space.QuadPart(),
+            allocation_granularity: cluster_size(root_path)?,
+            filesystem_id: info.as_ref().map(|info| info.serial_number as u64),
+            kind: info.map(|info| FsKind::from_name(&info.fs_name)),
+        })
+    }
+}
+
+/// Returns the cluster size of the volume with the root `root_path`.
+#[cfg(any(feature = "allocate", feature = "statvfs", feature = "utilities"))]
+fn cluster_size(root_path: &[u16]) -> Result<u64> {
+    let mut sectors_per_cluster = 0;
+    let mut bytes_per_sector = 0;
+    let mut number_of_free_clusters = 0;
+    let mut total_number_of_clusters = 0;
+    if unsafe { GetDiskFreeSpaceW(root_path.as_ptr(),
+                                  &mut sectors_per_cluster,
+                                  &mut bytes_per_sector,
+                                  &mut number_of_free_clusters,
+                                  &mut total_number_of_clusters) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(sectors_per_cluster as u64 * bytes_per_sector as u64)
+}
+
+/// The information `GetVolumeInformationW` reports about a volume.
+struct VolumeInformation {
+    serial_number: DWORD,
+    max_component_length: DWORD,
+    flags: DWORD,
+    fs_name: String,
+}
+
+fn volume_information(root_path: &[u16]) -> Result<VolumeInformation> {
+    query_volume_information(|serial_number, max_component_length, flags, fs_name, fs_name_len| unsafe {
+        GetVolumeInformationW(root_path.as_ptr(),
+                              ptr::null_mut(),
+                              0,
+                              serial_number,
+                              max_component_length,
+                              flags,
+                              fs_name,
+                              fs_name_len)
+    })
+}
+
+/// Like `volume_information`, but for the volume containing `file`, without resolving its path.
+fn file_volume_information(file: &File) -> Result<VolumeInformation> {
+    query_volume_information(|serial_number, max_component_length, flags, fs_name, fs_name_len| unsafe {
+        GetVolumeInformationByHandleW(file.as_raw_handle(),
+                                      ptr::null_mut(),
+                                      0,
+                                      serial_number,
+                                      max_component_length,
+                                      flags,
+                                      fs_name,
+                                      fs_name_len)
+    })
+}
+
+fn query_volume_information<F>(query: F) -> Result<VolumeInformation>
+    where F: FnOnce(&mut DWORD, &mut DWORD, &mut DWORD, *mut u16, DWORD) -> BOOL
+{
+    let mut serial_number = 0;
+    let mut max_component_length = 0;
+    let mut flags = 0;
+    let mut fs_name = [0u16; MAX_PATH + 1];
+    let ret = query(&mut serial_number,
+                    &mut max_component_length,
+                    &mut flags,
+                    fs_name.as_mut_ptr(),
+                    fs_name.len() as DWORD);
+    if ret == 0 {
+        return Err(Error::last_os_error());
+    }
+    let len = fs_name.iter().position(|&c| c == 0).unwrap_or(fs_name.len());
+    Ok(VolumeInformation {
+        serial_number,
+        max_component_length,
+        flags,
+        fs_name: String::from_utf16_lossy(&fs_name[..len]),
+    })
+}
+
+#[cfg(feature = "statvfs")]
+pub fn statvfs_ext(path: &Path) -> Result<FsStatsExt> {
+    let info = volume_information(&volume_path(path)?)?;
+    let stats = statvfs(path)?;
+    let max_file_size = stats.kind().and_then(fs_kind::format_max_file_size);
+    Ok(FsStatsExt {
+        stats,
+        max_name_len: Some(info.max_component_length as u64),
+        max_file_size,
+        flags: Some(info.flags as u64),
+        fragment_size: None,
+        total_inodes: None,
+        free_inodes: None,
+        available_inodes: None,
+    })
+}
+
+#[cfg(feature = "allocate")]
+pub fn max_file_size(file: &File) -> Result<Option<u64>> {
+    // The volume of a handle can not always be queried (e.g. for pipes), and then no limit is
+    // known.
+    let info = match file_volume_information(file) {
+        Ok(info) => info,
+        Err(_) => return Ok(None),
+    };
+    Ok(fs_kind::format_max_file_size(&FsKind::from_name(&info.fs_name)))
+}
+
+/// Windows has no per-process file size limit.
+#[cfg(feature = "allocate")]
+pub fn file_size_limit() -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Returns the NetBIOS name of the machine, if it is set in the environment.
+#[cfg(feature = "utilities")]
+pub fn hostname() -> Option<String> {
+    ::std::env::var("COMPUTERNAME").ok()
+}
+
+/// Returns the name of the user, if it is set in the environment.
+#[cfg(feature = "utilities")]
+pub fn user_name() -> Option<String> {
+    ::std::env::var("USERNAME").ok()
+}
+
+/// Returns when the process `pid` started, or `None` if it is running but its start time can
+/// not be determined. Fails with `ErrorKind::NotFound` if there is no such process.
+#[cfg(feature = "utilities")]
+pub fn process_start_time(pid: u32) -> Result<Option<SystemTime>> {
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if process.is_null() {
+        let err = Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(code) if code == ERROR_INVALID_PARAMETER as i32 => Err(Error::new(ErrorKind::NotFound, "no such process")),
+            // A process which can not be opened because it belongs to another user still exists.
+            Some(code) if code == ERROR_ACCESS_DENIED as i32 => Ok(None),
+            _ => Err(err),
+        };
+    }
+    let mut exit_code: DWORD = 0;
+    let mut times: [FILETIME; 4] = unsafe { mem::zeroed() };
+    let result = unsafe {
+        if GetExitCodeProcess(process, &mut exit_code) == 0 {
             Err(Error::last_os_error())
+        } else if exit_code != STILL_ACTIVE {
+            // The process has exited, but another handle keeps its object alive.
+            Err(Error::new(ErrorKind::NotFound, "no such process"))
+        } else if GetProcessTimes(process, &mut times[0], &mut times[1], &mut times[2], &mut times[3]) == 0 {
+            Ok(None)
         } else {
-            let bytes_per_cluster = sectors_per_cluster as u64 * bytes_per_sector as u64;
-            let free_space = bytes_per_cluster * number_of_free_clusters as u64;
-            let total_space = bytes_per_cluster * total_number_of_clusters as u64;
-            Ok(FsStats {
-                free_space: free_space,
-                available_space: free_space,
-                total_space: total_space,
-                allocation_granularity: bytes_per_cluster,
-            })
+            // FILETIME counts 100ns intervals since 1601.
+            let intervals = (times[0].dwHighDateTime as u64) << 32 | times[0].dwLowDateTime as u64;
+            let since_1601 = Duration::new(intervals / 10_000_000, (intervals % 10_000_000) as u32 * 100);
+            Ok(since_1601.checked_sub(Duration::from_secs(11_644_473_600)).map(|since| UNIX_EPOCH + since))
         }
+    };
+    unsafe { CloseHandle(process) };
+    result
+}
+
+#[cfg(feature = "statvfs")]
+pub fn fstatvfs(file: &File) -> Result<FsStats> {
+    statvfs(&path(file)?)
+}
+
+#[cfg(any(feature = "allocate", feature = "utilities"))]
+pub fn allocation_granularity(file: &File) -> Result<u64> {
+    cluster_size(&volume_path(&path(file)?)?)
+}
+
+/// A view of a file mapping, unmapped when dropped.
+#[cfg(feature = "locks")]
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: LPVOID,
+    file: File,
+}
+
+/// Maps the first `len` bytes of `file` into memory, shared with other
+/// mappings of the file. `len` must not be 0.
+#[cfg(feature = "locks")]
+pub fn map(file: &File, len: usize, writable: bool) -> Result<Mmap> {
+    let (protect, access) = if writable {
+        (PAGE_READWRITE, FILE_MAP_WRITE)
+    } else {
+        (PAGE_READONLY, FILE_MAP_READ)
+    };
+    // The view is flushed through a handle to the file, and the mapping
+    // object can be closed once the view exists.
+    let file = duplicate(file)?;
+    unsafe {
+        let mapping = CreateFileMappingW(file.as_raw_handle(), ptr::null_mut(), protect, 0, 0, ptr::null());
+        if mapping.is_null() {
+            return Err(Error::last_os_error());
+        }
+        let mapping = File::from_raw_handle(mapping);
+        let ptr = MapViewOfFile(mapping.as_raw_handle(), access, 0, 0, len);
+        if ptr.is_null() {
+            return Err(Error::last_os_error());
+        }
+        Ok(Mmap { ptr, file })
+    }
+}
+
+#[cfg(feature = "locks")]
+impl Mmap {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr as *mut u8
+    }
+
+    /// Writes modified pages back to the file, and waits for them to be written.
+    pub fn flush(&self) -> Result<()> {
+        unsafe {
+            if FlushViewOfFile(self.ptr, 0) == 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        self.file.sync_data()
+    }
+}
+
+#[cfg(feature = "locks")]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            UnmapViewOfFile(self.ptr);
+        }
+    }
+}
+
+/// Files are not watched on Windows, where change notifications are only
+/// available for directories.
+#[cfg(feature = "utilities")]
+#[derive(Debug)]
+pub enum Watch {}
+
+#[cfg(feature = "utilities")]
+pub fn watch(_path: &Path) -> Result<Option<Watch>> {
+    Ok(None)
+}
+
+#[cfg(feature = "utilities")]
+impl Watch {
+    pub fn wait(&self, _timeout: Option<Duration>) -> Result<()> {
+        match *self {}
     }
 }
 
@@ -159,101 +1177,125 @@ mod test {
 
     extern crate tempdir;
 
+    #[cfg(feature = "statvfs")]
+    use std::ffi::OsString;
     use std::fs;
+    use std::io::{self, Write};
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    use std::io::{Seek, SeekFrom};
+    #[cfg(feature = "locks")]
+    use std::io::ErrorKind;
+    use std::os::windows::ffi::OsStrExt;
+    #[cfg(feature = "statvfs")]
+    use std::os::windows::ffi::OsStringExt;
+    use std::os::windows::fs::OpenOptionsExt;
+    #[cfg(feature = "duplicate")]
     use std::os::windows::io::AsRawHandle;
+    use std::path::{Path, PathBuf};
 
-    use {FileExt, lock_contended_error};
+    #[cfg(feature = "statvfs")]
+    use winapi::um::fileapi::GetVolumeNameForVolumeMountPointW;
+    use winapi::um::winnt::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+    use FileExt;
+    #[cfg(feature = "locks")]
+    use lock_contended_error;
 
     /// The duplicate method returns a file with a new file handle.
+    #[cfg(feature = "duplicate")]
     #[test]
     fn duplicate_new_handle() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
         let file2 = file1.duplicate().unwrap();
         assert!(file1.as_raw_handle() != file2.as_raw_handle());
     }
 
     /// A duplicated file handle does not have access to the original handle's locks.
+    #[cfg(all(feature = "duplicate", feature = "locks"))]
     #[test]
     fn lock_duplicate_handle_independence() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
         let file2 = file1.duplicate().unwrap();
 
         // Locking the original file handle will block the duplicate file handle from opening a lock.
-        file1.lock_shared().unwrap();
-        assert_eq!(file2.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        FileExt::lock_shared(&file1).unwrap();
+        assert_eq!(file2.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
 
         // Once the original file handle is unlocked, the duplicate handle can proceed with a lock.
-        file1.unlock().unwrap();
+        FileExt::unlock(&file1).unwrap();
         file2.lock_exclusive().unwrap();
     }
 
     /// A file handle may not be exclusively locked multiple times, or exclusively locked and then
     /// shared locked.
+    #[cfg(feature = "locks")]
     #[test]
     fn lock_non_reentrant() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
         // Multiple exclusive locks fails.
         file.lock_exclusive().unwrap();
-        assert_eq!(file.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
-        file.unlock().unwrap();
+        assert_eq!(file.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
+        FileExt::unlock(&file).unwrap();
 
         // Shared then Exclusive locks fails.
-        file.lock_shared().unwrap();
-        assert_eq!(file.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        FileExt::lock_shared(&file).unwrap();
+        assert_eq!(file.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
     }
 
     /// A file handle can hold an exclusive lock and any number of shared locks, all of which must
     /// be unlocked independently.
+    #[cfg(feature = "locks")]
     #[test]
     fn lock_layering() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
         // Open two shared locks on the file, and then try and fail to open an exclusive lock.
         file.lock_exclusive().unwrap();
-        file.lock_shared().unwrap();
-        file.lock_shared().unwrap();
-        assert_eq!(file.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        FileExt::lock_shared(&file).unwrap();
+        FileExt::lock_shared(&file).unwrap();
+        assert_eq!(file.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
 
         // Pop one of the shared locks and try again.
-        file.unlock().unwrap();
-        assert_eq!(file.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        FileExt::unlock(&file).unwrap();
+        assert_eq!(file.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
 
         // Pop the second shared lock and try again.
-        file.unlock().unwrap();
-        assert_eq!(file.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        FileExt::unlock(&file).unwrap();
+        assert_eq!(file.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
 
         // Pop the exclusive lock and finally succeed.
-        file.unlock().unwrap();
+        FileExt::unlock(&file).unwrap();
         file.lock_exclusive().unwrap();
     }
 
     /// A file handle with multiple open locks will have all locks closed on drop.
+    #[cfg(feature = "locks")]
     #[test]
     fn lock_layering_cleanup() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
-        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let file2 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
 
         // Open two shared locks on the file, and then try and fail to open an exclusive lock.
-        file1.lock_shared().unwrap();
-        assert_eq!(file2.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        FileExt::lock_shared(&file1).unwrap();
+        assert_eq!(file2.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
 
         drop(file1);
         file2.lock_exclusive().unwrap();
@@ -261,19 +1303,145 @@ mod test {
 
     /// A file handle's locks will not be released until the original handle and all of its
     /// duplicates have been closed. This on really smells like a bug in Windows.
+    #[cfg(all(feature = "duplicate", feature = "locks"))]
     #[test]
     fn lock_duplicate_cleanup() {
         let tempdir = tempdir::TempDir::new("fs2").unwrap();
         let path = tempdir.path().join("fs2");
-        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let file1 = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
         let file2 = file1.duplicate().unwrap();
 
         // Open a lock on the original handle, then close it.
-        file1.lock_shared().unwrap();
+        FileExt::lock_shared(&file1).unwrap();
         drop(file1);
 
         // Attempting to create a lock on the file with the duplicate handle will fail.
-        assert_eq!(file2.try_lock_exclusive().unwrap_err().raw_os_error(),
-                   lock_contended_error().raw_os_error());
+        assert_eq!(file2.try_lock_exclusive().unwrap_err().kind(),
+                   lock_contended_error().kind());
+    }
+
+    /// Locking a handle without read or write data access fails with a
+    /// permission denied error.
+    #[cfg(feature = "locks")]
+    #[test]
+    fn lock_without_data_access() {
+        const FILE_READ_ATTRIBUTES: u32 = 0x80;
+
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let file = fs::OpenOptions::new().access_mode(FILE_READ_ATTRIBUTES).open(&path).unwrap();
+
+        assert_eq!(ErrorKind::PermissionDenied, file.lock_exclusive().unwrap_err().kind());
+        assert_eq!(ErrorKind::PermissionDenied,
+                   FileExt::try_lock_shared(&file).unwrap_err().kind());
+    }
+
+    /// Paths are converted to wide strings whether or not they fit on the
+    /// stack, and paths too long for the stack are given the extended-length
+    /// prefix.
+    #[test]
+    fn wide_path_conversion() {
+        let short = Path::new("C:\\");
+        let converted = super::with_wide_path(short, |wide| Ok(wide.to_vec())).unwrap();
+        assert_eq!(super::to_wide("C:\\\0"), converted);
+
+        let long = PathBuf::from(format!("C:\\{}", "a".repeat(super::MAX_STACK_PATH)));
+        let converted = super::with_wide_path(&long, |wide| Ok(wide.to_vec())).unwrap();
+        let mut expected = super::to_wide("\\\\?\\");
+        expected.extend(long.as_os_str().encode_wide().chain(Some(0)));
+        assert_eq!(expected, converted);
+    }
+
+    /// Drive and UNC paths are converted to extended-length form and back.
+    #[test]
+    fn verbatim_paths() {
+        use super::{simplify_root, to_wide, verbatim};
+
+        assert_eq!(to_wide("\\\\?\\C:\\dir\0"), verbatim(&to_wide("C:\\dir")));
+        assert_eq!(to_wide("\\\\?\\UNC\\server\\share\\dir\0"),
+                   verbatim(&to_wide("\\\\server\\share\\dir")));
+
+        assert_eq!(to_wide("C:\\\0"), simplify_root(to_wide("\\\\?\\C:\\\0")));
+        assert_eq!(to_wide("\\\\server\\share\\\0"),
+                   simplify_root(to_wide("\\\\?\\UNC\\server\\share\\\0")));
+        let guid = to_wide("\\\\?\\Volume{00000000-0000-0000-0000-000000000000}\\\0");
+        assert_eq!(guid, simplify_root(guid.clone()));
+    }
+
+    /// Filesystem stats can be queried for paths longer than `MAX_PATH`.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn statvfs_long_path() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let mut path = tempdir.path().to_path_buf();
+        while path.as_os_str().len() <= super::MAX_STACK_PATH {
+            path.push("node_modules");
+        }
+        fs::create_dir_all(&path).unwrap();
+
+        let expected = ::statvfs(tempdir.path()).unwrap();
+        assert_eq!(expected.total_space(), ::statvfs(&path).unwrap().total_space());
+        assert_eq!(expected.total_space(), ::total_space(&path).unwrap());
+    }
+
+    /// Filesystem stats can be queried through a volume GUID path.
+    #[cfg(feature = "statvfs")]
+    #[test]
+    fn statvfs_volume_guid() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let root = super::volume_path(tempdir.path()).unwrap();
+        let mut guid = vec![0u16; 50];
+        let ret = unsafe {
+            GetVolumeNameForVolumeMountPointW(root.as_ptr(), guid.as_mut_ptr(), guid.len() as u32)
+        };
+        assert!(ret != 0, "{}", io::Error::last_os_error());
+        let len = guid.iter().position(|&c| c == 0).unwrap();
+        let guid = PathBuf::from(OsString::from_wide(&guid[..len]));
+
+        let expected = ::statvfs(tempdir.path()).unwrap();
+        assert_eq!(expected.total_space(), ::statvfs(&guid).unwrap().total_space());
+    }
+
+    /// The allocated size of a sparse file counts only the clusters stored on
+    /// disk.
+    #[cfg(all(feature = "allocate", feature = "statvfs"))]
+    #[test]
+    fn allocated_size_sparse() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let blksize = ::allocation_granularity(&path).unwrap();
+
+        file.mark_sparse().unwrap();
+        file.set_len(1024 * blksize).unwrap();
+        assert!(file.allocated_size().unwrap() < blksize);
+
+        // Writing at the end of the file allocates a cluster there.
+        file.seek(SeekFrom::Start(1023 * blksize)).unwrap();
+        file.write_all(&vec![1; blksize as usize]).unwrap();
+        file.sync_all().unwrap();
+        let allocated = file.allocated_size().unwrap();
+        assert!(allocated >= blksize && allocated < 1024 * blksize);
+
+        // Truncating the written cluster away releases it.
+        file.set_len(512 * blksize).unwrap();
+        assert!(file.allocated_size().unwrap() < allocated);
+    }
+
+    /// Tests that a file is reopened at its current path, so renaming it does
+    /// not matter.
+    #[test]
+    fn reopen_renamed() {
+        let tempdir = tempdir::TempDir::new("fs2").unwrap();
+        let path = tempdir.path().join("fs2");
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                                             .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+                                             .open(&path).unwrap();
+        file.write_all(b"fs2").unwrap();
+        fs::rename(&path, tempdir.path().join("renamed")).unwrap();
+
+        let reopened = file.reopen(fs::OpenOptions::new().read(true)).unwrap();
+        assert_eq!(b"fs2".to_vec(), io::Read::bytes(reopened).map(|b| b.unwrap()).collect::<Vec<_>>());
     }
 }